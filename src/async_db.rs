@@ -0,0 +1,70 @@
+use std::ops::Bound;
+
+use crate::db::HanoiDB;
+use crate::error::*;
+use crate::shared::SharedHanoiDB;
+
+/// A handle to a database for use from async code, available with the `tokio`
+/// feature. Every operation runs the blocking API on tokio's blocking thread pool,
+/// so disk IO and any merges a write triggers don't hold up the event loop. Handles
+/// are cheap to clone and share the same database.
+#[derive(Clone)]
+pub struct AsyncHanoiDB(SharedHanoiDB);
+
+impl AsyncHanoiDB {
+    pub fn new(db: HanoiDB) -> Self {
+        Self(SharedHanoiDB::new(db))
+    }
+
+    /// Looks up a key in the database and returns its value if it is present.
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let db = self.0.clone();
+        blocking(move || db.get(&key)).await
+    }
+
+    /// Inserts a key-value pair into the database.
+    pub async fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let db = self.0.clone();
+        blocking(move || db.insert(key, value)).await
+    }
+
+    /// Deletes a key from the database.
+    pub async fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let db = self.0.clone();
+        blocking(move || db.delete(key)).await
+    }
+
+    /// Returns the keys and values in the database that fall between `from` and
+    /// `to`, in ascending key order. The whole range is read before it is returned.
+    pub async fn range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let db = self.0.clone();
+        blocking(move || db.range(from, to)?.collect()).await
+    }
+
+    /// Returns the blocking handle to the same database.
+    pub fn shared(&self) -> &SharedHanoiDB {
+        &self.0
+    }
+}
+
+impl From<HanoiDB> for AsyncHanoiDB {
+    fn from(db: HanoiDB) -> Self {
+        Self::new(db)
+    }
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        // Blocking tasks can't be cancelled, so the task must have panicked
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
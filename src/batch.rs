@@ -0,0 +1,49 @@
+use crate::entry::Entry;
+
+/// A group of inserts and deletes that are written to the database atomically.
+/// Operations are applied in the order they were added, so a later operation on
+/// the same key wins.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    entries: Vec<Entry>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an insert of a key-value pair to the batch.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.entries.push(Entry::KeyVal {
+            key,
+            value,
+            timestamp: None,
+            write_timestamp: None,
+        });
+    }
+
+    /// Adds a delete of a key to the batch.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.entries.push(Entry::Deleted {
+            key,
+            timestamp: None,
+            write_timestamp: None,
+        });
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the batch contains no operations.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn into_entries(self) -> Vec<Entry> {
+        self.entries
+    }
+}
@@ -1,38 +1,66 @@
 use crate::compression::Compression;
-use crate::entry::Entry;
+use crate::counters::Counters;
+use crate::entry::{Entry, EntryStart};
 use crate::error::*;
+use crate::file_pool::TreeFile;
 use crate::TAG_END;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
 
+/// Set in the compression byte of a block header when the keys in the block are
+/// prefix compressed.
+pub(crate) const BLOCK_PREFIX_KEYS: u8 = 0x80;
+
+/// A block in a tree file. On disk a block is laid out as
+///
+/// ```text
+/// <<blocklen:32, level:16, prefix_keys:1, compression:7, contents:(blocklen - 3)/binary>>
+/// ```
+///
+/// The header is 7 bytes. After decompression, the contents of a block with
+/// entries start with a `TAG_END` byte, followed by the entries, each of which
+/// ends with its own `TAG_END`. If `prefix_keys` is set, each entry is preceded by
+/// `<<shared:16>>`, and its key only holds what follows the first `shared` bytes of
+/// the previous entry's key.
 #[derive(Debug)]
-pub struct Block<'a> {
+pub struct Block {
     pub start: u64,
     pub blocklen: u32,
     pub level: u16,
     pub compression: Compression,
-    file: &'a File,
+    pub prefix_keys: bool,
+    file: TreeFile,
+    counters: Counters,
 }
 
-impl<'a> Block<'a> {
-    pub fn from_start(mut file: &'a File, start: u64) -> Result<Self> {
-        file.seek(SeekFrom::Start(start))?;
+impl Block {
+    pub fn from_start(file: &TreeFile, start: u64) -> Result<Self> {
+        let open_file = file.get()?;
+        let mut reader = open_file.as_ref();
+        reader.seek(SeekFrom::Start(start))?;
         let mut header = vec![0; 7];
-        file.read_exact(&mut header)?;
+        reader.read_exact(&mut header)?;
         let blocklen = u32::from_be_bytes(header[0..4].try_into()?);
         let level = u16::from_be_bytes(header[4..6].try_into()?);
-        let compression: Compression = header[6].try_into()?;
+        let compression: Compression = (header[6] & !BLOCK_PREFIX_KEYS).try_into()?;
 
         Ok(Self {
             start,
             blocklen,
             level,
             compression,
-            file,
+            prefix_keys: header[6] & BLOCK_PREFIX_KEYS != 0,
+            file: file.clone(),
+            counters: Counters::default(),
         })
     }
 
-    pub fn from_start_length(file: &'a File, start: u64, length: u32) -> Result<Self> {
+    /// Counts the reads of the block's contents in `counters`.
+    pub(crate) fn with_counters(mut self, counters: Counters) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    pub fn from_start_length(file: &TreeFile, start: u64, length: u32) -> Result<Self> {
         let block = Self::from_start(file, start)?;
         if block.blocklen == length {
             Ok(block)
@@ -42,13 +70,42 @@ impl<'a> Block<'a> {
     }
 
     pub fn entries(&self) -> Result<EntryIterator> {
-        let mut decompressor = self.compression.reader(BlockContentsReader::new(self)?);
+        Ok(EntryIterator {
+            contents: Some(self.contents()?),
+            prefix_keys: self.prefix_keys,
+            last_key: vec![],
+        })
+    }
 
+    /// Returns a reader over the decompressed entries of the block, positioned at
+    /// the first entry.
+    fn contents(&self) -> Result<Box<dyn Read + Send>> {
+        let contents = BlockContentsReader::new(self)?;
+        self.counters.block_read(4 + self.blocklen as u64);
+        if self.compression == Compression::None {
+            return self.start_entries(Box::new(contents));
+        }
+        // A block that doesn't decompress, or doesn't start like a block once it
+        // does, was most likely written with a different compression than its
+        // header claims
+        self.compression
+            .reader(contents)
+            .and_then(|decompressor| self.start_entries(decompressor))
+            .map_err(|_| Error::CorruptedBlock {
+                pos: self.start,
+                source: Box::new(Error::CorruptedFile("compression/header mismatch")),
+            })
+    }
+
+    fn start_entries(
+        &self,
+        mut decompressor: Box<dyn Read + Send>,
+    ) -> Result<Box<dyn Read + Send>> {
         // SAFETY: If the blocklen is 0, then reading from the block will never fill
         // a buffer because start > end. Therefore we don't need to check for the tag
         // byte or advance the reader at all.
         if self.blocklen == 0 {
-            return Ok(EntryIterator(decompressor));
+            return Ok(decompressor);
         }
 
         // Each block that has entries contains a TAG_END byte at the beginning.
@@ -58,7 +115,7 @@ impl<'a> Block<'a> {
         decompressor.read_exact(&mut tag)?;
 
         if tag[0] == TAG_END {
-            Ok(EntryIterator(decompressor))
+            Ok(decompressor)
         } else {
             Err(Error::CorruptedFile(
                 "block entries did not start with TAG_END",
@@ -67,15 +124,17 @@ impl<'a> Block<'a> {
     }
 }
 
+/// Reads the contents of a block. The file is fetched again for every read, so a
+/// pooled file that was closed in between is opened again.
 struct BlockContentsReader {
-    file: File,
+    file: TreeFile,
     start: u64,
     end: u64,
 }
 
 impl BlockContentsReader {
     fn new(block: &Block) -> Result<Self> {
-        let file = block.file.try_clone()?;
+        let file = block.file.clone();
         // The 4-byte blocklen field at the head of the block is not included in the length
         let start_after_blocklen = block.start + 4;
         Ok(BlockContentsReader {
@@ -90,13 +149,17 @@ impl BlockContentsReader {
 
 impl Read for BlockContentsReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.start > self.end {
+        if self.start >= self.end {
             return Ok(0);
         }
 
-        self.file.seek(SeekFrom::Start(self.start))?;
+        let file = self.file.get()?;
+        let mut file = file.as_ref();
+        file.seek(SeekFrom::Start(self.start))?;
 
-        let result = self.file.read(buf);
+        // Never read past the end of the block, into the next block or the trailer
+        let remaining = (self.end - self.start).min(buf.len() as u64) as usize;
+        let result = file.read(&mut buf[..remaining]);
         if let Ok(len) = result {
             self.start += len as u64;
         }
@@ -104,23 +167,256 @@ impl Read for BlockContentsReader {
     }
 }
 
-pub struct EntryIterator(Box<dyn Read>);
+pub struct EntryIterator {
+    contents: Option<Box<dyn Read + Send>>,
+    prefix_keys: bool,
+    /// The key of the last entry read, which the next key shares a prefix with if
+    /// the keys are prefix compressed.
+    last_key: Vec<u8>,
+}
+
+impl EntryIterator {
+    /// Reads the next entry like `Entry::read_until_value`. After a `KeyVal`, the
+    /// value and its `TAG_END` must be read with `into_contents` or passed over with
+    /// `skip_bytes` before reading on.
+    pub(crate) fn read_until_value(&mut self) -> Result<EntryStart> {
+        let shared = self.read_shared_len()?;
+        let contents = self.contents.as_mut().ok_or(Error::EndOfFile)?;
+        let mut start = Entry::read_until_value(contents)?;
+        match &mut start {
+            EntryStart::KeyVal { key, .. } => self.restore_key(shared, key)?,
+            EntryStart::Other(entry) => self.restore_entry_key(shared, entry)?,
+        }
+        Ok(start)
+    }
+
+    /// Passes over `len` bytes of the block.
+    pub(crate) fn skip_bytes(&mut self, len: u64) -> Result<()> {
+        let contents = self.contents.as_mut().ok_or(Error::EndOfFile)?;
+        std::io::copy(&mut contents.take(len), &mut std::io::sink())?;
+        Ok(())
+    }
+
+    /// Returns the rest of the block's contents.
+    pub(crate) fn into_contents(self) -> Result<Box<dyn Read + Send>> {
+        self.contents.ok_or(Error::EndOfFile)
+    }
+
+    fn read_entry(&mut self) -> Result<Entry> {
+        let shared = self.read_shared_len()?;
+        let contents = self.contents.as_mut().ok_or(Error::EndOfFile)?;
+        let mut entry = Entry::read(contents)?;
+        self.restore_entry_key(shared, &mut entry)?;
+        Ok(entry)
+    }
+
+    fn read_shared_len(&mut self) -> Result<usize> {
+        if !self.prefix_keys {
+            return Ok(0);
+        }
+        let contents = self.contents.as_mut().ok_or(Error::EndOfFile)?;
+        let mut shared = [0; 2];
+        contents.read_exact(&mut shared).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                Error::EndOfFile
+            } else {
+                err.into()
+            }
+        })?;
+        Ok(u16::from_be_bytes(shared) as usize)
+    }
+
+    fn restore_entry_key(&mut self, shared: usize, entry: &mut Entry) -> Result<()> {
+        match entry.key_mut() {
+            Some(key) => self.restore_key(shared, key),
+            None => Ok(()),
+        }
+    }
+
+    /// Puts the shared prefix back in front of a prefix compressed key.
+    fn restore_key(&mut self, shared: usize, key: &mut Vec<u8>) -> Result<()> {
+        if !self.prefix_keys {
+            return Ok(());
+        }
+        let prefix = self.last_key.get(..shared).ok_or(Error::CorruptedFile(
+            "shared key prefix is longer than the last key",
+        ))?;
+        key.splice(0..0, prefix.iter().copied());
+        self.last_key.clone_from(key);
+        Ok(())
+    }
+}
 
 impl Iterator for EntryIterator {
-    type Item = Entry;
+    type Item = Result<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Entry::read(&mut self.0).ok()
+        self.contents.as_ref()?;
+        match self.read_entry() {
+            Ok(entry) => Some(Ok(entry)),
+            Err(err) => {
+                // Stop after the end of the block or the first error
+                self.contents = None;
+                match err {
+                    Error::EndOfFile => None,
+                    err => Some(Err(err)),
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::tree::Tree;
-    use crate::writer::Writer;
+    use crate::writer::{Writer, WriterOptions, MIN_BLOCK_SIZE};
 
     use tempfile::tempdir;
 
+    #[test]
+    fn block_layout() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        let entry = Entry::KeyVal {
+            key: "key".as_bytes().to_vec(),
+            value: "value".as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        writer.add(entry.clone()).unwrap();
+        writer.close().unwrap();
+
+        // The first block starts right after the magic
+        let contents = std::fs::read(&data).unwrap();
+        let header = &contents[4..11];
+        let encoded = entry.encode().unwrap();
+        let blocklen = 2 + 1 + 1 + encoded.len() as u32;
+        assert_eq!(header[0..4], blocklen.to_be_bytes());
+        assert_eq!(header[4..6], 0u16.to_be_bytes());
+        assert_eq!(header[6], Compression::None as u8);
+        assert_eq!(contents[11], TAG_END);
+        assert_eq!(contents[12..12 + encoded.len()], encoded);
+
+        let file = TreeFile::from(std::fs::File::open(&data).unwrap());
+        let block = Block::from_start(&file, 4).unwrap();
+        assert_eq!(block.blocklen, blocklen);
+        assert_eq!(block.level, 0);
+        assert_eq!(block.compression, Compression::None);
+        let entries: Vec<_> = block.entries().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(entries, vec![entry]);
+    }
+
+    #[test]
+    fn blocks_must_start_with_tag_end() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        writer
+            .add(Entry::KeyVal {
+                key: "key".as_bytes().to_vec(),
+                value: "value".as_bytes().to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let mut contents = std::fs::read(&data).unwrap();
+        contents[11] = 0;
+        std::fs::write(&data, contents).unwrap();
+
+        let file = TreeFile::from(std::fs::File::open(&data).unwrap());
+        let block = Block::from_start(&file, 4).unwrap();
+        assert!(matches!(block.entries(), Err(Error::CorruptedFile(_))));
+    }
+
+    #[test]
+    fn mismatched_compression_is_reported() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        writer
+            .add(Entry::KeyVal {
+                key: "key".as_bytes().to_vec(),
+                value: "value".as_bytes().to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        // Claim that the uncompressed first block is gzipped
+        let mut contents = std::fs::read(&data).unwrap();
+        contents[4 + 6] = Compression::Gzip as u8;
+        std::fs::write(&data, contents).unwrap();
+
+        let file = TreeFile::from(std::fs::File::open(&data).unwrap());
+        let block = Block::from_start(&file, 4).unwrap();
+        let Err(Error::CorruptedBlock { pos, source }) = block.entries() else {
+            panic!("mismatched compression was not reported");
+        };
+        assert_eq!(pos, 4);
+        assert!(matches!(
+            *source,
+            Error::CorruptedFile("compression/header mismatch")
+        ));
+    }
+
+    #[test]
+    fn reads_stop_at_block_boundary() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let options = WriterOptions {
+            block_size: MIN_BLOCK_SIZE,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_options(&data, options).unwrap();
+        for i in 0..100 {
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("key-{i:03}").into_bytes(),
+                    value: vec![b'v'; 20],
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        let root = tree.root_block().unwrap();
+        let children: Vec<_> = root.entries().unwrap().map(Result::unwrap).collect();
+        let first = tree.block_from_poslen_entry(&children[0]).unwrap();
+        let second = tree.block_from_poslen_entry(&children[1]).unwrap();
+        assert_eq!(first.level, 0);
+        assert_eq!(first.start + 4 + first.blocklen as u64, second.start);
+
+        // A single read into a large buffer stops at the end of the block
+        let mut reader = BlockContentsReader::new(&first).unwrap();
+        let mut buf = vec![0; 64 * 1024];
+        let len = reader.read(&mut buf).unwrap();
+        assert_eq!(len, first.blocklen as usize - 3);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let first_keys: Vec<Vec<u8>> = first
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().key().to_vec())
+            .collect();
+        let second_key = second.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(first_keys[0], b"key-000");
+        assert_eq!(
+            first_keys.last(),
+            Some(&format!("key-{:03}", first_keys.len() - 1).into_bytes())
+        );
+        assert_eq!(
+            second_key.key(),
+            format!("key-{:03}", first_keys.len()).as_bytes()
+        );
+    }
+
     #[test]
     fn block_from_start_accepts_empty_blocks() {
         let dir = tempdir().unwrap();
@@ -131,4 +427,35 @@ mod tests {
         let root_block = tree.root_block().unwrap();
         assert_eq!(root_block.entries().unwrap().count(), 0)
     }
+
+    #[test]
+    fn corrupted_entries_are_reported() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        for key in ["a", "b", "c"] {
+            writer
+                .add(Entry::KeyVal {
+                    key: key.as_bytes().to_vec(),
+                    value: key.as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        // Flip a byte in the value of the second entry:
+        // magic (4) + block header (7) + TAG_END (1) + first entry (16) + entry
+        // header (8) + tag (1) + key length (4) + key (1)
+        let mut contents = std::fs::read(&data).unwrap();
+        contents[4 + 7 + 1 + 16 + 8 + 1 + 4 + 1] ^= 0xFF;
+        std::fs::write(&data, contents).unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        let entries: Vec<_> = tree.root_block().unwrap().entries().unwrap().collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_ok());
+        assert!(matches!(entries[1], Err(Error::CorruptedFile(_))));
+    }
 }
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::entry::Entry;
+use crate::error::*;
+
+/// Identifies a block: the id of the tree file it was read from (see
+/// `next_file_id`) and the block's position in the file.
+pub(crate) type BlockKey = (u64, u64);
+
+/// The decoded entries of a block, along with the block's level in its tree.
+#[derive(Debug)]
+pub(crate) struct BlockEntries {
+    pub level: u16,
+    pub entries: Vec<Entry>,
+}
+
+impl BlockEntries {
+    fn size(&self) -> usize {
+        self.entries.iter().map(Entry::encoded_size).sum()
+    }
+}
+
+/// Returns a new id for a tree file that was just opened. Tree files are never
+/// modified once written, so blocks cached under an id never go stale.
+pub(crate) fn next_file_id() -> u64 {
+    static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A least-recently-used cache of decoded blocks, bounded by the encoded size of
+/// the entries it holds.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    size: usize,
+    tick: u64,
+    blocks: HashMap<BlockKey, (Arc<BlockEntries>, u64)>,
+    // Keys by the tick at which they were last used, oldest first
+    lru: BTreeMap<u64, BlockKey>,
+    loads: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Default::default(),
+        }
+    }
+
+    /// Returns the cached block for `key`, or loads it with `load` and caches it.
+    /// Blocks larger than the whole cache are returned without being cached.
+    pub fn get_or_load(
+        &self,
+        key: BlockKey,
+        load: impl FnOnce() -> Result<BlockEntries>,
+    ) -> Result<Arc<BlockEntries>> {
+        if let Some(block) = self.lock().get(key) {
+            return Ok(block);
+        }
+        // Load without holding the lock, so that other lookups aren't held up by
+        // the IO. Two threads may both load the same block, which is harmless.
+        let block = Arc::new(load()?);
+        let mut inner = self.lock();
+        inner.loads += 1;
+        inner.insert(key, block.clone(), self.capacity);
+        Ok(block)
+    }
+
+    /// Returns the number of blocks that were loaded into the cache.
+    #[cfg(test)]
+    pub fn loads(&self) -> u64 {
+        self.lock().loads
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        // WONT PANIC: nothing panics while holding the lock
+        self.inner.lock().expect("block cache lock poisoned")
+    }
+}
+
+impl CacheInner {
+    fn get(&mut self, key: BlockKey) -> Option<Arc<BlockEntries>> {
+        self.tick += 1;
+        let (block, tick) = self.blocks.get_mut(&key)?;
+        self.lru.remove(tick);
+        *tick = self.tick;
+        self.lru.insert(self.tick, key);
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Arc<BlockEntries>, capacity: usize) {
+        let size = block.size();
+        if size > capacity || self.blocks.contains_key(&key) {
+            return;
+        }
+        while self.size + size > capacity {
+            // WONT PANIC: the cache holds at least one block while it is over capacity
+            let (_, oldest) = self.lru.pop_first().unwrap();
+            let (evicted, _) = self.blocks.remove(&oldest).unwrap();
+            self.size -= evicted.size();
+        }
+        self.tick += 1;
+        self.size += size;
+        self.blocks.insert(key, (block, self.tick));
+        self.lru.insert(self.tick, key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(value_len: usize) -> BlockEntries {
+        BlockEntries {
+            level: 0,
+            entries: vec![Entry::KeyVal {
+                key: b"key".to_vec(),
+                value: vec![0; value_len],
+                timestamp: None,
+                write_timestamp: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let size = block(100).size();
+        let cache = BlockCache::new(size * 2);
+        cache.get_or_load((0, 0), || Ok(block(100))).unwrap();
+        cache.get_or_load((0, 1), || Ok(block(100))).unwrap();
+        // Use the first block, so that the second one is evicted
+        cache.get_or_load((0, 0), || unreachable!()).unwrap();
+        cache.get_or_load((0, 2), || Ok(block(100))).unwrap();
+        assert_eq!(cache.loads(), 3);
+        cache.get_or_load((0, 0), || unreachable!()).unwrap();
+        cache.get_or_load((0, 2), || unreachable!()).unwrap();
+        cache.get_or_load((0, 1), || Ok(block(100))).unwrap();
+        assert_eq!(cache.loads(), 4);
+        assert!(cache.lock().size <= size * 2);
+    }
+
+    #[test]
+    fn oversized_blocks_are_not_cached() {
+        let cache = BlockCache::new(10);
+        cache.get_or_load((0, 0), || Ok(block(100))).unwrap();
+        cache.get_or_load((0, 0), || Ok(block(100))).unwrap();
+        assert_eq!(cache.loads(), 2);
+        assert!(cache.lock().blocks.is_empty());
+    }
+}
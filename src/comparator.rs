@@ -0,0 +1,89 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// Orders keys for a database opened with `OpenOptions::with_comparator`. It must be
+/// a total order, and it must return `Ordering::Equal` only for identical keys.
+pub type Comparator = Arc<dyn Fn(&[u8], &[u8]) -> Ordering + Send + Sync>;
+
+/// The order of the keys in a database: bytewise, unless a `Comparator` was given.
+#[derive(Clone, Default)]
+pub(crate) struct KeyOrder(Option<Comparator>);
+
+impl KeyOrder {
+    pub fn new(comparator: Option<Comparator>) -> Self {
+        Self(comparator)
+    }
+
+    pub fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match &self.0 {
+            Some(comparator) => comparator(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    pub fn is_bytewise(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Checks whether any of the `start..end` ranges covers the key.
+    pub fn covers(&self, ranges: &[(Vec<u8>, Vec<u8>)], key: &[u8]) -> bool {
+        ranges.iter().any(|(start, end)| {
+            self.cmp(start, key) != Ordering::Greater && self.cmp(key, end) == Ordering::Less
+        })
+    }
+}
+
+impl std::fmt::Debug for KeyOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("KeyOrder::Custom"),
+            None => f.write_str("KeyOrder::Bytewise"),
+        }
+    }
+}
+
+impl PartialEq for KeyOrder {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A key that sorts by its `KeyOrder`, for keeping keys in a `BTreeMap`.
+#[derive(Debug, Clone)]
+pub(crate) struct OrderedKey {
+    pub key: Vec<u8>,
+    order: KeyOrder,
+}
+
+impl OrderedKey {
+    pub fn new(key: Vec<u8>, order: &KeyOrder) -> Self {
+        Self {
+            key,
+            order: order.clone(),
+        }
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order.cmp(&self.key, &other.key)
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 
 use crate::error::*;
 
@@ -11,6 +11,7 @@ pub enum Compression {
     Snappy = 1,
     Gzip = 2,
     Lz4 = 3,
+    Zstd = 4,
 }
 
 impl TryFrom<u8> for Compression {
@@ -22,13 +23,17 @@ impl TryFrom<u8> for Compression {
             1 => Ok(Compression::Snappy),
             2 => Ok(Compression::Gzip),
             3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Zstd),
             _ => Err(Error::InvalidCompression(value)),
         }
     }
 }
 
 impl Compression {
-    pub fn compress(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+    /// Compresses a block. The `level` trades speed for size; `None` uses the
+    /// algorithm's default. Gzip accepts levels 0-9 and zstd 1-22, larger levels are
+    /// clamped. Snappy and lz4 have no levels, so it is ignored for them.
+    pub fn compress(&self, input: Vec<u8>, level: Option<u32>) -> Result<Vec<u8>> {
         match self {
             Compression::None => Ok(input),
             Compression::Snappy => {
@@ -39,12 +44,15 @@ impl Compression {
                     .map_err(|err| Error::SnappyCompression(err.into_error()))
             }
             Compression::Gzip => {
-                let mut writer = flate2::write::GzEncoder::new(
-                    Vec::with_capacity(input.len()),
-                    Default::default(),
-                );
-                writer.write_all(&input)?;
-                Ok(writer.finish()?)
+                let level = level
+                    .map(|level| flate2::Compression::new(level.min(9)))
+                    .unwrap_or_default();
+                let mut writer =
+                    flate2::write::GzEncoder::new(Vec::with_capacity(input.len()), level);
+                writer
+                    .write_all(&input)
+                    .and_then(|_| writer.finish())
+                    .map_err(Error::GzipCompression)
             }
             Compression::Lz4 => {
                 let capacity = lz4_flex::block::get_maximum_output_size(input.len());
@@ -52,15 +60,81 @@ impl Compression {
                 writer.write_all(&input)?;
                 Ok(writer.finish()?)
             }
+            Compression::Zstd => {
+                // Level 0 selects zstd's default level
+                let level = level.map(|level| level.min(22) as i32).unwrap_or(0);
+                Ok(zstd::stream::encode_all(input.as_slice(), level)?)
+            }
         }
     }
 
-    pub fn reader<'a>(&self, r: impl Read + 'a) -> Box<dyn Read + 'a> {
-        match self {
+    pub fn reader<'a>(&self, r: impl Read + Send + 'a) -> Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
             Compression::None => Box::new(r),
             Compression::Snappy => Box::new(snap::read::FrameDecoder::new(r)),
-            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(r)),
+            Compression::Gzip => {
+                // Empty input, as in the root block of an empty tree, decompresses
+                // to nothing
+                let mut r = BufReader::new(r);
+                if r.fill_buf()?.is_empty() {
+                    Box::new(r)
+                } else {
+                    Box::new(GzipReader(flate2::bufread::GzDecoder::new(r)))
+                }
+            }
             Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(r)),
-        }
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(r)?),
+        })
+    }
+}
+
+/// Decompresses a gzip stream as it is read. Its errors are carried inside I/O
+/// errors as `GzipDecompression`, so that a damaged stream is reported as a gzip
+/// error rather than as the end of the block or a truncated entry.
+struct GzipReader<R>(flate2::bufread::GzDecoder<R>);
+
+impl<R: BufRead> Read for GzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read(buf)
+            .map_err(|err| std::io::Error::other(Error::GzipDecompression(err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_gzip_is_a_gzip_error() {
+        let input = b"some block contents ".repeat(20);
+        let compressed = Compression::Gzip.compress(input.clone(), None).unwrap();
+
+        let mut output = vec![];
+        Compression::Gzip
+            .reader(compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        let err = Compression::Gzip
+            .reader(truncated)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap_err();
+        assert!(matches!(Error::from_read(err), Error::GzipDecompression(_)));
+    }
+
+    #[test]
+    fn empty_gzip_is_empty() {
+        let mut output = vec![];
+        Compression::Gzip
+            .reader(&[][..])
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert!(output.is_empty());
     }
 }
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Running totals of the IO done by a database since it was opened, see
+/// `HanoiDB::io_counters`. Comparing them with the amount of data written and read
+/// by the caller gives the read and write amplification of the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoCounters {
+    /// The number of blocks read from the tree files. Blocks served from the block
+    /// cache are not counted.
+    pub blocks_read: u64,
+    /// The number of bytes in those blocks, as they are on disk.
+    pub bytes_read: u64,
+    /// The number of entries written to the leaf blocks of new tree files, by
+    /// promotions of the nursery and by merges.
+    pub entries_written: u64,
+    /// The number of merges that ran to completion.
+    pub merges_run: u64,
+}
+
+#[derive(Debug, Default)]
+struct AtomicCounters {
+    blocks_read: AtomicU64,
+    bytes_read: AtomicU64,
+    entries_written: AtomicU64,
+    merges_run: AtomicU64,
+}
+
+/// A handle to the IO counters of a database, shared by its trees, writers and
+/// merges. The default handle counts nothing, for trees and files that are used on
+/// their own. The counters are only updated with relaxed atomics, so they're cheap
+/// enough to always keep.
+#[derive(Clone, Default)]
+pub(crate) struct Counters(Option<Arc<AtomicCounters>>);
+
+impl Counters {
+    pub fn new() -> Self {
+        Self(Some(Arc::default()))
+    }
+
+    /// Counts a block of `bytes` bytes read from a tree file.
+    pub fn block_read(&self, bytes: u64) {
+        if let Some(counters) = &self.0 {
+            counters.blocks_read.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub fn entries_written(&self, count: u64) {
+        if let Some(counters) = &self.0 {
+            counters.entries_written.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    pub fn merge_run(&self) {
+        if let Some(counters) = &self.0 {
+            counters.merges_run.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the current totals. The counters are read one at a time, so they may
+    /// be slightly out of step with each other while the database is in use.
+    pub fn snapshot(&self) -> IoCounters {
+        let Some(counters) = &self.0 else {
+            return IoCounters::default();
+        };
+        IoCounters {
+            blocks_read: counters.blocks_read.load(Ordering::Relaxed),
+            bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+            entries_written: counters.entries_written.load(Ordering::Relaxed),
+            merges_run: counters.merges_run.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl std::fmt::Debug for Counters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+impl PartialEq for Counters {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
@@ -1,19 +1,68 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
+use rayon::prelude::*;
+
+use crate::batch::WriteBatch;
+use crate::cache::BlockCache;
+use crate::comparator::{Comparator, KeyOrder};
 use crate::compression::Compression;
-use crate::entry::Entry;
+use crate::counters::{Counters, IoCounters};
+use crate::entry::{expiry_timestamp, now_timestamp, Entry};
 use crate::error::*;
-use crate::level::{level_size, Level};
-use crate::nursery::{Nursery, Value};
-use crate::scan::Scanner;
+use crate::export::write_ndjson_line;
+use crate::file_pool::FilePool;
+use crate::level::{level_size, Level, MergeStrategy};
+use crate::merger::MergeObserver;
+use crate::nursery::{
+    log_segment_number, log_segments, Nursery, NurseryData, NurseryOptions, SyncStrategy,
+};
+use crate::scan::{ScanDirection, Scanner};
+use crate::snapshot::{is_snapshot_dir, Snapshot};
+use crate::stats::{BloomStats, CompactionPlan, DbStats, LevelInfo, LevelPlan};
+use crate::tree::Tree;
+use crate::verify::{verify_log, FileReport, VerifyReport};
+use crate::writer::{
+    Writer, WriterOptions, DEFAULT_BLOCK_SIZE, DEFAULT_BLOOM_FP_RATE, DEFAULT_BLOOM_SEED,
+    MIN_BLOCK_SIZE, MIN_FANOUT,
+};
 
 /// Options used to open a HanoiDB instance.
 pub struct OpenOptions<P: AsRef<Path>> {
     path: P,
     min_level: u32,
     max_level: u32,
-    compression: Compression,
+    compression: Box<dyn Fn(u32) -> Compression>,
+    compression_level: Option<u32>,
+    block_size: usize,
+    min_fanout: usize,
+    bloom_fp_rate: f64,
+    bloom_seed: u128,
+    sync_strategy: SyncStrategy,
+    merge_strategy: MergeStrategy,
+    background_merge: bool,
+    auto_compact_interval: Option<Duration>,
+    block_cache_bytes: usize,
+    parallel_get: bool,
+    nursery_max_bytes: Option<usize>,
+    nursery_log_segment_size: Option<usize>,
+    nursery_log_framing: bool,
+    recover_promote_threshold: usize,
+    merge_observer: Option<MergeObserver>,
+    max_open_files: Option<usize>,
+    temp_dir: Option<PathBuf>,
+    key_prefix_compression: bool,
+    comparator: Option<Comparator>,
+    create_mode: CreateMode,
+    in_memory: bool,
 }
 
 impl<P: AsRef<Path>> OpenOptions<P> {
@@ -23,7 +72,29 @@ impl<P: AsRef<Path>> OpenOptions<P> {
             path,
             min_level: 10,
             max_level: 25,
-            compression: Compression::None,
+            compression: Box::new(|_| Compression::None),
+            compression_level: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            min_fanout: MIN_FANOUT,
+            bloom_fp_rate: DEFAULT_BLOOM_FP_RATE,
+            bloom_seed: DEFAULT_BLOOM_SEED,
+            sync_strategy: SyncStrategy::EveryWrite,
+            merge_strategy: MergeStrategy::Fast,
+            background_merge: false,
+            auto_compact_interval: None,
+            block_cache_bytes: 0,
+            parallel_get: false,
+            nursery_max_bytes: None,
+            nursery_log_segment_size: None,
+            nursery_log_framing: false,
+            recover_promote_threshold: 0,
+            merge_observer: None,
+            max_open_files: None,
+            temp_dir: None,
+            key_prefix_compression: false,
+            comparator: None,
+            create_mode: CreateMode::OpenOrCreate,
+            in_memory: false,
         }
     }
 
@@ -41,7 +112,200 @@ impl<P: AsRef<Path>> OpenOptions<P> {
 
     /// Sets the compression algorithm for the HanoiDB instances. Defaults to `Compresssion::None`.
     pub fn with_compression(mut self, compression: Compression) -> Self {
-        self.compression = compression;
+        self.compression = Box::new(move |_| compression);
+        self
+    }
+
+    /// Sets the compression algorithm for each level of the HanoiDB instance, so that
+    /// for example the small, frequently rewritten levels can use a fast algorithm
+    /// while the large, deep levels use a dense one. Replaces `with_compression`.
+    pub fn with_level_compression(
+        mut self,
+        compression: impl Fn(u32) -> Compression + 'static,
+    ) -> Self {
+        self.compression = Box::new(compression);
+        self
+    }
+
+    /// Sets the compression level, trading write speed for smaller files. Only gzip
+    /// and zstd support levels. Defaults to the algorithm's own default level.
+    pub fn with_compression_level(mut self, compression_level: u32) -> Self {
+        self.compression_level = Some(compression_level);
+        self
+    }
+
+    /// Sets the size of the blocks in the tree files, before compression. Larger blocks
+    /// make for shallower trees, but more data is read to look up a key. Must be at
+    /// least 512 bytes. Defaults to 8KiB.
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the number of blocks an inner block of a tree file points at before it is
+    /// written, even once it's over the block size. Inner blocks with more children
+    /// make for shallower trees, so fewer blocks are read to look up a key, at the
+    /// cost of larger inner blocks to read. Only the last inner block of each level
+    /// of a tree may have fewer children. Must be at least 2. Defaults to 2.
+    pub fn with_min_fanout(mut self, min_fanout: usize) -> Self {
+        self.min_fanout = min_fanout;
+        self
+    }
+
+    /// Sets the false positive rate of the bloom filters in the tree files. A lower
+    /// rate avoids more needless reads for missing keys, at the cost of larger
+    /// filters. Must be between 0 and 1, exclusive. Defaults to 0.01.
+    pub fn with_bloom_fp_rate(mut self, bloom_fp_rate: f64) -> Self {
+        self.bloom_fp_rate = bloom_fp_rate;
+        self
+    }
+
+    /// Seeds the hasher of the bloom filters in new tree files. With keys chosen by
+    /// an attacker, a secret seed keeps them from crafting keys that all collide in
+    /// the filter. The seed is stored in each file, so a database can be reopened
+    /// with a different seed, or none. Filters with different seeds can't be
+    /// combined, so a level checks each of its trees separately until they are
+    /// merged.
+    pub fn with_bloom_seed(mut self, bloom_seed: u64) -> Self {
+        self.bloom_seed = bloom_seed.into();
+        self
+    }
+
+    /// Sets how often the nursery log is synced to disk. Defaults to `SyncStrategy::EveryWrite`.
+    pub fn with_sync_strategy(mut self, sync_strategy: SyncStrategy) -> Self {
+        self.sync_strategy = sync_strategy;
+        self
+    }
+
+    /// Sets how merge work is scheduled across levels. Defaults to `MergeStrategy::Fast`.
+    pub fn with_merge_strategy(mut self, merge_strategy: MergeStrategy) -> Self {
+        self.merge_strategy = merge_strategy;
+        self
+    }
+
+    /// Runs merges on a background thread instead of inside the writes that trigger
+    /// them. Writes still move the nursery into the first level themselves, so reads
+    /// always see every completed write, but only queue up the merge work. If the
+    /// merges fall far enough behind that a level is full, the write finishes that
+    /// level's merge itself. Defaults to `false`.
+    pub fn with_background_merge(mut self, background_merge: bool) -> Self {
+        self.background_merge = background_merge;
+        self
+    }
+
+    /// Has the background merge thread (see `with_background_merge`, which this
+    /// needs) keep merging when no writes come in, so that the merges left pending
+    /// by a burst of writes still finish. Each time the thread has been idle for
+    /// `interval` it does as much merge work as a write would. While there is
+    /// nothing to merge, it waits up to eight times as long between checks.
+    pub fn with_auto_compact_interval(mut self, interval: Duration) -> Self {
+        self.auto_compact_interval = Some(interval);
+        self
+    }
+
+    /// Sets the size of the cache of decoded blocks that is shared by the tree files
+    /// of all the levels, so that lookups of recently read keys don't go back to
+    /// disk. The size counts the encoded size of the cached entries. Defaults to 0,
+    /// which disables the cache.
+    pub fn with_block_cache_bytes(mut self, block_cache_bytes: usize) -> Self {
+        self.block_cache_bytes = block_cache_bytes;
+        self
+    }
+
+    /// Makes `get` probe the tree files of all the levels at the same time on rayon's
+    /// thread pool, instead of one after the other until the key is found. This
+    /// mostly speeds up lookups of keys that are missing or in the larger levels.
+    /// The result is the same either way. Defaults to `false`.
+    pub fn with_parallel_get(mut self, parallel_get: bool) -> Self {
+        self.parallel_get = parallel_get;
+        self
+    }
+
+    /// Promotes the nursery into the first level once this many bytes were written to
+    /// its log, even if it holds fewer entries than the first level. This bounds the
+    /// memory used by the nursery when values are large. By default the nursery is
+    /// only promoted when it holds as many entries as the first level.
+    pub fn with_nursery_max_bytes(mut self, nursery_max_bytes: usize) -> Self {
+        self.nursery_max_bytes = Some(nursery_max_bytes);
+        self
+    }
+
+    /// Splits the nursery log into segments of about this many bytes: once a write
+    /// would take the current segment past the size, the log moves on to a new
+    /// `nursery-<n>.log` file. All the segments are replayed on recovery. By default
+    /// the nursery log is a single file.
+    pub fn with_nursery_log_segment_size(mut self, nursery_log_segment_size: usize) -> Self {
+        self.nursery_log_segment_size = Some(nursery_log_segment_size);
+        self
+    }
+
+    /// Wraps each record of the nursery log in a frame holding its length and CRC32,
+    /// so that recovery can tell where every record ends even if the entry inside
+    /// it is damaged. Logs written either way can be recovered. Defaults to false.
+    pub fn with_nursery_log_framing(mut self, nursery_log_framing: bool) -> Self {
+        self.nursery_log_framing = nursery_log_framing;
+        self
+    }
+
+    /// Keeps a nursery log recovered on open in memory if it holds fewer than this
+    /// many entries, and goes on appending to it, instead of writing it out to a
+    /// tree file in the first level. This saves writing a tiny file after a crash.
+    /// Defaults to 0, which always writes the recovered log out.
+    pub fn with_recover_promote_threshold(mut self, recover_promote_threshold: usize) -> Self {
+        self.recover_promote_threshold = recover_promote_threshold;
+        self
+    }
+
+    /// Calls `merge_observer` with the progress of merges as they run, including the
+    /// merges run by `HanoiDB::compact`. See `MergeObserver` for what it may do.
+    pub fn with_merge_observer(mut self, merge_observer: MergeObserver) -> Self {
+        self.merge_observer = Some(merge_observer);
+        self
+    }
+
+    /// Caps the number of tree files held open at once. Files over the cap are closed
+    /// and opened again by path when they're next read, which trades some speed for
+    /// file descriptors when there are many levels or scans. By default every tree
+    /// file is held open.
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = Some(max_open_files);
+        self
+    }
+
+    /// Writes the files of merges in progress, and the links to the tree files that
+    /// scans and snapshots read, into `temp_dir` instead of the database's
+    /// directory, for example to put them on a faster disk. If `temp_dir` is on
+    /// another filesystem, a finished merge is copied into the database's directory,
+    /// and scans and snapshots copy the tree files instead of linking them, which
+    /// is much slower for a large database. The directory must already exist, and
+    /// must not be shared with another database. By default the database's own
+    /// directory is used.
+    pub fn with_temp_dir(mut self, temp_dir: PathBuf) -> Self {
+        self.temp_dir = Some(temp_dir);
+        self
+    }
+
+    /// Writes the keys in each block of the tree files with the prefix they share
+    /// with the key before them left out, which makes files with long, structured
+    /// keys smaller. Files written either way can be read. Defaults to `false`.
+    pub fn with_key_prefix_compression(mut self, key_prefix_compression: bool) -> Self {
+        self.key_prefix_compression = key_prefix_compression;
+        self
+    }
+
+    /// Orders keys with `comparator` instead of bytewise, in scans and in the tree
+    /// files. The files are written in this order, so a database must always be
+    /// opened with the same comparator. Prefix scans and `delete_range` rely on
+    /// bytewise order and fall back to slower ways of working under a comparator.
+    pub fn with_comparator(mut self, comparator: Comparator) -> Self {
+        self.comparator = Some(comparator);
+        self
+    }
+
+    /// Sets what to do with a database that already exists in the directory.
+    /// Defaults to `CreateMode::OpenOrCreate`.
+    pub fn with_create_mode(mut self, create_mode: CreateMode) -> Self {
+        self.create_mode = create_mode;
         self
     }
 
@@ -51,13 +315,60 @@ impl<P: AsRef<Path>> OpenOptions<P> {
     }
 }
 
+impl OpenOptions<PathBuf> {
+    /// Creates a new options builder for a database that is held entirely in memory,
+    /// for tests and caches that don't need to outlive the process. Nothing is
+    /// written to disk: the nursery is never promoted into the levels, so all the
+    /// data stays in the nursery, and deleted keys are dropped rather than kept as
+    /// tombstones. The options that only affect files, such as the compression, the
+    /// block size and the sync strategy, are ignored. `HanoiDB::checkpoint`,
+    /// `HanoiDB::ingest_sorted` and `HanoiDB::absorb` fail with `Error::InMemory`.
+    pub fn in_memory() -> Self {
+        Self {
+            in_memory: true,
+            ..Self::new(PathBuf::new())
+        }
+    }
+}
+
+/// What opening a database does with one that already exists in the directory.
+/// Only the database's own files count (see `HanoiDB::destroy`), other files in
+/// the directory are left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CreateMode {
+    /// Open the existing database, or start an empty one if there is none.
+    #[default]
+    OpenOrCreate,
+    /// Fail with `Error::DatabaseExists` if there is already a database.
+    CreateNew,
+    /// Remove the existing database, if any, and start an empty one.
+    Truncate,
+}
+
+/// Where `HanoiDB::debug_lookup` found an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Nursery,
+    Level(u32),
+}
+
 /// A HanoiDB instance wrapping a directory of files.
+///
+/// The levels are kept behind a lock that is shared with the background merge
+/// thread, if there is one. Every read and every command against the levels holds
+/// the lock for its duration, so reads never see a half-applied promotion or merge.
 pub struct HanoiDB {
     path: PathBuf,
+    /// See `OpenOptions::with_temp_dir`.
+    temp_dir: Option<PathBuf>,
     nursery: Nursery,
-    min_level: u32,
-    max_level: u32,
-    levels: Vec<Level>,
+    levels: Arc<Mutex<Levels>>,
+    merge_worker: Option<MergeWorker>,
+    parallel_get: bool,
+    counters: Counters,
+    /// Whether dropping the database moves the nursery into the first level, see
+    /// `close_without_flush`.
+    flush_on_drop: bool,
 }
 
 impl HanoiDB {
@@ -75,69 +386,984 @@ impl HanoiDB {
             min_level,
             max_level,
             compression,
+            compression_level,
+            block_size,
+            min_fanout,
+            bloom_fp_rate,
+            bloom_seed,
+            sync_strategy,
+            merge_strategy,
+            background_merge,
+            auto_compact_interval,
+            block_cache_bytes,
+            parallel_get,
+            nursery_max_bytes,
+            nursery_log_segment_size,
+            nursery_log_framing,
+            recover_promote_threshold,
+            merge_observer,
+            max_open_files,
+            temp_dir,
+            key_prefix_compression,
+            comparator,
+            create_mode,
+            in_memory,
         } = options;
+        if block_size < MIN_BLOCK_SIZE {
+            return Err(Error::BlockSizeTooSmall(block_size));
+        }
+        if min_fanout < MIN_FANOUT {
+            return Err(Error::MinFanoutTooSmall(min_fanout));
+        }
+        if !(bloom_fp_rate > 0.0 && bloom_fp_rate < 1.0) {
+            return Err(Error::InvalidBloomFpRate(bloom_fp_rate));
+        }
+        let key_order = KeyOrder::new(comparator);
+        let counters = Counters::new();
+        let writer_options = |level| WriterOptions {
+            compression: compression(level),
+            compression_level,
+            block_size,
+            min_fanout,
+            bloom_fp_rate,
+            bloom_seed,
+            prefix_keys: key_prefix_compression,
+            key_order: key_order.clone(),
+            counters: counters.clone(),
+        };
         let path = path.as_ref().to_path_buf();
-        let (nursery, recovery) = Nursery::new(&path, min_level, compression)?;
+        let nursery_options = NurseryOptions {
+            writer_options: writer_options(min_level),
+            sync_strategy,
+            max_bytes: nursery_max_bytes,
+            log_segment_size: nursery_log_segment_size,
+            framed_log: nursery_log_framing,
+            recover_promote_threshold,
+        };
+        if in_memory {
+            let levels = Levels {
+                levels: vec![],
+                min_level,
+                max_level,
+                merge_strategy,
+            };
+            return Ok(Self {
+                path,
+                temp_dir,
+                nursery: Nursery::in_memory(min_level, nursery_options),
+                levels: Arc::new(Mutex::new(levels)),
+                merge_worker: None,
+                parallel_get,
+                counters,
+                flush_on_drop: true,
+            });
+        }
+        match create_mode {
+            CreateMode::OpenOrCreate => (),
+            CreateMode::CreateNew => {
+                if has_database_files(&path)? {
+                    return Err(Error::DatabaseExists { path });
+                }
+            }
+            CreateMode::Truncate => Self::destroy(&path)?,
+        }
+        let (nursery, recovery) = Nursery::new(&path, min_level, nursery_options)?;
+        let block_cache =
+            (block_cache_bytes > 0).then(|| Arc::new(BlockCache::new(block_cache_bytes)));
+        let file_pool =
+            max_open_files.map(|max_open_files| Arc::new(FilePool::new(max_open_files)));
+        // Merged files are usually promoted into the next level, so write them with
+        // its compression. The last level keeps its merged files.
         let levels = (min_level..=max_level)
-            .map(|level| Level::new(&path, level, compression))
+            .map(|level| {
+                Ok(
+                    Level::new(&path, level, writer_options(level), block_cache.clone())?
+                        .with_merge_writer_options(writer_options((level + 1).min(max_level)))
+                        .with_merge_observer(merge_observer.clone())
+                        .with_file_pool(file_pool.clone())
+                        .with_temp_dir(temp_dir.clone()),
+                )
+            })
             .collect::<Result<Vec<Level>>>()?;
-        let mut db = Self {
-            path,
-            nursery,
+        let mut levels = Levels {
+            levels,
             min_level,
             max_level,
-            levels,
+            merge_strategy,
         };
+        // Promote files left by merges that completed before a crash, starting from
+        // the deepest level so that the levels they go into have the most room
+        for level in (min_level..max_level).rev() {
+            let recovered_merge = levels.level_mut(level).unwrap().take_recovered_merge();
+            if let Some(path) = recovered_merge {
+                levels.handle_commands(vec![Command::PromoteFile {
+                    path,
+                    target_level: level + 1,
+                }])?;
+            }
+        }
         // Promote nursery.data into the first level, if it was recovered
         if let Some(command) = recovery {
-            let commands = db.handle_command(command)?;
+            let commands = levels.handle_command(command)?;
             assert!(commands.is_empty());
         }
-        Ok(db)
+        let levels = Arc::new(Mutex::new(levels));
+        let merge_worker = background_merge
+            .then(|| MergeWorker::spawn(levels.clone(), auto_compact_interval))
+            .transpose()?;
+        Ok(Self {
+            path,
+            temp_dir,
+            nursery,
+            levels,
+            merge_worker,
+            parallel_get,
+            counters,
+            flush_on_drop: true,
+        })
+    }
+
+    /// Removes all of the files belonging to a HanoiDB instance from a directory,
+    /// leaving any other files in place. The database must not be open while it is
+    /// destroyed; drop any `HanoiDB` for the directory first. Files that are
+    /// already missing are ignored.
+    pub fn destroy(path: impl AsRef<Path>) -> Result<()> {
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !is_database_file(&name) {
+                continue;
+            }
+            // Snapshots that weren't dropped, because their process crashed, leave
+            // their directories behind
+            let removed = if is_snapshot_dir(&name) && entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())
+            } else {
+                std::fs::remove_file(entry.path())
+            };
+            match removed {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+        Ok(())
     }
 
     /// Looks up a key in the database and returns its value if it is present.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        //    - check the nursery first for the key
+        let levels = &self.lock_levels().levels;
+        if self.parallel_get {
+            lookup_parallel(self.nursery.data(), levels, key)
+        } else {
+            lookup(self.nursery.data(), levels, key)
+        }
+    }
+
+    /// Looks up a key like `get`, but borrows the value instead of copying it when
+    /// the key is in the nursery. Values read from the levels are returned owned.
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<Cow<'_, [u8]>>> {
+        // An older value can win over one with a write timestamp, see `insert_at`
         match self.nursery.get_value(key) {
-            Some(Value::Deleted) => return Ok(None),
-            Some(Value::Plain(value)) => return Ok(Some(value.clone())),
-            None => (),
+            Some(value) if value.write_timestamp().is_none() => {
+                let value = value.live_value(now_timestamp());
+                return Ok(value.map(|value| Cow::Borrowed(value.as_slice())));
+            }
+            _ => (),
         }
-        //    - check the levels in order until you find it or a tombstone
-        for level in &self.levels {
-            match level.get_entry(key)? {
-                Some(Entry::Deleted { .. }) => return Ok(None),
-                Some(Entry::KeyVal { value, .. }) => return Ok(Some(value)),
-                Some(Entry::PosLen { .. }) => unreachable!("get entry returned a poslen entry"),
+        Ok(self.get(key)?.map(Cow::Owned))
+    }
+
+    /// Looks up a key like `get`, but returns a reader over its value, so that a
+    /// large value can be streamed rather than read into memory all at once. Values
+    /// still in the nursery are copied. With `with_max_open_files`, finish reading
+    /// before writing again, as the write can merge away the file being read.
+    pub fn value_reader(&self, key: &[u8]) -> Result<Option<Box<dyn Read + Send>>> {
+        let now = now_timestamp();
+        'versioned: {
+            if let Some(value) = self.nursery.get_value(key) {
+                if value.write_timestamp().is_some() {
+                    break 'versioned;
+                }
+                let value = value.live_value(now).cloned();
+                return Ok(value.map(|value| Box::new(Cursor::new(value)) as Box<dyn Read + Send>));
+            }
+            for level in &self.lock_levels().levels {
+                if !level.might_contain(key) {
+                    continue;
+                }
+                for tree in level.trees() {
+                    if let Some(reader) = tree.value_reader(key)? {
+                        if reader.write_timestamp().is_some() {
+                            break 'versioned;
+                        }
+                        return Ok((!reader.is_expired(now)).then(|| Box::new(reader) as _));
+                    }
+                    // Tombstones hide the older trees, and are small enough to read whole
+                    match tree.get_entry(key)? {
+                        Some(entry) if entry.write_timestamp().is_some() => break 'versioned,
+                        Some(_) => return Ok(None),
+                        None => (),
+                    }
+                }
+            }
+            return Ok(None);
+        }
+        // An older value can win over one with a write timestamp (see `insert_at`), so
+        // the value is looked up like `get` does, and read whole
+        let value = self.get(key)?;
+        Ok(value.map(|value| Box::new(Cursor::new(value)) as Box<dyn Read + Send>))
+    }
+
+    /// Lists every entry for a key, newest first, including the older values and
+    /// tombstones that the newest one shadows until they are merged away. This is
+    /// for debugging; `get` stops at the newest entry.
+    pub fn debug_lookup(&self, key: &[u8]) -> Result<Vec<(Source, Entry)>> {
+        let mut found = vec![];
+        if let Some(value) = self.nursery.get_value(key) {
+            found.push((Source::Nursery, value.clone().into_entry(key.to_vec())));
+        }
+        for level in &self.lock_levels().levels {
+            for tree in level.trees() {
+                if let Some(entry) = tree.get_entry(key)? {
+                    found.push((Source::Level(level.level()), entry));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Creates a consistent, read-only view of the database as it is right now.
+    /// Later writes, merges and promotions are not visible through the snapshot.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(
+            self.temp_dir.as_ref().unwrap_or(&self.path),
+            self.nursery.shared_data(),
+            &self.lock_levels().levels,
+        )
+    }
+
+    /// Writes a consistent copy of the database as it is right now into `dest`,
+    /// which can then be opened on its own. Level files never change once they are
+    /// written, so they are hard-linked rather than copied; `dest` must be on the
+    /// same filesystem. The nursery log is copied. Merges are held up while the
+    /// checkpoint is taken.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        if self.nursery.is_in_memory() {
+            return Err(Error::InMemory("checkpoint"));
+        }
+        let dest = dest.as_ref();
+        std::fs::create_dir_all(dest)?;
+        let levels = self.lock_levels();
+        for level in &levels.levels {
+            for file in level.tree_files() {
+                // WONT PANIC: tree files always have a file name
+                std::fs::hard_link(&file, dest.join(file.file_name().unwrap()))?;
+            }
+        }
+        for segment in log_segments(&self.path)? {
+            // WONT PANIC: log segments always have a file name
+            std::fs::copy(&segment, dest.join(segment.file_name().unwrap()))?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether a key is present in the database without returning its value.
+    /// Levels whose bloom filters rule out the key are skipped entirely; a bloom
+    /// filter hit is confirmed with a real lookup. A deleted or expired key is not
+    /// present.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        let levels = &self.lock_levels().levels;
+        Ok(lookup(self.nursery.data(), levels, key)?.is_some())
+    }
+
+    /// Looks up several keys in the database at once, returning their values in the
+    /// same order as `keys`. Each level's trees are only opened once for all the keys.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let now = now_timestamp();
+        let key_order = self.nursery.data().key_order();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|a, b| key_order.cmp(keys[*a], keys[*b]));
+
+        // `None` means that the key has not been found yet
+        let mut results: Vec<Option<Option<Vec<u8>>>> = vec![None; keys.len()];
+        // Keys found with a write timestamp, which are looked up again one at a time,
+        // as an older value can win over them (see `insert_at`)
+        let mut versioned = vec![];
+        for index in order.iter().copied() {
+            match self.nursery.get_value(keys[index]) {
+                Some(value) if value.write_timestamp().is_some() => versioned.push(index),
+                Some(value) => results[index] = Some(value.live_value(now).cloned()),
                 None => (),
             }
         }
-        Ok(None)
+
+        let levels = &self.lock_levels().levels;
+        for level in levels {
+            order.retain(|index| results[*index].is_none() && !versioned.contains(index));
+            if order.is_empty() {
+                break;
+            }
+            let level_keys: Vec<&[u8]> = order.iter().map(|index| keys[*index]).collect();
+            for (index, entry) in order.iter().zip(level.get_entries(&level_keys)?) {
+                match entry {
+                    Some(entry) if entry.write_timestamp().is_some() => versioned.push(*index),
+                    Some(entry) => results[*index] = Some(entry.into_live_value(now)),
+                    None => (),
+                }
+            }
+        }
+        for index in versioned {
+            results[index] = Some(lookup(self.nursery.data(), levels, keys[index])?);
+        }
+        Ok(results.into_iter().map(Option::flatten).collect())
+    }
+
+    /// Returns the smallest live key in the database, or `None` if it is empty.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        self.edge_key(ScanDirection::Forward)
+    }
+
+    /// Returns the largest live key in the database, or `None` if it is empty.
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        self.edge_key(ScanDirection::Reverse)
+    }
+
+    fn edge_key(&self, direction: ScanDirection) -> Result<Option<Vec<u8>>> {
+        let levels = &self.lock_levels().levels;
+        let nursery = self.nursery.shared_data();
+        // Find the smallest (or largest) key held anywhere, live or not, from the
+        // edges of the nursery and of each tree
+        let mut candidates = vec![];
+        for tree in levels.iter().flat_map(Level::trees) {
+            candidates.extend(match direction {
+                ScanDirection::Forward => tree.first_key()?,
+                ScanDirection::Reverse => tree.last_key()?,
+            });
+        }
+        let key_order = nursery.key_order();
+        let edge = match direction {
+            ScanDirection::Forward => candidates
+                .into_iter()
+                .chain(nursery.keys().next().cloned())
+                .min_by(|a, b| key_order.cmp(a, b)),
+            ScanDirection::Reverse => candidates
+                .into_iter()
+                .chain(nursery.keys().next_back().cloned())
+                .max_by(|a, b| key_order.cmp(a, b)),
+        };
+        let Some(edge) = edge else {
+            return Ok(None);
+        };
+        // That key is usually live. If it was deleted or has expired, scan past it.
+        if lookup(&nursery, levels, &edge)?.is_some() {
+            return Ok(Some(edge));
+        }
+        let mut scanner = match direction {
+            ScanDirection::Forward => {
+                Scanner::with_range(nursery, levels, Bound::Excluded(edge), Bound::Unbounded)?
+            }
+            ScanDirection::Reverse => {
+                Scanner::new_reverse(nursery, levels, Bound::Unbounded, Bound::Excluded(edge))?
+            }
+        };
+        scanner
+            .next()
+            .transpose()
+            .map(|entry| entry.map(|(key, _)| key))
     }
 
-    /// Inserts a key-value pair into the database.
+    /// Inserts a key-value pair into the database. Any byte string is a valid key,
+    /// including the empty key, which sorts before all other keys. The value may be
+    /// empty too, which `get` tells apart from a missing key by returning `Some`.
     pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let commands = self.nursery.add(key, value)?;
         self.handle_commands(commands)
     }
 
+    /// Inserts a key-value pair into the database that expires after `ttl`. Once
+    /// expired, the key is treated as if it had been deleted.
+    pub fn insert_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let commands = self
+            .nursery
+            .add_expiring(key, value, expiry_timestamp(ttl))?;
+        self.handle_commands(commands)
+    }
+
     /// Deletes a key from the database.
     pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
         let commands = self.nursery.delete(key)?;
         self.handle_commands(commands)
     }
 
-    /// Returns the directory that contains this database's files.
+    /// Inserts a key-value pair with a write timestamp chosen by the caller, such as
+    /// the time the value was written elsewhere. Reads return the newest write to a
+    /// key, unless it has a write timestamp and an older write to the key has a later
+    /// one, which then wins even though it was written to the database first. Merges
+    /// keep the write that reads return.
+    pub fn insert_at(&mut self, key: Vec<u8>, value: Vec<u8>, timestamp: u32) -> Result<()> {
+        let commands = self.nursery.add_at(key, value, timestamp)?;
+        self.handle_commands(commands)
+    }
+
+    /// Deletes a key with a write timestamp chosen by the caller, see `insert_at`.
+    pub fn delete_at(&mut self, key: Vec<u8>, timestamp: u32) -> Result<()> {
+        let commands = self.nursery.delete_at(key, timestamp)?;
+        self.handle_commands(commands)
+    }
+
+    /// Deletes every live key between `from` and `to`, returning how many were
+    /// deleted. The keys are counted with a scan, and then covered by a single range
+    /// tombstone from the first of them to just past the last, however many there are.
+    /// With a custom comparator there's no telling which key comes just past the last,
+    /// so each key gets its own tombstone instead, written in one batch.
+    pub fn delete_range(&mut self, from: Bound<Vec<u8>>, to: Bound<Vec<u8>>) -> Result<usize> {
+        if !self.nursery.data().key_order().is_bytewise() {
+            let mut batch = WriteBatch::new();
+            for key in self.keys_range(from, to)? {
+                batch.delete(key?);
+            }
+            let count = batch.len();
+            if count > 0 {
+                self.write(batch)?;
+            }
+            return Ok(count);
+        }
+        let mut count = 0;
+        let mut first_and_last = None;
+        for key in self.keys_range(from, to)? {
+            let key = key?;
+            count += 1;
+            match &mut first_and_last {
+                None => first_and_last = Some((key.clone(), key)),
+                Some((_, last)) => *last = key,
+            }
+        }
+        let Some((start, mut end)) = first_and_last else {
+            return Ok(0);
+        };
+        // The smallest key after the last one deleted
+        end.push(0);
+        let commands = self.nursery.delete_range(start, end)?;
+        self.handle_commands(commands)?;
+        Ok(count)
+    }
+
+    /// Writes `new` to `key` (deleting it if `new` is `None`), but only if its current
+    /// value is `expected`, with `None` meaning that it's absent. Returns whether the
+    /// write was made.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.insert(key.to_vec(), value)?,
+            None => self.delete(key.to_vec())?,
+        }
+        Ok(true)
+    }
+
+    /// Reads the current value of `key`, passes it to `f` and writes back what `f`
+    /// returns, deleting the key if it returns `None`. Nothing else can write to the
+    /// database in between, as this holds the only mutable borrow of it.
+    pub fn merge_op<F>(&mut self, key: Vec<u8>, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let current = self.get(&key)?;
+        match f(current.as_deref()) {
+            Some(value) => self.insert(key, value),
+            None if current.is_some() => self.delete(key),
+            // Already absent, so there is nothing to delete
+            None => Ok(()),
+        }
+    }
+
+    /// Applies all the inserts and deletes in a batch atomically. If the process
+    /// crashes during the write, either all or none of the batch is recovered.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let commands = self.nursery.write_batch(batch.into_entries())?;
+        self.handle_commands(commands)
+    }
+
+    /// Loads key-value pairs straight into a tree file, bypassing the nursery and
+    /// its log, and promotes the file into the smallest level that fits it. The
+    /// keys must be in strictly ascending order, otherwise `Error::OutOfOrderWrite`
+    /// is returned and nothing is loaded. The iterator's size hint is used to size
+    /// the file's bloom filter, and to pick the level whose compression it is
+    /// written with. If the file goes into a level with another compression after
+    /// all, it is rewritten.
+    ///
+    /// The file is added as the newest file of its level, but values for the same
+    /// keys in the nursery or in smaller levels still take precedence, so this is
+    /// meant for loading keys that are not already in the database.
+    pub fn ingest_sorted(&mut self, iter: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let (lower, upper) = iter.size_hint();
+        self.ingest(iter, upper.unwrap_or(lower))
+    }
+
+    /// Imports every live key and value of `other`, with the values from `other`
+    /// replacing any that are already here for the same keys.
+    ///
+    /// Keys that have any entry here, even a tombstone, are written through the
+    /// nursery first, so that they replace the entries here wherever those are, as
+    /// a file loaded underneath them would be hidden. Then all of `other`'s keys
+    /// are loaded at once, like `ingest_sorted`. This isn't atomic: if reading
+    /// `other` fails part way through, some of its keys may have been imported.
+    /// A database held in memory has no levels to load into, so all of `other`'s
+    /// keys are written through its nursery.
+    pub fn absorb(&mut self, other: &HanoiDB) -> Result<()> {
+        if self.nursery.is_in_memory() {
+            for entry in other.scan()? {
+                let (key, value) = entry?;
+                self.insert(key, value)?;
+            }
+            return Ok(());
+        }
+        let mut count = 0;
+        for entry in other.scan()? {
+            let (key, value) = entry?;
+            count += 1;
+            if !self.debug_lookup(&key)?.is_empty() {
+                self.insert(key, value)?;
+            }
+        }
+        let mut error = None;
+        let entries = other
+            .scan()?
+            .map_while(|entry| entry.map_err(|err| error = Some(err)).ok());
+        self.ingest(entries, count)?;
+        match error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn ingest(
+        &mut self,
+        iter: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+        expected_num_items: usize,
+    ) -> Result<()> {
+        if self.nursery.is_in_memory() {
+            return Err(Error::InMemory("ingest"));
+        }
+        let expected_num_items = expected_num_items.max(1);
+        // Written with the options of the level that the expected number of items
+        // goes into
+        let (_, writer_options) = self.lock_levels().ingest_level(expected_num_items);
+        let path = self.path.join("ingest.data");
+        let count = match self.write_sorted(&path, iter, expected_num_items, writer_options.clone())
+        {
+            Ok(count) => count,
+            Err(err) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(err);
+            }
+        };
+        if count == 0 {
+            std::fs::remove_file(&path)?;
+            return Ok(());
+        }
+
+        let (target_level, target_options) = self.lock_levels().ingest_level(count);
+        // The number of items was off, and they go into a level with other options
+        let path = if target_options == writer_options {
+            path
+        } else {
+            let rewritten = self.path.join("ingest-rewrite.data");
+            let result = self.rewrite_sorted(&path, &rewritten, count, target_options);
+            std::fs::remove_file(&path)?;
+            if let Err(err) = result {
+                let _ = std::fs::remove_file(&rewritten);
+                return Err(err);
+            }
+            rewritten
+        };
+        self.handle_commands(vec![Command::PromoteFile { path, target_level }])
+    }
+
+    fn write_sorted(
+        &self,
+        path: &Path,
+        iter: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+        expected_num_items: usize,
+        writer_options: WriterOptions,
+    ) -> Result<usize> {
+        let mut writer = Writer::with_expected_num_items(path, expected_num_items, writer_options)?;
+        let key_order = self.nursery.data().key_order();
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut count = 0;
+        for (key, value) in iter {
+            // The writer accepts repeated keys, but a tree must not hold any
+            if last_key
+                .as_ref()
+                .is_some_and(|last_key| key_order.cmp(last_key, &key) != Ordering::Less)
+            {
+                return Err(Error::OutOfOrderWrite);
+            }
+            last_key = Some(key.clone());
+            writer.add(Entry::KeyVal {
+                key,
+                value,
+                timestamp: None,
+                write_timestamp: None,
+            })?;
+            count += 1;
+        }
+        writer.close()?;
+        Ok(count)
+    }
+
+    /// Copies the `count` entries of the tree file at `path` into `rewritten`, with
+    /// the given options.
+    fn rewrite_sorted(
+        &self,
+        path: &Path,
+        rewritten: &Path,
+        count: usize,
+        writer_options: WriterOptions,
+    ) -> Result<()> {
+        let tree = Tree::from_file(path)?.with_key_order(writer_options.key_order.clone());
+        let mut writer = Writer::with_expected_num_items(rewritten, count, writer_options)?;
+        for entry in tree.entries()? {
+            writer.add(entry?)?;
+        }
+        writer.close()
+    }
+
+    /// Forces the contents of the nursery out into the first level, so that recent
+    /// writes no longer depend on replaying the nursery log.
+    pub fn flush(&mut self) -> Result<()> {
+        let commands = self.nursery.flush()?;
+        self.handle_commands(commands)
+    }
+
+    /// Closes the database without moving the nursery into the first level, so that
+    /// the next open replays its log as it would after a crash. For testing recovery.
+    #[doc(hidden)]
+    pub fn close_without_flush(mut self) {
+        self.flush_on_drop = false;
+    }
+
+    /// Moves the nursery into the first level when the database is dropped. Only the
+    /// promotion is done: if the first level is full, which would take a merge to
+    /// make room, or the merges have failed, the log is left to be replayed instead.
+    /// This never panics, as a panic while dropping during unwinding would abort.
+    fn flush_nursery_on_drop(&mut self) -> Result<()> {
+        if !self.flush_on_drop {
+            return Ok(());
+        }
+        if self
+            .merge_worker
+            .as_ref()
+            .is_some_and(MergeWorker::is_stopped)
+        {
+            return Ok(());
+        }
+        let Ok(mut levels) = self.levels.lock() else {
+            return Ok(());
+        };
+        if levels.levels.first().is_some_and(Level::is_full) {
+            return Ok(());
+        }
+        let commands = self.nursery.flush()?;
+        levels.handle_commands(commands)
+    }
+
+    /// Flushes the nursery and runs every pending merge to completion, leaving at
+    /// most one file in each level. Tombstones and expired values are dropped when
+    /// they are merged into the last level. This blocks until all the merges are
+    /// done, which can take a long time for a large database.
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush()?;
+        self.lock_levels().compact()
+    }
+
+    /// Works out the merges that `compact` would run, without running them or
+    /// touching any file. The nursery is counted as a file flushed into the first
+    /// level, and the merges of each level as they would cascade into the next one.
+    /// The plan is estimated from the number of values recorded in each file, so
+    /// it is exact for files without tombstones, and otherwise undercounts. Files
+    /// written before values were counted (magic `HAN3`) are counted as empty.
+    pub fn plan_compaction(&self) -> CompactionPlan {
+        let nursery_entries = self.nursery.data().len() as u64;
+        self.lock_levels().plan_compaction(nursery_entries)
+    }
+
+    /// Returns an estimate of the number of keys in the database. Keys that were
+    /// overwritten or deleted may still be counted in more than one level, and
+    /// expired keys are counted until they are merged away, so this is an upper
+    /// bound rather than an exact count. Tree files written before values were
+    /// counted (magic `HAN3`) add nothing until they are merged into new files.
+    pub fn approximate_len(&self) -> u64 {
+        let levels: u64 = self
+            .lock_levels()
+            .levels
+            .iter()
+            .map(Level::value_count)
+            .sum();
+        levels + self.nursery.value_count() as u64
+    }
+
+    /// Checks the integrity of every file in the database: each level's tree files
+    /// (see `verify_file`), the nursery log segments and a `nursery.data` left over
+    /// from recovery. All the files are checked even if some are corrupt, and the
+    /// report lists the status of each one. Merges are held up while the levels are
+    /// checked.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let key_order = self.nursery.data().key_order();
+        let verify_file = |path: &Path| {
+            Tree::from_file(path)?
+                .with_key_order(key_order.clone())
+                .verify()
+        };
+        let mut files = vec![];
+        if self.nursery.is_in_memory() {
+            return Ok(VerifyReport { files });
+        }
+        for path in log_segments(&self.path)? {
+            let status = verify_log(&path);
+            files.push(FileReport { path, status });
+        }
+        let path = self.path.join("nursery.data");
+        if path.exists() {
+            let status = verify_file(&path).map(|_| ());
+            files.push(FileReport { path, status });
+        }
+        for level in &self.lock_levels().levels {
+            for path in level.tree_files() {
+                let status = verify_file(&path).map(|_| ());
+                files.push(FileReport { path, status });
+            }
+        }
+        Ok(VerifyReport { files })
+    }
+
+    /// Estimates the bytes on disk of the keys from `from` up to, but not including,
+    /// `to`, across the tree files of every level (see `Tree::range_size_estimate`).
+    /// Only the inner blocks of the trees are read. Overwritten and deleted keys are
+    /// counted in every level that holds them, and the nursery is not counted.
+    pub fn range_size_estimate(&self, from: &[u8], to: &[u8]) -> Result<u64> {
+        let levels = &self.lock_levels().levels;
+        let mut size = 0;
+        for tree in levels.iter().flat_map(Level::trees) {
+            size += tree.range_size_estimate(from, to)?;
+        }
+        Ok(size)
+    }
+
+    /// Returns the files held by each level and the size of the nursery.
+    pub fn stats(&self) -> DbStats {
+        DbStats {
+            nursery_entries: self.nursery.data().len(),
+            nursery_bytes: self.nursery.total_size(),
+            levels: self.lock_levels().levels.iter().map(Level::stats).collect(),
+        }
+    }
+
+    /// Returns the levels of the database, from `min_level` to `max_level`, with
+    /// the number of entries each is sized for and how many it holds, for capacity
+    /// planning. An in-memory database has no levels.
+    pub fn level_info(&self) -> Vec<LevelInfo> {
+        self.lock_levels().levels.iter().map(Level::info).collect()
+    }
+
+    /// Reports how full the bloom filter of each tree file is, to help tune
+    /// `OpenOptions::with_bloom_fp_rate`. Only the filters already held in memory
+    /// are read.
+    pub fn bloom_stats(&self) -> BloomStats {
+        BloomStats {
+            fp_rate: self.nursery.writer_options().bloom_fp_rate,
+            trees: self
+                .lock_levels()
+                .levels
+                .iter()
+                .flat_map(Level::bloom_stats)
+                .collect(),
+        }
+    }
+
+    /// Returns the IO done by the database since it was opened: the blocks read by
+    /// lookups and scans, the entries written to new tree files and the merges run.
+    /// Divided by the keys read and written, these give the read and write
+    /// amplification, for tuning the block size and levels.
+    pub fn io_counters(&self) -> IoCounters {
+        self.counters.snapshot()
+    }
+
+    /// Returns the directory that contains this database's files, which is empty for
+    /// a database held in memory.
     pub fn path(&self) -> &Path {
         self.path.as_ref()
     }
 
-    /// Scans all keys and values in the database.
-    pub fn scan(&self) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>> {
-        Scanner::new(&self.nursery, &self.levels)
+    /// Scans all keys and values in the database. Reading a corrupted tree file
+    /// yields an error item; the scan should not be continued after that.
+    pub fn scan(&self) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Scanner::new(self.nursery.shared_data(), &self.lock_levels().levels)
+    }
+
+    /// Iterates over every live key and value in the database in ascending key
+    /// order, the same as `scan`. Each key is emitted once, with its newest value.
+    /// The nursery is read in place rather than copied, unless the database is
+    /// written to while the iterator is alive.
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        self.scan()
+    }
+
+    /// Folds over every live key and value in the database in ascending key order,
+    /// returning the final accumulator, or the first error hit while scanning.
+    pub fn fold<B, F>(&self, init: B, mut f: F) -> Result<B>
+    where
+        F: FnMut(B, &[u8], &[u8]) -> B,
+    {
+        self.scan()?.try_fold(init, |acc, entry| {
+            let (key, value) = entry?;
+            Ok(f(acc, &key, &value))
+        })
+    }
+
+    /// Writes every live key and value in the database to `out` as newline-delimited
+    /// JSON, one `{"key":"...","value":"..."}` object per line in ascending key
+    /// order. Keys and values are base64 encoded. The database is streamed, so this
+    /// doesn't hold more than one key and value in memory at a time.
+    pub fn export_ndjson<W: Write>(&self, mut out: W) -> Result<()> {
+        for entry in self.scan()? {
+            let (key, value) = entry?;
+            write_ndjson_line(&mut out, &key, &value)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Scans all the live keys in the database, in ascending order, without their
+    /// values. Like `scan`, an error item ends the scan.
+    pub fn keys(&self) -> Result<impl Iterator<Item = Result<Vec<u8>>>> {
+        self.keys_range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Scans the live keys in the database that fall between `from` and `to`, in
+    /// ascending order, without their values.
+    pub fn keys_range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>>>> {
+        Ok(self.range(from, to)?.map(|entry| entry.map(|(key, _)| key)))
+    }
+
+    /// Scans the keys and values in the database that fall between `from` and `to`,
+    /// in ascending key order.
+    pub fn range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Scanner::with_range(
+            self.nursery.shared_data(),
+            &self.lock_levels().levels,
+            from,
+            to,
+        )
+    }
+
+    /// Scans at most `limit` keys and values in the database that fall between
+    /// `from` and `to`, in ascending key order. Deleted keys do not count toward
+    /// the limit.
+    pub fn range_limited(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Ok(self.range(from, to)?.take(limit.unwrap_or(usize::MAX)))
+    }
+
+    /// Scans the keys and values in the database that fall between `from` and `to`,
+    /// in descending key order.
+    pub fn range_rev(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Scanner::new_reverse(
+            self.nursery.shared_data(),
+            &self.lock_levels().levels,
+            from,
+            to,
+        )
     }
 
+    /// Scans the keys and values in the database whose keys start with `prefix`,
+    /// in ascending key order. With a custom comparator the keys with the prefix
+    /// need not be next to each other, so the whole database is scanned for them.
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        let (start, end) = if self.nursery.data().key_order().is_bytewise() {
+            let end = match prefix_upper_bound(prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            };
+            (Bound::Included(prefix.to_vec()), end)
+        } else {
+            (Bound::Unbounded, Bound::Unbounded)
+        };
+        let prefix = prefix.to_vec();
+        Ok(self.range(start, end)?.filter(move |entry| {
+            entry
+                .as_ref()
+                .map_or(true, |(key, _)| key.starts_with(&prefix))
+        }))
+    }
+
+    fn lock_levels(&self) -> MutexGuard<'_, Levels> {
+        // WONT PANIC: the lock is only poisoned if a merge panicked, which leaves the
+        // levels in an unknown state
+        self.levels.lock().expect("levels lock poisoned")
+    }
+
+    fn handle_commands(&mut self, commands: Vec<Command>) -> Result<()> {
+        let Some(merge_worker) = &mut self.merge_worker else {
+            return self
+                .levels
+                .lock()
+                .expect("levels lock poisoned")
+                .handle_commands(commands);
+        };
+        // Promotions are applied right away, so that reads see the data that left the
+        // nursery. Merges are left to the background thread.
+        let (merges, promotions): (Vec<_>, Vec<_>) = commands
+            .into_iter()
+            .partition(|command| matches!(command, Command::Merge { .. }));
+        self.levels
+            .lock()
+            .expect("levels lock poisoned")
+            .handle_commands(promotions)?;
+        merge_worker.send(merges)
+    }
+}
+
+/// The levels of a database and the settings needed to run commands against them.
+struct Levels {
+    levels: Vec<Level>,
+    min_level: u32,
+    max_level: u32,
+    merge_strategy: MergeStrategy,
+}
+
+impl Levels {
     fn handle_commands(&mut self, commands: Vec<Command>) -> Result<()> {
         let mut commands = VecDeque::from(commands);
         while let Some(command) = commands.pop_front() {
@@ -151,17 +1377,30 @@ impl HanoiDB {
         let step_size = level_size(self.min_level) / 2;
         let min_level = self.min_level;
         let max_level = self.max_level;
+        let merge_strategy = self.merge_strategy;
         match command {
             Command::PromoteFile { path, target_level } => {
+                let level = self.level_mut(target_level).unwrap();
+                if level.is_full() {
+                    // Merges have fallen behind, so finish this level's merge to make
+                    // room. A merge budget of a full merge per work unit is enough.
+                    let work_unit = level_size(target_level) * 2;
+                    let commands =
+                        level.merge(0, work_unit, min_level, max_level, MergeStrategy::Fast)?;
+                    self.handle_commands(commands)?;
+                }
                 self.level_mut(target_level).unwrap().promote_file(path)
             }
             Command::Merge {
                 steps,
                 target_level,
-            } if target_level <= self.max_level => self
-                .level_mut(target_level)
-                .unwrap()
-                .merge(steps, step_size, min_level, max_level),
+            } if target_level <= self.max_level => self.level_mut(target_level).unwrap().merge(
+                steps,
+                step_size,
+                min_level,
+                max_level,
+                merge_strategy,
+            ),
             Command::Merge { .. } => {
                 // NOTE: If we reached the largest level already, no more merges
                 // can be done
@@ -170,6 +1409,95 @@ impl HanoiDB {
         }
     }
 
+    fn compact(&mut self) -> Result<()> {
+        // Merging a level can promote a file into the next level and give it work,
+        // so keep going from the smallest level with work until none is left
+        while let Some(level) = self.levels.iter_mut().find(|l| l.has_pending_merge()) {
+            let work_unit = level_size(level.level()) * 2;
+            let commands = level.merge(
+                0,
+                work_unit,
+                self.min_level,
+                self.max_level,
+                MergeStrategy::Fast,
+            )?;
+            self.handle_commands(commands)?;
+        }
+        Ok(())
+    }
+
+    /// Works out the merges `compact` would run, like `compact` does them: from the
+    /// smallest level up, merging the two oldest files of a level until at most one
+    /// is left. A merged file that is too big for its level is promoted into the
+    /// next one, and the nursery is flushed into the first level as a file of
+    /// `nursery_entries`.
+    fn plan_compaction(&self, nursery_entries: u64) -> CompactionPlan {
+        let mut promoted: Vec<u64> = vec![];
+        if nursery_entries > 0 {
+            promoted.push(nursery_entries);
+        }
+        let mut plans = vec![];
+        for level in &self.levels {
+            let mut files: VecDeque<u64> = level.file_value_counts().into();
+            files.extend(promoted.drain(..));
+            // Only the first merge can already be running
+            let mut merged = level.merge_progress().unwrap_or(0) as u64;
+            let mut plan = LevelPlan {
+                level: level.level(),
+                merges: 0,
+                steps: 0,
+                output_entries: 0,
+            };
+            while files.len() >= 2 {
+                // WONT PANIC: there are at least two files
+                let count = files.pop_front().unwrap() + files.pop_front().unwrap();
+                plan.merges += 1;
+                plan.steps += count.saturating_sub(std::mem::take(&mut merged));
+                plan.output_entries += count;
+                if count == 0 {
+                    continue;
+                }
+                // The last level has nowhere to promote to
+                if count as usize <= level_size(level.level()) || level.level() == self.max_level {
+                    files.push_front(count);
+                } else {
+                    promoted.push(count);
+                }
+            }
+            plans.push(plan);
+        }
+        CompactionPlan { levels: plans }
+    }
+
+    /// Does as much merge work as a write would, starting from the smallest level
+    /// with a merge pending. Returns `false` if there was nothing to merge.
+    fn merge_pending(&mut self) -> Result<bool> {
+        let Some(target_level) = self
+            .levels
+            .iter()
+            .find(|level| level.has_pending_merge())
+            .map(Level::level)
+        else {
+            return Ok(false);
+        };
+        self.handle_commands(vec![Command::Merge {
+            steps: 0,
+            target_level,
+        }])?;
+        Ok(true)
+    }
+
+    /// Returns the smallest level that fits a file of `count` entries, or the last
+    /// level, with the options its files are written with.
+    fn ingest_level(&self, count: usize) -> (u32, WriterOptions) {
+        let level = (self.min_level..self.max_level)
+            .find(|level| level_size(*level) >= count)
+            .unwrap_or(self.max_level);
+        // WONT PANIC: the level is between the first and the last
+        let options = self.levels[(level - self.min_level) as usize].writer_options();
+        (level, options)
+    }
+
     fn level_mut(&mut self, level: u32) -> Option<&mut Level> {
         assert!(
             (self.min_level..=self.max_level).contains(&level),
@@ -180,8 +1508,283 @@ impl HanoiDB {
     }
 }
 
+/// A thread that runs the merge commands queued up by writes, in order, and merges
+/// on its own while idle if it has an auto compaction interval.
+struct MergeWorker {
+    sender: Option<Sender<Vec<Command>>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl MergeWorker {
+    fn spawn(levels: Arc<Mutex<Levels>>, auto_compact_interval: Option<Duration>) -> Result<Self> {
+        let (sender, receiver) = channel::<Vec<Command>>();
+        let thread = std::thread::Builder::new()
+            .name("hanoidb-merge".to_owned())
+            .spawn(move || {
+                let lock = || levels.lock().expect("levels lock poisoned");
+                let mut idle_ticks = 0;
+                loop {
+                    let received = match auto_compact_interval {
+                        Some(interval) => {
+                            // Back off while there's nothing to merge
+                            match receiver.recv_timeout(interval * (1 << idle_ticks)) {
+                                Err(RecvTimeoutError::Timeout) => {
+                                    idle_ticks = match lock().merge_pending()? {
+                                        true => 0,
+                                        false => (idle_ticks + 1).min(3),
+                                    };
+                                    continue;
+                                }
+                                received => received.ok(),
+                            }
+                        }
+                        None => receiver.recv().ok(),
+                    };
+                    // The sender is dropped when the database closes
+                    let Some(commands) = received else {
+                        return Ok(());
+                    };
+                    idle_ticks = 0;
+                    lock().handle_commands(commands)?;
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues merge commands. Fails with the error of the merge that stopped the
+    /// thread, if one did, and with `MergeWorkerStopped` after that.
+    fn send(&mut self, commands: Vec<Command>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let Some(sender) = &self.sender else {
+            return Err(Error::MergeWorkerStopped);
+        };
+        if sender.send(commands).is_err() {
+            // The thread stopped because a merge failed, report why
+            self.stop()?;
+            return Err(Error::MergeWorkerStopped);
+        }
+        Ok(())
+    }
+
+    /// Checks whether the thread has stopped, because the database is closing or a
+    /// merge failed.
+    fn is_stopped(&self) -> bool {
+        self.sender.is_none() || self.thread.as_ref().is_some_and(JoinHandle::is_finished)
+    }
+
+    /// Waits for the queued merges to finish and stops the thread. This runs when
+    /// the database is dropped, so a merge that panicked is reported as an error.
+    fn stop(&mut self) -> Result<()> {
+        let _ = self.sender.take();
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or(Err(Error::MergeWorkerStopped)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for HanoiDB {
+    fn drop(&mut self) {
+        // Best effort: move the nursery into the levels, so that the next open
+        // doesn't have to replay the log. The log is still there if this fails.
+        let _result = self.flush_nursery_on_drop();
+        #[cfg(feature = "tracing")]
+        if let Err(err) = _result {
+            tracing::warn!(
+                path = %self.path.display(),
+                error = %err,
+                "failed to flush the nursery on close"
+            );
+        }
+    }
+}
+
+impl Drop for MergeWorker {
+    fn drop(&mut self) {
+        // Let the queued merges finish, so that no partial merge files are left behind
+        let _ = self.stop();
+    }
+}
+
+/// Looks up a key in the nursery data first and then in the levels in order, until
+/// the key or a tombstone is found (see `latest_entry`).
+pub(crate) fn lookup(
+    nursery: &NurseryData,
+    levels: &[Level],
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let now = now_timestamp();
+    //    - check the nursery first for the key
+    let found = nursery
+        .get(key)
+        .map(|value| value.clone().into_entry(key.to_vec()));
+    //    - check the levels in order until you find it or a tombstone
+    let trees = levels
+        .iter()
+        .filter(|level| level.might_contain(key))
+        .flat_map(Level::trees);
+    let found = latest_entry(found, trees.map(|tree| probe(tree, key)))?;
+    Ok(found.and_then(|entry| entry.into_live_value(now)))
+}
+
+/// Looks up a key like `lookup`, but probes the trees of all the levels at the same
+/// time. The results are then checked from the newest tree to the oldest, so the
+/// same entry wins as in `lookup`.
+fn lookup_parallel(nursery: &NurseryData, levels: &[Level], key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let now = now_timestamp();
+    let found = nursery
+        .get(key)
+        .map(|value| value.clone().into_entry(key.to_vec()));
+    if found
+        .as_ref()
+        .is_some_and(|entry| entry.write_timestamp().is_none())
+    {
+        return Ok(found.and_then(|entry| entry.into_live_value(now)));
+    }
+    let trees: Vec<&Tree> = levels.iter().flat_map(Level::trees).collect();
+    let probes: Vec<Result<(Option<Entry>, bool)>> =
+        trees.par_iter().map(|tree| probe(tree, key)).collect();
+    let found = latest_entry(found, probes)?;
+    Ok(found.and_then(|entry| entry.into_live_value(now)))
+}
+
+/// Looks up a key in a tree, and checks whether the tree's range tombstones cover it.
+fn probe(tree: &Tree, key: &[u8]) -> Result<(Option<Entry>, bool)> {
+    Ok((tree.get_entry(key)?, tree.range_deleted(key)))
+}
+
+/// Picks the entry that reads return for a key, from the one found in the nursery
+/// (if any) and the probes of the trees from the newest to the oldest. The first
+/// entry found wins, unless an older one was written later (see
+/// `Entry::written_before`), as it would when they are merged. So the probing only
+/// stops at an entry without a write timestamp, or at a tree whose range tombstones
+/// delete the key in the older trees.
+fn latest_entry(
+    mut found: Option<Entry>,
+    probes: impl IntoIterator<Item = Result<(Option<Entry>, bool)>>,
+) -> Result<Option<Entry>> {
+    let mut probes = probes.into_iter();
+    while found
+        .as_ref()
+        .is_none_or(|entry| entry.write_timestamp().is_some())
+    {
+        let Some(probe) = probes.next() else {
+            break;
+        };
+        let (entry, range_deleted) = probe?;
+        if let Some(entry) = entry {
+            if found
+                .as_ref()
+                .is_none_or(|latest| latest.written_before(&entry))
+            {
+                found = Some(entry);
+            }
+        }
+        if range_deleted {
+            break;
+        }
+    }
+    Ok(found)
+}
+
+/// Checks whether the directory holds any of a database's files.
+fn has_database_files(path: &Path) -> Result<bool> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        if is_database_file(&entry?.file_name().to_string_lossy()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks whether a file name is one that HanoiDB creates: the nursery files (including
+/// log segments like `nursery-1.log`), level files like `A-10.data` (including
+/// in-progress `X`, `R` and `M` files), the hard links made for scans like
+/// `A-10.scan-123-4` and the directories of snapshots like `snapshot-123-4`.
+fn is_database_file(name: &str) -> bool {
+    if log_segment_number(name).is_some()
+        || name == "nursery.data"
+        || name == "ingest.data"
+        || name == "ingest-rewrite.data"
+    {
+        return true;
+    }
+    if is_snapshot_dir(name) {
+        return true;
+    }
+    let Some((stem, extension)) = name.split_once('.') else {
+        return false;
+    };
+    let Some((prefix, level)) = stem.split_once('-') else {
+        return false;
+    };
+    ["A", "B", "C", "M", "R", "X"].contains(&prefix)
+        && !level.is_empty()
+        && level.bytes().all(|b| b.is_ascii_digit())
+        && (extension == "data" || extension.starts_with("scan-"))
+}
+
+/// Computes the smallest key that is larger than every key starting with `prefix`,
+/// or `None` if there is no such key (the prefix is empty or all `0xFF` bytes).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last_incrementable = prefix.iter().rposition(|byte| *byte != 0xFF)?;
+    let mut end = prefix[..=last_incrementable].to_vec();
+    end[last_incrementable] += 1;
+    Some(end)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     PromoteFile { path: PathBuf, target_level: u32 },
     Merge { steps: usize, target_level: u32 },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_file_names() {
+        assert!(is_database_file("nursery.log"));
+        assert!(is_database_file("nursery.data"));
+        assert!(is_database_file("nursery-12.log"));
+        assert!(!is_database_file("nursery-.log"));
+        assert!(!is_database_file("nursery-x.log"));
+        assert!(is_database_file("A-10.data"));
+        assert!(is_database_file("X-25.data"));
+        assert!(is_database_file("R-11.data"));
+        assert!(is_database_file("C-12.scan-1234"));
+        assert!(is_database_file("C-12.scan-123-4"));
+        assert!(!is_database_file("D-10.data"));
+        assert!(!is_database_file("A-.data"));
+        assert!(!is_database_file("A-10.txt"));
+        assert!(!is_database_file("README"));
+        assert!(is_database_file("snapshot-123-4"));
+        assert!(is_database_file("snapshot-1729000000000000000"));
+        assert!(!is_database_file("snapshot-"));
+        assert!(!is_database_file("snapshot-123-"));
+        assert!(!is_database_file("snapshot-abc"));
+    }
+
+    #[test]
+    fn prefix_upper_bounds() {
+        assert_eq!(prefix_upper_bound(b""), None);
+        assert_eq!(prefix_upper_bound(&[0xFF, 0xFF]), None);
+        assert_eq!(prefix_upper_bound(b"a"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(&[0x01, 0xFF]), Some(vec![0x02]));
+        assert_eq!(
+            prefix_upper_bound(&[0x01, 0xFE, 0xFF]),
+            Some(vec![0x01, 0xFF])
+        );
+    }
+}
@@ -1,29 +1,145 @@
+use crate::comparator::KeyOrder;
 use crate::error::*;
+use crate::writer::FIRST_BLOCK_POS;
 
-use crate::{TAG_DELETED, TAG_DELETED2, TAG_END, TAG_KV_DATA, TAG_KV_DATA2, TAG_POSLEN32};
+use crate::{
+    TAG_DELETED, TAG_DELETED2, TAG_DELETED3, TAG_END, TAG_KV_DATA, TAG_KV_DATA2, TAG_KV_DATA3,
+    TAG_POSLEN32, TAG_RANGE_DELETED, TAG_TRANSACT,
+};
 
+use std::cmp::Ordering;
 use std::io::{ErrorKind, Read};
+use std::time::{Duration, SystemTime};
 
+/// Returns the current time in the format used by entry timestamps: seconds since
+/// the Unix epoch.
+pub fn now_timestamp() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    now.as_secs().min(u32::MAX as u64) as u32
+}
+
+/// Computes the timestamp at which an entry written now with the given time-to-live
+/// expires, rounded up to the next whole second.
+pub fn expiry_timestamp(ttl: Duration) -> u32 {
+    let expiry = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        + ttl;
+    let secs = expiry.as_secs() + u64::from(expiry.subsec_nanos() > 0);
+    secs.min(u32::MAX as u64) as u32
+}
+
+/// An entry in a tree file. With the `serde` feature, entries can also be
+/// serialized through serde, for inspecting them or writing test fixtures; this
+/// doesn't affect the binary format written by `encode`.
+///
+/// The variants may gain fields, so outside this crate entries are built with
+/// `Entry::key_val`, `Entry::deleted` and `Entry::pos_len`, which also check them.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(dead_code)]
 pub enum Entry {
+    #[non_exhaustive]
     KeyVal {
         key: Vec<u8>,
         value: Vec<u8>,
+        /// When the value expires, in seconds since the Unix epoch (see
+        /// `now_timestamp`). This is not the time the value was written.
         timestamp: Option<u32>,
+        /// The time the value was written, given with `HanoiDB::insert_at`. When
+        /// two entries for a key both have one, the later write wins a merge;
+        /// otherwise the entry from the newer file does.
+        write_timestamp: Option<u32>,
     },
+    #[non_exhaustive]
     Deleted {
         key: Vec<u8>,
+        /// Carried by the format like the timestamp of a value, but tombstones
+        /// never expire.
         timestamp: Option<u32>,
+        /// The time the key was deleted, given with `HanoiDB::delete_at`. See the
+        /// `write_timestamp` of a value.
+        write_timestamp: Option<u32>,
     },
+    #[non_exhaustive]
     PosLen {
         blockpos: u64,
         blocklen: u32,
         key: Vec<u8>,
     },
+    #[non_exhaustive]
+    Transaction { entries: Vec<Entry> },
+    /// A tombstone for every key in `start..end` that was written before it. In a
+    /// tree file it sorts by `start`, before a value or tombstone with the same key,
+    /// and the values and tombstones in its range in the same file are newer than it.
+    #[non_exhaustive]
+    RangeDeleted {
+        start: Vec<u8>,
+        end: Vec<u8>,
+        timestamp: Option<u32>,
+    },
+}
+
+/// The start of an entry, see `Entry::read_until_value`.
+pub(crate) enum EntryStart {
+    /// A value, of which the `value_len` bytes of the value and the closing
+    /// `TAG_END` are still to be read.
+    KeyVal {
+        key: Vec<u8>,
+        timestamp: Option<u32>,
+        write_timestamp: Option<u32>,
+        value_len: u64,
+    },
+    /// Any other entry, read in full.
+    Other(Entry),
 }
 
 impl Entry {
+    /// Creates a value that doesn't expire. Fails with `Error::EntryTooLarge` if it
+    /// is too large to encode.
+    pub fn key_val(key: Vec<u8>, value: Vec<u8>) -> Result<Self> {
+        let entry = Entry::KeyVal {
+            key,
+            value,
+            timestamp: None,
+            write_timestamp: None,
+        };
+        entry.check_size()?;
+        Ok(entry)
+    }
+
+    /// Creates a tombstone for a key. Fails with `Error::EntryTooLarge` if the key
+    /// is too large to encode.
+    pub fn deleted(key: Vec<u8>) -> Result<Self> {
+        let entry = Entry::Deleted {
+            key,
+            timestamp: None,
+            write_timestamp: None,
+        };
+        entry.check_size()?;
+        Ok(entry)
+    }
+
+    /// Creates a pointer to the block of `blocklen` bytes (after its length field)
+    /// at `blockpos`, whose first key is `key`. Fails with `Error::InvalidPosLen`
+    /// if the block can't be in a tree file: it must come after the file's magic,
+    /// be long enough for a block header and end within a u64 offset.
+    pub fn pos_len(blockpos: u64, blocklen: u32, key: Vec<u8>) -> Result<Self> {
+        let ends = blockpos.checked_add(4 + blocklen as u64).is_some();
+        if blockpos < FIRST_BLOCK_POS || blocklen < 3 || !ends {
+            return Err(Error::InvalidPosLen { blockpos, blocklen });
+        }
+        let entry = Entry::PosLen {
+            blockpos,
+            blocklen,
+            key,
+        };
+        entry.check_size()?;
+        Ok(entry)
+    }
+
     ///Returns `true` if this value is of type `
     ///KeyVal
     ///`. Returns `false` otherwise
@@ -49,11 +165,96 @@ impl Entry {
         matches!(self, Entry::PosLen { .. })
     }
 
+    ///Returns `true` if this value is of type `
+    ///Transaction
+    ///`. Returns `false` otherwise
+    #[inline]
+    #[must_use]
+    pub const fn is_transaction(&self) -> bool {
+        matches!(self, Entry::Transaction { .. })
+    }
+
+    ///Returns `true` if this value is of type `
+    ///RangeDeleted
+    ///`. Returns `false` otherwise
+    #[inline]
+    #[must_use]
+    pub const fn is_range_deleted(&self) -> bool {
+        matches!(self, Entry::RangeDeleted { .. })
+    }
+
+    /// Orders entries the way they are written to a tree file: by key, with a range
+    /// tombstone before a value or tombstone for the key its range starts at.
+    pub fn cmp_position(&self, other: &Entry) -> Ordering {
+        self.cmp_position_by(other, &KeyOrder::default())
+    }
+
+    /// Orders entries like `cmp_position`, with the keys in the given order.
+    pub(crate) fn cmp_position_by(&self, other: &Entry, order: &KeyOrder) -> Ordering {
+        order
+            .cmp(self.key(), other.key())
+            .then_with(|| other.is_range_deleted().cmp(&self.is_range_deleted()))
+    }
+
+    /// Returns `true` if this is a value whose expiry timestamp has been reached at
+    /// time `now`.
+    pub fn is_expired(&self, now: u32) -> bool {
+        matches!(self, Entry::KeyVal { timestamp: Some(expiry), .. } if *expiry <= now)
+    }
+
+    /// Returns the value of this entry, or `None` if it is a tombstone or it has
+    /// expired at time `now`.
+    pub fn into_live_value(self, now: u32) -> Option<Vec<u8>> {
+        match self {
+            entry if entry.is_expired(now) => None,
+            Entry::KeyVal { value, .. } => Some(value),
+            Entry::Deleted { .. } => None,
+            Entry::PosLen { .. } | Entry::Transaction { .. } | Entry::RangeDeleted { .. } => {
+                unreachable!("expected a data entry")
+            }
+        }
+    }
+
+    /// Returns the write timestamp of a value or tombstone written with
+    /// `HanoiDB::insert_at` or `HanoiDB::delete_at`.
+    pub fn write_timestamp(&self) -> Option<u32> {
+        match self {
+            Entry::KeyVal {
+                write_timestamp, ..
+            }
+            | Entry::Deleted {
+                write_timestamp, ..
+            } => *write_timestamp,
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if both entries have a write timestamp and this one's is
+    /// earlier, so that `other` wins over this entry even from an older file.
+    pub(crate) fn written_before(&self, other: &Entry) -> bool {
+        written_before(self.write_timestamp(), other.write_timestamp())
+    }
+
+    /// Returns the key of the entry to be changed in place, or `None` for a
+    /// transaction, which has no key of its own.
+    pub(crate) fn key_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            Entry::KeyVal { key, .. } | Entry::Deleted { key, .. } | Entry::PosLen { key, .. } => {
+                Some(key)
+            }
+            Entry::Transaction { .. } => None,
+            Entry::RangeDeleted { start, .. } => Some(start),
+        }
+    }
+
     pub fn key(&self) -> &[u8] {
         match self {
             Entry::KeyVal { key, .. } | Entry::Deleted { key, .. } | Entry::PosLen { key, .. } => {
                 key.as_slice()
             }
+            // Transactions only group other entries and have no key of their own
+            Entry::Transaction { .. } => &[],
+            Entry::RangeDeleted { start, .. } => start.as_slice(),
         }
     }
 
@@ -63,7 +264,7 @@ impl Entry {
             if err.kind() == ErrorKind::UnexpectedEof {
                 Error::EndOfFile
             } else {
-                err.into()
+                Error::from_read(err)
             }
         })?;
         let length = u32::from_be_bytes(header[0..4].try_into()?);
@@ -73,7 +274,7 @@ impl Entry {
             if err.kind() == ErrorKind::UnexpectedEof {
                 Error::IncompleteEntry(err)
             } else {
-                err.into()
+                Error::from_read(err)
             }
         })?;
         let crc = crc32fast::hash(&entry_data);
@@ -90,6 +291,7 @@ impl Entry {
                     key,
                     value,
                     timestamp: None,
+                    write_timestamp: None,
                 }
             }
             TAG_KV_DATA2 => {
@@ -101,6 +303,7 @@ impl Entry {
                     key,
                     value,
                     timestamp: Some(timestamp),
+                    write_timestamp: None,
                 }
             }
             TAG_DELETED => {
@@ -108,6 +311,7 @@ impl Entry {
                 Self::Deleted {
                     key,
                     timestamp: None,
+                    write_timestamp: None,
                 }
             }
             TAG_DELETED2 => {
@@ -116,6 +320,31 @@ impl Entry {
                 Self::Deleted {
                     key,
                     timestamp: Some(timestamp),
+                    write_timestamp: None,
+                }
+            }
+            TAG_KV_DATA3 => {
+                let (timestamp, write_timestamp, offset) = read_timestamps(&entry_data)?;
+                let keylen = u32::from_be_bytes(entry_data[offset..offset + 4].try_into()?);
+                let mut key = entry_data.split_off(offset + 4);
+                if key.len() < keylen as usize {
+                    return Err(Error::CorruptedFile("Entry key is longer than the entry"));
+                }
+                let value = key.split_off(keylen as usize);
+                Self::KeyVal {
+                    key,
+                    value,
+                    timestamp,
+                    write_timestamp: Some(write_timestamp),
+                }
+            }
+            TAG_DELETED3 => {
+                let (timestamp, write_timestamp, offset) = read_timestamps(&entry_data)?;
+                let key = entry_data.split_off(offset);
+                Self::Deleted {
+                    key,
+                    timestamp,
+                    write_timestamp: Some(write_timestamp),
                 }
             }
             TAG_POSLEN32 => {
@@ -128,6 +357,53 @@ impl Entry {
                     key,
                 }
             }
+            TAG_TRANSACT => {
+                let count = u32::from_be_bytes(entry_data[1..5].try_into()?);
+                let mut nested = &entry_data[5..];
+                let entries = (0..count)
+                    .map(|_| match Entry::read(&mut nested) {
+                        Err(Error::EndOfFile | Error::IncompleteEntry(_)) => Err(
+                            Error::CorruptedFile("Transaction contained a truncated entry"),
+                        ),
+                        Ok(entry)
+                            if entry.is_transaction()
+                                || entry.is_pos_len()
+                                || entry.is_range_deleted() =>
+                        {
+                            Err(Error::CorruptedFile(
+                                "Transaction contained a non-data entry",
+                            ))
+                        }
+                        result => result,
+                    })
+                    .collect::<Result<Vec<Entry>>>()?;
+                if !nested.is_empty() {
+                    return Err(Error::CorruptedFile(
+                        "Transaction had trailing bytes after its entries",
+                    ));
+                }
+                Self::Transaction { entries }
+            }
+            TAG_RANGE_DELETED => {
+                let timestamp = match entry_data[1] {
+                    0 => None,
+                    _ => Some(u32::from_be_bytes(entry_data[2..6].try_into()?)),
+                };
+                let offset = if timestamp.is_some() { 6 } else { 2 };
+                let startlen = u32::from_be_bytes(entry_data[offset..offset + 4].try_into()?);
+                let mut start = entry_data.split_off(offset + 4);
+                if start.len() < startlen as usize {
+                    return Err(Error::CorruptedFile(
+                        "Range tombstone start is longer than the entry",
+                    ));
+                }
+                let end = start.split_off(startlen as usize);
+                Self::RangeDeleted {
+                    start,
+                    end,
+                    timestamp,
+                }
+            }
             tag => {
                 return Err(Error::InvalidEntryTag(tag));
             }
@@ -140,7 +416,79 @@ impl Entry {
         Ok(entry)
     }
 
-    pub fn encode(&self) -> Vec<u8> {
+    /// Reads an entry like `read`, but stops at the start of the value of a `KeyVal`
+    /// entry so that the value can be streamed instead of buffered. The CRC of a
+    /// value read this way isn't checked, as that would mean reading all of it.
+    pub(crate) fn read_until_value(file: &mut impl Read) -> Result<EntryStart> {
+        // The length, the CRC and the tag
+        let mut start = vec![0; 9];
+        file.read_exact(&mut start).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                Error::EndOfFile
+            } else {
+                err.into()
+            }
+        })?;
+        let length = u32::from_be_bytes(start[0..4].try_into()?) as u64;
+        let (fields_len, timestamp, write_timestamp) = match start[8] {
+            TAG_KV_DATA => (1 + 4, None, None),
+            TAG_KV_DATA2 => {
+                let mut timestamp = [0; 4];
+                file.read_exact(&mut timestamp)?;
+                (1 + 4 + 4, Some(u32::from_be_bytes(timestamp)), None)
+            }
+            TAG_KV_DATA3 => {
+                let mut flag = [0; 1];
+                file.read_exact(&mut flag)?;
+                let mut timestamp = [0; 4];
+                let timestamp = if flag[0] == 0 {
+                    None
+                } else {
+                    file.read_exact(&mut timestamp)?;
+                    Some(u32::from_be_bytes(timestamp))
+                };
+                let mut write_timestamp = [0; 4];
+                file.read_exact(&mut write_timestamp)?;
+                (
+                    1 + 1 + timestamp.map_or(0, |_| 4) + 4 + 4,
+                    timestamp,
+                    Some(u32::from_be_bytes(write_timestamp)),
+                )
+            }
+            _ => return Entry::read(&mut start.as_slice().chain(file)).map(EntryStart::Other),
+        };
+        let mut keylen = [0; 4];
+        file.read_exact(&mut keylen)?;
+        let mut key = vec![0; u32::from_be_bytes(keylen) as usize];
+        file.read_exact(&mut key)?;
+        let value_len = length
+            .checked_sub(fields_len + key.len() as u64)
+            .ok_or(Error::CorruptedFile("Entry key is longer than the entry"))?;
+        Ok(EntryStart::KeyVal {
+            key,
+            timestamp,
+            write_timestamp,
+            value_len,
+        })
+    }
+
+    /// Checks that the entry can be encoded, which requires its length to fit in
+    /// the u32 length field of the entry header.
+    pub fn check_size(&self) -> Result<()> {
+        if !length_fits(self.encoded_size()) {
+            let (key_len, value_len) = match self {
+                Entry::KeyVal { key, value, .. } => (key.len(), value.len()),
+                Entry::Transaction { .. } => (0, self.encoded_size()),
+                Entry::RangeDeleted { start, end, .. } => (start.len() + end.len(), 0),
+                _ => (self.key().len(), 0),
+            };
+            return Err(Error::EntryTooLarge { key_len, value_len });
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        self.check_size()?;
         let total_size = self.encoded_size();
         let mut entry = Vec::with_capacity(total_size);
         entry.extend(((total_size - 9) as u32).to_be_bytes());
@@ -150,25 +498,40 @@ impl Entry {
                 key,
                 value,
                 timestamp,
+                write_timestamp,
             } => {
-                if let Some(ts) = timestamp {
-                    entry.push(TAG_KV_DATA2);
-                    entry.extend(ts.to_be_bytes());
-                } else {
-                    entry.push(TAG_KV_DATA);
+                match (timestamp, write_timestamp) {
+                    (_, Some(written)) => {
+                        entry.push(TAG_KV_DATA3);
+                        encode_timestamps(&mut entry, *timestamp, *written);
+                    }
+                    (Some(ts), None) => {
+                        entry.push(TAG_KV_DATA2);
+                        entry.extend(ts.to_be_bytes());
+                    }
+                    (None, None) => entry.push(TAG_KV_DATA),
                 }
-                // UNSAFE: usize could exceed u32
+                // The key is shorter than the entry, which was checked to fit in a u32
                 let key_size = (key.len() as u32).to_be_bytes();
                 entry.extend(key_size);
                 entry.extend(key);
                 entry.extend(value);
             }
-            Entry::Deleted { key, timestamp } => {
-                if let Some(ts) = timestamp {
-                    entry.push(TAG_DELETED2);
-                    entry.extend(ts.to_be_bytes());
-                } else {
-                    entry.push(TAG_DELETED);
+            Entry::Deleted {
+                key,
+                timestamp,
+                write_timestamp,
+            } => {
+                match (timestamp, write_timestamp) {
+                    (_, Some(written)) => {
+                        entry.push(TAG_DELETED3);
+                        encode_timestamps(&mut entry, *timestamp, *written);
+                    }
+                    (Some(ts), None) => {
+                        entry.push(TAG_DELETED2);
+                        entry.extend(ts.to_be_bytes());
+                    }
+                    (None, None) => entry.push(TAG_DELETED),
                 }
                 entry.extend(key);
             }
@@ -182,11 +545,36 @@ impl Entry {
                 entry.extend(blocklen.to_be_bytes());
                 entry.extend(key);
             }
+            Entry::Transaction { entries } => {
+                entry.push(TAG_TRANSACT);
+                entry.extend((entries.len() as u32).to_be_bytes());
+                for nested in entries {
+                    entry.extend(nested.encode()?);
+                }
+            }
+            Entry::RangeDeleted {
+                start,
+                end,
+                timestamp,
+            } => {
+                entry.push(TAG_RANGE_DELETED);
+                match timestamp {
+                    Some(ts) => {
+                        entry.push(1);
+                        entry.extend(ts.to_be_bytes());
+                    }
+                    None => entry.push(0),
+                }
+                // The start is shorter than the entry, which was checked to fit in a u32
+                entry.extend((start.len() as u32).to_be_bytes());
+                entry.extend(start);
+                entry.extend(end);
+            }
         }
         let crc = crc32fast::hash(&entry[8..(total_size - 1)]).to_be_bytes();
         entry[4..8].copy_from_slice(&crc);
         entry.push(TAG_END);
-        entry
+        Ok(entry)
     }
 
     pub fn encoded_size(&self) -> usize {
@@ -196,18 +584,304 @@ impl Entry {
                 key,
                 value,
                 timestamp,
+                write_timestamp,
             } => {
-                // Tag + optional timestamp u32 + key len + key + value
-                1 + timestamp.as_ref().map(|_| 4).unwrap_or_default() + 4 + key.len() + value.len()
+                // Tag + timestamps + key len + key + value
+                1 + timestamps_size(*timestamp, *write_timestamp) + 4 + key.len() + value.len()
             }
-            Entry::Deleted { key, timestamp } => {
-                // Tag + optional timestamp u32 + key
-                1 + timestamp.as_ref().map(|_| 4).unwrap_or_default() + key.len()
+            Entry::Deleted {
+                key,
+                timestamp,
+                write_timestamp,
+            } => {
+                // Tag + timestamps + key
+                1 + timestamps_size(*timestamp, *write_timestamp) + key.len()
             }
             Entry::PosLen { key, .. } => {
                 // Tag + blockpos + blocklen + key
                 1 + 8 + 4 + key.len()
             }
+            Entry::Transaction { entries } => {
+                // Tag + entry count + nested entries
+                1 + 4 + entries.iter().map(Entry::encoded_size).sum::<usize>()
+            }
+            Entry::RangeDeleted {
+                start,
+                end,
+                timestamp,
+            } => {
+                // Tag + timestamp flag + optional timestamp u32 + start len + start + end
+                1 + 1
+                    + timestamp.as_ref().map(|_| 4).unwrap_or_default()
+                    + 4
+                    + start.len()
+                    + end.len()
+            }
+        }
+    }
+}
+
+/// Returns `true` if both write timestamps are set and the first is earlier, so
+/// that a write with the second wins over it even if it was written first.
+pub(crate) fn written_before(write_timestamp: Option<u32>, other: Option<u32>) -> bool {
+    matches!((write_timestamp, other), (Some(a), Some(b)) if a < b)
+}
+
+/// Returns the size of the timestamps of a value or tombstone: an optional expiry
+/// timestamp, or with a write timestamp, a flag byte, the optional expiry
+/// timestamp and the write timestamp.
+fn timestamps_size(timestamp: Option<u32>, write_timestamp: Option<u32>) -> usize {
+    let timestamp = timestamp.map(|_| 4).unwrap_or_default();
+    match write_timestamp {
+        Some(_) => 1 + timestamp + 4,
+        None => timestamp,
+    }
+}
+
+/// Writes the timestamps of a `TAG_KV_DATA3` or `TAG_DELETED3` entry.
+fn encode_timestamps(entry: &mut Vec<u8>, timestamp: Option<u32>, write_timestamp: u32) {
+    match timestamp {
+        Some(ts) => {
+            entry.push(1);
+            entry.extend(ts.to_be_bytes());
+        }
+        None => entry.push(0),
+    }
+    entry.extend(write_timestamp.to_be_bytes());
+}
+
+/// Reads the timestamps written by `encode_timestamps`, returning them and the
+/// offset of the rest of the entry.
+fn read_timestamps(entry_data: &[u8]) -> Result<(Option<u32>, u32, usize)> {
+    let offset = if entry_data.get(1) == Some(&0) { 2 } else { 6 };
+    let fields = entry_data
+        .get(..offset + 4)
+        .ok_or(Error::CorruptedFile("Entry is shorter than its timestamps"))?;
+    let timestamp = (offset == 6)
+        .then(|| fields[2..6].try_into().map(u32::from_be_bytes))
+        .transpose()?;
+    let write_timestamp = u32::from_be_bytes(fields[offset..offset + 4].try_into()?);
+    Ok((timestamp, write_timestamp, offset + 4))
+}
+
+/// Checks whether an entry of `encoded_size` bytes can be encoded. Its length, which
+/// leaves out the length field, the CRC and the trailing `TAG_END`, must fit in a u32.
+fn length_fits(encoded_size: usize) -> bool {
+    encoded_size - 9 <= u32::MAX as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn oversized_entries_are_rejected() {
+        // Entries at the limit are too large to allocate in a test, so only the
+        // arithmetic is checked
+        assert!(length_fits(u32::MAX as usize + 9));
+        assert!(!length_fits(u32::MAX as usize + 10));
+        let entry = Entry::key_val(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(length_fits(entry.encoded_size()));
+        assert!(entry.check_size().is_ok());
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(
+            Entry::key_val(b"key".to_vec(), b"value".to_vec()).unwrap(),
+            Entry::KeyVal {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            }
+        );
+        assert_eq!(
+            Entry::deleted(b"key".to_vec()).unwrap(),
+            Entry::Deleted {
+                key: b"key".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            }
+        );
+        let pos_len = Entry::pos_len(4, 100, b"key".to_vec()).unwrap();
+        assert_eq!(
+            Entry::read(&mut pos_len.encode().unwrap().as_slice()).unwrap(),
+            pos_len
+        );
+
+        for (blockpos, blocklen) in [(0, 100), (3, 100), (4, 2), (u64::MAX - 10, 100)] {
+            assert!(
+                matches!(
+                    Entry::pos_len(blockpos, blocklen, b"key".to_vec()),
+                    Err(Error::InvalidPosLen { .. })
+                ),
+                "{blockpos}, {blocklen}"
+            );
+        }
+    }
+
+    #[test]
+    fn transaction_roundtrip() {
+        let transaction = Entry::Transaction {
+            entries: vec![
+                Entry::KeyVal {
+                    key: "key".as_bytes().to_vec(),
+                    value: "value".as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                },
+                Entry::Deleted {
+                    key: "deleted".as_bytes().to_vec(),
+                    timestamp: Some(12345),
+                    write_timestamp: None,
+                },
+            ],
+        };
+        let encoded = transaction.encode().unwrap();
+        assert_eq!(encoded.len(), transaction.encoded_size());
+        assert_eq!(encoded[8], TAG_TRANSACT);
+        let decoded = Entry::read(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded, transaction);
+    }
+
+    #[test]
+    fn transaction_crc_covers_nested_entries() {
+        let transaction = Entry::Transaction {
+            entries: vec![Entry::KeyVal {
+                key: "key".as_bytes().to_vec(),
+                value: "value".as_bytes().to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            }],
+        };
+        let mut encoded = transaction.encode().unwrap();
+        // Flip a byte in the nested entry's value
+        let last_value_byte = encoded.len() - 3;
+        encoded[last_value_byte] ^= 0xFF;
+        let error = Entry::read(&mut encoded.as_slice()).unwrap_err();
+        assert!(matches!(error, Error::CorruptedFile(_)));
+    }
+
+    #[test]
+    fn nested_transactions_are_rejected() {
+        let transaction = Entry::Transaction {
+            entries: vec![Entry::Transaction { entries: vec![] }],
+        };
+        let encoded = transaction.encode().unwrap();
+        let error = Entry::read(&mut encoded.as_slice()).unwrap_err();
+        assert!(matches!(error, Error::CorruptedFile(_)));
+    }
+
+    #[test]
+    fn range_deleted_roundtrip() {
+        for timestamp in [None, Some(12345)] {
+            let range = Entry::RangeDeleted {
+                start: "key-100".as_bytes().to_vec(),
+                end: "key-200".as_bytes().to_vec(),
+                timestamp,
+            };
+            let encoded = range.encode().unwrap();
+            assert_eq!(encoded.len(), range.encoded_size());
+            assert_eq!(encoded[8], TAG_RANGE_DELETED);
+            let decoded = Entry::read(&mut encoded.as_slice()).unwrap();
+            assert_eq!(decoded, range);
+        }
+    }
+
+    #[test]
+    fn write_timestamp_roundtrip() {
+        for timestamp in [None, Some(12345)] {
+            let entries = [
+                Entry::KeyVal {
+                    key: "key".as_bytes().to_vec(),
+                    value: "value".as_bytes().to_vec(),
+                    timestamp,
+                    write_timestamp: Some(67890),
+                },
+                Entry::Deleted {
+                    key: "key".as_bytes().to_vec(),
+                    timestamp,
+                    write_timestamp: Some(67890),
+                },
+            ];
+            for (entry, tag) in entries.into_iter().zip([TAG_KV_DATA3, TAG_DELETED3]) {
+                let encoded = entry.encode().unwrap();
+                assert_eq!(encoded.len(), entry.encoded_size());
+                assert_eq!(encoded[8], tag);
+                let decoded = Entry::read(&mut encoded.as_slice()).unwrap();
+                assert_eq!(decoded, entry);
+            }
+        }
+    }
+
+    // Readers that predate a tag reject entries that use it, rather than misreading
+    // them as some other entry
+    #[test]
+    fn unknown_tags_are_rejected() {
+        let range = Entry::RangeDeleted {
+            start: "start".as_bytes().to_vec(),
+            end: "end".as_bytes().to_vec(),
+            timestamp: None,
+        };
+        let mut encoded = range.encode().unwrap();
+        encoded[8] = TAG_DELETED3 + 1;
+        let crc = crc32fast::hash(&encoded[8..encoded.len() - 1]);
+        encoded[4..8].copy_from_slice(&crc.to_be_bytes());
+        let error = Entry::read(&mut encoded.as_slice()).unwrap_err();
+        assert!(matches!(error, Error::InvalidEntryTag(tag) if tag == TAG_DELETED3 + 1));
+    }
+
+    #[test]
+    fn range_tombstones_sort_before_their_start_key() {
+        let range = Entry::RangeDeleted {
+            start: "b".as_bytes().to_vec(),
+            end: "c".as_bytes().to_vec(),
+            timestamp: None,
+        };
+        let deleted = |key: &str| Entry::Deleted {
+            key: key.as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        assert_eq!(range.cmp_position(&deleted("b")), Ordering::Less);
+        assert_eq!(deleted("b").cmp_position(&range), Ordering::Greater);
+        assert_eq!(deleted("a").cmp_position(&range), Ordering::Less);
+        assert_eq!(range.cmp_position(&range), Ordering::Equal);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let key_val = Entry::KeyVal {
+            key: "key".as_bytes().to_vec(),
+            value: "value".as_bytes().to_vec(),
+            timestamp: Some(12345),
+            write_timestamp: None,
+        };
+        let deleted = Entry::Deleted {
+            key: "deleted".as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        let entries = vec![
+            key_val.clone(),
+            deleted.clone(),
+            Entry::PosLen {
+                blockpos: 4,
+                blocklen: 100,
+                key: "key".as_bytes().to_vec(),
+            },
+            Entry::Transaction {
+                entries: vec![key_val, deleted],
+            },
+        ];
+        for entry in entries {
+            let json = serde_json::to_string(&entry).unwrap();
+            let decoded: Entry = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, entry);
+            assert_eq!(decoded.encode().unwrap(), entry.encode().unwrap());
         }
     }
 }
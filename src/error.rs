@@ -33,6 +33,35 @@ pub enum Error {
     #[error("out-of-order write")]
     OutOfOrderWrite,
 
+    #[error("entry too large to encode: key is {key_len} bytes, value is {value_len} bytes")]
+    EntryTooLarge { key_len: usize, value_len: usize },
+
+    #[error("transactions cannot be written to tree files")]
+    TransactionInTree,
+
+    #[error("corrupted block at position {pos}: {source}")]
+    CorruptedBlock {
+        pos: u64,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("bad level file {}: {source}", path.display())]
+    BadLevelFile {
+        path: std::path::PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("block size {0} is too small, blocks must be at least 512 bytes")]
+    BlockSizeTooSmall(usize),
+
+    #[error("minimum fanout {0} is too small, inner blocks must point at 2 blocks or more")]
+    MinFanoutTooSmall(usize),
+
+    #[error("bloom filter false positive rate {0} must be between 0 and 1")]
+    InvalidBloomFpRate(f64),
+
     #[error("bloom filter too large")]
     BloomFilterTooLarge,
 
@@ -45,8 +74,47 @@ pub enum Error {
     #[error("snappy compression error: {0}")]
     SnappyCompression(std::io::Error),
 
+    #[error("could not encode a typed key or value: {0}")]
+    Encode(String),
+
+    #[error("could not decode a typed key or value: {0}")]
+    Decode(String),
+
+    #[error("gzip compression error: {0}")]
+    GzipCompression(std::io::Error),
+
+    #[error("gzip decompression error: {0}")]
+    GzipDecompression(std::io::Error),
+
     #[error("lz4 compression error: {0}")]
     Lz4Compression(#[from] lz4_flex::frame::Error),
+
+    #[error("level {level} is full, its merge must finish before another file is promoted")]
+    LevelFull { level: u32 },
+
+    #[error("block at {blockpos} with length {blocklen} can't be in a tree file")]
+    InvalidPosLen { blockpos: u64, blocklen: u32 },
+
+    #[error("a database already exists in {}", path.display())]
+    DatabaseExists { path: std::path::PathBuf },
+
+    #[error("a database held in memory has no files to {0}")]
+    InMemory(&'static str),
+
+    #[error("the background merge thread stopped after a merge failed")]
+    MergeWorkerStopped,
+}
+
+impl Error {
+    /// Converts an I/O error from reading a block, recovering the decompression
+    /// error that a decompressor carried inside it.
+    pub(crate) fn from_read(err: std::io::Error) -> Error {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            // WONT PANIC: the inner error was just checked
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,70 @@
+use std::io::Write;
+
+use crate::error::*;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Writes a key and value as a single line of newline-delimited JSON, in the form
+/// `{"key":"<base64>","value":"<base64>"}`.
+pub(crate) fn write_ndjson_line(out: &mut impl Write, key: &[u8], value: &[u8]) -> Result<()> {
+    // Base64 never needs escaping inside a JSON string
+    writeln!(
+        out,
+        r#"{{"key":"{}","value":"{}"}}"#,
+        base64(key),
+        base64(value)
+    )?;
+    Ok(())
+}
+
+/// Encodes bytes with the standard, padded base64 alphabet (RFC 4648).
+fn base64(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3F;
+                output.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_test_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(base64(input.as_bytes()), expected);
+        }
+        assert_eq!(base64(&[0xFB, 0xFF]), "+/8=");
+    }
+
+    #[test]
+    fn ndjson_lines() {
+        let mut out = vec![];
+        write_ndjson_line(&mut out, b"key", b"").unwrap();
+        assert_eq!(out, b"{\"key\":\"a2V5\",\"value\":\"\"}\n");
+    }
+}
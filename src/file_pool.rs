@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A least-recently-used set of open tree files, which caps how many of them are
+/// open at once. Files evicted from the pool are closed once the reads using them
+/// finish, and opened again by path the next time they're read.
+#[derive(Debug)]
+pub(crate) struct FilePool {
+    max_open: usize,
+    // Files by the id of their tree (see `next_file_id`), the most recently used last
+    files: Mutex<VecDeque<(u64, Arc<File>)>>,
+}
+
+impl FilePool {
+    pub fn new(max_open: usize) -> Self {
+        Self {
+            max_open: max_open.max(1),
+            files: Default::default(),
+        }
+    }
+
+    /// Returns the open file of the tree `id`, opening it from `path` if it isn't
+    /// in the pool.
+    pub fn get(&self, id: u64, path: &Path) -> std::io::Result<Arc<File>> {
+        let mut files = self.lock();
+        if let Some(index) = files.iter().position(|(file_id, _)| *file_id == id) {
+            // WONT PANIC: the index was just found
+            let entry = files.remove(index).unwrap();
+            let file = entry.1.clone();
+            files.push_back(entry);
+            return Ok(file);
+        }
+        let file = Arc::new(File::open(path)?);
+        while files.len() >= self.max_open {
+            files.pop_front();
+        }
+        files.push_back((id, file.clone()));
+        Ok(file)
+    }
+
+    /// Closes the file of the tree `id`, if it is open.
+    pub fn remove(&self, id: u64) {
+        self.lock().retain(|(file_id, _)| *file_id != id);
+    }
+
+    /// Returns the number of files held open by the pool.
+    #[cfg(test)]
+    pub fn open_files(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<(u64, Arc<File>)>> {
+        self.files.lock().expect("file pool lock poisoned")
+    }
+}
+
+/// The file of a tree, either held open by the tree or borrowed from a `FilePool`
+/// for each read.
+#[derive(Debug, Clone)]
+pub(crate) enum TreeFile {
+    Open(Arc<File>),
+    Pooled {
+        pool: Arc<FilePool>,
+        id: u64,
+        path: PathBuf,
+    },
+}
+
+impl TreeFile {
+    pub fn get(&self) -> std::io::Result<Arc<File>> {
+        match self {
+            TreeFile::Open(file) => Ok(file.clone()),
+            TreeFile::Pooled { pool, id, path } => pool.get(*id, path),
+        }
+    }
+}
+
+impl From<File> for TreeFile {
+    fn from(file: File) -> Self {
+        TreeFile::Open(Arc::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn least_recently_used_files_are_closed() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..3)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.data"));
+                std::fs::write(&path, b"data").unwrap();
+                path
+            })
+            .collect();
+        let pool = FilePool::new(2);
+        let first = pool.get(0, &paths[0]).unwrap();
+        pool.get(1, &paths[1]).unwrap();
+        // Using the first file again makes the second the least recently used
+        assert!(Arc::ptr_eq(&first, &pool.get(0, &paths[0]).unwrap()));
+        pool.get(2, &paths[2]).unwrap();
+        assert_eq!(pool.open_files(), 2);
+        assert!(Arc::ptr_eq(&first, &pool.get(0, &paths[0]).unwrap()));
+
+        pool.remove(0);
+        assert_eq!(pool.open_files(), 1);
+        // A removed file is opened again when it's needed
+        assert!(!Arc::ptr_eq(&first, &pool.get(0, &paths[0]).unwrap()));
+    }
+}
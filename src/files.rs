@@ -0,0 +1,33 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::error::*;
+
+/// Moves a file, copying it when `to` is on another filesystem than `from`, which
+/// `rename` can't do. The copy is synced before `from` is removed, so at any point
+/// there is a whole copy of the file, but `to` may be incomplete after a crash.
+pub(crate) fn rename_or_copy(from: &Path, to: &Path) -> Result<()> {
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            File::open(to)?.sync_all()?;
+            std::fs::remove_file(from)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Links `link` to the file `original`, or copies the file when they are on
+/// different filesystems. Tree files never change once they are written, so the
+/// copy reads the same as a link would.
+pub(crate) fn hard_link_or_copy(original: &Path, link: &Path) -> Result<()> {
+    match std::fs::hard_link(original, link) {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            std::fs::copy(original, link)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
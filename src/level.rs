@@ -1,11 +1,20 @@
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::cache::BlockCache;
+use crate::comparator::KeyOrder;
+use crate::counters::Counters;
 use crate::db::Command;
-use crate::entry::Entry;
+use crate::entry::{now_timestamp, Entry};
 use crate::error::*;
+use crate::file_pool::FilePool;
+use crate::files::rename_or_copy;
 use crate::merger::*;
+use crate::stats::{LevelInfo, LevelStats, TreeBloomStats};
 use crate::tree::Tree;
-use crate::Compression;
+use crate::writer::{Writer, WriterOptions};
+
+use fastbloom::BloomFilter;
 
 pub struct Level {
     level: u32,
@@ -14,27 +23,48 @@ pub struct Level {
     b: Option<Tree>,
     c: Option<Tree>,
     merger: Option<Merger>,
-    compression: Compression,
+    writer_options: WriterOptions,
+    /// The options merged files are written with, which are those of the level they
+    /// are usually promoted into.
+    merge_writer_options: WriterOptions,
+    block_cache: Option<Arc<BlockCache>>,
+    /// The union of the trees' bloom filters, if they can be combined.
+    bloom: Option<BloomFilter>,
+    merge_observer: Option<MergeObserver>,
+    file_pool: Option<Arc<FilePool>>,
+    /// Where merges write their output and scans link the trees, if not `path`.
+    temp_dir: Option<PathBuf>,
+    /// A merged file left behind by a merge that completed before a crash, which
+    /// still has to be promoted into the next level (see `recover_merge_files`).
+    recovered_merge: Option<PathBuf>,
 }
 
 impl Level {
-    pub fn new(path: impl AsRef<Path>, level: u32, compression: Compression) -> Result<Self> {
+    /// Opens the files of a level. `writer_options` are those of the level's own
+    /// files, which are also used for the files written when merging this level
+    /// unless `with_merge_writer_options` is set. Lookups in the level's trees go
+    /// through `block_cache`, if there is one.
+    pub fn new(
+        path: impl AsRef<Path>,
+        level: u32,
+        writer_options: WriterOptions,
+        block_cache: Option<Arc<BlockCache>>,
+    ) -> Result<Self> {
         let path: PathBuf = path.as_ref().to_path_buf();
+        let recovered_merge = recover_merge_files(&path, level)?;
+        let open_tree = |file| -> Result<Tree> {
+            Ok(open_level_file(file)?
+                .with_key_order(writer_options.key_order.clone())
+                .with_block_cache(block_cache.clone())
+                .with_counters(writer_options.counters.clone()))
+        };
         let a_file = data_file_name(&path, level, "A");
-        let a = a_file
-            .exists()
-            .then(|| Tree::from_file(a_file))
-            .transpose()?;
+        let a = a_file.exists().then(|| open_tree(a_file)).transpose()?;
         let b_file = data_file_name(&path, level, "B");
-        let b = b_file
-            .exists()
-            .then(|| Tree::from_file(b_file))
-            .transpose()?;
+        let b = b_file.exists().then(|| open_tree(b_file)).transpose()?;
         let c_file = data_file_name(&path, level, "C");
-        let c = c_file
-            .exists()
-            .then(|| Tree::from_file(c_file))
-            .transpose()?;
+        let c = c_file.exists().then(|| open_tree(c_file)).transpose()?;
+        // The merger for A and B (if present) is created on the next call to `merge`
         let mut level = Self {
             level,
             path,
@@ -42,12 +72,125 @@ impl Level {
             b,
             c,
             merger: None,
-            compression,
+            merge_writer_options: writer_options.clone(),
+            writer_options,
+            block_cache,
+            bloom: None,
+            merge_observer: None,
+            file_pool: None,
+            temp_dir: None,
+            recovered_merge,
         };
-        level.maybe_create_merger()?;
+        level.bloom = level.bloom_union();
         Ok(level)
     }
 
+    /// Writes merged files with `merge_writer_options`, for the level they are
+    /// promoted into. A merged file that stays in this level is rewritten with the
+    /// level's own options if they differ.
+    pub(crate) fn with_merge_writer_options(mut self, merge_writer_options: WriterOptions) -> Self {
+        self.merge_writer_options = merge_writer_options;
+        self
+    }
+
+    /// Opens the trees of this level through `file_pool`, if there is one.
+    pub(crate) fn with_file_pool(mut self, file_pool: Option<Arc<FilePool>>) -> Self {
+        for tree in [&mut self.a, &mut self.b, &mut self.c] {
+            *tree = tree
+                .take()
+                .map(|tree| tree.with_file_pool(file_pool.clone()));
+        }
+        self.file_pool = file_pool;
+        self
+    }
+
+    /// Returns the file pool that the trees of this level are opened through.
+    pub(crate) fn file_pool(&self) -> Option<Arc<FilePool>> {
+        self.file_pool.clone()
+    }
+
+    /// Writes merged files into `temp_dir` and links the trees for scans there,
+    /// rather than in the level's own directory.
+    pub(crate) fn with_temp_dir(mut self, temp_dir: Option<PathBuf>) -> Self {
+        self.temp_dir = temp_dir;
+        self
+    }
+
+    /// Returns the directory for the level's merged files and scan links.
+    pub(crate) fn temp_dir(&self) -> &Path {
+        self.temp_dir.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Returns the merged file recovered when the level was opened, if there is one
+    /// still waiting to be promoted into the next level.
+    pub fn take_recovered_merge(&mut self) -> Option<PathBuf> {
+        self.recovered_merge.take()
+    }
+
+    /// Reports the progress of this level's merges to `merge_observer`.
+    pub fn with_merge_observer(mut self, merge_observer: Option<MergeObserver>) -> Self {
+        self.merge_observer = merge_observer;
+        self
+    }
+
+    /// Checks whether the level holds three files, so that it has no room to take
+    /// another until its merge finishes.
+    pub fn is_full(&self) -> bool {
+        self.a.is_some() && self.b.is_some() && self.c.is_some()
+    }
+
+    /// Checks whether the level holds two files that still have to be merged.
+    pub fn has_pending_merge(&self) -> bool {
+        self.merger.is_some() || (self.a.is_some() && self.b.is_some())
+    }
+
+    pub fn stats(&self) -> LevelStats {
+        LevelStats {
+            level: self.level,
+            a: self.a.as_ref().map(Tree::file_size),
+            b: self.b.as_ref().map(Tree::file_size),
+            c: self.c.as_ref().map(Tree::file_size),
+            merging: self.merger.is_some(),
+        }
+    }
+
+    pub fn info(&self) -> LevelInfo {
+        LevelInfo {
+            level: self.level,
+            capacity: level_size(self.level),
+            files: self.trees().count(),
+            entries: self.value_count(),
+        }
+    }
+
+    /// Returns the bloom filter stats of the trees of this level, from the newest to
+    /// the oldest.
+    pub fn bloom_stats(&self) -> Vec<TreeBloomStats> {
+        self.trees()
+            .map(|tree| TreeBloomStats::new(self.level, tree.path().to_path_buf(), tree.bloom()))
+            .collect()
+    }
+
+    /// Returns the order of the keys in this level's trees.
+    pub(crate) fn key_order(&self) -> KeyOrder {
+        self.writer_options.key_order.clone()
+    }
+
+    /// Returns the options the level's own files are written with.
+    pub(crate) fn writer_options(&self) -> WriterOptions {
+        self.writer_options.clone()
+    }
+
+    /// Returns the IO counters that this level's reads and merges are counted in.
+    pub(crate) fn counters(&self) -> Counters {
+        self.writer_options.counters.clone()
+    }
+
+    /// Returns the number of this level.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
     pub fn tree_files(&self) -> Vec<PathBuf> {
         ["C", "B", "A"]
             .into_iter()
@@ -58,32 +201,123 @@ impl Level {
             .collect()
     }
 
-    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+    /// Returns the trees of this level, from the newest to the oldest.
+    pub fn trees(&self) -> impl Iterator<Item = &Tree> {
+        [&self.c, &self.b, &self.a].into_iter().flatten()
+    }
+
+    /// Returns the number of values written to each tree of this level, from the
+    /// oldest to the newest. Trees without a recorded count are counted as empty.
+    pub fn file_value_counts(&self) -> Vec<u64> {
+        [&self.a, &self.b, &self.c]
+            .into_iter()
+            .flatten()
+            .map(|tree| tree.value_count().unwrap_or(0))
+            .collect()
+    }
+
+    /// Returns the number of entries read so far by the merge in progress, if there
+    /// is one.
+    pub fn merge_progress(&self) -> Option<usize> {
+        self.merger.as_ref().map(Merger::entries_merged)
+    }
+
+    /// Returns the number of values written to the trees in this level. Keys that
+    /// appear in more than one tree are counted more than once, and trees without a
+    /// recorded count aren't counted.
+    pub fn value_count(&self) -> u64 {
+        [&self.c, &self.b, &self.a]
+            .into_iter()
+            .flatten()
+            .filter_map(Tree::value_count)
+            .sum()
+    }
+
+    /// Checks the bloom filters and range tombstones of the trees in this level for
+    /// the key. A `false` result means the key is definitely not in this level.
+    pub fn might_contain(&self, key: &[u8]) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.contains(key) || self.trees().any(|tree| tree.range_deleted(key)),
+            None => self.trees().any(|tree| tree.might_contain(key)),
+        }
+    }
+
+    /// Combines the bloom filters of the trees in this level into one, so that a key
+    /// that is in none of them can be ruled out with a single check. Returns `None`
+    /// if the level is empty or the filters can't be combined, because they were
+    /// sized for different numbers of items or hash with different seeds.
+    pub fn bloom_union(&self) -> Option<BloomFilter> {
+        let mut trees = self.trees();
+        let mut union = trees.next()?.bloom().clone();
+        let mut empty = union.clone();
+        empty.clear();
+        for tree in trees {
+            // Filters can only be combined if they have the same size, number of
+            // hashes and seed. They are equal once cleared when they have the same
+            // size and number of hashes, but equality ignores the seed, which files
+            // written before `DEFAULT_BLOOM_SEED` was fixed chose at random.
+            let mut other = tree.bloom().clone();
+            other.clear();
+            if other != empty || other.source_hash(&0u8) != empty.source_hash(&0u8) {
+                return None;
+            }
+            union.union(tree.bloom());
+        }
+        Some(union)
+    }
+
+    /// Looks up several sorted keys in this level. Each tree is only probed for the
+    /// keys that were not found in a newer tree.
+    pub fn get_entries(&self, keys: &[&[u8]]) -> Result<Vec<Option<Entry>>> {
+        let mut found: Vec<Option<Entry>> = vec![None; keys.len()];
         for tree in [&self.c, &self.b, &self.a].into_iter().flatten() {
-            let entry = tree.get_entry(key)?;
-            if entry.is_some() {
-                return Ok(entry);
+            let (indexes, missing): (Vec<usize>, Vec<&[u8]>) = keys
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| found[*index].is_none())
+                .map(|(index, key)| (index, *key))
+                .unzip();
+            if missing.is_empty() {
+                break;
+            }
+            for (index, entry) in indexes.into_iter().zip(tree.get_entries(&missing)?) {
+                found[index] = entry;
             }
         }
-        Ok(None)
+        Ok(found)
     }
 
+    /// Moves a file into the first free slot of this level. A level with all of `A`,
+    /// `B` and `C` taken returns `Error::LevelFull` and leaves the file where it is;
+    /// `Levels::handle_command` runs the level's merge to completion first to make
+    /// room.
     pub fn promote_file(&mut self, path: PathBuf) -> Result<Vec<Command>> {
         if self.a.is_none() {
             let new_filename = self.data_file_name("A");
             std::fs::rename(&path, &new_filename)?;
-            self.a = Some(Tree::from_file(new_filename)?);
+            self.a = Some(self.open_tree(new_filename)?);
         } else if self.b.is_none() {
             let new_filename = self.data_file_name("B");
             std::fs::rename(&path, &new_filename)?;
-            self.b = Some(Tree::from_file(new_filename)?);
+            self.b = Some(self.open_tree(new_filename)?);
         } else if self.c.is_none() {
             let new_filename = self.data_file_name("C");
             std::fs::rename(&path, &new_filename)?;
-            self.c = Some(Tree::from_file(new_filename)?);
+            self.c = Some(self.open_tree(new_filename)?);
         } else {
-            unreachable!("level is full");
+            return Err(Error::LevelFull { level: self.level });
         };
+        self.bloom = self.bloom_union();
+        #[cfg(feature = "tracing")]
+        if let Some(tree) = self.trees().next() {
+            tracing::info!(
+                level = self.level,
+                from = %path.display(),
+                file = %tree.path().display(),
+                entries = tree.value_count(),
+                "promoted a file into the level"
+            );
+        }
         Ok(vec![])
     }
 
@@ -93,37 +327,65 @@ impl Level {
         work_unit: usize,
         min_level: u32,
         max_level: u32,
+        strategy: MergeStrategy,
     ) -> Result<Vec<Command>> {
-        self.maybe_create_merger()?;
+        // Expired values and tombstones can only be dropped from the last level,
+        // because a merge in any other level could otherwise resurrect an older
+        // version of the key from a deeper level.
+        let expiry_now = (self.level >= max_level).then(now_timestamp);
+        self.maybe_create_merger(expiry_now)?;
         if let Some(merger) = self.merger.take() {
             let mut commands = vec![];
 
             // Compute how many steps to take in this merge
 
-            // TODO: track how much was actually done previously
-            // Put this in the merger?
-            let work_left_here = level_size(self.level) * 2;
+            // A merge reads at most two files of the level size. Always leave room for
+            // at least one step, so that the merge can finish.
+            let work_left_here = (level_size(self.level) * 2)
+                .saturating_sub(merger.entries_merged())
+                .max(1);
             // TODO: can self.level ever be greater than max_level as configured?
             // let max_level = max_level.max(self.level);
             let depth = max_level - min_level + 1;
             let work_units_left = ((depth as usize) * work_unit).saturating_sub(work_completed);
-            // This assumes the "fast" merge strategy, as opposed to the "predictable" strategy.
             // See https://github.com/basho/hanoidb/issues/7
-            let steps = work_units_left.min(work_left_here);
-            let work_including_here = steps + work_completed;
+            let steps = match strategy {
+                MergeStrategy::Fast => work_units_left.min(work_left_here),
+                // A level receives a new file at most every `level_size` inserts and
+                // a merge takes `2 * level_size` steps, so two work units per work
+                // unit of inserts finishes each merge in time.
+                MergeStrategy::Predictable => work_left_here.min(2 * work_unit),
+            };
 
             // If there's no merge budget left, stop propagating merge commands
             // to the next level
             if steps == 0 {
+                self.merger.replace(merger);
                 return Ok(vec![]);
             }
 
-            match merger.incremental_merge(steps)? {
+            let merged_before = merger.entries_merged();
+            let outcome = merger.incremental_merge(steps)?;
+            if let Some(merge_observer) = &self.merge_observer {
+                let (steps, complete) = match &outcome {
+                    MergeOutcome::Continue(merger) => {
+                        (merger.entries_merged() - merged_before, false)
+                    }
+                    MergeOutcome::Complete { steps, .. } => (*steps, true),
+                };
+                merge_observer(MergeEvent {
+                    level: self.level,
+                    steps,
+                    entries_merged: merged_before + steps,
+                    complete,
+                });
+            }
+            match outcome {
                 MergeOutcome::Continue(new_merger) => {
                     // Merge is incomplete, put it back into the struct member and
                     // propagate merge work
                     commands.push(Command::Merge {
-                        steps: work_including_here,
+                        steps: work_completed + new_merger.entries_merged() - merged_before,
                         target_level: self.level + 1,
                     });
                     self.merger.replace(new_merger);
@@ -132,33 +394,58 @@ impl Level {
                     count,
                     steps: completed,
                 } => {
+                    // The last level has nowhere to promote to, so it keeps the
+                    // merged file however large it is
+                    let keep = count <= level_size(self.level) || self.level >= max_level;
+                    // Bring the merged file into the level's own directory, where
+                    // recovery can find it
+                    if self.temp_dir.is_some() {
+                        let merged = data_file_name(self.temp_dir(), self.level, "X");
+                        rename_or_copy(&merged, &self.data_file_name("X"))?;
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(
+                        level = self.level,
+                        file = %self.data_file_name("X").display(),
+                        entries = count,
+                        steps = completed,
+                        outcome = match count {
+                            0 => "removed",
+                            _ if keep => "kept",
+                            _ => "promoted",
+                        },
+                        "completed a merge"
+                    );
                     // Merge completed, the X-{level}.data file is possibly ready
                     // to be promoted to the next level
                     match count {
                         0 => {
                             // This file merged into 0 entries, so cleanup
+                            std::fs::remove_file(self.data_file_name("X"))?;
                             self.close_and_delete_a_and_b()?;
-                            if self.c.take().is_some() {
+                            if let Some(c_tree) = self.c.take() {
+                                c_tree.evict();
                                 let a = self.data_file_name("A");
                                 let c = self.data_file_name("C");
                                 std::fs::rename(&c, &a)?;
-                                self.a.replace(Tree::from_file(a)?);
+                                self.a.replace(self.open_tree(a)?);
                             }
                         }
-                        count if count <= level_size(self.level) => {
+                        _ if keep => {
                             // The merged file is small enough to fit into this level.
                             // Move it to "M" temporarily so it will be picked up in recovery
                             let a = self.data_file_name("A");
                             let m = self.data_file_name("M");
-                            std::fs::rename(self.data_file_name("X"), &m)?;
+                            self.keep_merged_file(&m)?;
                             self.close_and_delete_a_and_b()?;
                             std::fs::rename(&m, &a)?;
-                            self.a.replace(Tree::from_file(a)?);
-                            if self.c.take().is_some() {
+                            self.a.replace(self.open_tree(a)?);
+                            if let Some(c_tree) = self.c.take() {
+                                c_tree.evict();
                                 let c = self.data_file_name("C");
                                 let b = self.data_file_name("B");
                                 std::fs::rename(&c, &b)?;
-                                self.b.replace(Tree::from_file(b)?);
+                                self.b.replace(self.open_tree(b)?);
                             }
                         }
                         _ => {
@@ -167,11 +454,20 @@ impl Level {
                                 path: self.data_file_name("X"),
                                 target_level: self.level + 1,
                             });
+                            // A file that arrived during the merge is now the oldest
+                            if let Some(c_tree) = self.c.take() {
+                                c_tree.evict();
+                                let a = self.data_file_name("A");
+                                let c = self.data_file_name("C");
+                                std::fs::rename(&c, &a)?;
+                                self.a.replace(self.open_tree(a)?);
+                            }
                         }
                     }
+                    self.bloom = self.bloom_union();
                     // If there's still more merge work to be done, trigger the next level
                     commands.push(Command::Merge {
-                        steps: work_including_here - completed,
+                        steps: work_completed + completed,
                         target_level: self.level + 1,
                     });
                 }
@@ -190,27 +486,70 @@ impl Level {
         }
     }
 
-    fn maybe_create_merger(&mut self) -> Result<()> {
+    fn maybe_create_merger(&mut self, expiry_now: Option<u32>) -> Result<()> {
         if let (Some(a_tree), Some(b_tree), None) = (&self.a, &self.b, &self.merger) {
+            // Recovery doesn't look in the temp dir, so a merged file there is
+            // always left over from before a crash
+            let stale = data_file_name(self.temp_dir(), self.level, "X");
+            if self.temp_dir.is_some() && stale.exists() {
+                std::fs::remove_file(stale)?;
+            }
             self.merger = Some(Merger::new(
-                &self.path,
+                self.temp_dir(),
                 self.level,
                 a_tree,
                 b_tree,
-                self.compression,
+                self.merge_writer_options.clone(),
+                expiry_now,
             )?);
         }
         Ok(())
     }
 
+    fn open_tree(&self, path: PathBuf) -> Result<Tree> {
+        Ok(open_level_file(path)?
+            .with_key_order(self.key_order())
+            .with_block_cache(self.block_cache.clone())
+            .with_file_pool(self.file_pool.clone())
+            .with_counters(self.counters()))
+    }
+
+    /// Moves the merged `X` file to `m`. It was written for the next level, so if
+    /// that level's options differ it is first rewritten into `R`, with this level's
+    /// own options, which is only renamed to `m` once it is complete.
+    fn keep_merged_file(&self, m: &Path) -> Result<()> {
+        let x = self.data_file_name("X");
+        if self.merge_writer_options == self.writer_options {
+            std::fs::rename(&x, m)?;
+            return Ok(());
+        }
+        let r = self.data_file_name("R");
+        let merged = Tree::from_file(&x)?.with_key_order(self.key_order());
+        // Sized like the filters of the merges in this level
+        let mut writer = Writer::with_expected_num_items(
+            &r,
+            level_size(self.level + 1),
+            self.writer_options.clone(),
+        )?;
+        for entry in merged.entries()? {
+            writer.add(entry?)?;
+        }
+        writer.close()?;
+        drop(merged);
+        std::fs::remove_file(&x)?;
+        std::fs::rename(&r, m)?;
+        Ok(())
+    }
+
     fn data_file_name(&self, prefix: &str) -> PathBuf {
         data_file_name(&self.path, self.level, prefix)
     }
 
     fn close_and_delete_a_and_b(&mut self) -> Result<()> {
         assert!(self.a.is_some() && self.b.is_some());
-        let _ = self.a.take();
-        let _ = self.b.take();
+        for tree in [self.a.take(), self.b.take()].into_iter().flatten() {
+            tree.evict();
+        }
         std::fs::remove_file(self.data_file_name("A"))?;
         std::fs::remove_file(self.data_file_name("B"))?;
         Ok(())
@@ -221,7 +560,244 @@ fn data_file_name(path: &Path, level: u32, prefix: &str) -> PathBuf {
     path.join(format!("{prefix}-{level}.data"))
 }
 
+/// Opens one of a level's tree files, naming the file in any error.
+fn open_level_file(path: PathBuf) -> Result<Tree> {
+    Tree::from_file(&path).map_err(|err| Error::BadLevelFile {
+        path,
+        source: Box::new(err),
+    })
+}
+
+/// Puts the files of a level back in order after a crash part way through a merge
+/// (see `Level::merge`), and returns the path of a merged file that still has to be
+/// promoted into the next level.
+///
+/// - `R` is a finished merge being rewritten for this level, which is removed. The
+///   merge it was rewritten from is still there as `X`, or was already removed, in
+///   which case the merge starts over.
+/// - `M` is a finished merge of `A` and `B`, so it replaces them as the new `A`.
+/// - `X` is an unfinished merge if `A` and `B` are both still there, and is
+///   removed so that the merge starts over. Otherwise it's a finished merge that
+///   was too big for this level.
+/// - The remaining files are then renamed so that they fill the slots from `A`
+///   on, keeping them in order from the oldest to the newest.
+fn recover_merge_files(path: &Path, level: u32) -> Result<Option<PathBuf>> {
+    let file = |prefix| data_file_name(path, level, prefix);
+    if file("R").exists() {
+        std::fs::remove_file(file("R"))?;
+    }
+    if file("M").exists() {
+        for prefix in ["A", "B"] {
+            if file(prefix).exists() {
+                std::fs::remove_file(file(prefix))?;
+            }
+        }
+        std::fs::rename(file("M"), file("A"))?;
+    }
+    let mut recovered_merge = None;
+    if file("X").exists() {
+        if file("A").exists() && file("B").exists() {
+            std::fs::remove_file(file("X"))?;
+        } else {
+            recovered_merge = Some(file("X"));
+        }
+    }
+    let present: Vec<PathBuf> = ["A", "B", "C"]
+        .into_iter()
+        .map(file)
+        .filter(|file| file.exists())
+        .collect();
+    for (present, slot) in present.iter().zip(["A", "B", "C"].map(file)) {
+        if *present != slot {
+            std::fs::rename(present, slot)?;
+        }
+    }
+    Ok(recovered_merge)
+}
+
+/// How merge work is scheduled across the levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Spend the whole merge budget of a write on the first levels that have merges
+    /// in progress. Merges finish sooner, but a single write can do a lot of work.
+    #[default]
+    Fast,
+    /// Spread merge work evenly over all levels with merges in progress, so that
+    /// the work done by any single write stays small.
+    Predictable,
+}
+
 #[inline]
 pub fn level_size(level: u32) -> usize {
     1 << level as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::lookup;
+    use crate::nursery::NurseryData;
+
+    use tempfile::tempdir;
+
+    fn get(level: &Level, key: &[u8]) -> Option<Vec<u8>> {
+        lookup(&NurseryData::default(), std::slice::from_ref(level), key).unwrap()
+    }
+
+    fn write_level_file(path: &Path, prefix: &str, keys: impl Iterator<Item = usize>) {
+        let mut writer = Writer::new(data_file_name(path, 10, prefix)).unwrap();
+        for key in keys {
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("{key:05}").into_bytes(),
+                    value: prefix.as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    fn merge_steps(strategy: MergeStrategy) -> usize {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", (0..2048).step_by(2));
+        write_level_file(dir.path(), "B", (1..2048).step_by(2));
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        let commands = level.merge(0, 512, 10, 25, strategy).unwrap();
+        match commands.last() {
+            Some(Command::Merge { steps, .. }) => *steps,
+            command => panic!("expected a merge command, got {command:?}"),
+        }
+    }
+
+    #[test]
+    fn level_bloom_filter() {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", (0..1000).step_by(2));
+        write_level_file(dir.path(), "B", (1..1000).step_by(2));
+        let level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        let bloom = level.bloom.as_ref().unwrap();
+        for key in 0..1000 {
+            assert!(bloom.contains(format!("{key:05}").as_bytes()));
+        }
+        // A missing key is rejected by the level's filter, without checking the trees
+        let missing = (1000..2000)
+            .map(|key| format!("{key:05}").into_bytes())
+            .find(|key| !bloom.contains(key))
+            .unwrap();
+        assert!(!level.might_contain(&missing));
+        assert_eq!(get(&level, &missing), None);
+        assert_eq!(get(&level, b"00001"), Some(b"B".to_vec()));
+    }
+
+    #[test]
+    fn promote_into_full_level() {
+        let dir = tempdir().unwrap();
+        for prefix in ["A", "B", "C"] {
+            write_level_file(dir.path(), prefix, 0..10);
+        }
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        let path = data_file_name(dir.path(), 10, "X");
+        write_level_file(dir.path(), "X", 0..10);
+        assert!(matches!(
+            level.promote_file(path.clone()),
+            Err(Error::LevelFull { level: 10 })
+        ));
+        // The file is left in place for the caller to promote once there's room
+        assert!(path.exists());
+        assert_eq!(level.tree_files().len(), 3);
+    }
+
+    // A finished merge in M replaces A and B, and C moves down into B
+    #[test]
+    fn recover_finished_merge_in_m() {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", 0..10);
+        write_level_file(dir.path(), "B", 5..15);
+        write_level_file(dir.path(), "M", 0..15);
+        write_level_file(dir.path(), "C", 20..25);
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        assert_eq!(level.take_recovered_merge(), None);
+        assert!(!data_file_name(dir.path(), 10, "M").exists());
+        assert!(!data_file_name(dir.path(), 10, "C").exists());
+        assert_eq!(get(&level, b"00000"), Some(b"M".to_vec()));
+        assert_eq!(get(&level, b"00020"), Some(b"C".to_vec()));
+    }
+
+    // X is removed while A and B are there to merge again, and kept for promotion
+    // once they're gone
+    #[test]
+    fn recover_merge_in_x() {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", 0..10);
+        write_level_file(dir.path(), "B", 5..15);
+        write_level_file(dir.path(), "X", 0..10);
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        assert_eq!(level.take_recovered_merge(), None);
+        assert!(!data_file_name(dir.path(), 10, "X").exists());
+        drop(level);
+
+        std::fs::remove_file(data_file_name(dir.path(), 10, "A")).unwrap();
+        std::fs::remove_file(data_file_name(dir.path(), 10, "B")).unwrap();
+        write_level_file(dir.path(), "X", 0..15);
+        write_level_file(dir.path(), "C", 20..25);
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        assert_eq!(
+            level.take_recovered_merge(),
+            Some(data_file_name(dir.path(), 10, "X"))
+        );
+        assert_eq!(level.tree_files(), [data_file_name(dir.path(), 10, "A")]);
+    }
+
+    // A merge that was being rewritten in R is started over
+    #[test]
+    fn recover_rewrite_in_r() {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", 0..10);
+        write_level_file(dir.path(), "B", 5..15);
+        write_level_file(dir.path(), "X", 0..15);
+        write_level_file(dir.path(), "R", 0..5);
+        let mut level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        assert_eq!(level.take_recovered_merge(), None);
+        assert!(!data_file_name(dir.path(), 10, "R").exists());
+        assert!(!data_file_name(dir.path(), 10, "X").exists());
+        assert!(level.has_pending_merge());
+    }
+
+    #[test]
+    fn mismatched_bloom_filters_are_not_combined() {
+        let dir = tempdir().unwrap();
+        write_level_file(dir.path(), "A", 0..10);
+        // Sized for a different number of items than the A file
+        let mut writer = Writer::with_expected_num_items(
+            data_file_name(dir.path(), 10, "B"),
+            10,
+            Default::default(),
+        )
+        .unwrap();
+        writer
+            .add(Entry::KeyVal {
+                key: b"00010".to_vec(),
+                value: b"B".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        assert!(level.bloom.is_none());
+        assert!(level.might_contain(b"00000"));
+        assert!(level.might_contain(b"00010"));
+        assert!(get(&level, b"00010").is_some());
+    }
+
+    #[test]
+    fn predictable_merges_do_bounded_work() {
+        // The fast strategy finishes the whole merge right away...
+        assert_eq!(merge_steps(MergeStrategy::Fast), 2048);
+        // ...while the predictable strategy only does two work units of it
+        assert_eq!(merge_steps(MergeStrategy::Predictable), 1024);
+    }
+}
@@ -1,26 +1,70 @@
+#[cfg(feature = "tokio")]
+mod async_db;
+mod batch;
 mod block;
+mod cache;
+mod comparator;
 mod compression;
+mod counters;
 mod db;
 mod entry;
 mod error;
+mod export;
+mod file_pool;
+mod files;
 mod level;
 mod merger;
 mod nursery;
 mod scan;
+mod shared;
+mod snapshot;
+mod stats;
 mod trailer;
 mod tree;
+mod typed;
+mod verify;
 mod writer;
 
 const TAG_KV_DATA: u8 = 0x80;
 const TAG_DELETED: u8 = 0x81;
 const TAG_POSLEN32: u8 = 0x82;
-#[allow(dead_code)]
 const TAG_TRANSACT: u8 = 0x83;
 const TAG_KV_DATA2: u8 = 0x84;
 const TAG_DELETED2: u8 = 0x85;
+const TAG_RANGE_DELETED: u8 = 0x86;
+/// Like `TAG_KV_DATA2` and `TAG_DELETED2`, but with a write timestamp after an
+/// optional expiry timestamp, which is preceded by a flag byte.
+const TAG_KV_DATA3: u8 = 0x87;
+const TAG_DELETED3: u8 = 0x88;
 const TAG_END: u8 = 0xFF;
-const MAGIC: &str = "HAN3";
+const MAGIC: &str = "HAN4";
+/// The magic of the tree files written before the trailer held a value count.
+const HAN3_MAGIC: &str = "HAN3";
+/// The magic of the tree files written by the original Erlang hanoidb.
+const LEGACY_MAGIC: &str = "HAN2";
 
+#[cfg(feature = "tokio")]
+pub use async_db::AsyncHanoiDB;
+pub use batch::WriteBatch;
+pub use comparator::Comparator;
 pub use compression::Compression;
-pub use db::{HanoiDB, OpenOptions};
+pub use counters::IoCounters;
+pub use db::{CreateMode, HanoiDB, OpenOptions, Source};
+pub use entry::Entry;
 pub use error::*;
+pub use level::MergeStrategy;
+pub use merger::{MergeEvent, MergeObserver};
+pub use nursery::SyncStrategy;
+pub use shared::SharedHanoiDB;
+pub use snapshot::Snapshot;
+pub use stats::{
+    BloomStats, CompactionPlan, DbStats, LevelInfo, LevelPlan, LevelStats, TreeBloomStats,
+};
+pub use tree::{
+    dump_file, verify_file, DumpEntryIterator, ReverseTreeEntryIterator, SizeHistogram, Tree,
+    TreeEntryIterator, TreeStats, ValueReader,
+};
+#[cfg(feature = "bincode")]
+pub use typed::BincodeCodec;
+pub use typed::{Codec, RawCodec, TypedDB};
+pub use verify::{FileReport, VerifyReport};
@@ -0,0 +1,66 @@
+use std::io::Write;
+use std::process::ExitCode;
+
+use hanoidb::{dump_file, HanoiDB, Result};
+
+const USAGE: &str = "\
+usage: hanoidb <command> <args>
+
+commands:
+  get <dir> <key>            print the value of a key
+  put <dir> <key> <value>    set the value of a key
+  delete <dir> <key>         delete a key
+  scan <dir> [prefix]        print the keys and values, optionally only those
+                             whose keys start with the prefix
+  dump <file>                print every entry of a single .data file";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match run(&args, &mut std::io::stdout().lock()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs one command, writing what it prints to `out`. Keys and values are taken
+/// as UTF-8 strings, and printed lossily.
+fn run(args: &[&str], out: &mut impl Write) -> Result<ExitCode> {
+    match args {
+        ["get", dir, key] => match HanoiDB::open(dir)?.get(key.as_bytes())? {
+            Some(value) => writeln!(out, "{}", String::from_utf8_lossy(&value))?,
+            // Missing keys print nothing, like a lookup in an empty map
+            None => return Ok(ExitCode::from(1)),
+        },
+        ["put", dir, key, value] => {
+            HanoiDB::open(dir)?.insert(key.as_bytes().to_vec(), value.as_bytes().to_vec())?
+        }
+        ["delete", dir, key] => HanoiDB::open(dir)?.delete(key.as_bytes().to_vec())?,
+        ["scan", dir, prefix @ ..] if prefix.len() <= 1 => {
+            let db = HanoiDB::open(dir)?;
+            let prefix = prefix.first().copied().unwrap_or_default();
+            for entry in db.scan_prefix(prefix.as_bytes())? {
+                let (key, value) = entry?;
+                writeln!(
+                    out,
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                )?;
+            }
+        }
+        ["dump", file] => {
+            for entry in dump_file(file)? {
+                writeln!(out, "{:?}", entry?)?;
+            }
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            return Ok(ExitCode::from(2));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
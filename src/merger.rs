@@ -1,77 +1,226 @@
 use std::cmp::Ordering;
 use std::iter::Peekable;
 use std::path::Path;
+use std::sync::Arc;
 
-use crate::tree::{Tree, TreeEntryIterator};
-use crate::writer::Writer;
-use crate::{error::*, Compression};
+use crate::comparator::KeyOrder;
+use crate::counters::Counters;
+use crate::entry::Entry;
+use crate::error::*;
+use crate::tree::{take_error, Tree, TreeEntryIterator};
+use crate::writer::{Writer, WriterOptions};
+
+/// Progress of a merge in one level, reported to the observer set with
+/// `OpenOptions::with_merge_observer` each time the merge does some work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeEvent {
+    /// The level whose files are being merged.
+    pub level: u32,
+    /// The number of entries read from the level's files in this round of work.
+    pub steps: usize,
+    /// The number of entries read from the level's files since the merge started.
+    pub entries_merged: usize,
+    /// Whether the merge has finished.
+    pub complete: bool,
+}
+
+/// Receives `MergeEvent`s. It is called on the thread running the merge while the
+/// levels are locked, so it must not call back into the database or wait on
+/// anything that does, or the merge deadlocks.
+pub type MergeObserver = Arc<dyn Fn(MergeEvent) + Send + Sync>;
 
 pub struct Merger {
-    a: Peekable<TreeEntryIterator>,
-    b: Peekable<TreeEntryIterator>,
+    /// The entries of the input trees, from oldest to newest.
+    inputs: Vec<Peekable<TreeEntryIterator>>,
     x: Writer,
+    expiry_now: Option<u32>,
+    entries_merged: usize,
+    /// For each input, the range tombstones of the newer inputs after it. Values
+    /// and tombstones from the input that they cover are dropped.
+    newer_range_tombstones: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+    key_order: KeyOrder,
+    counters: Counters,
 }
 
 impl std::fmt::Debug for Merger {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Merger").field("writer", &self.x).finish()
+        f.debug_struct("Merger")
+            .field("writer", &self.x)
+            .field("entries_merged", &self.entries_merged)
+            .finish()
     }
 }
 
 impl Merger {
+    /// Creates a merger of the `a_tree` and `b_tree` into a new `X` file.
+    ///
+    /// When `expiry_now` is set, values that have expired at that time are dropped
+    /// instead of being copied into the merged file, and so are tombstones, which
+    /// have nothing left to shadow. This is only safe when no older version of a
+    /// key could exist in a deeper level (see `Level::merge`). Tombstones with a
+    /// write timestamp are kept, as they still win over the values written before
+    /// them that are yet to come (see `HanoiDB::delete_at`).
     pub fn new(
         path: impl AsRef<Path>,
         level: u32,
         a_tree: &Tree,
         b_tree: &Tree,
-        compression: Compression,
+        writer_options: WriterOptions,
+        expiry_now: Option<u32>,
     ) -> Result<Self> {
-        let a = a_tree.entries()?.peekable();
-        let b = b_tree.entries()?.peekable();
+        Self::new_multi(
+            path,
+            level,
+            vec![a_tree, b_tree],
+            writer_options,
+            expiry_now,
+        )
+    }
+
+    /// Creates a merger of any number of trees of a level, given from oldest to
+    /// newest, in a single pass. Where the trees hold the same key, the entry from
+    /// the newest of them is kept, unless an older one has a later write timestamp.
+    /// See `new` for `expiry_now`.
+    pub fn new_multi(
+        path: impl AsRef<Path>,
+        level: u32,
+        inputs: Vec<&Tree>,
+        writer_options: WriterOptions,
+        expiry_now: Option<u32>,
+    ) -> Result<Self> {
+        let mut newer_range_tombstones = vec![vec![]; inputs.len()];
+        for (index, tree) in inputs.iter().enumerate() {
+            for ranges in &mut newer_range_tombstones[..index] {
+                ranges.extend(tree.range_tombstones().iter().cloned());
+            }
+        }
         let xfile = path.as_ref().to_path_buf().join(format!("X-{level}.data"));
-        let x = Writer::with_expected_num_items(&xfile, 1 << (level + 1), compression)?;
-        Ok(Self { a, b, x })
+        let key_order = writer_options.key_order.clone();
+        let counters = writer_options.counters.clone();
+        let expected_num_items = inputs.len() << level;
+        let inputs = inputs
+            .into_iter()
+            .map(|tree| Ok(tree.entries()?.peekable()))
+            .collect::<Result<_>>()?;
+        let x = Writer::with_expected_num_items(&xfile, expected_num_items, writer_options)?;
+        Ok(Self {
+            inputs,
+            x,
+            expiry_now,
+            entries_merged: 0,
+            newer_range_tombstones,
+            key_order,
+            counters,
+        })
     }
 
+    /// Returns the number of entries read from the input trees so far, across all
+    /// calls to `incremental_merge`.
+    pub fn entries_merged(&self) -> usize {
+        self.entries_merged
+    }
+
+    /// Merges at least `work` entries from the input trees, or all that are left.
+    /// Completing the merge when the inputs run out takes no extra work, so the
+    /// steps reported across all calls add up to the number of entries merged.
     pub fn incremental_merge(mut self, work: usize) -> Result<MergeOutcome> {
-        for i in 0..work {
+        let mut steps = 0;
+        while steps < work {
             let step = self.merge_step()?;
             if step == 0 {
-                let count = self.x.count();
-                return self.x.close().map(|_| MergeOutcome::Complete {
-                    count,
-                    steps: i + 1,
-                });
+                break;
             }
+            steps += step;
+        }
+        self.entries_merged += steps;
+        if self.inputs.iter_mut().all(|input| input.peek().is_none()) {
+            let count = self.x.count();
+            self.x.close()?;
+            self.counters.merge_run();
+            return Ok(MergeOutcome::Complete { count, steps });
         }
         Ok(MergeOutcome::Continue(self))
     }
 
     fn merge_step(&mut self) -> Result<usize> {
-        match (self.a.peek(), self.b.peek()) {
-            (None, None) => Ok(0),
-            (Some(_), None) => {
-                let entry = self.a.next().unwrap();
-                self.x.add(entry).map(|_| 1)
+        for input in self.inputs.iter_mut() {
+            take_error(input)?;
+        }
+        let peeked: Vec<Option<&Entry>> = self
+            .inputs
+            .iter_mut()
+            .map(|input| input.peek().and_then(|entry| entry.as_ref().ok()))
+            .collect();
+        // The inputs whose next entry comes first, newest first
+        let mut first: Vec<usize> = vec![];
+        for index in (0..peeked.len()).rev() {
+            let Some(entry) = peeked[index] else {
+                continue;
+            };
+            let order = match first.first().and_then(|first| peeked[*first]) {
+                Some(first) => entry.cmp_position_by(first, &self.key_order),
+                None => Ordering::Less,
+            };
+            match order {
+                Ordering::Less => first = vec![index],
+                Ordering::Equal => first.push(index),
+                Ordering::Greater => (),
             }
-            (None, Some(_)) => {
-                let entry = self.b.next().unwrap();
-                self.x.add(entry).map(|_| 1)
+        }
+        let Some(newest) = first.first().copied() else {
+            return Ok(0);
+        };
+        // Range tombstones that start at the same key are all kept, the others are
+        // taken one at a time. Of the entries for the same key, the newest is kept,
+        // unless an older one was written later (see `Entry::written_before`).
+        if peeked[newest].is_some_and(Entry::is_range_deleted) {
+            first.truncate(1);
+        }
+        let mut winner = newest;
+        for older in &first[1..] {
+            if let (Some(winning), Some(entry)) = (peeked[winner], peeked[*older]) {
+                // A newer range tombstone deletes the older entry whatever its
+                // write timestamp
+                let deleted = self
+                    .key_order
+                    .covers(&self.newer_range_tombstones[*older], entry.key());
+                if winning.written_before(entry) && !deleted {
+                    winner = *older;
+                }
             }
-            (Some(a), Some(b)) => {
-                let order = a.key().cmp(b.key());
-                let (entry, count) = match order {
-                    Ordering::Less => (self.a.next().unwrap(), 1),
-                    Ordering::Equal => {
-                        // discard A because it's older
-                        let _ = self.a.next().unwrap();
-                        (self.b.next().unwrap(), 2)
-                    }
-                    Ordering::Greater => (self.b.next().unwrap(), 1),
-                };
-                self.x.add(entry).map(|_| count)
+        }
+        let mut kept = None;
+        for index in &first {
+            // WONT PANIC: the entry was peeked above
+            let entry = self.inputs[*index].next().unwrap()?;
+            if *index == winner {
+                kept = Some(entry);
             }
         }
+        // WONT PANIC: the winner is one of the inputs taken from
+        self.add_from(winner, kept.unwrap()).map(|_| first.len())
+    }
+
+    /// Adds an entry from the input at `index`, unless a range tombstone in a newer
+    /// input covers it.
+    fn add_from(&mut self, index: usize, entry: Entry) -> Result<()> {
+        if !entry.is_range_deleted()
+            && self
+                .key_order
+                .covers(&self.newer_range_tombstones[index], entry.key())
+        {
+            return Ok(());
+        }
+        self.add(entry)
+    }
+
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        match self.expiry_now {
+            Some(now) if entry.is_expired(now) => Ok(()),
+            Some(_) if entry.is_deleted() && entry.write_timestamp().is_none() => Ok(()),
+            Some(_) if entry.is_range_deleted() => Ok(()),
+            _ => self.x.add(entry),
+        }
     }
 }
 
@@ -91,7 +240,6 @@ pub enum MergeOutcome {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::entry::Entry;
     use crate::tree::Tree;
     use crate::writer::tests::write_8kb;
     use tempfile::tempdir;
@@ -109,6 +257,7 @@ mod tests {
                 key: "a".as_bytes().to_vec(),
                 value: "a_value".as_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         a_writer.close().unwrap();
@@ -121,6 +270,7 @@ mod tests {
                 key: "b".as_bytes().to_vec(),
                 value: "b_value".as_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         b_writer.close().unwrap();
@@ -128,14 +278,14 @@ mod tests {
         // Open the trees and do a complete merge
         let a_tree = Tree::from_file(&a_data).unwrap();
         let b_tree = Tree::from_file(&b_data).unwrap();
-        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default()).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
 
         let result = merger.incremental_merge(512).unwrap();
         let MergeOutcome::Complete { count, steps } = result else {
             panic!("merge did not complete");
         };
         assert_eq!(count, 2);
-        assert_eq!(steps, 3);
+        assert_eq!(steps, 2);
         assert!(std::fs::exists(dir.as_ref().join("X-10.data")).unwrap());
     }
 
@@ -159,12 +309,218 @@ mod tests {
         // Open the trees and do an incomplete merge
         let a_tree = Tree::from_file(&a_data).unwrap();
         let b_tree = Tree::from_file(&b_data).unwrap();
-        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default()).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
 
         let result = merger.incremental_merge(1).unwrap();
         assert!(matches!(result, MergeOutcome::Continue(_)));
     }
 
+    // The steps reported across resumed merges add up to the entries merged
+    #[test]
+    fn resumed_merge_tracks_progress() {
+        let dir = tempdir().unwrap();
+        let a_data = dir.as_ref().join("A-10.data");
+        let mut a_writer = Writer::new(&a_data).unwrap();
+        for i in (0..200).step_by(2) {
+            a_writer
+                .add(Entry::KeyVal {
+                    key: format!("{i:03}").into_bytes(),
+                    value: "a".as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        a_writer.close().unwrap();
+        let b_data = dir.as_ref().join("B-10.data");
+        let mut b_writer = Writer::new(&b_data).unwrap();
+        for i in 0..150 {
+            b_writer
+                .add(Entry::KeyVal {
+                    key: format!("{:03}", i * 2 + 1).into_bytes(),
+                    value: "b".as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        b_writer.close().unwrap();
+
+        let a_tree = Tree::from_file(&a_data).unwrap();
+        let b_tree = Tree::from_file(&b_data).unwrap();
+        let mut merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
+        let mut total_steps = 0;
+        loop {
+            let merged_before = merger.entries_merged();
+            match merger.incremental_merge(30).unwrap() {
+                MergeOutcome::Continue(resumed) => {
+                    let steps = resumed.entries_merged() - merged_before;
+                    assert_eq!(steps, 30);
+                    total_steps += steps;
+                    merger = resumed;
+                }
+                MergeOutcome::Complete { count, steps } => {
+                    total_steps += steps;
+                    assert_eq!(count, 250);
+                    break;
+                }
+            }
+        }
+        assert_eq!(total_steps, 250);
+    }
+
+    // Entry timestamps are expiry times, so they don't decide which value wins: B
+    // is newer even when A's value would expire later
+    #[test]
+    fn newer_file_wins_regardless_of_expiry() {
+        let dir = tempdir().unwrap();
+        let entry = |value: &str, expiry: u32| Entry::KeyVal {
+            key: b"key".to_vec(),
+            value: value.as_bytes().to_vec(),
+            timestamp: Some(expiry),
+            write_timestamp: None,
+        };
+        let a_data = dir.as_ref().join("A-10.data");
+        let mut a_writer = Writer::new(&a_data).unwrap();
+        a_writer.add(entry("older", u32::MAX)).unwrap();
+        a_writer.close().unwrap();
+        let b_data = dir.as_ref().join("B-10.data");
+        let mut b_writer = Writer::new(&b_data).unwrap();
+        b_writer.add(entry("newer", u32::MAX - 1)).unwrap();
+        b_writer.close().unwrap();
+
+        let a_tree = Tree::from_file(&a_data).unwrap();
+        let b_tree = Tree::from_file(&b_data).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
+        let MergeOutcome::Complete { count, .. } = merger.incremental_merge(512).unwrap() else {
+            panic!("merge did not complete");
+        };
+        assert_eq!(count, 1);
+        let x_tree = Tree::from_file(dir.as_ref().join("X-10.data")).unwrap();
+        assert_eq!(
+            x_tree.get_entry(b"key").unwrap(),
+            Some(entry("newer", u32::MAX - 1))
+        );
+    }
+
+    // Of two entries for a key that both have a write timestamp, the later write
+    // wins, even from A. Without one on both, B wins as usual.
+    #[test]
+    fn later_write_timestamp_wins() {
+        let dir = tempdir().unwrap();
+        let entry = |key: &str, value: &str, write_timestamp: Option<u32>| Entry::KeyVal {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp,
+        };
+        let a_entries = [
+            entry("a-later", "a", Some(200)),
+            Entry::Deleted {
+                key: b"a-later-deleted".to_vec(),
+                timestamp: None,
+                write_timestamp: Some(200),
+            },
+            entry("b-later", "a", Some(100)),
+            entry("b-unversioned", "a", Some(200)),
+            entry("covered", "a", Some(200)),
+        ];
+        let b_entries = [
+            entry("a-later", "b", Some(100)),
+            entry("a-later-deleted", "b", Some(100)),
+            entry("b-later", "b", Some(200)),
+            entry("b-unversioned", "b", None),
+            Entry::RangeDeleted {
+                start: b"covered".to_vec(),
+                end: b"covered\0".to_vec(),
+                timestamp: None,
+            },
+            entry("covered", "b", Some(100)),
+        ];
+        let a_data = dir.as_ref().join("A-10.data");
+        let mut a_writer = Writer::new(&a_data).unwrap();
+        for entry in a_entries {
+            a_writer.add(entry).unwrap();
+        }
+        a_writer.close().unwrap();
+        let b_data = dir.as_ref().join("B-10.data");
+        let mut b_writer = Writer::new(&b_data).unwrap();
+        for entry in b_entries {
+            b_writer.add(entry).unwrap();
+        }
+        b_writer.close().unwrap();
+
+        let a_tree = Tree::from_file(&a_data).unwrap();
+        let b_tree = Tree::from_file(&b_data).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
+        let MergeOutcome::Complete { steps, .. } = merger.incremental_merge(512).unwrap() else {
+            panic!("merge did not complete");
+        };
+        assert_eq!(steps, 11);
+        let x_tree = Tree::from_file(dir.as_ref().join("X-10.data")).unwrap();
+        let kept = |key: &str| x_tree.get_entry(key.as_bytes()).unwrap().unwrap();
+        assert_eq!(kept("a-later"), entry("a-later", "a", Some(200)));
+        assert!(kept("a-later-deleted").is_deleted());
+        assert_eq!(kept("b-later"), entry("b-later", "b", Some(200)));
+        assert_eq!(kept("b-unversioned"), entry("b-unversioned", "b", None));
+        // The range tombstone in B deletes A's entry, so B's is kept
+        assert_eq!(kept("covered"), entry("covered", "b", Some(100)));
+    }
+
+    // A range tombstone in B drops the entries it covers from A, but not B's own
+    // entries in its range
+    #[test]
+    fn range_tombstones_shadow_older_entries() {
+        let dir = tempdir().unwrap();
+        let key_val = |key: &str, value: &str| Entry::KeyVal {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        let range = Entry::RangeDeleted {
+            start: b"b".to_vec(),
+            end: b"d".to_vec(),
+            timestamp: None,
+        };
+
+        let a_data = dir.as_ref().join("A-10.data");
+        let mut a_writer = Writer::new(&a_data).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            a_writer.add(key_val(key, "a_value")).unwrap();
+        }
+        a_writer.close().unwrap();
+        let b_data = dir.as_ref().join("B-10.data");
+        let mut b_writer = Writer::new(&b_data).unwrap();
+        b_writer.add(range.clone()).unwrap();
+        b_writer.add(key_val("c", "b_value")).unwrap();
+        b_writer.close().unwrap();
+
+        let a_tree = Tree::from_file(&a_data).unwrap();
+        let b_tree = Tree::from_file(&b_data).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
+        let MergeOutcome::Complete { .. } = merger.incremental_merge(512).unwrap() else {
+            panic!("merge did not complete");
+        };
+
+        let x_tree = Tree::from_file(dir.as_ref().join("X-10.data")).unwrap();
+        let entries: Vec<Entry> = x_tree.entries().unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(
+            entries,
+            [
+                key_val("a", "a_value"),
+                range,
+                key_val("c", "b_value"),
+                key_val("d", "a_value"),
+            ]
+        );
+        assert!(x_tree.get_entry(b"b").unwrap().unwrap().is_deleted());
+        assert_eq!(
+            x_tree.get_entry(b"c").unwrap(),
+            Some(key_val("c", "b_value"))
+        );
+    }
+
     // Completed merge with disjoint keysets results in a merged file with all keys
     #[test]
     fn complete_merge_with_disjoint_keys() {
@@ -179,6 +535,7 @@ mod tests {
                 key: a_key.clone(),
                 value: "a_value".as_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         a_writer.close().unwrap();
@@ -192,6 +549,7 @@ mod tests {
                 key: b_key.clone(),
                 value: "b_value".as_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         b_writer.close().unwrap();
@@ -199,7 +557,7 @@ mod tests {
         // Open the trees and do a complete merge
         let a_tree = Tree::from_file(&a_data).unwrap();
         let b_tree = Tree::from_file(&b_data).unwrap();
-        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default()).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
 
         let result = merger.incremental_merge(512).unwrap();
         assert!(matches!(result, MergeOutcome::Complete { .. }));
@@ -223,6 +581,7 @@ mod tests {
                 key: a_key.clone(),
                 value: "a_value".as_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         a_writer.close().unwrap();
@@ -236,6 +595,7 @@ mod tests {
                 key: a_key.clone(),
                 value: b_value.clone(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         b_writer.close().unwrap();
@@ -243,7 +603,7 @@ mod tests {
         // Open the trees and do a complete merge
         let a_tree = Tree::from_file(&a_data).unwrap();
         let b_tree = Tree::from_file(&b_data).unwrap();
-        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default()).unwrap();
+        let merger = Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), None).unwrap();
 
         let result = merger.incremental_merge(512).unwrap();
         assert!(matches!(result, MergeOutcome::Complete { .. }));
@@ -254,8 +614,157 @@ mod tests {
             Entry::KeyVal {
                 key: a_key,
                 value: b_value,
-                timestamp: None
+                timestamp: None,
+                write_timestamp: None,
             }
         );
     }
+
+    // A multi-way merge keeps the entry from the newest tree that holds each key
+    #[test]
+    fn multi_merge_prefers_newest() {
+        let dir = tempdir().unwrap();
+        let key = |i: usize| format!("key-{i:02}").into_bytes();
+        let value = |i: usize, value: &str| Entry::KeyVal {
+            key: key(i),
+            value: value.as_bytes().to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        let write = |name: &str, entries: Vec<Entry>| {
+            let data = dir.as_ref().join(name);
+            let mut writer = Writer::new(&data).unwrap();
+            for entry in entries {
+                writer.add(entry).unwrap();
+            }
+            writer.close().unwrap();
+            Tree::from_file(&data).unwrap()
+        };
+
+        let a_tree = write("A-10.data", (0..10).map(|i| value(i, "a")).collect());
+        // B's range tombstone hides A's key-03
+        let mut b_entries = vec![Entry::RangeDeleted {
+            start: key(3),
+            end: key(4),
+            timestamp: None,
+        }];
+        b_entries.extend((5..15).map(|i| value(i, "b")));
+        let b_tree = write("B-10.data", b_entries);
+        let mut c_entries = vec![Entry::Deleted {
+            key: key(1),
+            timestamp: None,
+            write_timestamp: None,
+        }];
+        c_entries.extend((8..20).map(|i| value(i, "c")));
+        let c_tree = write("C-10.data", c_entries);
+
+        let inputs = vec![&a_tree, &b_tree, &c_tree];
+        let merger = Merger::new_multi(&dir, 10, inputs, Default::default(), None).unwrap();
+        let MergeOutcome::Complete { count, steps } = merger.incremental_merge(512).unwrap() else {
+            panic!("merge did not complete");
+        };
+        assert_eq!(steps, 10 + 11 + 13);
+
+        let x_tree = Tree::from_file(dir.as_ref().join("X-10.data")).unwrap();
+        let entries: Vec<Entry> = x_tree.entries().unwrap().map(Result::unwrap).collect();
+        let mut expected = vec![
+            value(0, "a"),
+            Entry::Deleted {
+                key: key(1),
+                timestamp: None,
+                write_timestamp: None,
+            },
+            value(2, "a"),
+            Entry::RangeDeleted {
+                start: key(3),
+                end: key(4),
+                timestamp: None,
+            },
+            value(4, "a"),
+        ];
+        expected.extend((5..8).map(|i| value(i, "b")));
+        expected.extend((8..20).map(|i| value(i, "c")));
+        assert_eq!(entries, expected);
+        assert_eq!(count, expected.len());
+    }
+
+    // Expired values are dropped from the merged file
+    #[test]
+    fn merge_drops_expired_values() {
+        let dir = tempdir().unwrap();
+        let expired_key = "expired".as_bytes().to_vec();
+        let live_key = "live".as_bytes().to_vec();
+
+        // "A"
+        let a_data = dir.as_ref().join("A-10.data");
+        let mut a_writer = Writer::new(&a_data).unwrap();
+        a_writer
+            .add(Entry::KeyVal {
+                key: expired_key.clone(),
+                value: "a_value".as_bytes().to_vec(),
+                timestamp: Some(100),
+                write_timestamp: None,
+            })
+            .unwrap();
+        a_writer.close().unwrap();
+
+        // "B"
+        let b_data = dir.as_ref().join("B-10.data");
+        let mut b_writer = Writer::new(&b_data).unwrap();
+        b_writer
+            .add(Entry::KeyVal {
+                key: live_key.clone(),
+                value: "b_value".as_bytes().to_vec(),
+                timestamp: Some(200),
+                write_timestamp: None,
+            })
+            .unwrap();
+        b_writer.close().unwrap();
+
+        let a_tree = Tree::from_file(&a_data).unwrap();
+        let b_tree = Tree::from_file(&b_data).unwrap();
+        let merger =
+            Merger::new(&dir, 10, &a_tree, &b_tree, Default::default(), Some(150)).unwrap();
+
+        let result = merger.incremental_merge(512).unwrap();
+        let MergeOutcome::Complete { count, .. } = result else {
+            panic!("merge did not complete");
+        };
+        assert_eq!(count, 1);
+
+        let x_tree = Tree::from_file(dir.as_ref().join("X-10.data")).unwrap();
+        assert!(x_tree.get_entry(&expired_key).unwrap().is_none());
+        assert!(x_tree.get_entry(&live_key).unwrap().is_some());
+    }
+
+    // The bloom filter of a merge into a deep level is capped, rather than sized for
+    // the billions of keys the level could hold
+    #[test]
+    fn deep_level_bloom_is_capped() {
+        let dir = tempdir().unwrap();
+        let mut trees = vec![];
+        for (name, key) in [("A-30.data", "a"), ("B-30.data", "b")] {
+            let data = dir.as_ref().join(name);
+            let mut writer = Writer::new(&data).unwrap();
+            writer
+                .add(Entry::KeyVal {
+                    key: key.as_bytes().to_vec(),
+                    value: "value".as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+            writer.close().unwrap();
+            trees.push(Tree::from_file(&data).unwrap());
+        }
+
+        let merger = Merger::new(&dir, 30, &trees[0], &trees[1], Default::default(), None).unwrap();
+        let result = merger.incremental_merge(512).unwrap();
+        assert!(matches!(result, MergeOutcome::Complete { count: 2, .. }));
+
+        let x_tree = Tree::from_file(dir.as_ref().join("X-30.data")).unwrap();
+        assert!(x_tree.bloom().num_bits() / 8 < 32 * 1024 * 1024);
+        assert!(x_tree.get_entry(b"a").unwrap().is_some());
+        assert!(x_tree.get_entry(b"b").unwrap().is_some());
+    }
 }
@@ -1,58 +1,428 @@
 use std::collections::BTreeMap;
 use std::fs::{remove_file, File, OpenOptions};
-use std::io::Write;
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::comparator::{KeyOrder, OrderedKey};
 use crate::db::Command;
-use crate::entry::Entry;
-use crate::writer::Writer;
-use crate::{error::*, Compression};
+use crate::entry::{written_before, Entry};
+use crate::error::*;
+use crate::writer::{Writer, WriterOptions};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
     Plain(Vec<u8>),
-    // Timestampped(Vec<u8>, time value?)
+    /// A value that expires at the given timestamp (see `entry::now_timestamp`).
+    Timestamped(Vec<u8>, u32),
     Deleted,
+    /// A value, or a tombstone if `value` is `None`, written with a write timestamp
+    /// (see `HanoiDB::insert_at`).
+    Written {
+        value: Option<Vec<u8>>,
+        expiry: Option<u32>,
+        write_timestamp: u32,
+    },
 }
 
-pub type NurseryData = BTreeMap<Vec<u8>, Value>;
+impl Value {
+    /// Returns the value, or `None` if it was deleted or it has expired at time `now`.
+    pub fn live_value(&self, now: u32) -> Option<&Vec<u8>> {
+        match self {
+            Value::Plain(value) => Some(value),
+            Value::Timestamped(value, expiry) if *expiry > now => Some(value),
+            Value::Written {
+                value: Some(value),
+                expiry,
+                ..
+            } if expiry.is_none_or(|expiry| expiry > now) => Some(value),
+            Value::Timestamped(..) | Value::Deleted | Value::Written { .. } => None,
+        }
+    }
+
+    fn is_deleted(&self) -> bool {
+        matches!(self, Value::Deleted | Value::Written { value: None, .. })
+    }
+
+    pub(crate) fn write_timestamp(&self) -> Option<u32> {
+        match self {
+            Value::Written {
+                write_timestamp, ..
+            } => Some(*write_timestamp),
+            _ => None,
+        }
+    }
+
+    fn from_entry(entry: &Entry) -> (Vec<u8>, Value) {
+        match entry {
+            Entry::KeyVal {
+                key,
+                value,
+                timestamp,
+                write_timestamp: Some(write_timestamp),
+            } => (
+                key.clone(),
+                Value::Written {
+                    value: Some(value.clone()),
+                    expiry: *timestamp,
+                    write_timestamp: *write_timestamp,
+                },
+            ),
+            Entry::KeyVal {
+                key,
+                value,
+                timestamp: None,
+                ..
+            } => (key.clone(), Value::Plain(value.clone())),
+            Entry::KeyVal {
+                key,
+                value,
+                timestamp: Some(expiry),
+                ..
+            } => (key.clone(), Value::Timestamped(value.clone(), *expiry)),
+            Entry::Deleted {
+                key,
+                timestamp,
+                write_timestamp: Some(write_timestamp),
+            } => (
+                key.clone(),
+                Value::Written {
+                    value: None,
+                    expiry: *timestamp,
+                    write_timestamp: *write_timestamp,
+                },
+            ),
+            Entry::Deleted { key, .. } => (key.clone(), Value::Deleted),
+            _ => unreachable!("nursery only holds data entries"),
+        }
+    }
+
+    pub(crate) fn into_entry(self, key: Vec<u8>) -> Entry {
+        match self {
+            Value::Plain(value) => Entry::KeyVal {
+                key,
+                value,
+                timestamp: None,
+                write_timestamp: None,
+            },
+            Value::Timestamped(value, expiry) => Entry::KeyVal {
+                key,
+                value,
+                timestamp: Some(expiry),
+                write_timestamp: None,
+            },
+            Value::Deleted => Entry::Deleted {
+                key,
+                timestamp: None,
+                write_timestamp: None,
+            },
+            Value::Written {
+                value: Some(value),
+                expiry,
+                write_timestamp,
+            } => Entry::KeyVal {
+                key,
+                value,
+                timestamp: expiry,
+                write_timestamp: Some(write_timestamp),
+            },
+            Value::Written {
+                value: None,
+                expiry,
+                write_timestamp,
+            } => Entry::Deleted {
+                key,
+                timestamp: expiry,
+                write_timestamp: Some(write_timestamp),
+            },
+        }
+    }
+}
+
+/// Returned by `NurseryData::get` for keys that are only covered by a range tombstone.
+static RANGE_DELETED: Value = Value::Deleted;
+
+/// The values, tombstones and range tombstones held by the nursery. A range
+/// tombstone removes the values it covers when it is added, so a value in `values`
+/// is always newer than any range that covers it.
+#[derive(Debug, Clone, Default)]
+pub struct NurseryData {
+    values: BTreeMap<OrderedKey, Value>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    key_order: KeyOrder,
+}
+
+impl NurseryData {
+    pub fn new(key_order: KeyOrder) -> Self {
+        Self {
+            values: BTreeMap::new(),
+            ranges: vec![],
+            key_order,
+        }
+    }
+
+    /// Returns the value or tombstone for the key. A key that is only covered by a
+    /// range tombstone is returned as `Value::Deleted`.
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.values
+            .get(&self.ordered_key(key.to_vec()))
+            .or_else(|| {
+                let deleted = self.key_order.covers(&self.ranges, key);
+                deleted.then_some(&RANGE_DELETED)
+            })
+    }
+
+    /// Returns the values and tombstones between `start` and `end`, in key order.
+    pub fn range(
+        &self,
+        (start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> impl DoubleEndedIterator<Item = (&Vec<u8>, &Value)> {
+        let start = start.map(|key| self.ordered_key(key));
+        let end = end.map(|key| self.ordered_key(key));
+        self.values
+            .range((start, end))
+            .map(|(key, value)| (&key.key, value))
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &Vec<u8>> {
+        self.values.keys().map(|key| &key.key)
+    }
+
+    /// Returns the order the keys are kept in.
+    pub fn key_order(&self) -> &KeyOrder {
+        &self.key_order
+    }
+
+    /// Returns the `start..end` ranges of the range tombstones.
+    pub fn ranges(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.ranges
+    }
+
+    /// Returns the number of values, tombstones and range tombstones.
+    pub fn len(&self) -> usize {
+        self.values.len() + self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.ranges.is_empty()
+    }
+
+    fn apply(&mut self, entry: &Entry) {
+        match entry {
+            Entry::RangeDeleted { start, end, .. } => {
+                let range = [(start.clone(), end.clone())];
+                let key_order = &self.key_order;
+                self.values
+                    .retain(|key, _| !key_order.covers(&range, &key.key));
+                self.ranges.extend(range);
+            }
+            entry => {
+                let (key, value) = Value::from_entry(entry);
+                let key = self.ordered_key(key);
+                // A write with an earlier write timestamp than the one it replaces
+                // loses to it, like it would in a merge
+                let written_before = self.values.get(&key).is_some_and(|current| {
+                    written_before(value.write_timestamp(), current.write_timestamp())
+                });
+                if !written_before {
+                    self.values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// Applies an entry like `apply`, for data with nothing older underneath it for
+    /// tombstones to hide, so tombstones remove values and are then dropped.
+    fn apply_without_tombstones(&mut self, entry: &Entry) {
+        match entry {
+            Entry::RangeDeleted { start, end, .. } => {
+                let range = [(start.clone(), end.clone())];
+                let key_order = &self.key_order;
+                self.values
+                    .retain(|key, _| !key_order.covers(&range, &key.key));
+            }
+            // Tombstones with a write timestamp are kept, as a later write to the
+            // key with an earlier write timestamp must still lose to them
+            Entry::Deleted {
+                key,
+                write_timestamp: None,
+                ..
+            } => {
+                self.values.remove(&self.ordered_key(key.clone()));
+            }
+            entry => self.apply(entry),
+        }
+    }
+
+    fn ordered_key(&self, key: Vec<u8>) -> OrderedKey {
+        OrderedKey::new(key, &self.key_order)
+    }
+
+    /// Returns the entries to write to a tree file, in the order they are written.
+    fn into_entries(self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self
+            .values
+            .into_iter()
+            .map(|(key, value)| value.into_entry(key.key))
+            .chain(
+                self.ranges
+                    .into_iter()
+                    .map(|(start, end)| Entry::RangeDeleted {
+                        start,
+                        end,
+                        timestamp: None,
+                    }),
+            )
+            .collect();
+        entries.sort_by(|a, b| a.cmp_position_by(b, &self.key_order));
+        entries
+    }
+}
+
+/// Controls how often writes to the nursery log are synced to disk. Anything other
+/// than `EveryWrite` trades durability of the most recent writes for throughput.
+/// The log is always synced when the database is flushed or dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncStrategy {
+    /// Sync the log after every write.
+    #[default]
+    EveryWrite,
+    /// Never explicitly sync the log, leaving it up to the operating system.
+    Never,
+    /// Sync the log on the first write after the interval has elapsed since the last sync.
+    Interval(Duration),
+    /// Sync the log after every N writes.
+    EveryN(usize),
+}
+
+/// Settings for the nursery.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NurseryOptions {
+    /// Used to write `nursery.data` when the nursery is promoted.
+    pub writer_options: WriterOptions,
+    pub sync_strategy: SyncStrategy,
+    /// Promote the nursery once this many bytes were written to the log, even if it
+    /// holds fewer entries than the first level.
+    pub max_bytes: Option<usize>,
+    /// Start a new log segment once the current one holds this many bytes.
+    pub log_segment_size: Option<usize>,
+    /// Wrap each log record in a length and CRC32 frame, see `frame_record`.
+    pub framed_log: bool,
+    /// Keep a recovered log with fewer entries than this in memory and go on
+    /// appending to it, instead of writing it out to `nursery.data`.
+    pub recover_promote_threshold: usize,
+}
 
 #[derive(Debug)]
 pub struct Nursery {
-    log: File,
+    /// `None` for a database held in memory, whose nursery is never written out.
+    log: Option<File>,
     directory: PathBuf,
-    data: NurseryData,
+    /// Shared with the scans that are reading it. A write while a scan holds the
+    /// data copies it first, so that the scan keeps seeing it as it was.
+    data: Arc<NurseryData>,
     min_level: u32,
     total_size: usize,
     step: usize,
-    compression: Compression,
+    options: NurseryOptions,
+    /// The sequence number of the log segment being written, see `log_file_name`.
+    segment: u64,
+    /// The number of bytes written to the current log segment.
+    segment_size: usize,
+    unsynced_writes: usize,
+    last_sync: Instant,
 }
 
 impl Nursery {
     pub fn new(
         directory: impl AsRef<Path>,
         min_level: u32,
-        compression: Compression,
+        options: NurseryOptions,
     ) -> Result<(Self, Option<Command>)> {
         let directory = directory.as_ref().to_path_buf();
-        let file = directory.join("nursery.log");
-        let recovery = Self::recover(&file, min_level, compression)?;
-        let log = OpenOptions::new()
-            .create_new(true)
-            .append(true)
-            .open(file)?;
-        Ok((
-            Self {
-                log,
-                directory,
-                data: Default::default(),
-                min_level,
-                total_size: 0,
-                step: 0,
-                compression,
-            },
-            recovery,
-        ))
+        let log_files = log_segments(&directory)?;
+        let mut data = NurseryData::new(options.writer_options.key_order.clone());
+        let mut total_size = 0;
+        for log_file in &log_files {
+            total_size += replay_log_segment(log_file, &mut data)?;
+        }
+        let threshold = options.recover_promote_threshold.min(1 << min_level);
+        if data.is_empty() || data.len() >= threshold {
+            let writer_options = options.writer_options.clone();
+            let recovery = Self::recover(&directory, min_level, log_files, data, writer_options)?;
+            let log = create_log_segment(&log_file_name(&directory, 0), options.framed_log)?;
+            return Ok((
+                Self::with_log(Some(log), directory, min_level, options),
+                recovery,
+            ));
+        }
+
+        // A small log is cheaper to keep in memory and go on appending to than to
+        // write out to a tree file
+        // WONT PANIC: something was recovered, so there is a log segment
+        let last_segment = log_files.last().unwrap();
+        let (log, segment, segment_size) =
+            continue_log_segment(&directory, last_segment, options.framed_log)?;
+        let mut nursery = Self::with_log(Some(log), directory, min_level, options);
+        nursery.data = Arc::new(data);
+        nursery.total_size = total_size;
+        nursery.segment = segment;
+        nursery.segment_size = segment_size;
+        Ok((nursery, None))
+    }
+
+    /// Creates an empty nursery that holds everything in memory, without a log, and
+    /// is never promoted.
+    pub fn in_memory(min_level: u32, options: NurseryOptions) -> Self {
+        Self::with_log(None, PathBuf::new(), min_level, options)
+    }
+
+    /// Creates an empty nursery that writes to `log`, the first log segment.
+    fn with_log(
+        log: Option<File>,
+        directory: PathBuf,
+        min_level: u32,
+        options: NurseryOptions,
+    ) -> Self {
+        Self {
+            log,
+            directory,
+            data: Arc::new(NurseryData::new(options.writer_options.key_order.clone())),
+            min_level,
+            total_size: 0,
+            step: 0,
+            options,
+            segment: 0,
+            segment_size: 0,
+            unsynced_writes: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Returns whether the nursery is held in memory, see `Nursery::in_memory`.
+    pub fn is_in_memory(&self) -> bool {
+        self.log.is_none()
+    }
+
+    /// Returns the number of values (not tombstones) held in the nursery.
+    pub fn value_count(&self) -> usize {
+        self.data
+            .values
+            .values()
+            .filter(|value| !value.is_deleted())
+            .count()
+    }
+
+    /// Returns the options used to write `nursery.data`.
+    pub fn writer_options(&self) -> WriterOptions {
+        self.options.writer_options.clone()
+    }
+
+    /// Returns the number of bytes written to the log since the nursery was last flushed.
+    pub fn total_size(&self) -> usize {
+        self.total_size
     }
 
     pub fn get_value(&self, key: &[u8]) -> Option<&Value> {
@@ -60,122 +430,263 @@ impl Nursery {
     }
 
     pub fn add(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Vec<Command>> {
-        let bin_entry = Entry::KeyVal {
-            key: key.clone(),
-            value: value.clone(),
+        self.write_entry(Entry::KeyVal {
+            key,
+            value,
             timestamp: None,
-        }
-        .encode();
-        self.write_internal(key, Value::Plain(value), bin_entry)
+            write_timestamp: None,
+        })
+    }
+
+    /// Adds a key-value pair that expires at the given timestamp.
+    pub fn add_expiring(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expiry: u32,
+    ) -> Result<Vec<Command>> {
+        self.write_entry(Entry::KeyVal {
+            key,
+            value,
+            timestamp: Some(expiry),
+            write_timestamp: None,
+        })
     }
 
     pub fn delete(&mut self, key: Vec<u8>) -> Result<Vec<Command>> {
-        let bin_entry = Entry::Deleted {
-            key: key.clone(),
+        self.write_entry(Entry::Deleted {
+            key,
             timestamp: None,
-        }
-        .encode();
-        self.write_internal(key, Value::Deleted, bin_entry)
+            write_timestamp: None,
+        })
     }
 
-    fn write_internal(
+    /// Adds a key-value pair written at the given write timestamp.
+    pub fn add_at(
         &mut self,
         key: Vec<u8>,
-        value: Value,
-        bin_entry: Vec<u8>,
+        value: Vec<u8>,
+        write_timestamp: u32,
     ) -> Result<Vec<Command>> {
-        self.data.insert(key, value);
-        self.log.write_all(&bin_entry)?;
-        self.log.sync_data()?;
+        self.write_entry(Entry::KeyVal {
+            key,
+            value,
+            timestamp: None,
+            write_timestamp: Some(write_timestamp),
+        })
+    }
+
+    /// Deletes a key at the given write timestamp.
+    pub fn delete_at(&mut self, key: Vec<u8>, write_timestamp: u32) -> Result<Vec<Command>> {
+        self.write_entry(Entry::Deleted {
+            key,
+            timestamp: None,
+            write_timestamp: Some(write_timestamp),
+        })
+    }
+
+    /// Deletes every key in `start..end` with a single range tombstone.
+    pub fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) -> Result<Vec<Command>> {
+        self.write_entry(Entry::RangeDeleted {
+            start,
+            end,
+            timestamp: None,
+        })
+    }
+
+    /// Writes a group of entries to the log as a single transaction record, so
+    /// that either all or none of them are recovered after a crash.
+    pub fn write_batch(&mut self, entries: Vec<Entry>) -> Result<Vec<Command>> {
+        if entries.is_empty() {
+            return Ok(vec![]);
+        }
+        let transaction = Entry::Transaction { entries };
+        let bin_entry = transaction.encode()?;
+        let Entry::Transaction { entries } = transaction else {
+            unreachable!()
+        };
+        self.write_internal(entries, bin_entry)
+    }
+
+    fn write_entry(&mut self, entry: Entry) -> Result<Vec<Command>> {
+        let bin_entry = entry.encode()?;
+        self.write_internal(vec![entry], bin_entry)
+    }
+
+    fn write_internal(&mut self, entries: Vec<Entry>, bin_entry: Vec<u8>) -> Result<Vec<Command>> {
+        if self.is_in_memory() {
+            // There's no log to write and no level to promote to
+            let data = Arc::make_mut(&mut self.data);
+            for entry in &entries {
+                data.apply_without_tombstones(entry);
+            }
+            return Ok(vec![]);
+        }
+        let count = entries.len();
+        let bin_entry = if self.options.framed_log {
+            frame_record(&bin_entry)
+        } else {
+            bin_entry
+        };
+        self.maybe_roll_log(bin_entry.len())?;
+        // WONT PANIC: a nursery held in memory returned above
+        self.log.as_mut().unwrap().write_all(&bin_entry)?;
+        self.segment_size += bin_entry.len();
+        self.unsynced_writes += 1;
+        let should_sync = match self.options.sync_strategy {
+            SyncStrategy::EveryWrite => true,
+            SyncStrategy::Never => false,
+            SyncStrategy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncStrategy::EveryN(n) => self.unsynced_writes >= n,
+        };
+        if should_sync {
+            self.sync()?;
+        }
+        for entry in &entries {
+            Arc::make_mut(&mut self.data).apply(entry);
+        }
         self.total_size += bin_entry.len();
         let mut commands = vec![];
 
         // Check if the in-memory data is big enough to promote to the next level
         let min_level_size = 1 << self.min_level;
-        if self.data.len() >= min_level_size {
-            let filename = self.directory.join("nursery.data");
-            let mut writer =
-                Writer::with_expected_num_items(&filename, min_level_size, self.compression)?;
-            let data = std::mem::take(&mut self.data);
-            for (key, value) in data.into_iter() {
-                let entry = match value {
-                    Value::Plain(value) => Entry::KeyVal {
-                        key,
-                        value,
-                        timestamp: None,
-                    },
-                    Value::Deleted => Entry::Deleted {
-                        key,
-                        timestamp: None,
-                    },
-                };
-                writer.add(entry)?;
-            }
-            writer.close()?;
-            commands.push(Command::PromoteFile {
-                path: filename,
-                target_level: self.min_level,
-            });
-
-            // Truncate the log file and replace the existing handle
-            self.log = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(self.directory.join("nursery.log"))?;
+        let over_budget = self
+            .options
+            .max_bytes
+            .is_some_and(|max| self.total_size > max);
+        if self.data.len() >= min_level_size || over_budget {
+            commands.push(self.promote()?);
         }
 
         // Trigger incremental merge
         let min_steps_to_merge = min_level_size / 2;
-        if self.step + 1 >= min_steps_to_merge {
+        self.step += count;
+        if self.step >= min_steps_to_merge {
             commands.push(Command::Merge {
-                steps: self.step + 1,
+                steps: self.step,
                 target_level: self.min_level,
             });
             self.step = 0;
-        } else {
-            self.step += 1;
         }
         Ok(commands)
     }
 
-    fn recover(
-        log_file: impl AsRef<Path>,
-        target_level: u32,
-        compression: Compression,
-    ) -> Result<Option<Command>> {
-        if !log_file.as_ref().exists() {
-            return Ok(None);
-        }
-
-        let mut file = OpenOptions::new().read(true).open(&log_file)?;
-        let mut data: BTreeMap<Vec<u8>, Entry> = Default::default();
-        loop {
-            let entry = match Entry::read(&mut file) {
-                Ok(entry) => entry,
-                Err(err) => {
-                    if !matches!(err, Error::EndOfFile) {
-                        eprintln!("Error reading {}, {err}", log_file.as_ref().display());
-                    }
-                    break;
-                }
-            };
+    /// Moves on to a new log segment if writing `len` more bytes would take the
+    /// current one past the segment size. A single write is never split.
+    fn maybe_roll_log(&mut self, len: usize) -> Result<()> {
+        let Some(log_segment_size) = self.options.log_segment_size else {
+            return Ok(());
+        };
+        if self.segment_size == 0 || self.segment_size + len <= log_segment_size {
+            return Ok(());
+        }
+        // Finish the current segment, so that it is complete on disk before any
+        // writes go to the next one
+        self.sync()?;
+        self.segment += 1;
+        self.log = Some(create_log_segment(
+            &log_file_name(&self.directory, self.segment),
+            self.options.framed_log,
+        )?);
+        self.segment_size = 0;
+        Ok(())
+    }
 
-            if entry.is_pos_len() {
-                unreachable!("nursery log contained b-tree internal entries");
+    /// Syncs any outstanding writes to the nursery log to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(log) = &self.log {
+            if self.unsynced_writes > 0 {
+                log.sync_data()?;
+                self.unsynced_writes = 0;
             }
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
 
-            data.insert(entry.key().to_owned(), entry);
+    /// Writes out the current contents of the nursery to be promoted into the first
+    /// level, even if the nursery is not full yet. Does nothing if the nursery is empty,
+    /// or held in memory.
+    pub fn flush(&mut self) -> Result<Vec<Command>> {
+        if self.data.is_empty() || self.is_in_memory() {
+            self.sync()?;
+            return Ok(vec![]);
         }
+        Ok(vec![self.promote()?])
+    }
 
+    /// Writes the in-memory data to `nursery.data`, truncates the first log segment
+    /// and removes the others.
+    fn promote(&mut self) -> Result<Command> {
+        let filename = self.directory.join("nursery.data");
+        let mut writer = Writer::with_expected_num_items(
+            &filename,
+            1 << self.min_level,
+            self.options.writer_options.clone(),
+        )?;
+        let empty = Arc::new(NurseryData::new(self.data.key_order().clone()));
+        let data = Arc::unwrap_or_clone(std::mem::replace(&mut self.data, empty));
+        for entry in data.into_entries() {
+            writer.add(entry)?;
+        }
+        #[cfg(feature = "tracing")]
+        let entries = writer.count();
+        writer.close()?;
+
+        // Truncate the log file and replace the existing handle
+        let mut log = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(log_file_name(&self.directory, 0))?;
+        write_log_header(&mut log, self.options.framed_log)?;
+        self.log = Some(log);
+        for segment in 1..=self.segment {
+            remove_file(log_file_name(&self.directory, segment))?;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            level = self.min_level,
+            file = %filename.display(),
+            entries,
+            log_bytes = self.total_size,
+            "promoted the nursery"
+        );
+        self.segment = 0;
+        self.segment_size = 0;
+        self.unsynced_writes = 0;
+        self.total_size = 0;
+
+        Ok(Command::PromoteFile {
+            path: filename,
+            target_level: self.min_level,
+        })
+    }
+
+    /// Writes the data replayed from the log segments in `directory` to
+    /// `nursery.data` and removes the segments.
+    fn recover(
+        directory: &Path,
+        target_level: u32,
+        log_files: Vec<PathBuf>,
+        data: NurseryData,
+        writer_options: WriterOptions,
+    ) -> Result<Option<Command>> {
         // Write out nursery.data from the recovered log
         let command = if !data.is_empty() {
-            let mut data_file = log_file.as_ref().to_path_buf();
-            data_file.set_file_name("nursery.data");
+            let data_file = directory.join("nursery.data");
             let mut writer =
-                Writer::with_expected_num_items(&data_file, 1 << target_level, compression)?;
-            for (_, entry) in data.into_iter() {
+                Writer::with_expected_num_items(&data_file, 1 << target_level, writer_options)?;
+            for entry in data.into_entries() {
                 writer.add(entry)?;
             }
+            #[cfg(feature = "tracing")]
+            tracing::info!(
+                level = target_level,
+                file = %data_file.display(),
+                entries = writer.count(),
+                log_segments = log_files.len(),
+                "recovered the nursery log"
+            );
             writer.close()?;
 
             Some(Command::PromoteFile {
@@ -186,13 +697,211 @@ impl Nursery {
             None
         };
 
-        remove_file(log_file)?;
+        for log_file in log_files {
+            remove_file(log_file)?;
+        }
         Ok(command)
     }
 
     pub fn data(&self) -> &NurseryData {
         &self.data
     }
+
+    /// Returns a handle to the data as it is now, for a scan to read from.
+    pub fn shared_data(&self) -> Arc<NurseryData> {
+        self.data.clone()
+    }
+}
+
+/// Marks the start of a log segment whose records are framed.
+const FRAMED_LOG_MAGIC: &[u8; 4] = b"HLF1";
+
+/// Creates a new log segment, starting it with the header if its records are framed.
+fn create_log_segment(path: &Path, framed: bool) -> Result<File> {
+    let mut log = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(path)?;
+    write_log_header(&mut log, framed)?;
+    Ok(log)
+}
+
+fn write_log_header(log: &mut File, framed: bool) -> Result<()> {
+    if framed {
+        log.write_all(FRAMED_LOG_MAGIC)?;
+    }
+    Ok(())
+}
+
+/// Reads the header of a log segment and returns whether its records are framed,
+/// leaving `log` at the first record. Segments written without framing have no
+/// header and start straight away with an entry.
+pub(crate) fn read_log_header(log: &mut (impl Read + Seek)) -> Result<bool> {
+    let mut magic = [0; 4];
+    let framed = match log.read_exact(&mut magic) {
+        Ok(()) => &magic == FRAMED_LOG_MAGIC,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err.into()),
+    };
+    if !framed {
+        log.rewind()?;
+    }
+    Ok(framed)
+}
+
+/// Wraps an encoded entry in a frame of its length and CRC32. The frame lets
+/// recovery check where each record ends without relying on the lengths inside
+/// the entry, so a record whose entry is damaged can't be misread as a
+/// different one.
+fn frame_record(bin_entry: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(bin_entry.len() + 8);
+    // WONT PANIC: entries are checked to fit a u32 length when they're encoded
+    record.extend_from_slice(&u32::try_from(bin_entry.len()).unwrap().to_be_bytes());
+    record.extend_from_slice(&crc32fast::hash(bin_entry).to_be_bytes());
+    record.extend_from_slice(bin_entry);
+    record
+}
+
+/// Reads the next record from a log segment, unwrapping its frame if it has one.
+pub(crate) fn read_log_record(log: &mut impl Read, framed: bool) -> Result<Entry> {
+    if !framed {
+        return Entry::read(log);
+    }
+    let mut header = [0; 8];
+    log.read_exact(&mut header).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            Error::EndOfFile
+        } else {
+            err.into()
+        }
+    })?;
+    let length = u32::from_be_bytes(header[0..4].try_into()?);
+    let orig_crc = u32::from_be_bytes(header[4..8].try_into()?);
+    // Read through `take`, so that a damaged length can't make us allocate more
+    // than is left in the file
+    let mut record = vec![];
+    log.take(length as u64).read_to_end(&mut record)?;
+    if record.len() < length as usize {
+        return Err(Error::IncompleteEntry(ErrorKind::UnexpectedEof.into()));
+    }
+    if crc32fast::hash(&record) != orig_crc {
+        return Err(Error::CorruptedFile("Log record had incorrect CRC32"));
+    }
+    let mut record = record.as_slice();
+    match Entry::read(&mut record) {
+        Ok(entry) if record.is_empty() => Ok(entry),
+        Ok(_) | Err(Error::EndOfFile | Error::IncompleteEntry(_)) => Err(Error::CorruptedFile(
+            "Log record length did not match its entry",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the entries in one log segment into `data`, and returns the number of bytes
+/// of records it holds. A torn write at the end of the segment is cut off, so the
+/// segment ends at the last well-formed entry.
+fn replay_log_segment(log_file: &Path, data: &mut NurseryData) -> Result<usize> {
+    let mut file = OpenOptions::new().read(true).write(true).open(log_file)?;
+    let file_len = file.metadata()?.len();
+    let framed = read_log_header(&mut file)?;
+    let records_start = file.stream_position()?;
+    let mut valid_len = records_start;
+    loop {
+        let entry = read_log_record(&mut file, framed);
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &entry {
+            if !matches!(err, Error::EndOfFile) {
+                tracing::warn!(file = %log_file.display(), error = %err, "bad log record");
+            }
+        }
+        // A partial write only loses the rest of its own segment
+        let Ok(entry) = entry else {
+            break;
+        };
+        valid_len = file.stream_position()?;
+
+        match entry {
+            Entry::Transaction { entries } => {
+                for entry in &entries {
+                    data.apply(entry);
+                }
+            }
+            Entry::PosLen { .. } => {
+                unreachable!("nursery log contained b-tree internal entries");
+            }
+            entry => data.apply(&entry),
+        }
+    }
+
+    if valid_len < file_len {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            file = %log_file.display(),
+            bytes = file_len - valid_len,
+            "discarding the bytes after the last complete log record"
+        );
+        file.set_len(valid_len)?;
+        file.sync_data()?;
+    }
+    Ok((valid_len - records_start) as usize)
+}
+
+/// Opens a recovered log segment to go on appending to it, and returns it with its
+/// segment number and the number of bytes of records it holds. If the segment's
+/// records are framed differently than `framed`, a new segment is started after
+/// it instead, as the records of a segment must all be framed the same way.
+fn continue_log_segment(directory: &Path, path: &Path, framed: bool) -> Result<(File, u64, usize)> {
+    // WONT PANIC: log segments are only listed if their name has a segment number
+    let segment = log_segment_number(&path.file_name().unwrap().to_string_lossy()).unwrap();
+    let mut log = OpenOptions::new().read(true).append(true).open(path)?;
+    if read_log_header(&mut log)? != framed {
+        let segment = segment + 1;
+        let log = create_log_segment(&log_file_name(directory, segment), framed)?;
+        return Ok((log, segment, 0));
+    }
+    let size = log.metadata()?.len() - log.stream_position()?;
+    Ok((log, segment, size as usize))
+}
+
+/// Returns the name of a log segment. The first segment is `nursery.log`, and the
+/// ones after it are `nursery-1.log`, `nursery-2.log` and so on.
+pub(crate) fn log_file_name(directory: &Path, segment: u64) -> PathBuf {
+    match segment {
+        0 => directory.join("nursery.log"),
+        segment => directory.join(format!("nursery-{segment}.log")),
+    }
+}
+
+/// Returns the log segment number in a file name, if it is one.
+pub(crate) fn log_segment_number(name: &str) -> Option<u64> {
+    if name == "nursery.log" {
+        return Some(0);
+    }
+    let segment = name.strip_prefix("nursery-")?.strip_suffix(".log")?;
+    if segment.is_empty() || !segment.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    segment.parse().ok()
+}
+
+/// Lists the log segments in `directory`, in the order they were written.
+pub(crate) fn log_segments(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut segments = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if let Some(segment) = log_segment_number(&entry.file_name().to_string_lossy()) {
+            segments.push((segment, entry.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+impl Drop for Nursery {
+    fn drop(&mut self) {
+        // Best effort: there's no way to report a failure from here
+        let _ = self.sync();
+    }
 }
 
 #[cfg(test)]
@@ -249,6 +958,208 @@ mod tests {
         assert_eq!(0, std::fs::metadata(&log).unwrap().len());
     }
 
+    // A small log is kept in memory and appended to, rather than promoted
+    #[test]
+    fn recover_small_log_in_memory() {
+        let dir = tempdir().unwrap();
+        let log = dir.as_ref().join("nursery.log");
+        let options = NurseryOptions {
+            recover_promote_threshold: 10,
+            ..Default::default()
+        };
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, options.clone()).unwrap();
+            for i in 0..3 {
+                nursery
+                    .add(format!("key-{i}").into_bytes(), b"value".to_vec())
+                    .unwrap();
+            }
+        }
+        let log_len = std::fs::metadata(&log).unwrap().len() as usize;
+        let (mut nursery, command) = Nursery::new(&dir, MIN_LEVEL, options.clone()).unwrap();
+        assert_eq!(command, None);
+        assert!(!std::fs::exists(dir.as_ref().join("nursery.data")).unwrap());
+        assert_eq!(nursery.data.len(), 3);
+        assert_eq!(nursery.total_size, log_len);
+        assert_eq!(
+            nursery.get_value(b"key-1"),
+            Some(&Value::Plain(b"value".to_vec()))
+        );
+
+        // The log goes on from where it was
+        nursery.add(b"key-3".to_vec(), b"value".to_vec()).unwrap();
+        drop(nursery);
+        assert!(std::fs::metadata(&log).unwrap().len() as usize > log_len);
+        let (nursery, command) = Nursery::new(&dir, MIN_LEVEL, options).unwrap();
+        assert_eq!(command, None);
+        assert_eq!(nursery.data.len(), 4);
+        drop(nursery);
+
+        // Logs that are not below the threshold are promoted as before
+        let (_, command) = Nursery::new(&dir, MIN_LEVEL, Default::default()).unwrap();
+        assert!(command.is_some());
+        let tree = crate::tree::Tree::from_file(dir.as_ref().join("nursery.data")).unwrap();
+        assert_eq!(tree.entries().unwrap().count(), 4);
+    }
+
+    // A log recovered with different framing is continued in a new segment
+    #[test]
+    fn recover_in_memory_with_other_framing() {
+        let dir = tempdir().unwrap();
+        let options = NurseryOptions {
+            recover_promote_threshold: 10,
+            ..Default::default()
+        };
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, options.clone()).unwrap();
+            nursery.add(b"key-0".to_vec(), b"value".to_vec()).unwrap();
+        }
+        let framed = NurseryOptions {
+            framed_log: true,
+            ..options
+        };
+        let (mut nursery, command) = Nursery::new(&dir, MIN_LEVEL, framed.clone()).unwrap();
+        assert_eq!(command, None);
+        assert_eq!(nursery.segment, 1);
+        nursery.add(b"key-1".to_vec(), b"value".to_vec()).unwrap();
+        drop(nursery);
+        assert_eq!(log_segments(dir.as_ref()).unwrap().len(), 2);
+
+        let (nursery, _) = Nursery::new(&dir, MIN_LEVEL, framed).unwrap();
+        assert_eq!(nursery.data.len(), 2);
+    }
+
+    // A batch whose log record was torn is not recovered at all
+    #[test]
+    fn recover_torn_batch() {
+        let dir = tempdir().unwrap();
+        let recovery_data = dir.as_ref().join("nursery.data");
+        let log = dir.as_ref().join("nursery.log");
+        let batch: Vec<Entry> = (0..50)
+            .map(|i| Entry::KeyVal {
+                key: format!("batch-{i}").into_bytes(),
+                value: format!("value-{i}").into_bytes(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .collect();
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, Default::default()).unwrap();
+            nursery
+                .add("key".as_bytes().to_owned(), "value".as_bytes().to_owned())
+                .unwrap();
+            nursery.write_batch(batch.clone()).unwrap();
+        }
+        // Chop the closing TAG_END off the batch record
+        let log_len = std::fs::metadata(&log).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        file.set_len(log_len - 1).unwrap();
+        drop(file);
+
+        let (_, command) = Nursery::new(&dir, MIN_LEVEL, Default::default()).unwrap();
+        assert!(command.is_some());
+        let tree = crate::tree::Tree::from_file(&recovery_data).unwrap();
+        let keys: Vec<Vec<u8>> = tree
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().key().to_vec())
+            .collect();
+        assert_eq!(keys, vec!["key".as_bytes().to_owned()]);
+    }
+
+    // A partial entry at the end of the log is cut off, keeping the entries before it
+    #[test]
+    fn truncate_torn_entry() {
+        let dir = tempdir().unwrap();
+        let log = dir.as_ref().join("nursery.log");
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, Default::default()).unwrap();
+            nursery.add(b"key".to_vec(), b"value".to_vec()).unwrap();
+        }
+        let valid_len = std::fs::metadata(&log).unwrap().len();
+        let torn = Entry::KeyVal {
+            key: b"torn".to_vec(),
+            value: b"value".to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        }
+        .encode()
+        .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+
+        let mut data = NurseryData::default();
+        replay_log_segment(&log, &mut data).unwrap();
+        assert_eq!(data.keys().collect::<Vec<_>>(), [b"key"]);
+        assert_eq!(valid_len, std::fs::metadata(&log).unwrap().len());
+    }
+
+    // A framed record whose entry doesn't fill it is caught, and the log is cut off
+    // before it
+    #[test]
+    fn recover_mislengthed_framed_record() {
+        let dir = tempdir().unwrap();
+        let log = dir.as_ref().join("nursery.log");
+        let options = NurseryOptions {
+            framed_log: true,
+            ..Default::default()
+        };
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, options).unwrap();
+            nursery.add(b"key".to_vec(), b"value".to_vec()).unwrap();
+        }
+        let valid_len = std::fs::metadata(&log).unwrap().len();
+        let encode = |key: &[u8]| {
+            Entry::KeyVal {
+                key: key.to_vec(),
+                value: b"value".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            }
+            .encode()
+            .unwrap()
+        };
+        // Shorten the entry's own length field and fix up its CRC, so that without
+        // the frame it would read as a well-formed entry followed by garbage
+        let mut damaged = encode(b"damaged");
+        let length = u32::from_be_bytes(damaged[0..4].try_into().unwrap()) - 1;
+        damaged[0..4].copy_from_slice(&length.to_be_bytes());
+        let crc = crc32fast::hash(&damaged[8..8 + length as usize]);
+        damaged[4..8].copy_from_slice(&crc.to_be_bytes());
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&frame_record(&damaged)).unwrap();
+        file.write_all(&frame_record(&encode(b"after"))).unwrap();
+        drop(file);
+
+        let mut data = NurseryData::default();
+        replay_log_segment(&log, &mut data).unwrap();
+        assert_eq!(data.keys().collect::<Vec<_>>(), [b"key"]);
+        assert_eq!(valid_len, std::fs::metadata(&log).unwrap().len());
+    }
+
+    // Writes are only synced as often as the strategy asks
+    #[test]
+    fn sync_every_n_writes() {
+        let dir = tempdir().unwrap();
+        let (mut nursery, _) = Nursery::new(
+            &dir,
+            MIN_LEVEL,
+            NurseryOptions {
+                sync_strategy: SyncStrategy::EveryN(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for i in 0..4 {
+            let key = format!("key-{i}").into_bytes();
+            nursery.add(key, vec![]).unwrap();
+        }
+        assert_eq!(nursery.unsynced_writes, 1);
+        nursery.sync().unwrap();
+        assert_eq!(nursery.unsynced_writes, 0);
+    }
+
     // Write a KV pair and read it back
     #[test]
     fn write_and_read() {
@@ -350,7 +1261,10 @@ mod tests {
             std::fs::metadata(&data).unwrap().len(),
             "nursery data file for promotion was empty"
         );
-        assert_ne!(0, nursery.total_size);
+        assert_eq!(
+            0, nursery.total_size,
+            "log size was not reset after promotion"
+        );
         assert_eq!(
             3,
             commands.len(),
@@ -374,4 +1288,106 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn byte_budget_promotes_early() {
+        let dir = tempdir().unwrap();
+        let (mut nursery, _) = Nursery::new(
+            &dir,
+            MIN_LEVEL,
+            NurseryOptions {
+                max_bytes: Some(64 * 1024),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let value = vec![0; 10 * 1024];
+        let mut promoted_at = None;
+        for i in 0..10 {
+            let commands = nursery.add(vec![i], value.clone()).unwrap();
+            if commands
+                .iter()
+                .any(|command| matches!(command, Command::PromoteFile { .. }))
+            {
+                promoted_at = Some(i);
+                break;
+            }
+        }
+        // The seventh value takes the log past 64KiB, long before the nursery holds
+        // 1024 entries
+        assert_eq!(promoted_at, Some(6));
+        assert!(nursery.data.is_empty());
+        assert_eq!(nursery.total_size(), 0);
+    }
+
+    // Recovering a log that was split into segments replays all of them
+    #[test]
+    fn recover_log_segments() {
+        let dir = tempdir().unwrap();
+        let options = NurseryOptions {
+            log_segment_size: Some(1024),
+            ..Default::default()
+        };
+        {
+            let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, options.clone()).unwrap();
+            for i in 0..100 {
+                let key = format!("key-{i:03}").into_bytes();
+                nursery.add(key, vec![b'v'; 20]).unwrap();
+            }
+            // Overwritten in a later segment, so recovery must replay them in order
+            nursery.add(b"key-000".to_vec(), b"newer".to_vec()).unwrap();
+        }
+        let segments = log_segments(dir.as_ref()).unwrap();
+        assert!(segments.len() > 1, "log was not split into segments");
+        assert_eq!(segments[0], dir.as_ref().join("nursery.log"));
+        assert_eq!(segments[1], dir.as_ref().join("nursery-1.log"));
+
+        let (_, command) = Nursery::new(&dir, MIN_LEVEL, options).unwrap();
+        assert!(command.is_some());
+        assert_eq!(
+            log_segments(dir.as_ref()).unwrap(),
+            [dir.as_ref().join("nursery.log")],
+            "recovered segments were not removed"
+        );
+        let tree = crate::tree::Tree::from_file(dir.as_ref().join("nursery.data")).unwrap();
+        let entries: Vec<Entry> = tree.entries().unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 100);
+        assert_eq!(
+            entries[0],
+            Entry::KeyVal {
+                key: b"key-000".to_vec(),
+                value: b"newer".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            }
+        );
+        assert_eq!(entries[99].key(), b"key-099");
+    }
+
+    // Promotion removes all but the first segment
+    #[test]
+    fn promotion_removes_log_segments() {
+        let dir = tempdir().unwrap();
+        let options = NurseryOptions {
+            log_segment_size: Some(16 * 1024),
+            ..Default::default()
+        };
+        let (mut nursery, _) = Nursery::new(&dir, MIN_LEVEL, options).unwrap();
+        for i in 0..1024 {
+            let key = format!("key-{i}").into_bytes();
+            let value = format!("value-{i}").into_bytes();
+            nursery.add(key, value).unwrap();
+        }
+        assert!(nursery.data.is_empty());
+        assert_eq!(
+            log_segments(dir.as_ref()).unwrap(),
+            [dir.as_ref().join("nursery.log")]
+        );
+        assert_eq!(
+            0,
+            std::fs::metadata(dir.as_ref().join("nursery.log"))
+                .unwrap()
+                .len()
+        );
+    }
 }
@@ -1,100 +1,233 @@
-use crate::entry::Entry;
-use crate::nursery::Value;
-use crate::tree::{Tree, TreeEntryIterator};
-use crate::{error::*, nursery};
-use crate::{level::Level, nursery::Nursery};
+use crate::comparator::KeyOrder;
+use crate::entry::{now_timestamp, Entry};
+use crate::error::*;
+use crate::files::hard_link_or_copy;
+use crate::level::Level;
+use crate::nursery::{NurseryData, Value};
+use crate::tree::{take_error, Tree};
 use std::cmp::Ordering;
-use std::collections::btree_map;
+use std::io::ErrorKind;
 use std::iter::Peekable;
-use std::time::SystemTime;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+/// The order in which a scan visits keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScanDirection {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+impl ScanDirection {
+    /// Orders two keys so that the key that should be emitted first is `Less`.
+    fn compare(&self, key_order: &KeyOrder, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            ScanDirection::Forward => key_order.cmp(a, b),
+            ScanDirection::Reverse => key_order.cmp(b, a),
+        }
+    }
+
+    /// Picks the index of the key that should be emitted next. Ties go to the
+    /// lowest index, which is always the newest source: the smallest level among
+    /// levels, and the newest tree (C, then B, then A) within a level.
+    fn select(&self, key_order: &KeyOrder, keys: &[Option<&[u8]>]) -> Option<usize> {
+        keys.iter()
+            .enumerate()
+            .min_by(|a, b| {
+                let order = match (a.1, b.1) {
+                    (None, None) => Ordering::Equal,
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (Some(a), Some(b)) => self.compare(key_order, a, b),
+                };
+                order.then(a.0.cmp(&b.0))
+            })
+            .map(|(i, _)| i)
+    }
+}
 
 pub struct Scanner {
-    id: u128,
-    nursery: Peekable<btree_map::IntoIter<Vec<u8>, Value>>,
+    nursery: NurseryCursor,
     levels: Vec<Peekable<LevelScanner>>,
+    /// For each level, the range tombstones in the nursery and in the levels before
+    /// it, which shadow the entries in that level that they cover.
+    shadowing_ranges: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    direction: ScanDirection,
+    key_order: KeyOrder,
+    now: u32,
 }
 
 impl Scanner {
-    pub fn new(nursery: &Nursery, levels: &[Level]) -> Result<Self> {
-        let id = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        let levels = levels
+    pub fn new(nursery: Arc<NurseryData>, levels: &[Level]) -> Result<Self> {
+        Self::with_range(nursery, levels, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Creates a scanner that only emits keys between `start` and `end`.
+    pub fn with_range(
+        nursery: Arc<NurseryData>,
+        levels: &[Level],
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Self> {
+        Self::with_direction(nursery, levels, start, end, ScanDirection::Forward)
+    }
+
+    /// Creates a scanner that emits keys between `start` and `end` in descending order.
+    pub fn new_reverse(
+        nursery: Arc<NurseryData>,
+        levels: &[Level],
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Self> {
+        Self::with_direction(nursery, levels, start, end, ScanDirection::Reverse)
+    }
+
+    fn with_direction(
+        nursery: Arc<NurseryData>,
+        levels: &[Level],
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        direction: ScanDirection,
+    ) -> Result<Self> {
+        let id = format!(
+            "{}-{}",
+            std::process::id(),
+            NEXT_SCAN_ID.fetch_add(1, AtomicOrdering::Relaxed)
+        );
+        let key_order = nursery.key_order().clone();
+        let level_scanners = levels
             .iter()
-            .map(|level| LevelScanner::new(level, &id).map(|l| l.peekable()))
+            .map(|level| LevelScanner::new(level, &id, direction))
             .collect::<Result<Vec<_>>>()?;
+        let mut shadowing_ranges = vec![];
+        let mut ranges = nursery.ranges().to_vec();
+        for level in &level_scanners {
+            shadowing_ranges.push(ranges.clone());
+            ranges.extend(level.tree_ranges.iter().flatten().cloned());
+        }
+        let mut levels: Vec<_> = level_scanners.into_iter().map(Iterator::peekable).collect();
+        // Skip the entries in each level that sort before the first key of the range
+        let first_bound = match direction {
+            ScanDirection::Forward => &start,
+            ScanDirection::Reverse => &end,
+        };
+        let before_range = |entry: &Result<Entry>| match entry {
+            Ok(entry) => !within_bound(&key_order, direction, first_bound, entry.key(), false),
+            Err(_) => false,
+        };
+        for level in levels.iter_mut() {
+            while level.next_if(before_range).is_some() {}
+        }
+        // BTreeMap::range panics on inverted ranges, so don't hand it one
+        let done = is_empty_range(&key_order, &start, &end);
         Ok(Self {
-            id,
-            nursery: nursery.data().clone().into_iter().peekable(),
+            nursery: NurseryCursor {
+                data: nursery,
+                start: start.clone(),
+                end: end.clone(),
+                direction,
+                peeked: None,
+                done,
+            },
             levels,
+            shadowing_ranges,
+            start,
+            end,
+            direction,
+            key_order,
+            now: now_timestamp(),
         })
     }
 
-    fn consume_level_keys(&mut self, smallest_key_index: usize, key: &[u8]) {
-        for index in smallest_key_index + 1..self.levels.len() {
-            if self.levels[index].peek().map(|e| e.key()) == Some(key) {
-                let _ = self.levels[index].next();
+    /// Consumes the entries for the key of `entry` in the levels from `first_index`
+    /// on, which are older than `entry`, and returns the entry that wins among them:
+    /// the newest one, unless an older one was written later (see
+    /// `Entry::written_before`).
+    fn consume_level_keys(&mut self, first_index: usize, mut entry: Entry) -> Entry {
+        for index in first_index..self.levels.len() {
+            let key = entry.key();
+            let Some(Ok(older)) =
+                self.levels[index].next_if(|older| peeked_key(older) == Some(key))
+            else {
+                continue;
+            };
+            // A newer range tombstone deletes the older entry whatever its write
+            // timestamp
+            let deleted = self
+                .key_order
+                .covers(&self.shadowing_ranges[index], older.key());
+            if entry.written_before(&older) && !deleted {
+                entry = older;
             }
         }
+        entry
     }
-}
-
-impl Iterator for Scanner {
-    type Item = (Vec<u8>, Vec<u8>);
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_unbounded(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
         loop {
-            let keys_and_indexes: Vec<(usize, Option<&[u8]>)> = self
+            for level in self.levels.iter_mut() {
+                if let Err(err) = take_error(level) {
+                    return Some(Err(err));
+                }
+            }
+            let keys: Vec<Option<&[u8]>> = self
                 .levels
                 .iter_mut()
-                .map(|i| i.peek().map(|e| e.key()))
-                .enumerate()
+                .map(|i| i.peek().and_then(peeked_key))
                 .collect();
-            let smallest_key_index: usize = keys_and_indexes
-                .iter()
-                .min_by(|a, b| match (a.1, b.1) {
-                    (None, None) => Ordering::Equal,
-                    (Some(_), None) => Ordering::Less,
-                    (None, Some(_)) => Ordering::Greater,
-                    _ => a.1.cmp(&b.1),
-                })
-                .map(|(i, _)| *i)
-                .expect("no levels to scan");
-
-            match self.nursery.peek().cloned() {
-                Some((nursery_key, nursery_value))
-                    if keys_and_indexes[smallest_key_index]
-                        .1
-                        .map(|k| k >= &nursery_key)
+            // A database held in memory has no levels to scan
+            let next_key_index = self.direction.select(&self.key_order, &keys);
+            let next_level_key = next_key_index.and_then(|index| keys[index]);
+
+            match self.nursery.peek() {
+                Some((nursery_key, _))
+                    if next_level_key
+                        .map(|k| {
+                            self.direction.compare(&self.key_order, k, nursery_key)
+                                != Ordering::Less
+                        })
                         .unwrap_or(true) =>
                 {
-                    // consume all the iterators in the levels that are the same as the nursery key
-                    self.consume_level_keys(0, &nursery_key);
-                    match nursery_value {
-                        Value::Plain(value) => return Some((nursery_key, value)),
-                        Value::Deleted => {
-                            // key was deleted, try the next one
-                            continue;
+                    // consume the nursery entry and all the iterators in the levels
+                    // that are the same as the nursery key
+                    let (nursery_key, nursery_value) = self.nursery.next().unwrap();
+                    let entry = self.consume_level_keys(0, nursery_value.into_entry(nursery_key));
+                    match entry {
+                        Entry::KeyVal { key, value, .. } if !entry.is_expired(self.now) => {
+                            return Some(Ok((key, value)))
                         }
+                        // key was deleted or expired, try the next one
+                        _ => continue,
                     };
                 }
-                // Either the nursery was exhausted, or the levels had a smaller key
+                // Either the nursery was exhausted, or the levels had a key that comes first
                 _ => (),
             }
             // Consume the first level iterator as the return value.
-            return Some(match self.levels[smallest_key_index].next() {
-                Some(entry) if entry.is_deleted() || entry.is_key_val() => {
-                    let key = entry.key();
-
-                    self.consume_level_keys(smallest_key_index, key);
-                    if let Entry::KeyVal { key, value, .. } = entry {
-                        (key, value)
-                    } else {
-                        // Key was deleted, there's nothing to return yet, try the next one
+            let next_key_index = next_key_index?;
+            return Some(match self.levels[next_key_index].next() {
+                Some(Ok(entry)) if entry.is_deleted() || entry.is_key_val() => {
+                    let deleted = self
+                        .key_order
+                        .covers(&self.shadowing_ranges[next_key_index], entry.key());
+                    let entry = self.consume_level_keys(next_key_index + 1, entry);
+                    if deleted {
                         continue;
                     }
+                    match entry {
+                        Entry::KeyVal { key, value, .. } if !entry.is_expired(self.now) => {
+                            Ok((key, value))
+                        }
+                        // Key was deleted or expired, there's nothing to return yet,
+                        // try the next one
+                        _ => continue,
+                    }
                 }
+                Some(Err(err)) => Err(err),
                 None => return None,
                 _ => unreachable!("level iterator emitted Entry::PosLen"),
             });
@@ -102,25 +235,187 @@ impl Iterator for Scanner {
     }
 }
 
+impl Iterator for Scanner {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.next_unbounded()? {
+            Ok(key_value) => key_value,
+            Err(err) => return Some(Err(err)),
+        };
+        let last_bound = match self.direction {
+            ScanDirection::Forward => &self.end,
+            ScanDirection::Reverse => &self.start,
+        };
+        if !within_bound(&self.key_order, self.direction, last_bound, &key, true) {
+            return None;
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+/// Walks the nursery data shared with the database one key at a time, rather than
+/// copying out the range up front.
+struct NurseryCursor {
+    data: Arc<NurseryData>,
+    /// The part of the range that hasn't been visited yet.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    direction: ScanDirection,
+    peeked: Option<(Vec<u8>, Value)>,
+    done: bool,
+}
+
+impl NurseryCursor {
+    fn peek(&mut self) -> Option<&(Vec<u8>, Value)> {
+        if self.peeked.is_none() && !self.done {
+            let mut range = self.data.range((self.start.clone(), self.end.clone()));
+            let next = match self.direction {
+                ScanDirection::Forward => range.next(),
+                ScanDirection::Reverse => range.next_back(),
+            };
+            match next {
+                Some((key, value)) => {
+                    match self.direction {
+                        ScanDirection::Forward => self.start = Bound::Excluded(key.clone()),
+                        ScanDirection::Reverse => self.end = Bound::Excluded(key.clone()),
+                    }
+                    // The remaining range may now be empty, which `range` rejects
+                    self.done = is_empty_range(self.data.key_order(), &self.start, &self.end);
+                    self.peeked = Some((key.clone(), value.clone()));
+                }
+                None => self.done = true,
+            }
+        }
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<(Vec<u8>, Value)> {
+        self.peek();
+        self.peeked.take()
+    }
+}
+
+/// The key of a peeked entry, or `None` if the entry is an error.
+fn peeked_key(entry: &Result<Entry>) -> Option<&[u8]> {
+    entry.as_ref().ok().map(Entry::key)
+}
+
+/// Checks whether `key` is on the inside of `bound`. The first bound of a scan
+/// (`is_last == false`) is the start in forward scans and the end in reverse
+/// scans.
+fn within_bound(
+    key_order: &KeyOrder,
+    direction: ScanDirection,
+    bound: &Bound<Vec<u8>>,
+    key: &[u8],
+    is_last: bool,
+) -> bool {
+    let (bound, inclusive) = match bound {
+        Bound::Included(bound) => (bound.as_slice(), true),
+        Bound::Excluded(bound) => (bound.as_slice(), false),
+        Bound::Unbounded => return true,
+    };
+    let order = direction.compare(key_order, key, bound);
+    match (order, is_last) {
+        (Ordering::Equal, _) => inclusive,
+        (Ordering::Less, is_last) => is_last,
+        (Ordering::Greater, is_last) => !is_last,
+    }
+}
+
+fn is_empty_range(key_order: &KeyOrder, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => {
+            key_order.cmp(start, end) == Ordering::Greater
+        }
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => {
+            key_order.cmp(start, end) != Ordering::Less
+        }
+        _ => false,
+    }
+}
+
+/// Numbers the scans made by this process, whose links to the tree files are
+/// named `{file}.scan-{pid}-{id}`.
+static NEXT_SCAN_ID: AtomicU64 = AtomicU64::new(0);
+
 struct LevelScanner {
-    trees: Vec<Peekable<TreeEntryIterator>>,
+    trees: Vec<Peekable<Box<dyn Iterator<Item = Result<Entry>>>>>,
+    /// The range tombstones of each tree, which shadow the entries they cover in
+    /// the older trees after it.
+    tree_ranges: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+    direction: ScanDirection,
+    key_order: KeyOrder,
+    scan_trees: Vec<Tree>,
 }
 
 impl LevelScanner {
-    fn new(level: &Level, id: &u128) -> Result<Self> {
-        let mut trees = vec![];
+    fn new(level: &Level, id: &str, direction: ScanDirection) -> Result<Self> {
+        // Build the scanner up front so that the links made so far are removed if
+        // anything fails part way through.
+        let mut scanner = Self {
+            trees: vec![],
+            tree_ranges: vec![],
+            direction,
+            key_order: level.key_order(),
+            scan_trees: vec![],
+        };
         for source_file in level.tree_files().iter() {
-            let scan_file = source_file.with_extension(format!("scan-{id}"));
-            std::fs::hard_link(source_file, &scan_file)?;
-            trees.push(Tree::from_file(scan_file)?.entries()?.peekable());
+            // WONT PANIC: tree files always have a file name
+            let scan_file = level
+                .temp_dir()
+                .join(source_file.file_name().unwrap())
+                .with_extension(format!("scan-{id}"));
+            if let Err(Error::Io(err)) = hard_link_or_copy(source_file, &scan_file) {
+                if err.kind() != ErrorKind::AlreadyExists {
+                    return Err(err.into());
+                }
+                // Left behind by a process with the same pid that crashed mid-scan
+                std::fs::remove_file(&scan_file)?;
+                hard_link_or_copy(source_file, &scan_file)?;
+            }
+            let tree = match Tree::from_file(&scan_file) {
+                Ok(tree) => tree
+                    .with_file_pool(level.file_pool())
+                    .with_key_order(level.key_order())
+                    .with_counters(level.counters()),
+                Err(err) => {
+                    let _ = std::fs::remove_file(&scan_file);
+                    return Err(err);
+                }
+            };
+            let entries: Box<dyn Iterator<Item = Result<Entry>>> = match direction {
+                ScanDirection::Forward => Box::new(tree.entries()?),
+                ScanDirection::Reverse => Box::new(tree.entries_rev()?),
+            };
+            scanner.trees.push(entries.peekable());
+            scanner.tree_ranges.push(tree.range_tombstones().to_vec());
+            scanner.scan_trees.push(tree);
         }
 
-        Ok(Self { trees })
+        Ok(scanner)
+    }
+}
+
+impl Drop for LevelScanner {
+    fn drop(&mut self) {
+        // Close the trees before removing their files. Removal is best effort, the
+        // file may already be gone.
+        self.trees.clear();
+        for tree in self.scan_trees.drain(..) {
+            tree.evict();
+            let scan_file = tree.path().to_path_buf();
+            drop(tree);
+            let _ = std::fs::remove_file(scan_file);
+        }
     }
 }
 
 impl Iterator for LevelScanner {
-    type Item = Entry;
+    type Item = Result<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // If there are no trees in this level, don't do anything.
@@ -143,35 +438,66 @@ impl Iterator for LevelScanner {
         //
         // [_, A, A] => A(2); consume iterator on 3
         // (drop empty iterators?)
-        let keys_and_indexes: Vec<_> = self
+        //
+        // Reverse scans do the same, but look for the largest key instead.
+        for tree in self.trees.iter_mut() {
+            if let Err(err) = take_error(tree) {
+                return Some(Err(err));
+            }
+            // Range tombstones are applied through `tree_ranges` instead, so skip
+            // them before they can be mistaken for the key they start at
+            while tree
+                .next_if(|entry| matches!(entry, Ok(entry) if entry.is_range_deleted()))
+                .is_some()
+            {}
+        }
+        let keys: Vec<_> = self
             .trees
             .iter_mut()
-            .map(|i| i.peek().map(|e| e.key()))
-            .enumerate()
+            .map(|i| i.peek().and_then(peeked_key))
             .collect();
-        let smallest_key_index: usize = keys_and_indexes
-            .iter()
-            .min_by(|a, b| match (a.1, b.1) {
-                (None, None) => Ordering::Equal,
-                (Some(_), None) => Ordering::Less,
-                (None, Some(_)) => Ordering::Greater,
-                _ => a.1.cmp(&b.1),
-            })
-            .map(|(i, _)| *i)
+        let next_key_index: usize = self
+            .direction
+            .select(&self.key_order, &keys)
             .expect("no trees to scan in level");
 
         // Consume the first iterator as the return value.
-        Some(match self.trees[smallest_key_index].next() {
-            Some(entry) if entry.is_deleted() || entry.is_key_val() => {
-                let key = entry.key();
-                // loop through the rest of the iterators and consume this key
-                for index in smallest_key_index + 1..self.trees.len() {
-                    if self.trees[index].peek().map(|e| e.key()) == Some(key) {
-                        let _ = self.trees[index].next();
+        Some(match self.trees[next_key_index].next() {
+            Some(Ok(mut entry)) if entry.is_deleted() || entry.is_key_val() => {
+                // loop through the rest of the iterators and consume this key, keeping
+                // the newest entry unless an older one was written later (see
+                // `Entry::written_before`)
+                for index in next_key_index + 1..self.trees.len() {
+                    let key = entry.key();
+                    let Some(Ok(older)) =
+                        self.trees[index].next_if(|older| peeked_key(older) == Some(key))
+                    else {
+                        continue;
+                    };
+                    // A newer range tombstone deletes the older entry whatever its
+                    // write timestamp
+                    let deleted = self.tree_ranges[..index]
+                        .iter()
+                        .any(|ranges| self.key_order.covers(ranges, older.key()));
+                    if entry.written_before(&older) && !deleted {
+                        entry = older;
                     }
                 }
-                entry
+                let key = entry.key();
+                if self.tree_ranges[..next_key_index]
+                    .iter()
+                    .any(|ranges| self.key_order.covers(ranges, key))
+                {
+                    Ok(Entry::Deleted {
+                        key: key.to_vec(),
+                        timestamp: None,
+                        write_timestamp: None,
+                    })
+                } else {
+                    Ok(entry)
+                }
             }
+            Some(Err(err)) => Err(err),
             None => return None,
             _ => unreachable!("tree iterator emitted Entry::PosLen"),
         })
@@ -201,6 +527,7 @@ mod tests {
                     key: format!("{key}").into_bytes(),
                     value: format!("A{key}").into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 })
                 .unwrap();
         }
@@ -212,6 +539,7 @@ mod tests {
                     key: format!("{key}").into_bytes(),
                     value: format!("B{key}").into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 })
                 .unwrap();
         }
@@ -222,6 +550,7 @@ mod tests {
                 key: "1".to_owned().into_bytes(),
                 value: "C1".to_owned().into_bytes(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         c_file
@@ -229,53 +558,141 @@ mod tests {
                 key: "3".to_owned().into_bytes(),
                 value: "C3".to_owned().into_bytes(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         c_file
             .add(Entry::Deleted {
                 key: "6".to_owned().into_bytes(),
                 timestamp: None,
+                write_timestamp: None,
             })
             .unwrap();
         c_file.close().unwrap();
 
-        let level = Level::new(&dir, 10, Default::default()).unwrap();
-        let id: u128 = 123456;
-        let scanner = LevelScanner::new(&level, &id).unwrap();
+        let level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        // A link left behind by a crashed process with the same pid is replaced
+        std::fs::write(dir.as_ref().join("A-10.scan-123-456"), b"stale").unwrap();
+        let scanner = LevelScanner::new(&level, "123-456", ScanDirection::Forward).unwrap();
+        assert_eq!(scanner.scan_trees.len(), 3);
         // => C1, B2, C3, A4, B5, C6T
         assert_eq!(
-            scanner.collect::<Vec<Entry>>(),
+            scanner.collect::<Result<Vec<Entry>>>().unwrap(),
             vec![
                 Entry::KeyVal {
                     key: "1".to_owned().into_bytes(),
                     value: "C1".to_owned().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
                 Entry::KeyVal {
                     key: "2".to_string().into_bytes(),
                     value: "B2".to_string().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
                 Entry::KeyVal {
                     key: "3".to_owned().into_bytes(),
                     value: "C3".to_owned().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
                 Entry::KeyVal {
                     key: "4".to_string().into_bytes(),
                     value: "A4".to_string().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
                 Entry::KeyVal {
                     key: "5".to_string().into_bytes(),
                     value: "B5".to_string().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
                 Entry::Deleted {
                     key: "6".to_owned().into_bytes(),
                     timestamp: None,
+                    write_timestamp: None,
                 },
             ]
         )
     }
+
+    // Of the entries for a key, the one with the latest write timestamp wins,
+    // unless a newer range tombstone deletes it
+    #[test]
+    fn level_scanner_write_timestamps() {
+        let dir = tempdir().unwrap();
+        // A - 1@300 2@300
+        // B - 1@200 2@200 [2, 3)
+        // C - 1@100 2@100
+        //
+        // => A1, B2
+        for (prefix, write_timestamp) in [("A", 300), ("B", 200), ("C", 100)] {
+            let mut file = Writer::new(dir.path().join(format!("{prefix}-10.data"))).unwrap();
+            for key in ["1", "2"] {
+                if prefix == "B" && key == "2" {
+                    file.add(Entry::RangeDeleted {
+                        start: b"2".to_vec(),
+                        end: b"3".to_vec(),
+                        timestamp: None,
+                    })
+                    .unwrap();
+                }
+                file.add(Entry::KeyVal {
+                    key: key.as_bytes().to_vec(),
+                    value: format!("{prefix}{key}").into_bytes(),
+                    timestamp: None,
+                    write_timestamp: Some(write_timestamp),
+                })
+                .unwrap();
+            }
+            file.close().unwrap();
+        }
+
+        let level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        let scanner = LevelScanner::new(&level, "123-789", ScanDirection::Forward).unwrap();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = scanner
+            .map(|entry| match entry.unwrap() {
+                Entry::KeyVal { key, value, .. } => (key, value),
+                entry => panic!("unexpected entry {entry:?}"),
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"1".to_vec(), b"A1".to_vec()),
+                (b"2".to_vec(), b"B2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_files_are_removed() {
+        let dir = tempdir().unwrap();
+        for prefix in ["A", "B"] {
+            let mut file = Writer::new(dir.path().join(format!("{prefix}-10.data"))).unwrap();
+            file.add(Entry::KeyVal {
+                key: prefix.as_bytes().to_vec(),
+                value: prefix.as_bytes().to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+            file.close().unwrap();
+        }
+        let level = Level::new(&dir, 10, Default::default(), None).unwrap();
+        let scanner = Scanner::new(Default::default(), &[level]).unwrap();
+        assert_eq!(scanner.count(), 2);
+
+        let leftovers: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.contains("scan-"))
+            .collect();
+        assert!(
+            leftovers.is_empty(),
+            "scan files were left behind: {leftovers:?}"
+        );
+    }
 }
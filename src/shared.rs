@@ -0,0 +1,80 @@
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::db::HanoiDB;
+use crate::error::*;
+
+/// A handle to a database that can be cloned and shared between threads.
+///
+/// Reads take a shared lock, so any number of them can run at the same time. Writes
+/// take the lock exclusively, but only for the write itself: merges that the write
+/// triggers run under the same lock unless background merges are enabled (see
+/// `OpenOptions::with_background_merge`), in which case readers are only held up
+/// while a write updates the nursery or promotes it into the first level.
+#[derive(Clone)]
+pub struct SharedHanoiDB(Arc<RwLock<HanoiDB>>);
+
+impl SharedHanoiDB {
+    pub fn new(db: HanoiDB) -> Self {
+        Self(Arc::new(RwLock::new(db)))
+    }
+
+    /// Looks up a key in the database and returns its value if it is present.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read().get(key)
+    }
+
+    /// Scans the keys and values in the database that fall between `from` and `to`,
+    /// in ascending key order. The lock is only held while the scan is set up, so
+    /// writes can proceed while the returned iterator is consumed.
+    pub fn range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        self.read().range(from, to)
+    }
+
+    /// Inserts a key-value pair into the database.
+    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write().insert(key, value)
+    }
+
+    /// Deletes a key from the database.
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        self.write().delete(key)
+    }
+
+    /// Locks the database for reading, for operations that have no shortcut here.
+    pub fn read(&self) -> RwLockReadGuard<'_, HanoiDB> {
+        // WONT PANIC: the lock is only poisoned if a write panicked, which leaves
+        // the database in an unknown state
+        self.0.read().expect("database lock poisoned")
+    }
+
+    /// Locks the database for writing, for operations that have no shortcut here.
+    pub fn write(&self) -> RwLockWriteGuard<'_, HanoiDB> {
+        // WONT PANIC: see `read`
+        self.0.write().expect("database lock poisoned")
+    }
+}
+
+impl From<HanoiDB> for SharedHanoiDB {
+    fn from(db: HanoiDB) -> Self {
+        Self::new(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn handles_can_be_shared_between_threads() {
+        assert_send::<HanoiDB>();
+        assert_send_sync::<SharedHanoiDB>();
+    }
+}
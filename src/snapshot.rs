@@ -0,0 +1,123 @@
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::db::lookup;
+use crate::error::*;
+use crate::files::hard_link_or_copy;
+use crate::level::Level;
+use crate::nursery::NurseryData;
+use crate::scan::Scanner;
+use crate::writer::WriterOptions;
+
+/// Numbers the snapshots taken by this process, whose directories are named
+/// `snapshot-{pid}-{id}`.
+static NEXT_SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Checks whether a file name is that of a snapshot's directory. Snapshots used to
+/// be named `snapshot-{nanos}`, which is matched too.
+pub(crate) fn is_snapshot_dir(name: &str) -> bool {
+    name.strip_prefix("snapshot-").is_some_and(|id| {
+        id.split('-')
+            .all(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+    })
+}
+
+/// A read-only view of a database at a point in time. The level files are
+/// hard-linked into a private directory so that merges in the database can't remove
+/// them (or copied, if the database's temp dir is on another filesystem), and the
+/// nursery is shared, to be copied by the database's next write. The links are
+/// removed when the snapshot is dropped.
+pub struct Snapshot {
+    /// `None` for a database held in memory, which has no level files to link.
+    path: Option<PathBuf>,
+    nursery: Arc<NurseryData>,
+    levels: Vec<Level>,
+}
+
+impl Snapshot {
+    /// Creates a snapshot, in a new directory in `dir`.
+    pub(crate) fn new(
+        dir: impl AsRef<Path>,
+        nursery: Arc<NurseryData>,
+        levels: &[Level],
+    ) -> Result<Self> {
+        if levels.is_empty() {
+            return Ok(Self {
+                path: None,
+                nursery,
+                levels: vec![],
+            });
+        }
+        // Skip over the directories of snapshots of another process that had the same
+        // pid, which it may have left behind if it crashed
+        let path = loop {
+            let id = NEXT_SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
+            let path = dir
+                .as_ref()
+                .join(format!("snapshot-{}-{id}", std::process::id()));
+            match std::fs::create_dir(&path) {
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                result => result?,
+            }
+            break path;
+        };
+        // Build the snapshot before linking, so a failure part way through cleans up
+        // the directory when it is dropped.
+        let mut snapshot = Self {
+            path: Some(path.clone()),
+            nursery,
+            levels: vec![],
+        };
+        for level in levels {
+            for file in level.tree_files() {
+                // WONT PANIC: tree files always have a file name
+                hard_link_or_copy(&file, &path.join(file.file_name().unwrap()))?;
+            }
+            snapshot.levels.push(Level::new(
+                &path,
+                level.level(),
+                WriterOptions {
+                    key_order: level.key_order(),
+                    counters: level.counters(),
+                    ..Default::default()
+                },
+                None,
+            )?);
+        }
+        Ok(snapshot)
+    }
+
+    /// Looks up a key in the snapshot and returns its value if it is present.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        lookup(&self.nursery, &self.levels, key)
+    }
+
+    /// Scans all keys and values in the snapshot. Reading a corrupted tree file
+    /// yields an error item; the scan should not be continued after that.
+    pub fn scan(&self) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Scanner::new(self.nursery.clone(), &self.levels)
+    }
+
+    /// Scans the keys and values in the snapshot that fall between `from` and `to`,
+    /// in ascending key order.
+    pub fn range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Scanner::with_range(self.nursery.clone(), &self.levels, from, to)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // Close the trees before removing their files. This is best effort, there's
+        // no way to report a failure from here.
+        self.levels.clear();
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_dir_all(path);
+        }
+    }
+}
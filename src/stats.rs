@@ -0,0 +1,135 @@
+use std::path::PathBuf;
+
+use fastbloom::BloomFilter;
+
+/// The shape of a database at the time `HanoiDB::stats` was called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbStats {
+    /// The number of entries in the nursery, including tombstones.
+    pub nursery_entries: usize,
+    /// The number of bytes written to the nursery log since it was last flushed.
+    pub nursery_bytes: usize,
+    /// The levels, from the smallest to the largest.
+    pub levels: Vec<LevelStats>,
+}
+
+/// The files of a single level. The sizes are the sizes of the tree files on disk,
+/// or `None` if the level doesn't hold that file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelStats {
+    pub level: u32,
+    pub a: Option<u64>,
+    pub b: Option<u64>,
+    pub c: Option<u64>,
+    /// Whether a merge of `A` and `B` is in progress.
+    pub merging: bool,
+}
+
+impl LevelStats {
+    /// Returns the number of tree files in the level.
+    pub fn file_count(&self) -> usize {
+        [self.a, self.b, self.c].into_iter().flatten().count()
+    }
+
+    /// Returns the total size of the tree files in the level.
+    pub fn total_size(&self) -> u64 {
+        [self.a, self.b, self.c].into_iter().flatten().sum()
+    }
+}
+
+/// The size of a level and how full it is, see `HanoiDB::level_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelInfo {
+    pub level: u32,
+    /// The number of entries a file of the level is sized for. Each level holds up
+    /// to three such files, and its files are merged into the next level once they
+    /// hold more.
+    pub capacity: usize,
+    /// The number of tree files in the level.
+    pub files: usize,
+    /// The number of values recorded in the level's files. Tombstones are left out.
+    pub entries: u64,
+}
+
+/// The merges that `HanoiDB::compact` would run, see `HanoiDB::plan_compaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionPlan {
+    /// The levels, from the smallest to the largest.
+    pub levels: Vec<LevelPlan>,
+}
+
+impl CompactionPlan {
+    /// Returns the number of merges across all the levels.
+    pub fn total_merges(&self) -> usize {
+        self.levels.iter().map(|level| level.merges).sum()
+    }
+
+    /// Returns the number of entries still to be read by the merges across all the
+    /// levels.
+    pub fn total_steps(&self) -> u64 {
+        self.levels.iter().map(|level| level.steps).sum()
+    }
+}
+
+/// The merges that compacting would run in a single level. Counts are estimated
+/// from the number of values recorded in each file, so tombstones are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelPlan {
+    pub level: u32,
+    /// The number of merges of two files, including one that is already running.
+    pub merges: usize,
+    /// The number of entries still to be read by the merges, which is what
+    /// `MergeEvent::steps` counts.
+    pub steps: u64,
+    /// The number of entries written by the merges. Keys held by both files of a
+    /// merge are only written once, so this is an upper bound.
+    pub output_entries: u64,
+}
+
+/// The bloom filters of the tree files of a database, see `HanoiDB::bloom_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomStats {
+    /// The false positive rate that new tree files are written for, see
+    /// `OpenOptions::with_bloom_fp_rate`.
+    pub fp_rate: f64,
+    /// The tree files, from the smallest level to the largest, and from the newest
+    /// file to the oldest within a level.
+    pub trees: Vec<TreeBloomStats>,
+}
+
+/// The bloom filter of a single tree file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeBloomStats {
+    pub level: u32,
+    pub path: PathBuf,
+    pub num_bits: u64,
+    pub num_hashes: u32,
+    /// The number of keys in the filter, estimated from how many of its bits are set.
+    pub estimated_items: u64,
+    /// The false positive rate of the filter with the keys it holds. This is above
+    /// the rate the filter was sized for if the file holds more keys than expected.
+    pub estimated_fp_rate: f64,
+}
+
+impl TreeBloomStats {
+    pub(crate) fn new(level: u32, path: PathBuf, bloom: &BloomFilter) -> Self {
+        let num_bits = bloom.num_bits() as f64;
+        let num_hashes = bloom.num_hashes() as f64;
+        let set_bits: u64 = bloom
+            .as_slice()
+            .iter()
+            .map(|word| word.count_ones() as u64)
+            .sum();
+        let fill = (set_bits as f64 / num_bits).min(1.0);
+        // Swamidass and Baldi's estimate of the number of items in a bloom filter
+        let estimated_items = -(num_bits / num_hashes) * (1.0 - fill).ln();
+        Self {
+            level,
+            path,
+            num_bits: bloom.num_bits() as u64,
+            num_hashes: bloom.num_hashes(),
+            estimated_items: estimated_items.round() as u64,
+            estimated_fp_rate: fill.powf(num_hashes),
+        }
+    }
+}
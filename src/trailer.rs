@@ -2,43 +2,69 @@ use crate::error::*;
 
 use fastbloom::BloomFilter;
 
+/// The trailer at the end of every tree file:
+/// `<<0:32, bloom/binary, bloom_len:32, value_count:64, root_pos:64>>`
+///
+/// Files with the `HAN3` magic were written before the value count was added, and
+/// their trailers end with `<<bloom_len:32, root_pos:64>>`.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Trailer {
     pub bloom: BloomFilter,
+    /// The number of values in the file, or `None` for a `HAN3` file.
+    pub value_count: Option<u64>,
     pub root_pos: u64,
 }
 
 impl Trailer {
-    pub fn with_bloom_filter(bloom: BloomFilter, root_pos: u64) -> Self {
-        Self { bloom, root_pos }
+    /// The size of the fixed-length fields at the end of the trailer.
+    pub const FIXED_SIZE: usize = 4 + 8 + 8;
+    /// The size of the fixed-length fields at the end of a `HAN3` file's trailer.
+    pub const HAN3_FIXED_SIZE: usize = 4 + 8;
+
+    pub fn with_bloom_filter(bloom: BloomFilter, value_count: u64, root_pos: u64) -> Self {
+        Self {
+            bloom,
+            value_count: Some(value_count),
+            root_pos,
+        }
     }
 
-    pub fn new(raw_bloom: Vec<u8>, root_pos: u64) -> Result<Self> {
+    pub fn new(raw_bloom: Vec<u8>, value_count: Option<u64>, root_pos: u64) -> Result<Self> {
         // Bloom filter is too big for our file format
         if raw_bloom.len() > u32::MAX as usize {
             return Err(Error::BloomFilterTooLarge);
         }
 
-        // The file is empty, so the bloom filter was written as 0-length
+        // The file is empty, so the bloom filter was written as 0-length. Nothing is
+        // ever added to this filter, so the false positive rate it was configured
+        // with doesn't matter.
         if raw_bloom.is_empty() {
             return Ok(Self {
                 bloom: BloomFilter::with_false_pos(0.01).expected_items(1024),
+                value_count,
                 root_pos,
             });
         }
 
+        // The hasher's seed is encoded with the filter, so it needs no configuring
         let bloom: BloomFilter = postcard::from_bytes(&raw_bloom)?;
 
-        Ok(Self::with_bloom_filter(bloom, root_pos))
+        Ok(Self {
+            bloom,
+            value_count,
+            root_pos,
+        })
     }
 
     pub fn encode(&self) -> Result<Vec<u8>> {
         let raw_bloom: Vec<u8> = postcard::to_stdvec(&self.bloom)?;
 
-        let mut buffer = Vec::with_capacity(raw_bloom.len() + 12);
+        let mut buffer = Vec::with_capacity(4 + raw_bloom.len() + Self::FIXED_SIZE);
         buffer.extend([0, 0, 0, 0]);
         buffer.extend(&raw_bloom);
         buffer.extend((raw_bloom.len() as u32).to_be_bytes());
+        // Only new files are written, and their values are always counted
+        buffer.extend(self.value_count.unwrap_or(0).to_be_bytes());
         buffer.extend(self.root_pos.to_be_bytes());
         Ok(buffer)
     }
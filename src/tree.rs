@@ -1,58 +1,266 @@
 use crate::block::{Block, EntryIterator};
-use crate::entry::Entry;
+use crate::cache::{next_file_id, BlockCache, BlockEntries};
+use crate::comparator::KeyOrder;
+use crate::counters::Counters;
+use crate::entry::{now_timestamp, Entry, EntryStart};
 use crate::error::*;
+use crate::file_pool::{FilePool, TreeFile};
 use crate::trailer::Trailer;
-use crate::MAGIC;
+use crate::writer::{DEFAULT_BLOOM_FP_RATE, DEFAULT_BLOOM_SEED, FIRST_BLOCK_POS};
+use crate::{HAN3_MAGIC, LEGACY_MAGIC, MAGIC};
+use fastbloom::BloomFilter;
+use std::cmp::Ordering;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Take};
+use std::iter::Peekable;
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+/// A single tree (`.data`) file. Besides being the files that make up the levels
+/// of a database, a tree can be opened on its own with `Tree::open`, as an
+/// immutable sorted map.
 pub struct Tree {
-    file: File,
+    file: TreeFile,
+    path: PathBuf,
+    file_size: u64,
     trailer: Trailer,
+    id: u64,
+    block_cache: Option<Arc<BlockCache>>,
+    /// The `start..end` ranges of the range tombstones in this tree.
+    range_tombstones: Arc<Vec<(Vec<u8>, Vec<u8>)>>,
+    key_order: KeyOrder,
+    counters: Counters,
+    /// The number of bytes that the lengths in `PosLen` entries count on top of the
+    /// `blocklen` of the block they point at, see `from_legacy_file`.
+    poslen_overhead: u32,
 }
 
 impl Tree {
+    /// Opens a tree file on its own, for reading with `get` and `range`. The file
+    /// must not be changed or removed while it is open, so it shouldn't be a file
+    /// of an open database.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_file(path)
+    }
+
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let mut file = File::open(path)?;
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
         let len = file.metadata()?.len();
+        // magic: 4, trailer padding: 4
+        if len < (4 + 4 + Trailer::HAN3_FIXED_SIZE) as u64 {
+            return Err(Error::CorruptedFile("file too short for trailer"));
+        }
         let mut magic: Vec<u8> = vec![0; 4];
         file.read_exact(&mut magic)?;
-        if magic == MAGIC.as_bytes() {
-            let trailer = Self::read_trailer(&file, len)?;
-            Ok(Self { file, trailer })
+        let counted = magic == MAGIC.as_bytes();
+        if counted || magic == HAN3_MAGIC.as_bytes() {
+            let (trailer, trailer_pos) = Self::read_trailer(&file, len, counted)?;
+            let file = TreeFile::from(file);
+            let range_tombstones =
+                Self::read_range_tombstones(&file, trailer.root_pos, trailer_pos)?;
+            Ok(Self {
+                file,
+                path,
+                file_size: len,
+                trailer,
+                id: next_file_id(),
+                block_cache: None,
+                range_tombstones: Arc::new(range_tombstones),
+                key_order: KeyOrder::default(),
+                counters: Counters::default(),
+                poslen_overhead: 0,
+            })
         } else {
             Err(Error::InvalidTreeFormat(magic))
         }
     }
 
+    /// Opens a tree file written by the original Erlang hanoidb (magic `HAN2`), for
+    /// migrating its data, for example into a database with `HanoiDB::ingest_sorted`.
+    /// The blocks and entries are laid out as in this crate's files, so the leaf
+    /// entries are read as `Entry::KeyVal` and `Entry::Deleted`, with their expiry
+    /// timestamps, but:
+    ///
+    /// - The lengths in the Erlang `PosLen` entries include the 4 byte `blocklen`
+    ///   field of the block, and are adjusted for when reading.
+    /// - The Erlang bloom filter is an Erlang term, which can't be read. A new one is
+    ///   built by reading every key when the file is opened.
+    /// - Erlang hanoidb wrote snappy and lz4 blocks in other formats than this crate
+    ///   does, so only uncompressed and gzip blocks can be read.
+    ///
+    /// Only single tree files can be opened this way, not the directory of an Erlang
+    /// database, and its nursery log can't be read.
+    pub fn from_legacy_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        // magic: 4, trailer padding: 4, bloom_len: 4, root_pos: 8
+        if len < 4 + 4 + 4 + 8 {
+            return Err(Error::CorruptedFile("file too short for trailer"));
+        }
+        let mut magic: Vec<u8> = vec![0; 4];
+        file.read_exact(&mut magic)?;
+        if magic != LEGACY_MAGIC.as_bytes() {
+            return Err(Error::InvalidTreeFormat(magic));
+        }
+        file.seek(SeekFrom::End(-12))?;
+        let mut buffer = vec![0; 12];
+        file.read_exact(&mut buffer)?;
+        let bloom_len = u32::from_be_bytes(buffer[0..4].try_into()?) as u64;
+        let root_pos = u64::from_be_bytes(buffer[4..12].try_into()?);
+        if len < 4 + 4 + bloom_len + 12 {
+            return Err(Error::CorruptedFile("file too short for bloom filter"));
+        }
+        if root_pos >= len {
+            return Err(Error::CorruptedFile(
+                "root block position outside bounds of file",
+            ));
+        }
+        let placeholder = BloomFilter::with_false_pos(DEFAULT_BLOOM_FP_RATE).expected_items(1);
+        let mut tree = Self {
+            file: TreeFile::from(file),
+            path,
+            file_size: len,
+            trailer: Trailer::with_bloom_filter(placeholder, 0, root_pos),
+            id: next_file_id(),
+            block_cache: None,
+            range_tombstones: Default::default(),
+            key_order: KeyOrder::default(),
+            counters: Counters::default(),
+            poslen_overhead: 4,
+        };
+
+        // Size the new bloom filter for the keys in the file, then fill it
+        let mut key_count = 0;
+        let mut value_count = 0;
+        for entry in tree.entries()? {
+            let entry = entry?;
+            key_count += 1;
+            value_count += u64::from(entry.is_key_val());
+        }
+        let mut bloom = BloomFilter::with_false_pos(DEFAULT_BLOOM_FP_RATE)
+            .seed(&DEFAULT_BLOOM_SEED)
+            .expected_items(key_count.max(1));
+        for entry in tree.entries()? {
+            bloom.insert(entry?.key());
+        }
+        tree.trailer = Trailer::with_bloom_filter(bloom, value_count, root_pos);
+        Ok(tree)
+    }
+
+    /// Returns another handle to the tree. The file itself is shared, not reopened.
     pub fn try_clone(&self) -> Result<Self> {
-        let file = self.file.try_clone()?;
+        let file = self.file.clone();
         let trailer = self.trailer.clone();
-        Ok(Self { file, trailer })
+        Ok(Self {
+            file,
+            path: self.path.clone(),
+            file_size: self.file_size,
+            trailer,
+            id: self.id,
+            block_cache: self.block_cache.clone(),
+            range_tombstones: self.range_tombstones.clone(),
+            key_order: self.key_order.clone(),
+            counters: self.counters.clone(),
+            poslen_overhead: self.poslen_overhead,
+        })
+    }
+
+    /// Opens the file through `file_pool` for each read, if there is one, instead of
+    /// holding it open.
+    pub(crate) fn with_file_pool(mut self, file_pool: Option<Arc<FilePool>>) -> Self {
+        if let Some(pool) = file_pool {
+            self.file = TreeFile::Pooled {
+                pool,
+                id: self.id,
+                path: self.path.clone(),
+            };
+        }
+        self
+    }
+
+    /// Closes the file if it's held open by a file pool, for when the file is about
+    /// to be removed.
+    pub(crate) fn evict(&self) {
+        if let TreeFile::Pooled { pool, id, .. } = &self.file {
+            pool.remove(*id);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Looks keys up in `key_order`, which must be the order the tree was written in.
+    pub(crate) fn with_key_order(mut self, key_order: KeyOrder) -> Self {
+        self.key_order = key_order;
+        self
     }
 
-    pub fn root_block(&self) -> Result<Block<'_>> {
-        Block::from_start(&self.file, self.trailer.root_pos)
+    /// Caches the blocks read by lookups in this tree in `block_cache`.
+    pub(crate) fn with_block_cache(mut self, block_cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = block_cache;
+        self
     }
 
-    pub fn block_from_poslen_entry(&self, entry: &Entry) -> Result<Block<'_>> {
+    /// Counts the blocks read from this tree in `counters`.
+    pub(crate) fn with_counters(mut self, counters: Counters) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    pub(crate) fn root_block(&self) -> Result<Block> {
+        self.read_block(self.trailer.root_pos, None)
+    }
+
+    pub(crate) fn block_from_poslen_entry(&self, entry: &Entry) -> Result<Block> {
         let Entry::PosLen {
             blockpos, blocklen, ..
         } = entry
         else {
             return Err(Error::PosLenEntryRequired);
         };
-        Block::from_start_length(&self.file, *blockpos, *blocklen)
+        self.read_block(*blockpos, Some(*blocklen))
     }
 
-    fn read_trailer(mut file: &File, len: u64) -> Result<Trailer> {
-        file.seek(SeekFrom::End(-12))?; // bloom_len: 4, root_pos: 8
-        let mut buffer = vec![0; 12];
+    /// Reads the header of the block at `pos`. `length` is the expected block
+    /// length, if it is known.
+    fn read_block(&self, pos: u64, length: Option<u32>) -> Result<Block> {
+        let block = match length {
+            Some(length) => {
+                let length = length.saturating_sub(self.poslen_overhead);
+                Block::from_start_length(&self.file, pos, length)?
+            }
+            None => Block::from_start(&self.file, pos)?,
+        };
+        Ok(block.with_counters(self.counters.clone()))
+    }
+
+    /// Reads the trailer, and returns it along with the position where it starts.
+    /// Only `counted` trailers hold a value count, see `Trailer`.
+    fn read_trailer(mut file: &File, len: u64, counted: bool) -> Result<(Trailer, u64)> {
+        // bloom_len: 4, value_count: 8 if counted, root_pos: 8
+        let fixed_size = if counted {
+            Trailer::FIXED_SIZE
+        } else {
+            Trailer::HAN3_FIXED_SIZE
+        };
+        file.seek(SeekFrom::End(-(fixed_size as i64)))?;
+        let mut buffer = vec![0; fixed_size];
         file.read_exact(&mut buffer)?;
-        let root_pos = u64::from_be_bytes(buffer[4..].try_into()?);
         let bloom_len = u32::from_be_bytes(buffer[0..4].try_into()?);
-        let bloom_start = bloom_len as i64 + 12;
+        let value_count = if counted {
+            Some(u64::from_be_bytes(buffer[4..12].try_into()?))
+        } else {
+            None
+        };
+        let root_pos = u64::from_be_bytes(buffer[fixed_size - 8..].try_into()?);
+        let bloom_start = bloom_len as i64 + fixed_size as i64;
+        if (len as i64) < 4 + 4 + bloom_start {
+            return Err(Error::CorruptedFile("file too short for bloom filter"));
+        }
         file.seek(SeekFrom::End(-bloom_start - 4))?;
         let mut padding = vec![0; 4];
         file.read_exact(&mut padding)?;
@@ -66,45 +274,488 @@ impl Tree {
                 "root block position outside bounds of file",
             ));
         }
-        Trailer::new(bloom, root_pos)
+        let trailer_pos = len - bloom_start as u64 - 4;
+        Ok((Trailer::new(bloom, value_count, root_pos)?, trailer_pos))
+    }
+
+    /// Reads the copies of the range tombstones that are written between the root
+    /// block and the trailer. Files without range tombstones have nothing there.
+    fn read_range_tombstones(
+        file: &TreeFile,
+        root_pos: u64,
+        trailer_pos: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let root = Block::from_start(file, root_pos)?;
+        // An empty tree's root is a bare 6 byte header
+        let root_end = match root.blocklen {
+            0 => root_pos + 6,
+            blocklen => root_pos + 4 + blocklen as u64,
+        };
+        if root_end >= trailer_pos {
+            return Ok(vec![]);
+        }
+        let mut section = vec![0; (trailer_pos - root_end) as usize];
+        let file = file.get()?;
+        let mut file = file.as_ref();
+        file.seek(SeekFrom::Start(root_end))?;
+        file.read_exact(&mut section)?;
+        let mut section = section.as_slice();
+        let mut range_tombstones = vec![];
+        while !section.is_empty() {
+            match Entry::read(&mut section)? {
+                Entry::RangeDeleted { start, end, .. } => range_tombstones.push((start, end)),
+                _ => {
+                    return Err(Error::CorruptedFile(
+                        "unexpected entry after the root block",
+                    ))
+                }
+            }
+        }
+        Ok(range_tombstones)
+    }
+
+    /// Returns the `start..end` ranges of the range tombstones in this tree.
+    pub fn range_tombstones(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.range_tombstones
+    }
+
+    /// Checks whether a range tombstone in this tree covers the key.
+    pub fn range_deleted(&self, key: &[u8]) -> bool {
+        self.key_order.covers(&self.range_tombstones, key)
+    }
+
+    /// Returns the size of the tree file in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the number of values (not tombstones) written to this tree, or `None`
+    /// if the file was written before the count was recorded (magic `HAN3`).
+    pub fn value_count(&self) -> Option<u64> {
+        self.trailer.value_count
     }
 
     pub fn entries(&self) -> Result<TreeEntryIterator> {
         TreeEntryIterator::new(self.try_clone()?)
     }
 
+    pub fn entries_rev(&self) -> Result<ReverseTreeEntryIterator> {
+        ReverseTreeEntryIterator::new(self.try_clone()?)
+    }
+
+    /// Walks every block in the file in the order they were written, from the
+    /// first leaf to the root, and yields all their entries. Unlike `entries`, this
+    /// includes the `PosLen` entries of the inner blocks, so it shows the structure
+    /// of the tree.
+    pub fn dump_entries(&self) -> Result<DumpEntryIterator> {
+        Ok(DumpEntryIterator {
+            tree: self.try_clone()?,
+            next_pos: Some(FIRST_BLOCK_POS),
+            block: None,
+        })
+    }
+
+    /// Checks the bloom filter and the range tombstones for the key. A `false`
+    /// result means the key is definitely not in this tree, but `true` may be a
+    /// false positive.
+    pub fn might_contain(&self, key: &[u8]) -> bool {
+        self.trailer.bloom.contains(key) || self.range_deleted(key)
+    }
+
+    pub fn bloom(&self) -> &BloomFilter {
+        &self.trailer.bloom
+    }
+
+    /// Looks up a key in the tree and returns its value, if it is present and has
+    /// not expired.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entry = self.get_entry(key)?;
+        Ok(entry.and_then(|entry| entry.into_live_value(now_timestamp())))
+    }
+
+    /// Scans the keys and values in the tree that fall between `from` and `to`, in
+    /// ascending key order. Deleted and expired keys are left out. Reading a
+    /// corrupted block yields an error item, and ends the scan.
+    pub fn range(
+        &self,
+        from: Bound<Vec<u8>>,
+        to: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        let now = now_timestamp();
+        let key_order = self.key_order.clone();
+        let range_tombstones = self.range_tombstones.clone();
+        let before_from = {
+            let key_order = key_order.clone();
+            move |key: &[u8]| match &from {
+                Bound::Included(from) => key_order.cmp(key, from) == Ordering::Less,
+                Bound::Excluded(from) => key_order.cmp(key, from) != Ordering::Greater,
+                Bound::Unbounded => false,
+            }
+        };
+        let before_to = {
+            let key_order = key_order.clone();
+            move |key: &[u8]| match &to {
+                Bound::Included(to) => key_order.cmp(key, to) != Ordering::Greater,
+                Bound::Excluded(to) => key_order.cmp(key, to) == Ordering::Less,
+                Bound::Unbounded => true,
+            }
+        };
+        let mut failed = false;
+        Ok(self
+            .entries()?
+            .skip_while(move |entry| matches!(entry, Ok(entry) if before_from(entry.key())))
+            .take_while(move |entry| {
+                // Stop after the first error, the rest of the file can't be trusted
+                let more = !failed && entry.as_ref().map_or(true, |entry| before_to(entry.key()));
+                failed = entry.is_err();
+                more
+            })
+            .filter_map(move |entry| match entry {
+                Ok(Entry::KeyVal {
+                    key,
+                    value,
+                    timestamp,
+                    ..
+                }) => {
+                    let expired = timestamp.is_some_and(|expiry| expiry <= now);
+                    let deleted = key_order.covers(&range_tombstones, &key);
+                    (!expired && !deleted).then_some(Ok((key, value)))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            }))
+    }
+
+    /// Looks up a key. A key that is only covered by one of the tree's range
+    /// tombstones is returned as an `Entry::Deleted`.
     pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if self.trailer.bloom.contains(key) {
+            let root = self.load_block(self.trailer.root_pos, None)?;
+            if let Some(entry) = self.find_entry(&root, key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(self.range_deleted_entry(key))
+    }
+
+    /// Looks up a key like `get_entry`, but returns a reader over its value instead
+    /// of reading the whole value into memory. Only the inner blocks on the way to
+    /// the leaf go through the block cache. Returns `None` if the key isn't in the
+    /// tree or is deleted in it.
+    pub fn value_reader(&self, key: &[u8]) -> Result<Option<ValueReader>> {
         if !self.trailer.bloom.contains(key) {
             return Ok(None);
         }
-
-        let mut block = self.root_block()?;
+        let Some(leaf) = self.find_leaf(self.trailer.root_pos, None, key)? else {
+            return Ok(None);
+        };
+        let mut entries = leaf.entries()?;
         loop {
-            // level > 0 -> inner block
-            // level == 0 -> leaf block
-            if block.level > 0 {
-                let entry = block
-                    .entries()?
-                    .take_while(|e| {
-                        !matches!(e, Entry::PosLen {
-                            key: first_key,
-                            ..
-                        } if key < first_key)
-                    })
-                    .last();
-                if let Some(inner_entry) = entry {
-                    // Go to the next lower level in the tree
-                    block = self.block_from_poslen_entry(&inner_entry)?;
-                } else {
+            let entry = match entries.read_until_value() {
+                Ok(entry) => entry,
+                Err(Error::EndOfFile) => return Ok(None),
+                Err(err) => return Err(corrupted_block(leaf.start, err)),
+            };
+            match entry {
+                EntryStart::KeyVal {
+                    key: entry_key,
+                    timestamp,
+                    write_timestamp,
+                    value_len,
+                } if entry_key == key => {
+                    return Ok(Some(ValueReader {
+                        value: entries.into_contents()?.take(value_len),
+                        timestamp,
+                        write_timestamp,
+                    }));
+                }
+                EntryStart::KeyVal { value_len, .. } => {
+                    // Skip the value and its TAG_END
+                    entries.skip_bytes(value_len + 1)?;
+                }
+                EntryStart::Other(entry) if entry.key() == key && !entry.is_range_deleted() => {
                     return Ok(None);
                 }
+                EntryStart::Other(_) => (),
+            }
+        }
+    }
+
+    /// Finds the leaf block that would hold the key, reading only the header of the
+    /// leaf itself.
+    fn find_leaf(&self, pos: u64, length: Option<u32>, key: &[u8]) -> Result<Option<Block>> {
+        let block = self.read_block(pos, length)?;
+        if block.level == 0 {
+            return Ok(Some(block));
+        }
+        let inner = self.load_block(pos, length)?;
+        let after = inner
+            .entries
+            .partition_point(|entry| self.key_order.cmp(entry.key(), key) != Ordering::Greater);
+        match after.checked_sub(1).map(|index| &inner.entries[index]) {
+            Some(Entry::PosLen {
+                blockpos, blocklen, ..
+            }) => self.find_leaf(*blockpos, Some(*blocklen), key),
+            Some(_) => Err(Error::PosLenEntryRequired),
+            None => Ok(None),
+        }
+    }
+
+    fn range_deleted_entry(&self, key: &[u8]) -> Option<Entry> {
+        self.range_deleted(key).then(|| Entry::Deleted {
+            key: key.to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        })
+    }
+
+    /// Returns the smallest key in the tree, including tombstones, by reading down
+    /// the leftmost path from the root.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        self.edge_key(|entries| entries.first())
+    }
+
+    /// Returns the largest key in the tree, including tombstones, by reading down
+    /// the rightmost path from the root.
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        self.edge_key(|entries| entries.last())
+    }
+
+    fn edge_key(&self, pick: impl Fn(&[Entry]) -> Option<&Entry>) -> Result<Option<Vec<u8>>> {
+        let mut block = self.load_block(self.trailer.root_pos, None)?;
+        while block.level > 0 {
+            match pick(&block.entries) {
+                Some(Entry::PosLen {
+                    blockpos, blocklen, ..
+                }) => block = self.load_block(*blockpos, Some(*blocklen))?,
+                Some(_) => return Err(Error::PosLenEntryRequired),
+                None => return Ok(None),
+            }
+        }
+        Ok(pick(&block.entries).map(|entry| entry.key().to_vec()))
+    }
+
+    /// Estimates the bytes on disk of the entries from `from` up to, but not
+    /// including, `to`: the sum of the lengths of the leaf blocks whose keys may fall
+    /// in that range. Only the inner blocks are read, the leaf lengths come from the
+    /// `PosLen` entries pointing at them.
+    pub fn range_size_estimate(&self, from: &[u8], to: &[u8]) -> Result<u64> {
+        let root = self.root_block()?;
+        if root.level > 0 {
+            return self.inner_range_size(root.start, root.blocklen, from, to);
+        }
+        // A tree that fits in a single leaf has no inner blocks to go by
+        let overlaps = match (self.first_key()?, self.last_key()?) {
+            (Some(first), Some(last)) => {
+                self.key_order.cmp(&first, to) == Ordering::Less
+                    && self.key_order.cmp(&last, from) != Ordering::Less
+            }
+            _ => false,
+        };
+        Ok(if overlaps { root.blocklen as u64 } else { 0 })
+    }
+
+    fn inner_range_size(&self, pos: u64, length: u32, from: &[u8], to: &[u8]) -> Result<u64> {
+        let block = self.load_block(pos, Some(length))?;
+        let mut size = 0;
+        for (index, entry) in block.entries.iter().enumerate() {
+            let Entry::PosLen {
+                blockpos,
+                blocklen,
+                key,
+            } = entry
+            else {
+                return Err(Error::PosLenEntryRequired);
+            };
+            // A child holds the keys from its own key up to the key of the next child
+            if self.key_order.cmp(key, to) != Ordering::Less {
+                break;
+            }
+            let before_from = block
+                .entries
+                .get(index + 1)
+                .is_some_and(|next| self.key_order.cmp(next.key(), from) != Ordering::Greater);
+            if before_from {
+                continue;
+            }
+            size += match block.level {
+                1 => blocklen.saturating_sub(self.poslen_overhead) as u64,
+                _ => self.inner_range_size(*blockpos, *blocklen, from, to)?,
+            };
+        }
+        Ok(size)
+    }
+
+    /// Looks up several keys, decoding the root block only once. The keys should
+    /// be sorted; the results are returned in the same order as the keys.
+    pub fn get_entries(&self, keys: &[&[u8]]) -> Result<Vec<Option<Entry>>> {
+        let mut root = None;
+        keys.iter()
+            .map(|key| {
+                if !self.trailer.bloom.contains(key) {
+                    return Ok(self.range_deleted_entry(key));
+                }
+                if root.is_none() {
+                    root = Some(self.load_block(self.trailer.root_pos, None)?);
+                }
+                // WONT PANIC: the root block was loaded above
+                match self.find_entry(root.as_ref().unwrap(), key)? {
+                    Some(entry) => Ok(Some(entry)),
+                    None => Ok(self.range_deleted_entry(key)),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks the whole tree: every block is read from the root down, its length
+    /// checked against the file, and its contents decompressed. Every entry's CRC is
+    /// checked, and the keys must be in strictly ascending order across all the
+    /// leaf blocks, except that range tombstones come before the key they start at.
+    /// The first problem found is returned as `Error::CorruptedBlock` with the
+    /// position of the block that holds it.
+    pub fn verify(&self) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        let root = self
+            .root_block()
+            .map_err(|err| corrupted_block(self.trailer.root_pos, err))?;
+        let mut last_entry = None;
+        self.verify_block(&root, &mut stats, &mut last_entry)?;
+        Ok(stats)
+    }
+
+    fn verify_block(
+        &self,
+        block: &Block,
+        stats: &mut TreeStats,
+        last_entry: &mut Option<Entry>,
+    ) -> Result<()> {
+        let pos = block.start;
+        if pos + 4 + block.blocklen as u64 > self.file_size {
+            return Err(corrupted_block(
+                pos,
+                Error::CorruptedFile("block extends past the end of the file"),
+            ));
+        }
+        stats.blocks += 1;
+        for entry in block.entries().map_err(|err| corrupted_block(pos, err))? {
+            let entry = entry.map_err(|err| corrupted_block(pos, err))?;
+            if block.level == 0 {
+                if entry.is_pos_len() {
+                    return Err(corrupted_block(
+                        pos,
+                        Error::CorruptedFile("leaf block holds a PosLen entry"),
+                    ));
+                }
+                let in_order = last_entry.as_ref().is_none_or(|last_entry| {
+                    match last_entry.cmp_position_by(&entry, &self.key_order) {
+                        Ordering::Less => true,
+                        // Range tombstones may share a start key
+                        Ordering::Equal => entry.is_range_deleted(),
+                        Ordering::Greater => false,
+                    }
+                });
+                if !in_order {
+                    return Err(corrupted_block(
+                        pos,
+                        Error::CorruptedFile("keys are not in ascending order"),
+                    ));
+                }
+                stats.entries += 1;
+                stats.key_sizes.add(entry.key().len());
+                if let Entry::KeyVal { value, .. } = &entry {
+                    stats.value_sizes.add(value.len());
+                }
+                *last_entry = Some(entry);
             } else {
-                return Ok(block.entries()?.find(|entry| entry.key() == key));
+                let Entry::PosLen { blockpos, .. } = &entry else {
+                    return Err(corrupted_block(pos, Error::PosLenEntryRequired));
+                };
+                let child = self
+                    .block_from_poslen_entry(&entry)
+                    .map_err(|err| corrupted_block(*blockpos, err))?;
+                if child.level + 1 != block.level {
+                    return Err(corrupted_block(
+                        *blockpos,
+                        Error::CorruptedFile("block is at the wrong level of the tree"),
+                    ));
+                }
+                self.verify_block(&child, stats, last_entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and decodes the block at `pos`, from the block cache if there is one.
+    /// `length` is the expected block length, if it is known.
+    fn load_block(&self, pos: u64, length: Option<u32>) -> Result<Arc<BlockEntries>> {
+        let load = || {
+            let block = self.read_block(pos, length)?;
+            Ok(BlockEntries {
+                level: block.level,
+                entries: block.entries()?.collect::<Result<_>>()?,
+            })
+        };
+        match &self.block_cache {
+            Some(block_cache) => block_cache.get_or_load((self.id, pos), load),
+            None => load().map(Arc::new),
+        }
+    }
+
+    fn find_entry(&self, block: &BlockEntries, key: &[u8]) -> Result<Option<Entry>> {
+        // level > 0 -> inner block
+        // level == 0 -> leaf block
+        if block.level == 0 {
+            let entry = block
+                .entries
+                .iter()
+                .find(|entry| entry.key() == key && !entry.is_range_deleted());
+            return Ok(entry.cloned());
+        }
+        // Find the last inner entry whose first key is not after the key. The
+        // entries are sorted by their first keys, so binary search for it.
+        let after = block
+            .entries
+            .partition_point(|entry| self.key_order.cmp(entry.key(), key) != Ordering::Greater);
+        let inner_entry = after.checked_sub(1).map(|index| &block.entries[index]);
+        match inner_entry {
+            Some(Entry::PosLen {
+                blockpos, blocklen, ..
+            }) => {
+                // Go to the next lower level in the tree
+                let block = self.load_block(*blockpos, Some(*blocklen))?;
+                self.find_entry(&block, key)
             }
+            Some(_) => Err(Error::PosLenEntryRequired),
+            None => Ok(None),
         }
     }
 }
 
+/// Reads a value straight out of its leaf block, see `Tree::value_reader`.
+pub struct ValueReader {
+    value: Take<Box<dyn Read + Send>>,
+    timestamp: Option<u32>,
+    write_timestamp: Option<u32>,
+}
+
+impl ValueReader {
+    /// Returns `true` if the value has expired at time `now`, like `Entry::is_expired`.
+    pub fn is_expired(&self, now: u32) -> bool {
+        matches!(self.timestamp, Some(expiry) if expiry <= now)
+    }
+
+    /// Returns the write timestamp of the value, like `Entry::write_timestamp`.
+    pub fn write_timestamp(&self) -> Option<u32> {
+        self.write_timestamp
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.value.read(buf)
+    }
+}
+
 pub struct TreeEntryIterator {
     tree: Tree,
     levels: Vec<EntryIterator>,
@@ -121,20 +772,25 @@ impl TreeEntryIterator {
 }
 
 impl Iterator for TreeEntryIterator {
-    type Item = Entry;
+    type Item = Result<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             let level = self.levels.last_mut()?;
             match level.next() {
-                Some(entry @ Entry::PosLen { .. }) => {
+                Some(Ok(entry @ Entry::PosLen { .. })) => {
                     let block_iterator = self
                         .tree
                         .block_from_poslen_entry(&entry)
-                        .ok()?
-                        .entries()
-                        .ok()?;
-                    self.levels.push(block_iterator);
+                        .and_then(|block| block.entries());
+                    match block_iterator {
+                        Ok(block_iterator) => self.levels.push(block_iterator),
+                        Err(err) => {
+                            // Stop iterating after the first error
+                            self.levels.clear();
+                            return Some(Err(err));
+                        }
+                    }
                     continue;
                 }
                 entry @ Some(_) => {
@@ -149,3 +805,462 @@ impl Iterator for TreeEntryIterator {
         }
     }
 }
+
+/// Yields the entries of every block of a tree in file order, see `Tree::dump_entries`.
+pub struct DumpEntryIterator {
+    tree: Tree,
+    next_pos: Option<u64>,
+    block: Option<EntryIterator>,
+}
+
+impl Iterator for DumpEntryIterator {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.block.as_mut().and_then(Iterator::next) {
+                if entry.is_err() {
+                    // Stop iterating after the first error
+                    self.next_pos = None;
+                    self.block = None;
+                }
+                return Some(entry);
+            }
+            let pos = self.next_pos.take()?;
+            let root_pos = self.tree.trailer.root_pos;
+            let entries = self.tree.read_block(pos, None).and_then(|block| {
+                // The root is the last block in the file
+                if pos < root_pos {
+                    self.next_pos = Some(pos + 4 + block.blocklen as u64);
+                }
+                block.entries()
+            });
+            match entries {
+                Ok(entries) => self.block = Some(entries),
+                Err(err) => {
+                    self.next_pos = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Walks the entries of a tree from the largest key to the smallest. Blocks can only
+/// be decoded front to back, so the entries of each block on the current path are
+/// buffered and replayed backwards.
+pub struct ReverseTreeEntryIterator {
+    tree: Tree,
+    levels: Vec<std::iter::Rev<std::vec::IntoIter<Entry>>>,
+}
+
+impl ReverseTreeEntryIterator {
+    fn new(tree: Tree) -> Result<Self> {
+        let root_entries = tree.root_block()?.entries()?.collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            tree,
+            levels: vec![root_entries.into_iter().rev()],
+        })
+    }
+}
+
+impl Iterator for ReverseTreeEntryIterator {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let level = self.levels.last_mut()?;
+            match level.next() {
+                Some(entry @ Entry::PosLen { .. }) => {
+                    let block_entries = self
+                        .tree
+                        .block_from_poslen_entry(&entry)
+                        .and_then(|block| block.entries()?.collect::<Result<Vec<_>>>());
+                    match block_entries {
+                        Ok(block_entries) => self.levels.push(block_entries.into_iter().rev()),
+                        Err(err) => {
+                            // Stop iterating after the first error
+                            self.levels.clear();
+                            return Some(Err(err));
+                        }
+                    }
+                    continue;
+                }
+                Some(entry) => {
+                    return Some(Ok(entry));
+                }
+                None => {
+                    // pop this iterator off
+                    let _ = self.levels.pop();
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+/// Opens a single tree (`.data`) file and checks its integrity, see `Tree::verify`.
+pub fn verify_file(path: impl AsRef<Path>) -> Result<TreeStats> {
+    Tree::from_file(path)?.verify()
+}
+
+/// Opens a single tree (`.data`) file and yields all of its entries in file order,
+/// see `Tree::dump_entries`.
+pub fn dump_file(path: impl AsRef<Path>) -> Result<DumpEntryIterator> {
+    Tree::from_file(path)?.dump_entries()
+}
+
+/// What `Tree::verify` found in a tree file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of blocks, including the root and inner blocks.
+    pub blocks: u64,
+    /// The number of entries in the leaf blocks, including tombstones.
+    pub entries: u64,
+    /// The lengths of the keys of the entries in the leaf blocks.
+    pub key_sizes: SizeHistogram,
+    /// The lengths of the values in the leaf blocks. Tombstones have no value.
+    pub value_sizes: SizeHistogram,
+}
+
+/// Counts of lengths in power-of-two buckets: bucket 0 counts the lengths of 0, and
+/// bucket `i` the lengths from `2^(i-1)` up to, but not including, `2^i`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Ends at the last bucket with anything in it.
+    pub buckets: Vec<u64>,
+}
+
+impl SizeHistogram {
+    pub fn add(&mut self, size: usize) {
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// Returns the lengths counted in bucket `index`.
+    pub fn bucket_range(index: usize) -> Range<u64> {
+        match index {
+            0 => 0..1,
+            index => 1 << (index - 1)..1 << index,
+        }
+    }
+
+    /// Returns the number of lengths counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+fn corrupted_block(pos: u64, err: Error) -> Error {
+    Error::CorruptedBlock {
+        pos,
+        source: Box::new(err),
+    }
+}
+
+/// Returns the next item of `entries` if it is an error, so that callers can bail out
+/// on a corrupted tree before comparing keys.
+pub(crate) fn take_error<I>(entries: &mut Peekable<I>) -> Result<()>
+where
+    I: Iterator<Item = Result<Entry>>,
+{
+    match entries.next_if(|entry| entry.is_err()) {
+        Some(Err(err)) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{Writer, WriterOptions};
+    use tempfile::tempdir;
+
+    fn write_tree(path: &Path) {
+        let options = WriterOptions {
+            block_size: 512,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_options(path, options).unwrap();
+        for i in 0..500 {
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("key-{i:03}").into_bytes(),
+                    value: "value".as_bytes().to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn short_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.as_ref().join("short.data");
+        std::fs::write(&path, b"HAN3\0").unwrap();
+        assert!(matches!(
+            Tree::from_file(&path),
+            Err(Error::CorruptedFile("file too short for trailer"))
+        ));
+    }
+
+    #[test]
+    fn dump_entries_in_file_order() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+        let tree = Tree::from_file(&data).unwrap();
+        let entries: Vec<Entry> = tree.dump_entries().unwrap().map(|e| e.unwrap()).collect();
+
+        let leaves: Vec<Entry> = entries.iter().filter(|e| e.is_key_val()).cloned().collect();
+        assert_eq!(
+            leaves,
+            tree.entries()
+                .unwrap()
+                .map(|e| e.unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(entries.iter().any(Entry::is_pos_len));
+        // The first block is a leaf, and the last is the root, an inner block
+        assert!(entries.first().unwrap().is_key_val());
+        assert!(entries.last().unwrap().is_pos_len());
+    }
+
+    #[test]
+    fn stream_large_value() {
+        let dir = tempdir().unwrap();
+        let path = dir.as_ref().join("large.data");
+        let large: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut writer = Writer::new(&path).unwrap();
+        for (key, value) in [
+            (&b"a"[..], b"small".to_vec()),
+            (b"b", large.clone()),
+            (b"c", b"after".to_vec()),
+        ] {
+            writer
+                .add(Entry::KeyVal {
+                    key: key.to_vec(),
+                    value,
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer
+            .add(Entry::Deleted {
+                key: b"d".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&path).unwrap();
+        let mut reader = tree.value_reader(b"b").unwrap().unwrap();
+        let mut read = vec![];
+        let mut chunk = vec![0; 4096];
+        loop {
+            let len = reader.read(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&chunk[..len]);
+        }
+        assert!(read == large);
+
+        // The large value is skipped over to get to the keys after it
+        let mut after = vec![];
+        let mut reader = tree.value_reader(b"c").unwrap().unwrap();
+        reader.read_to_end(&mut after).unwrap();
+        assert_eq!(after, b"after");
+        assert!(tree.value_reader(b"d").unwrap().is_none());
+        assert!(tree.value_reader(b"e").unwrap().is_none());
+    }
+
+    #[test]
+    fn verify_tree() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+        let stats = Tree::from_file(&data).unwrap().verify().unwrap();
+        assert_eq!(stats.entries, 500);
+        assert!(stats.blocks > 2);
+    }
+
+    #[test]
+    fn verify_collects_size_histograms() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        // Keys of 5 bytes, and values at either end of the buckets up to 512..1024
+        for i in 0..11 {
+            let len = match i {
+                0 => 0,
+                i if i % 2 == 0 => 1 << (i - 1),
+                i => (1 << i) - 1,
+            };
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("key{i:02}").into_bytes(),
+                    value: vec![b'v'; len],
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer
+            .add(Entry::Deleted {
+                key: b"key11-deleted".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let stats = Tree::from_file(&data).unwrap().verify().unwrap();
+        assert_eq!(stats.key_sizes.buckets, [0, 0, 0, 11, 1]);
+        assert_eq!(stats.value_sizes.buckets, vec![1; 11]);
+        assert_eq!(stats.value_sizes.count(), 11);
+        assert_eq!(SizeHistogram::bucket_range(0), 0..1);
+        assert_eq!(SizeHistogram::bucket_range(10), 512..1024);
+    }
+
+    #[test]
+    fn deep_tree_lookups() {
+        let dir = tempdir().unwrap();
+        let path = dir.as_ref().join("deep.data");
+        let options = WriterOptions {
+            block_size: 512,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_options(&path, options).unwrap();
+        for i in (0..8000).step_by(2) {
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("key-{i:04}").into_bytes(),
+                    value: vec![b'v'; 20],
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+        let tree = Tree::from_file(&path).unwrap();
+        assert!(tree.root_block().unwrap().level >= 2, "tree is not deep");
+
+        let entries: Vec<Entry> = tree.entries().unwrap().map(|e| e.unwrap()).collect();
+        let mut keys: Vec<Vec<u8>> = (0..8001)
+            .map(|i| format!("key-{i:04}").into_bytes())
+            .collect();
+        // Before the first key and after the last one
+        keys.extend([b"a".to_vec(), b"key-".to_vec(), b"z".to_vec()]);
+        for key in keys {
+            let linear = entries.iter().find(|entry| entry.key() == key).cloned();
+            assert_eq!(tree.get_entry(&key).unwrap(), linear);
+        }
+    }
+
+    #[test]
+    fn first_and_last_keys() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+        let tree = Tree::from_file(&data).unwrap();
+        assert!(tree.root_block().unwrap().level > 0);
+        assert_eq!(tree.first_key().unwrap(), Some(b"key-000".to_vec()));
+        assert_eq!(tree.last_key().unwrap(), Some(b"key-499".to_vec()));
+
+        let empty = dir.as_ref().join("empty.data");
+        Writer::with_options(&empty, Default::default())
+            .unwrap()
+            .close()
+            .unwrap();
+        let tree = Tree::from_file(&empty).unwrap();
+        assert_eq!(tree.first_key().unwrap(), None);
+        assert_eq!(tree.last_key().unwrap(), None);
+    }
+
+    #[test]
+    fn cached_lookups_read_blocks_once() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let tree = Tree::from_file(&data)
+            .unwrap()
+            .with_block_cache(Some(cache.clone()));
+
+        let value = Some(Entry::KeyVal {
+            key: b"key-250".to_vec(),
+            value: b"value".to_vec(),
+            timestamp: None,
+            write_timestamp: None,
+        });
+        assert_eq!(tree.get_entry(b"key-250").unwrap(), value);
+        // One block was read for each level of the tree
+        let loads = cache.loads();
+        assert!(loads >= 2);
+        for _ in 0..100 {
+            assert_eq!(tree.get_entry(b"key-250").unwrap(), value);
+        }
+        assert_eq!(cache.loads(), loads);
+
+        // Clones of the tree share the cached blocks, other files don't
+        assert_eq!(
+            tree.try_clone().unwrap().get_entry(b"key-250").unwrap(),
+            value
+        );
+        assert_eq!(cache.loads(), loads);
+        let other = Tree::from_file(&data)
+            .unwrap()
+            .with_block_cache(Some(cache.clone()));
+        assert_eq!(other.get_entry(b"key-250").unwrap(), value);
+        assert_eq!(cache.loads(), loads * 2);
+    }
+
+    #[test]
+    fn lookups_count_a_block_per_level() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+        let counters = Counters::new();
+        let tree = Tree::from_file(&data)
+            .unwrap()
+            .with_counters(counters.clone());
+        let depth = tree.root_block().unwrap().level as u64 + 1;
+        assert!(depth >= 2);
+        assert_eq!(counters.snapshot().blocks_read, 0);
+
+        assert!(tree.get_entry(b"key-250").unwrap().is_some());
+        let counted = counters.snapshot();
+        assert_eq!(counted.blocks_read, depth);
+        assert!(counted.bytes_read > 512);
+    }
+
+    #[test]
+    fn verify_reports_corrupted_block() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        write_tree(&data);
+
+        // The second leaf block follows the first, which starts after the magic
+        let mut contents = std::fs::read(&data).unwrap();
+        let first_blocklen = u32::from_be_bytes(contents[4..8].try_into().unwrap());
+        let second_block = 4 + 4 + first_blocklen as usize;
+        // Corrupt the compression byte of its header
+        contents[second_block + 6] = 0x7F;
+        std::fs::write(&data, contents).unwrap();
+
+        let error = Tree::from_file(&data).unwrap().verify().unwrap_err();
+        let Error::CorruptedBlock { pos, source } = error else {
+            panic!("unexpected error {error:?}");
+        };
+        assert_eq!(pos, second_block as u64);
+        assert!(matches!(*source, Error::InvalidCompression(0x7F)));
+    }
+}
@@ -0,0 +1,156 @@
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use crate::db::HanoiDB;
+use crate::error::*;
+
+/// Converts values of type `T` to and from the bytes stored in the database.
+///
+/// A codec used for keys must preserve order: if `a < b` then `encode(a)` must sort
+/// before `encode(b)` bytewise. Otherwise range scans return keys out of order, and
+/// ranges given in terms of `T` won't cover the keys expected.
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Stores byte strings and strings as they are, and unsigned integers as big-endian
+/// bytes. All of these encodings preserve order, so it can be used for keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl Codec<Vec<u8>> for RawCodec {
+    fn encode(value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec<String> for RawCodec {
+    fn encode(value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|err| Error::Decode(err.to_string()))
+    }
+}
+
+macro_rules! raw_codec_for_uint {
+    ($($uint:ty),*) => {
+        $(
+            impl Codec<$uint> for RawCodec {
+                fn encode(value: &$uint) -> Result<Vec<u8>> {
+                    Ok(value.to_be_bytes().to_vec())
+                }
+
+                fn decode(bytes: &[u8]) -> Result<$uint> {
+                    let bytes = bytes.try_into().map_err(|_| {
+                        Error::Decode(format!(
+                            "expected {} bytes for {}, got {}",
+                            std::mem::size_of::<$uint>(),
+                            stringify!($uint),
+                            bytes.len()
+                        ))
+                    })?;
+                    Ok(<$uint>::from_be_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+raw_codec_for_uint!(u8, u16, u32, u64, u128);
+
+/// Stores any serde type with bincode. Bincode writes integers little-endian, so
+/// this doesn't preserve order and is meant for values rather than keys.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for BincodeCodec {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|err| Error::Encode(err.to_string()))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|err| Error::Decode(err.to_string()))
+    }
+}
+
+/// A database whose keys and values are typed, encoded with the codecs `KC` and
+/// `VC`. The key codec must preserve order (see `Codec`).
+pub struct TypedDB<K, V, KC = RawCodec, VC = RawCodec> {
+    db: HanoiDB,
+    _types: PhantomData<(K, V, KC, VC)>,
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> TypedDB<K, V, KC, VC> {
+    pub fn new(db: HanoiDB) -> Self {
+        Self {
+            db,
+            _types: PhantomData,
+        }
+    }
+
+    /// Returns the database underneath, for operations that have no typed version.
+    pub fn inner(&mut self) -> &mut HanoiDB {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> HanoiDB {
+        self.db
+    }
+
+    /// Looks up a key in the database and returns its value if it is present.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.db
+            .get(&KC::encode(key)?)?
+            .map(|value| VC::decode(&value))
+            .transpose()
+    }
+
+    /// Inserts a key-value pair into the database.
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        self.db.insert(KC::encode(key)?, VC::encode(value)?)
+    }
+
+    /// Deletes a key from the database.
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.db.delete(KC::encode(key)?)
+    }
+
+    /// Scans the keys and values in the database that fall between `from` and `to`,
+    /// in ascending order of their encoded keys.
+    pub fn range(
+        &self,
+        from: Bound<K>,
+        to: Bound<K>,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        let entries = self
+            .db
+            .range(encode_bound::<K, KC>(from)?, encode_bound::<K, KC>(to)?)?;
+        Ok(entries.map(|entry| {
+            let (key, value) = entry?;
+            Ok((KC::decode(&key)?, VC::decode(&value)?))
+        }))
+    }
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> From<HanoiDB> for TypedDB<K, V, KC, VC> {
+    fn from(db: HanoiDB) -> Self {
+        Self::new(db)
+    }
+}
+
+fn encode_bound<K, KC: Codec<K>>(bound: Bound<K>) -> Result<Bound<Vec<u8>>> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(KC::encode(&key)?),
+        Bound::Excluded(key) => Bound::Excluded(KC::encode(&key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
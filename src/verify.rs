@@ -0,0 +1,44 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use crate::nursery::{read_log_header, read_log_record};
+
+/// The result of checking every file of a database with `HanoiDB::verify`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    /// Checks whether every file passed.
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|file| file.status.is_ok())
+    }
+
+    /// Returns the files that failed the check.
+    pub fn corrupt_files(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|file| file.status.is_err())
+    }
+}
+
+/// The status of a single file: `Ok` or the first problem found in it.
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub status: Result<()>,
+}
+
+/// Reads every entry of a nursery log segment, checking their CRCs.
+pub(crate) fn verify_log(path: impl AsRef<Path>) -> Result<()> {
+    let mut log = BufReader::new(File::open(path)?);
+    let framed = read_log_header(&mut log)?;
+    loop {
+        match read_log_record(&mut log, framed) {
+            Ok(_) => (),
+            Err(Error::EndOfFile) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -1,17 +1,79 @@
+use std::cmp::Ordering;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
 use fastbloom::BloomFilter;
 
+use crate::block::BLOCK_PREFIX_KEYS;
+use crate::comparator::KeyOrder;
 use crate::compression::Compression;
+use crate::counters::Counters;
 use crate::entry::Entry;
 use crate::error::*;
 use crate::trailer::Trailer;
 use crate::{MAGIC, TAG_END};
 
-const BLOCK_SIZE: usize = 8 * 1024;
-const FIRST_BLOCK_POS: u64 = 4;
+pub(crate) const DEFAULT_BLOCK_SIZE: usize = 8 * 1024;
+/// Blocks smaller than this hold too few entries for inner blocks to fan out.
+pub(crate) const MIN_BLOCK_SIZE: usize = 512;
+/// An inner block always points at two blocks or more, or a key as large as a block
+/// would make a new level of the tree for every level below it.
+pub(crate) const MIN_FANOUT: usize = 2;
+pub(crate) const DEFAULT_BLOOM_FP_RATE: f64 = 0.01;
+pub(crate) const FIRST_BLOCK_POS: u64 = 4;
+/// Bloom filters are sized for at most this many items, about 19MB at the default
+/// false positive rate. Levels are sized for `1 << level` items, so without a cap a
+/// merge into a deep level would allocate a filter for billions of keys up front,
+/// whether or not the level ever holds them. Files with more keys than this get a
+/// higher false positive rate than `bloom_fp_rate` instead. Every file is capped the
+/// same way, so the filters of a deep level can still be combined.
+pub(crate) const MAX_BLOOM_ITEMS: usize = 1 << 24;
+/// All bloom filters of a database hash with the same seed, so that the filters of
+/// trees sized for the same number of items can be combined (see `Level::bloom_union`).
+pub(crate) const DEFAULT_BLOOM_SEED: u128 = 0x0048_414e_4f49_4442;
+
+/// Settings that control how tree files are written. Apart from the key order, none
+/// of these are needed to read the files back.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WriterOptions {
+    pub compression: Compression,
+    /// See `Compression::compress`.
+    pub compression_level: Option<u32>,
+    /// The size at which a block is flushed to the file, before compression.
+    pub block_size: usize,
+    /// The number of blocks an inner block points at before it can be flushed, even
+    /// once it's over `block_size`. Only the last inner block of each level of the
+    /// tree may point at fewer.
+    pub min_fanout: usize,
+    /// The false positive rate the bloom filter is sized for.
+    pub bloom_fp_rate: f64,
+    /// The seed of the bloom filter's hasher. It is stored with the filter, so files
+    /// written with any seed can be read back.
+    pub bloom_seed: u128,
+    /// Write the keys of each block prefix compressed, see `block::Block`.
+    pub prefix_keys: bool,
+    /// The order the entries must be added in.
+    pub key_order: KeyOrder,
+    /// Counts the entries written to leaf blocks.
+    pub counters: Counters,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            compression_level: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            min_fanout: MIN_FANOUT,
+            bloom_fp_rate: DEFAULT_BLOOM_FP_RATE,
+            bloom_seed: DEFAULT_BLOOM_SEED,
+            prefix_keys: false,
+            key_order: KeyOrder::default(),
+            counters: Counters::default(),
+        }
+    }
+}
 
 #[derive(Default, Clone)]
 struct Block {
@@ -28,15 +90,21 @@ impl Block {
 
 pub struct Writer {
     name: PathBuf,
-    index_file: File,
+    // Blocks are written in small pieces, so buffer them. Positions in the file are
+    // tracked in `index_file_pos` rather than taken from the file cursor.
+    index_file: BufWriter<File>,
     index_file_pos: u64,
     last_node_pos: Option<u64>,
     last_node_size: Option<u32>,
     blocks: Vec<Block>,
     bloom: BloomFilter,
-    compression: Compression,
+    options: WriterOptions,
     value_count: usize,
     tombstone_count: usize,
+    /// Copies of the range tombstones written to the leaf blocks, which are also
+    /// written together after the root block so that they can be loaded at once
+    /// (see `Tree::range_tombstones`).
+    range_tombstones: Vec<Entry>,
 }
 
 impl std::fmt::Debug for Writer {
@@ -52,14 +120,18 @@ impl Writer {
     pub fn with_expected_num_items(
         name: impl AsRef<Path>,
         expected_num_items: usize,
-        compression: Compression,
+        options: WriterOptions,
     ) -> Result<Self> {
-        let bloom = BloomFilter::with_false_pos(0.01).expected_items(expected_num_items);
-
-        let mut index_file = OpenOptions::new()
-            .append(true)
-            .create_new(true)
-            .open(name.as_ref())?;
+        let bloom = BloomFilter::with_false_pos(options.bloom_fp_rate)
+            .seed(&options.bloom_seed)
+            .expected_items(expected_num_items.min(MAX_BLOOM_ITEMS));
+
+        let mut index_file = BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .create_new(true)
+                .open(name.as_ref())?,
+        );
         index_file.write_all(MAGIC.as_bytes())?;
         Ok(Self {
             name: name.as_ref().to_path_buf(),
@@ -69,9 +141,10 @@ impl Writer {
             last_node_size: None,
             blocks: Default::default(),
             bloom,
-            compression,
+            options,
             value_count: 0,
             tombstone_count: 0,
+            range_tombstones: vec![],
         })
     }
 
@@ -80,7 +153,13 @@ impl Writer {
     }
 
     pub fn add(&mut self, entry: Entry) -> Result<()> {
-        if !entry.is_pos_len() {
+        if entry.is_transaction() {
+            return Err(Error::TransactionInTree);
+        }
+        entry.check_size()?;
+        if entry.is_range_deleted() {
+            self.range_tombstones.push(entry.clone());
+        } else if !entry.is_pos_len() {
             self.bloom.insert(entry.key());
         }
         self.append_to_block(0, entry)?;
@@ -90,8 +169,10 @@ impl Writer {
     pub fn close(mut self) -> Result<()> {
         // Unwritten blocks: call flush_block_buffer to write them
         while let Some(block) = self.blocks.last() {
-            // 1 block with 1 entry in it where level is not 0, discard that block
-            if block.is_solo_inner_block() {
+            // The top block with 1 entry in it where level is not 0 only points at the
+            // root, discard that block. Lower blocks with 1 entry still need to be
+            // written, as the blocks above them don't point at their child yet.
+            if self.blocks.len() == 1 && block.is_solo_inner_block() {
                 break;
             }
             self.flush_block_buffer()?;
@@ -105,35 +186,44 @@ impl Writer {
                 FIRST_BLOCK_POS
             }
         };
-        let trailer = Trailer::with_bloom_filter(self.bloom, root_pos);
+        for range_tombstone in std::mem::take(&mut self.range_tombstones) {
+            self.index_file.write_all(&range_tombstone.encode()?)?;
+        }
+        let trailer = Trailer::with_bloom_filter(self.bloom, self.value_count as u64, root_pos);
         self.index_file.write_all(&trailer.encode()?)?;
-        self.index_file.sync_data()?;
+        let index_file = self
+            .index_file
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+        index_file.sync_data()?;
         Ok(())
     }
 
     fn append_to_block(&mut self, level: u16, entry: Entry) -> Result<()> {
         let mut tombstone_count = 0;
         let mut value_count = 0;
-        if entry.is_deleted() {
+        if entry.is_deleted() || entry.is_range_deleted() {
             tombstone_count += 1;
         } else if entry.is_key_val() {
             value_count += 1;
         }
 
+        let key_order = self.options.key_order.clone();
         let block: &mut Block = self.get_block_at_level(level);
         let new_size = block.size + entry.encoded_size();
         if let Some(last_entry) = block.members.last() {
-            if last_entry.key() > entry.key() {
+            if last_entry.cmp_position_by(&entry, &key_order) == Ordering::Greater {
                 return Err(Error::OutOfOrderWrite);
             }
         }
         block.size = new_size;
         block.members.push(entry);
+        let fanned_out = block.level == 0 || block.members.len() >= self.options.min_fanout;
 
         self.tombstone_count += tombstone_count;
         self.value_count += value_count;
 
-        if new_size >= BLOCK_SIZE {
+        if new_size >= self.options.block_size && fanned_out {
             self.flush_block_buffer()?;
         }
         Ok(())
@@ -145,24 +235,45 @@ impl Writer {
             .pop()
             .expect("cannot flush block that doesn't exist");
         let first_key = block.members.first().unwrap().key().to_owned();
+        let entry_count = block.members.len();
         // block size + level + compression
         let mut header = Vec::with_capacity(7);
         let mut contents = Vec::with_capacity(block.size);
         contents.push(TAG_END);
-        for entry in block.members {
-            contents.extend(entry.encode());
+        let mut last_key: Vec<u8> = vec![];
+        for mut entry in block.members {
+            if self.options.prefix_keys {
+                // WONT PANIC: transactions are never added to a tree
+                let key = entry.key_mut().unwrap();
+                let shared = shared_prefix_len(&last_key, key);
+                contents.extend((shared as u16).to_be_bytes());
+                last_key.clone_from(key);
+                key.drain(..shared);
+            }
+            contents.extend(entry.encode()?);
         }
-        let compressed = self.compression.compress(contents)?;
+        let compressed = self
+            .options
+            .compression
+            .compress(contents, self.options.compression_level)?;
 
         // blocklen = 2 bytes level + 1 byte compression + length of compressed contents
         let blocklen: u32 = (2 + 1 + compressed.len()).try_into().unwrap();
 
         header.extend(blocklen.to_be_bytes());
         header.extend(block.level.to_be_bytes());
-        header.push(self.compression as u8);
+        let prefix_keys = if self.options.prefix_keys {
+            BLOCK_PREFIX_KEYS
+        } else {
+            0
+        };
+        header.push(self.options.compression as u8 | prefix_keys);
 
         self.index_file.write_all(&header)?;
         self.index_file.write_all(&compressed)?;
+        if block.level == 0 {
+            self.options.counters.entries_written(entry_count as u64);
+        }
 
         let blockpos = self.index_file_pos;
         self.last_node_pos = Some(blockpos);
@@ -213,6 +324,13 @@ impl Writer {
     }
 }
 
+/// Returns the length of the prefix that two keys share, capped to what fits in the
+/// `shared` field of a prefix compressed entry.
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let shared = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    shared.min(u16::MAX as usize)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -225,7 +343,17 @@ pub mod tests {
         }
 
         pub fn with_compression(name: impl AsRef<Path>, compression: Compression) -> Result<Self> {
-            Self::with_expected_num_items(name, 1024, compression)
+            Self::with_options(
+                name,
+                WriterOptions {
+                    compression,
+                    ..Default::default()
+                },
+            )
+        }
+
+        pub fn with_options(name: impl AsRef<Path>, options: WriterOptions) -> Result<Self> {
+            Self::with_expected_num_items(name, 1024, options)
         }
     }
 
@@ -241,11 +369,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::new(&data).unwrap();
 
@@ -269,11 +399,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::with_compression(&data, Compression::Snappy).unwrap();
 
@@ -297,11 +429,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::with_compression(&data, Compression::Gzip).unwrap();
 
@@ -325,11 +459,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::with_compression(&data, Compression::Lz4).unwrap();
 
@@ -342,6 +478,236 @@ pub mod tests {
         assert_eq!(tree.get_entry(&deleted_key).unwrap(), Some(deleted));
     }
 
+    #[test]
+    fn zstd_roundtrip() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let deleted_key = "deleted".to_owned().into_bytes();
+        let key = "key".to_owned().into_bytes();
+        let value = "value".to_owned().into_bytes();
+
+        let deleted = Entry::Deleted {
+            key: deleted_key.clone(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        let kv = Entry::KeyVal {
+            key: key.clone(),
+            value: value.clone(),
+            timestamp: None,
+            write_timestamp: None,
+        };
+        let mut writer = Writer::with_compression(&data, Compression::Zstd).unwrap();
+
+        writer.add(deleted.clone()).unwrap();
+        writer.add(kv.clone()).unwrap();
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        assert_eq!(tree.get_entry(&key).unwrap(), Some(kv));
+        assert_eq!(tree.get_entry(&deleted_key).unwrap(), Some(deleted));
+    }
+
+    // The compression level changes the file size, but not the contents
+    #[test]
+    fn gzip_levels() {
+        let dir = tempdir().unwrap();
+        let entries: Vec<Entry> = (0..500)
+            .map(|i| Entry::KeyVal {
+                key: format!("key-{i:04}").into_bytes(),
+                value: vec![b'x'; 100],
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .collect();
+        let mut sizes = vec![];
+        let mut contents = vec![];
+        for level in [0, 9] {
+            let data = dir.as_ref().join(format!("test-{level}.data"));
+            let options = WriterOptions {
+                compression: Compression::Gzip,
+                compression_level: Some(level),
+                ..Default::default()
+            };
+            let mut writer = Writer::with_options(&data, options).unwrap();
+            for entry in entries.iter() {
+                writer.add(entry.clone()).unwrap();
+            }
+            writer.close().unwrap();
+
+            sizes.push(std::fs::metadata(&data).unwrap().len());
+            let tree = Tree::from_file(&data).unwrap();
+            contents.push(tree.entries().unwrap().collect::<Result<Vec<_>>>().unwrap());
+        }
+        assert!(sizes[1] < sizes[0], "sizes: {sizes:?}");
+        assert_eq!(contents[0], entries);
+        assert_eq!(contents[1], entries);
+    }
+
+    #[test]
+    fn prefix_keys_roundtrip() {
+        use std::io::Read;
+
+        let dir = tempdir().unwrap();
+        let mut entries: Vec<Entry> = (0..2000)
+            .map(|i| Entry::KeyVal {
+                key: format!("users/by-name/key-{i:04}").into_bytes(),
+                value: format!("value-{i:04}").into_bytes(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .collect();
+        entries.insert(
+            500,
+            Entry::Deleted {
+                key: b"users/by-name/key-0499-deleted".to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            },
+        );
+        let mut sizes = vec![];
+        for prefix_keys in [false, true] {
+            let data = dir.as_ref().join(format!("test-{prefix_keys}.data"));
+            let options = WriterOptions {
+                prefix_keys,
+                ..Default::default()
+            };
+            let mut writer = Writer::with_options(&data, options).unwrap();
+            for entry in entries.iter() {
+                writer.add(entry.clone()).unwrap();
+            }
+            writer.close().unwrap();
+            sizes.push(std::fs::metadata(&data).unwrap().len());
+
+            let tree = Tree::from_file(&data).unwrap();
+            let read: Vec<Entry> = tree.entries().unwrap().collect::<Result<_>>().unwrap();
+            assert_eq!(read, entries);
+            for i in [0, 499, 500, 1000, 2000] {
+                let entry = &entries[i];
+                assert_eq!(tree.get_entry(entry.key()).unwrap().as_ref(), Some(entry));
+            }
+            let mut value = vec![];
+            let mut reader = tree
+                .value_reader(b"users/by-name/key-1234")
+                .unwrap()
+                .unwrap();
+            reader.read_to_end(&mut value).unwrap();
+            assert_eq!(value, b"value-1234");
+            assert!(tree.verify().is_ok());
+        }
+        assert!(sizes[1] < sizes[0], "sizes: {sizes:?}");
+    }
+
+    #[test]
+    fn bloom_seed() {
+        let dir = tempdir().unwrap();
+        let keys: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("key-{i:04}").into_bytes())
+            .collect();
+        let mut trees = vec![];
+        for bloom_seed in [1, 2] {
+            let data = dir.as_ref().join(format!("test-{bloom_seed}.data"));
+            let options = WriterOptions {
+                bloom_seed,
+                ..Default::default()
+            };
+            let mut writer = Writer::with_options(&data, options).unwrap();
+            for key in keys.iter() {
+                writer
+                    .add(Entry::KeyVal {
+                        key: key.clone(),
+                        value: b"value".to_vec(),
+                        timestamp: None,
+                        write_timestamp: None,
+                    })
+                    .unwrap();
+            }
+            writer.close().unwrap();
+            // The seed is read back from the file, nothing needs to be configured
+            trees.push(Tree::from_file(&data).unwrap());
+        }
+        let (a, b) = (trees[0].bloom(), trees[1].bloom());
+        assert_ne!(a.as_slice(), b.as_slice());
+        for key in keys.iter() {
+            assert!(a.contains(key) && b.contains(key));
+        }
+        for i in 1000..2000 {
+            let key = format!("key-{i:04}").into_bytes();
+            assert_eq!(
+                trees[0].get_entry(&key).unwrap(),
+                trees[1].get_entry(&key).unwrap()
+            );
+        }
+    }
+
+    // The filter is stored whole, so its parameters are read back exactly rather
+    // than worked out from an assumed false positive rate
+    #[test]
+    fn bloom_parameters_roundtrip() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let options = WriterOptions {
+            bloom_fp_rate: 0.005,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_expected_num_items(&data, 500, options).unwrap();
+        for i in 0..500 {
+            writer
+                .add(Entry::KeyVal {
+                    key: format!("key-{i:03}").into_bytes(),
+                    value: b"value".to_vec(),
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        let expected = BloomFilter::with_false_pos(0.005)
+            .seed(&DEFAULT_BLOOM_SEED)
+            .expected_items(500);
+        let default_rate = BloomFilter::with_false_pos(DEFAULT_BLOOM_FP_RATE)
+            .seed(&DEFAULT_BLOOM_SEED)
+            .expected_items(500);
+        let tree = Tree::from_file(&data).unwrap();
+        let bloom = tree.bloom();
+        assert_eq!(bloom.num_bits(), expected.num_bits());
+        assert_eq!(bloom.num_hashes(), expected.num_hashes());
+        assert_ne!(bloom.num_bits(), default_rate.num_bits());
+        assert!((0..500).all(|i| bloom.contains(format!("key-{i:03}").as_bytes())));
+    }
+
+    // Compression is recorded per block, so a tree can mix algorithms
+    #[test]
+    fn mixed_compression_roundtrip() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::with_compression(&data, Compression::Lz4).unwrap();
+        let entries: Vec<Entry> = (0..2000)
+            .map(|i| Entry::KeyVal {
+                key: format!("key-{i:04}").into_bytes(),
+                value: format!("value-{i:04}").into_bytes(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .collect();
+        for (i, entry) in entries.iter().enumerate() {
+            if i == 1000 {
+                writer.options.compression = Compression::Zstd;
+            }
+            writer.add(entry.clone()).unwrap();
+        }
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        let read: Vec<Entry> = tree.entries().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(read, entries);
+        for i in [0, 999, 1000, 1999] {
+            let entry = &entries[i];
+            assert_eq!(tree.get_entry(entry.key()).unwrap().as_ref(), Some(entry));
+        }
+    }
+
     // Writes must be in byte-lexical order
     #[test]
     fn writes_are_in_byte_order() {
@@ -354,11 +720,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::new(&data).unwrap();
 
@@ -368,6 +736,18 @@ pub mod tests {
         assert_eq!(error.to_string(), "out-of-order write");
     }
 
+    // Transactions only belong in the nursery log
+    #[test]
+    fn transactions_are_rejected() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        let error = writer
+            .add(Entry::Transaction { entries: vec![] })
+            .unwrap_err();
+        assert!(matches!(error, Error::TransactionInTree));
+    }
+
     // Values and tombstone counts are tracked correctly
     #[test]
     fn key_counts() {
@@ -380,11 +760,13 @@ pub mod tests {
         let deleted = Entry::Deleted {
             key: deleted_key.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let kv = Entry::KeyVal {
             key: key.clone(),
             value: value.clone(),
             timestamp: None,
+            write_timestamp: None,
         };
         let mut writer = Writer::new(&data).unwrap();
 
@@ -399,6 +781,26 @@ pub mod tests {
         writer.close().unwrap();
     }
 
+    // The value count is persisted in the trailer
+    #[test]
+    fn value_count_roundtrip() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let mut writer = Writer::new(&data).unwrap();
+        let end = write_8kb(&mut writer, 0).unwrap();
+        writer
+            .add(Entry::Deleted {
+                key: end.to_be_bytes().to_vec(),
+                timestamp: None,
+                write_timestamp: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        assert_eq!(tree.value_count(), Some(end));
+    }
+
     // Blocks are closed when they reach 8KB
     #[test]
     fn max_block_size_is_8kb() {
@@ -416,6 +818,118 @@ pub mod tests {
         assert_eq!(writer.blocks[0].members.len(), 2);
     }
 
+    // Smaller blocks make for a deeper tree
+    #[test]
+    fn configurable_block_size() {
+        let dir = tempdir().unwrap();
+        let mut root_levels = vec![];
+        for block_size in [DEFAULT_BLOCK_SIZE, 1024] {
+            let data = dir.as_ref().join(format!("test-{block_size}.data"));
+            let options = WriterOptions {
+                block_size,
+                ..Default::default()
+            };
+            let mut writer = Writer::with_options(&data, options).unwrap();
+            let mut key = 0;
+            for _ in 0..10 {
+                key = write_8kb(&mut writer, key).unwrap();
+            }
+            writer.close().unwrap();
+
+            let tree = Tree::from_file(&data).unwrap();
+            assert_eq!(tree.entries().unwrap().count(), key as usize);
+            root_levels.push(tree.root_block().unwrap().level);
+        }
+        assert!(
+            root_levels[1] > root_levels[0],
+            "root levels: {root_levels:?}"
+        );
+    }
+
+    // Inner blocks that point at more blocks make for a shallower tree
+    #[test]
+    fn configurable_min_fanout() {
+        let dir = tempdir().unwrap();
+        let mut root_levels = vec![];
+        for min_fanout in [MIN_FANOUT, 200] {
+            let data = dir.as_ref().join(format!("test-{min_fanout}.data"));
+            let options = WriterOptions {
+                block_size: 512,
+                min_fanout,
+                ..Default::default()
+            };
+            let mut writer = Writer::with_options(&data, options).unwrap();
+            let mut key = 0;
+            for _ in 0..10 {
+                key = write_8kb(&mut writer, key).unwrap();
+            }
+            writer.close().unwrap();
+
+            let tree = Tree::from_file(&data).unwrap();
+            assert_eq!(tree.entries().unwrap().count(), key as usize);
+            let last = (key - 1).to_be_bytes();
+            assert!(tree.get_entry(&last).unwrap().is_some());
+            root_levels.push(tree.root_block().unwrap().level);
+        }
+        assert_eq!(root_levels, [2, 1]);
+    }
+
+    // Keys larger than a block don't make an inner block for each one
+    #[test]
+    fn keys_larger_than_a_block() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let options = WriterOptions {
+            block_size: 512,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_options(&data, options).unwrap();
+        for i in 0..100u8 {
+            let mut key = vec![i; 1000];
+            key.push(i);
+            writer
+                .add(Entry::KeyVal {
+                    key,
+                    value: vec![i],
+                    timestamp: None,
+                    write_timestamp: None,
+                })
+                .unwrap();
+        }
+        writer.close().unwrap();
+
+        let tree = Tree::from_file(&data).unwrap();
+        assert_eq!(tree.entries().unwrap().count(), 100);
+        assert!(tree.root_block().unwrap().level <= 7);
+    }
+
+    #[test]
+    fn buffered_output_is_unchanged() {
+        let dir = tempdir().unwrap();
+        let data = dir.as_ref().join("test.data");
+        let options = WriterOptions {
+            block_size: 1024,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_options(&data, options).unwrap();
+        let mut key = 0;
+        for _ in 0..10 {
+            key = write_8kb(&mut writer, key).unwrap();
+        }
+        writer.close().unwrap();
+
+        // Everything up to the end of the root block, which is the last one written.
+        // The length and CRC are those of the file written before the output was
+        // buffered (with the magic since bumped to `HAN4`), the trailer is left out
+        // as the bloom filter encoding may change.
+        let contents = std::fs::read(&data).unwrap();
+        let tree = Tree::from_file(&data).unwrap();
+        let root = tree.root_block().unwrap();
+        let blocks = &contents[..(root.start + 4 + root.blocklen as u64) as usize];
+        assert_eq!(blocks.len(), 85328);
+        assert_eq!(crc32fast::hash(blocks), 0x6b35194a);
+    }
+
     pub fn write_8kb(writer: &mut Writer, mut key: u64) -> Result<u64> {
         let mut written: usize = 0;
         while written < 8192 {
@@ -423,6 +937,7 @@ pub mod tests {
                 key: key.to_be_bytes().to_vec(),
                 value: key.to_be_bytes().to_vec(),
                 timestamp: None,
+                write_timestamp: None,
             };
             let entry_size = entry.encoded_size();
             writer.add(entry)?;
@@ -468,20 +983,25 @@ pub mod tests {
         // pad - 4
         // bloom - X
         // bloom_len - 4
+        // value_count - 8
         // root_pos - 8
-        assert!(contents.len() > 26);
-        assert_eq!(&contents[0..4], "HAN3".as_bytes()); // magic
+        assert!(contents.len() > 34);
+        assert_eq!(&contents[0..4], "HAN4".as_bytes()); // magic
         assert_eq!(&contents[4..8], &[0, 0, 0, 0]); // blocklen = 0
         assert_eq!(&contents[8..10], &[0, 0]); // level = 0
         assert_eq!(&contents[10..14], &[0, 0, 0, 0]); // pad
                                                       // skip bloom filter
         assert_eq!(&contents[contents.len() - 8..], &[0, 0, 0, 0, 0, 0, 0, 4]); // root_pos
+        assert_eq!(
+            &contents[contents.len() - 16..contents.len() - 8],
+            &[0, 0, 0, 0, 0, 0, 0, 0]
+        ); // value_count
         let bloom_len = u32::from_be_bytes(
-            contents[contents.len() - 12..contents.len() - 8]
+            contents[contents.len() - 20..contents.len() - 16]
                 .try_into()
                 .unwrap(),
         );
-        let raw_bloom = &contents[contents.len() - 12 - bloom_len as usize..contents.len() - 12];
+        let raw_bloom = &contents[contents.len() - 20 - bloom_len as usize..contents.len() - 20];
         let _: BloomFilter = postcard::from_bytes(raw_bloom).unwrap();
         let _tree = Tree::from_file(&data).unwrap();
     }
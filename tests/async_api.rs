@@ -0,0 +1,40 @@
+#![cfg(feature = "tokio")]
+
+use std::ops::Bound::*;
+
+use hanoidb::*;
+use tempfile::tempdir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_inserts_and_gets() {
+    let dir = tempdir().unwrap();
+    let db = AsyncHanoiDB::new(
+        OpenOptions::new(&dir)
+            .with_sync_strategy(SyncStrategy::Never)
+            .open()
+            .unwrap(),
+    );
+    let tasks: Vec<_> = (0..4)
+        .map(|task| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..500 {
+                    let key = format!("key-{task}-{i:03}").into_bytes();
+                    db.insert(key.clone(), b"value".to_vec()).await.unwrap();
+                    assert_eq!(db.get(key).await.unwrap(), Some(b"value".to_vec()));
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let range = db
+        .range(Included(b"key-1-".to_vec()), Excluded(b"key-2-".to_vec()))
+        .await
+        .unwrap();
+    assert_eq!(range.len(), 500);
+    db.delete(b"key-1-000".to_vec()).await.unwrap();
+    assert_eq!(db.get(b"key-1-000".to_vec()).await.unwrap(), None);
+}
@@ -0,0 +1,67 @@
+use std::process::{Command, Output};
+
+use tempfile::tempdir;
+
+fn hanoidb(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hanoidb"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn put_get_delete_scan() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path().to_str().unwrap();
+    for (key, value) in [
+        ("fruit:apple", "red"),
+        ("fruit:kiwi", "green"),
+        ("veg:leek", "white"),
+    ] {
+        stdout(&hanoidb(&["put", dir, key, value]));
+    }
+
+    assert_eq!(stdout(&hanoidb(&["get", dir, "fruit:kiwi"])), "green\n");
+    assert_eq!(
+        stdout(&hanoidb(&["scan", dir])),
+        "fruit:apple\tred\nfruit:kiwi\tgreen\nveg:leek\twhite\n"
+    );
+    assert_eq!(
+        stdout(&hanoidb(&["scan", dir, "fruit:"])),
+        "fruit:apple\tred\nfruit:kiwi\tgreen\n"
+    );
+
+    stdout(&hanoidb(&["delete", dir, "fruit:kiwi"]));
+    let missing = hanoidb(&["get", dir, "fruit:kiwi"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(missing.stdout.is_empty());
+}
+
+#[test]
+fn dump_a_data_file() {
+    let dir = tempdir().unwrap();
+    let mut db = hanoidb::HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    let data = dir.path().join("A-10.data");
+    let dump = stdout(&hanoidb(&["dump", data.to_str().unwrap()]));
+    assert!(dump.starts_with("KeyVal"), "{dump}");
+}
+
+#[test]
+fn usage_errors() {
+    let output = hanoidb(&["get"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("usage: hanoidb"));
+}
@@ -0,0 +1,294 @@
+use std::ops::Bound::*;
+
+use hanoidb::*;
+
+fn open() -> HanoiDB {
+    OpenOptions::in_memory().open().unwrap()
+}
+
+#[test]
+fn read_empty_database() {
+    let db = open();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    assert_eq!(db.scan().unwrap().count(), 0);
+    assert_eq!(db.path(), std::path::Path::new(""));
+}
+
+#[test]
+fn insert_and_delete() {
+    let mut db = open();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    db.delete(b"key".to_vec()).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    // Nothing is left behind for the deleted key
+    assert_eq!(db.stats().nursery_entries, 0);
+}
+
+#[test]
+fn delete_at_is_kept() {
+    let mut db = open();
+    db.delete_at(b"key".to_vec(), 200).unwrap();
+    // The tombstone is kept, so that it still wins over an earlier write timestamp
+    db.insert_at(b"key".to_vec(), b"value".to_vec(), 100)
+        .unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    db.insert_at(b"key".to_vec(), b"value".to_vec(), 300)
+        .unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn absorb() {
+    let mut db = open();
+    let mut other = open();
+    db.insert(b"key-1".to_vec(), b"mine".to_vec()).unwrap();
+    db.delete(b"key-2".to_vec()).unwrap();
+    other.insert(b"key-1".to_vec(), b"theirs".to_vec()).unwrap();
+    other.insert(b"key-2".to_vec(), b"theirs".to_vec()).unwrap();
+    other.insert(b"key-3".to_vec(), b"theirs".to_vec()).unwrap();
+
+    db.absorb(&other).unwrap();
+    assert_eq!(db.scan().unwrap().count(), 3);
+    for key in ["key-1", "key-2", "key-3"] {
+        assert_eq!(db.get(key.as_bytes()).unwrap(), Some(b"theirs".to_vec()));
+    }
+}
+
+#[test]
+fn lots_of_entries_stay_in_memory() {
+    let mut db = open();
+    for i in 0..5000 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    db.compact().unwrap();
+    for i in 0..5000 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        assert_eq!(db.get(&key).unwrap(), Some(value), "key {i}");
+    }
+    let stats = db.stats();
+    assert_eq!(stats.nursery_entries, 5000);
+    assert!(stats.levels.is_empty());
+    assert_eq!(db.approximate_len(), 5000);
+    assert_eq!(db.io_counters(), IoCounters::default());
+    assert_eq!(db.plan_compaction().total_merges(), 0);
+}
+
+#[test]
+fn range_scan() {
+    let mut db = open();
+    for i in 0..2048 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    db.delete(b"key-0150".to_vec()).unwrap();
+
+    let keys: Vec<Vec<u8>> = db
+        .range(
+            Included(b"key-0100".to_vec()),
+            Excluded(b"key-0200".to_vec()),
+        )
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = (100..200)
+        .filter(|i| *i != 150)
+        .map(|i| format!("key-{i:04}").into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<Vec<u8>> = db
+        .range_rev(
+            Excluded(b"key-0148".to_vec()),
+            Included(b"key-0152".to_vec()),
+        )
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            b"key-0152".to_vec(),
+            b"key-0151".to_vec(),
+            b"key-0149".to_vec()
+        ]
+    );
+    assert_eq!(db.scan_prefix(b"key-00").unwrap().count(), 100);
+    assert_eq!(
+        db.range_limited(Unbounded, Unbounded, Some(5))
+            .unwrap()
+            .count(),
+        5
+    );
+    assert_eq!(db.first_key().unwrap(), Some(b"key-0000".to_vec()));
+    assert_eq!(db.last_key().unwrap(), Some(b"key-2047".to_vec()));
+}
+
+#[test]
+fn write_batch() {
+    let mut db = open();
+    db.insert(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..50 {
+        batch.insert(
+            format!("key-{i}").into_bytes(),
+            format!("value-{i}").into_bytes(),
+        );
+    }
+    batch.delete(b"deleted".to_vec());
+    db.write(batch).unwrap();
+    for i in 0..50 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        assert_eq!(db.get(&key).unwrap(), Some(value));
+    }
+    assert_eq!(db.get(b"deleted").unwrap(), None);
+}
+
+#[test]
+fn delete_range() {
+    let mut db = open();
+    for i in 0..300 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    let deleted = db
+        .delete_range(Included(b"key-100".to_vec()), Excluded(b"key-200".to_vec()))
+        .unwrap();
+    assert_eq!(deleted, 100);
+    assert_eq!(db.get(b"key-099").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"key-100").unwrap(), None);
+    assert_eq!(db.get(b"key-199").unwrap(), None);
+    assert_eq!(db.scan().unwrap().count(), 200);
+
+    // A key written after the range was deleted is live again
+    db.insert(b"key-150".to_vec(), b"new".to_vec()).unwrap();
+    assert_eq!(db.get(b"key-150").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 201);
+}
+
+#[test]
+fn read_modify_write() {
+    let mut db = open();
+    assert!(db
+        .compare_and_swap(b"key", None, Some(b"old".to_vec()))
+        .unwrap());
+    assert!(!db
+        .compare_and_swap(b"key", Some(b"other"), Some(b"new".to_vec()))
+        .unwrap());
+    assert!(db
+        .compare_and_swap(b"key", Some(b"old"), Some(b"new".to_vec()))
+        .unwrap());
+    assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+    assert!(db.contains_key(b"key").unwrap());
+
+    db.merge_op(b"key".to_vec(), |_| None).unwrap();
+    assert!(!db.contains_key(b"key").unwrap());
+}
+
+#[test]
+fn get_ref_borrows_after_flush() {
+    use std::borrow::Cow;
+
+    let mut db = open();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert!(matches!(
+        db.get_ref(b"key").unwrap(),
+        Some(Cow::Borrowed(b"value"))
+    ));
+}
+
+#[test]
+fn snapshot_isolation() {
+    let mut db = open();
+    for i in 0..1500 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    let snapshot = db.snapshot().unwrap();
+    db.delete(b"key-0000".to_vec()).unwrap();
+    db.insert(b"key-1500".to_vec(), b"new".to_vec()).unwrap();
+
+    assert_eq!(
+        snapshot.get(b"key-0000").unwrap(),
+        Some(b"value-0000".to_vec())
+    );
+    assert_eq!(snapshot.get(b"key-1500").unwrap(), None);
+    assert_eq!(snapshot.scan().unwrap().count(), 1500);
+    assert_eq!(db.scan().unwrap().count(), 1500);
+    assert_eq!(db.get(b"key-0000").unwrap(), None);
+}
+
+#[test]
+fn custom_comparator() {
+    use std::sync::Arc;
+
+    let numeric: Comparator = Arc::new(|a: &[u8], b: &[u8]| {
+        let number = |key: &[u8]| std::str::from_utf8(key).unwrap().parse::<u64>().unwrap();
+        number(a).cmp(&number(b))
+    });
+    let mut db = OpenOptions::in_memory()
+        .with_comparator(numeric)
+        .open()
+        .unwrap();
+    for i in (0..200u64).rev() {
+        db.insert(i.to_string().into_bytes(), i.to_string().into_bytes())
+            .unwrap();
+    }
+    let range: Vec<_> = db
+        .keys_range(Included(b"8".to_vec()), Excluded(b"12".to_vec()))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(range, [&b"8"[..], b"9", b"10", b"11"]);
+    assert_eq!(
+        db.delete_range(Included(b"100".to_vec()), Excluded(b"150".to_vec()))
+            .unwrap(),
+        50
+    );
+    assert_eq!(db.scan().unwrap().count(), 150);
+}
+
+#[test]
+fn typed_db() {
+    let mut db: TypedDB<u64, String> = open().into();
+    for key in [300u64, 2, 1 << 40, 10] {
+        db.insert(&key, &format!("value-{key}")).unwrap();
+    }
+    db.delete(&10).unwrap();
+    let keys: Vec<u64> = db
+        .range(Included(2), Unbounded)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, [2, 300, 1 << 40]);
+}
+
+#[test]
+fn file_operations() {
+    let mut db = open();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert!(db.verify().unwrap().is_ok());
+    assert!(db.verify().unwrap().files.is_empty());
+
+    let dir = tempfile::tempdir().unwrap();
+    let error = db.checkpoint(dir.path().join("checkpoint")).err();
+    assert!(matches!(error, Some(Error::InMemory(_))));
+    let error = db
+        .ingest_sorted([(b"other".to_vec(), b"value".to_vec())].into_iter())
+        .err();
+    assert!(matches!(error, Some(Error::InMemory(_))));
+    assert_eq!(db.get(b"other").unwrap(), None);
+
+    // An in-memory database can still be copied into one on disk
+    let mut disk = HanoiDB::open(&dir).unwrap();
+    disk.absorb(&db).unwrap();
+    assert_eq!(disk.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use tempfile::tempdir;
 
 use hanoidb::*;
@@ -96,6 +98,2592 @@ fn lots_of_entries() {
     }
 }
 
+#[test]
+fn range_scan() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..2048 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    db.delete(b"key-0150".to_vec()).unwrap();
+
+    let keys: Vec<Vec<u8>> = db
+        .range(
+            Included(b"key-0100".to_vec()),
+            Excluded(b"key-0200".to_vec()),
+        )
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = (100..200)
+        .filter(|i| *i != 150)
+        .map(|i| format!("key-{i:04}").into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<Vec<u8>> = db
+        .range(Excluded(b"key-2045".to_vec()), Unbounded)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"key-2046".to_vec(), b"key-2047".to_vec()]);
+
+    let keys: Vec<Vec<u8>> = db
+        .range(Unbounded, Included(b"key-0001".to_vec()))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"key-0000".to_vec(), b"key-0001".to_vec()]);
+}
+
+#[test]
+fn fold_database() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.fold(0, |count, _, _| count + 1).unwrap(), 0);
+
+    let mut expected_xor = 0u8;
+    for i in 0..2048 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        expected_xor = value.iter().fold(expected_xor, |acc, b| acc ^ b);
+        db.insert(key, value).unwrap();
+    }
+    let (count, xor) = db
+        .fold((0, 0u8), |(count, xor), _, value| {
+            (count + 1, value.iter().fold(xor, |acc, b| acc ^ b))
+        })
+        .unwrap();
+    assert_eq!(count, 2048);
+    assert_eq!(xor, expected_xor);
+}
+
+#[test]
+fn reverse_range_scan() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..2048 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    db.delete(b"key-0150".to_vec()).unwrap();
+    db.insert(b"key-0151".to_vec(), b"updated".to_vec())
+        .unwrap();
+
+    let forward: Vec<(Vec<u8>, Vec<u8>)> = db
+        .range(Unbounded, Unbounded)
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    let mut reverse: Vec<(Vec<u8>, Vec<u8>)> = db
+        .range_rev(Unbounded, Unbounded)
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(forward.len(), 2047);
+    reverse.reverse();
+    assert_eq!(forward, reverse);
+
+    let keys: Vec<Vec<u8>> = db
+        .range_rev(
+            Excluded(b"key-0148".to_vec()),
+            Included(b"key-0152".to_vec()),
+        )
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            b"key-0152".to_vec(),
+            b"key-0151".to_vec(),
+            b"key-0149".to_vec()
+        ]
+    );
+}
+
+#[test]
+fn prefix_scan() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for key in [&b"a"[..], b"aa", b"ab", b"b", &[0xFF], &[0xFF, 0x00]] {
+        db.insert(key.to_vec(), b"value".to_vec()).unwrap();
+    }
+
+    let keys: Vec<Vec<u8>> = db
+        .scan_prefix(b"a")
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"a".to_vec(), b"aa".to_vec(), b"ab".to_vec()]);
+
+    let keys: Vec<Vec<u8>> = db
+        .scan_prefix(&[0xFF])
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![vec![0xFF], vec![0xFF, 0x00]]);
+
+    assert_eq!(db.scan_prefix(b"").unwrap().count(), 6);
+    assert_eq!(db.scan_prefix(b"c").unwrap().count(), 0);
+}
+
+#[test]
+fn limited_range_scan() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..10_000 {
+        let key = format!("key-{i:05}").into_bytes();
+        let value = format!("value-{i:05}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    // Tombstones at the start of the range must not count toward the limit
+    for i in 0..3 {
+        db.delete(format!("key-{i:05}").into_bytes()).unwrap();
+    }
+
+    let keys: Vec<Vec<u8>> = db
+        .range_limited(Unbounded, Unbounded, Some(5))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = (3..8).map(|i| format!("key-{i:05}").into_bytes()).collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(
+        db.range_limited(Unbounded, Unbounded, None)
+            .unwrap()
+            .count(),
+        9_997
+    );
+}
+
+#[test]
+fn write_batch_is_recovered() {
+    let dir = tempdir().unwrap();
+    {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        db.insert(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..50 {
+            batch.insert(
+                format!("key-{i}").into_bytes(),
+                format!("value-{i}").into_bytes(),
+            );
+        }
+        batch.delete(b"deleted".to_vec());
+        assert_eq!(batch.len(), 51);
+        db.write(batch).unwrap();
+        // Skip the flush on drop, as if the process crashed
+        db.close_without_flush();
+    }
+    let db = HanoiDB::open(&dir).unwrap();
+    for i in 0..50 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        assert_eq!(db.get(&key).unwrap(), Some(value));
+    }
+    assert_eq!(db.get(b"deleted").unwrap(), None);
+}
+
+#[test]
+fn get_many_matches_get() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..3000 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    for i in (0..3000).step_by(7) {
+        db.delete(format!("key-{i}").into_bytes()).unwrap();
+    }
+
+    // A deterministic shuffle of present, deleted, missing and repeated keys
+    let keys: Vec<Vec<u8>> = (0..500u64)
+        .map(|i| format!("key-{}", (i * 7919) % 3500).into_bytes())
+        .collect();
+    let key_refs: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    let expected: Vec<Option<Vec<u8>>> = key_refs.iter().map(|key| db.get(key).unwrap()).collect();
+    assert_eq!(db.get_many(&key_refs).unwrap(), expected);
+    assert!(expected.iter().any(Option::is_none));
+    assert!(expected.iter().any(Option::is_some));
+}
+
+#[test]
+fn contains_key() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..2048 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    // Push a tombstone down into the levels, and leave another one in the nursery
+    db.delete(b"key-10".to_vec()).unwrap();
+    for i in 2048..3071 {
+        db.insert(format!("pad-{i}").into_bytes(), vec![]).unwrap();
+    }
+    db.delete(b"key-2047".to_vec()).unwrap();
+
+    assert!(db.contains_key(b"key-0").unwrap());
+    assert!(db.contains_key(b"key-2046").unwrap());
+    assert!(!db.contains_key(b"key-10").unwrap());
+    assert!(!db.contains_key(b"key-2047").unwrap());
+    assert!(!db.contains_key(b"missing").unwrap());
+}
+
+#[test]
+fn insert_with_ttl() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let key = String::from("key").into_bytes();
+    let value = String::from("value").into_bytes();
+    db.insert_with_ttl(key.clone(), value.clone(), Duration::from_secs(1))
+        .unwrap();
+    db.insert(b"other".to_vec(), value.clone()).unwrap();
+    assert_eq!(db.get(&key).unwrap(), Some(value.clone()));
+    assert!(db.contains_key(&key).unwrap());
+
+    std::thread::sleep(Duration::from_millis(2100));
+    assert_eq!(db.get(&key).unwrap(), None);
+    assert!(!db.contains_key(&key).unwrap());
+    assert_eq!(db.scan().unwrap().count(), 1);
+
+    // Expiry survives recovery of the nursery log into a level
+    db.close_without_flush();
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(&key).unwrap(), None);
+    assert_eq!(db.get(b"other").unwrap(), Some(value));
+}
+
+#[test]
+fn insert_at() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    // In the nursery, an earlier write timestamp loses to the one it would replace
+    db.insert_at(b"key".to_vec(), b"later".to_vec(), 200)
+        .unwrap();
+    db.insert_at(b"key".to_vec(), b"earlier".to_vec(), 100)
+        .unwrap();
+    db.delete_at(b"key".to_vec(), 150).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"later".to_vec()));
+    db.delete_at(b"deleted".to_vec(), 200).unwrap();
+    db.insert_at(b"deleted".to_vec(), b"earlier".to_vec(), 100)
+        .unwrap();
+    assert_eq!(db.get(b"deleted").unwrap(), None);
+    // Writes without a write timestamp replace whatever is there
+    db.insert(b"deleted".to_vec(), b"plain".to_vec()).unwrap();
+    assert_eq!(db.get(b"deleted").unwrap(), Some(b"plain".to_vec()));
+
+    // The write timestamps survive recovery of the nursery log
+    db.close_without_flush();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"later".to_vec()));
+
+    // The older file has the later write timestamp, and wins the merge
+    db.insert_at(b"merged".to_vec(), b"later".to_vec(), 200)
+        .unwrap();
+    db.flush().unwrap();
+    db.insert_at(b"merged".to_vec(), b"earlier".to_vec(), 100)
+        .unwrap();
+    db.delete_at(b"key".to_vec(), 100).unwrap();
+    db.flush().unwrap();
+    db.compact().unwrap();
+    // Merged into a single entry
+    assert_eq!(db.debug_lookup(b"merged").unwrap().len(), 1);
+    assert_eq!(db.get(b"merged").unwrap(), Some(b"later".to_vec()));
+    assert_eq!(db.get(b"key").unwrap(), Some(b"later".to_vec()));
+}
+
+#[test]
+fn insert_at_before_merge() {
+    use std::io::Read;
+    use std::ops::Bound::*;
+
+    for parallel_get in [false, true] {
+        let dir = tempdir().unwrap();
+        let mut db = OpenOptions::new(&dir)
+            .with_min_level(10)
+            .with_max_level(10)
+            .with_parallel_get(parallel_get)
+            .open()
+            .unwrap();
+        let check = |db: &HanoiDB| {
+            assert_eq!(db.get(b"key").unwrap(), Some(b"later".to_vec()));
+            assert_eq!(db.get(b"deleted").unwrap(), None);
+            assert_eq!(db.get_ref(b"key").unwrap().as_deref(), Some(&b"later"[..]));
+            assert!(!db.contains_key(b"deleted").unwrap());
+            assert_eq!(
+                db.get_many(&[b"deleted", b"key"]).unwrap(),
+                [None, Some(b"later".to_vec())]
+            );
+            let mut value = vec![];
+            let mut reader = db.value_reader(b"key").unwrap().unwrap();
+            reader.read_to_end(&mut value).unwrap();
+            assert_eq!(value, b"later");
+            assert!(db.value_reader(b"deleted").unwrap().is_none());
+
+            let expected = [(b"key".to_vec(), b"later".to_vec())];
+            let entries: Vec<_> = db.scan().unwrap().collect::<Result<_>>().unwrap();
+            assert_eq!(entries, expected);
+            let entries: Vec<_> = db
+                .range_rev(Unbounded, Unbounded)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(entries, expected);
+        };
+
+        // The earlier write timestamps are written after the later ones, into the
+        // nursery, and then into a newer file of the level
+        db.insert_at(b"key".to_vec(), b"later".to_vec(), 300)
+            .unwrap();
+        db.delete_at(b"deleted".to_vec(), 300).unwrap();
+        db.flush().unwrap();
+        db.insert_at(b"key".to_vec(), b"earlier".to_vec(), 100)
+            .unwrap();
+        db.insert_at(b"deleted".to_vec(), b"earlier".to_vec(), 100)
+            .unwrap();
+        assert_eq!(db.debug_lookup(b"key").unwrap().len(), 2);
+        check(&db);
+        db.flush().unwrap();
+        check(&db);
+
+        // The tombstone is kept in the last level, as it still wins over the earlier
+        // writes to come
+        db.compact().unwrap();
+        assert_eq!(db.debug_lookup(b"deleted").unwrap().len(), 1);
+        db.insert_at(b"deleted".to_vec(), b"earliest".to_vec(), 50)
+            .unwrap();
+        db.insert_at(b"key".to_vec(), b"earliest".to_vec(), 50)
+            .unwrap();
+        check(&db);
+    }
+}
+
+#[test]
+fn approximate_len() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.approximate_len(), 0);
+    for i in 0..2000 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    assert_eq!(db.approximate_len(), 2000);
+    // Overwrites are counted again until they are merged away
+    db.insert(b"key-0".to_vec(), b"new value".to_vec()).unwrap();
+    assert!(db.approximate_len() >= 2000);
+}
+
+#[test]
+fn flush_nursery() {
+    let dir = tempdir().unwrap();
+    let log = dir.as_ref().join("nursery.log");
+    {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        // Flushing an empty nursery does nothing
+        db.flush().unwrap();
+        assert!(!dir.as_ref().join("A-10.data").exists());
+
+        for i in 0..10 {
+            let key = format!("key-{i}").into_bytes();
+            let value = format!("value-{i}").into_bytes();
+            db.insert(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(std::fs::metadata(&log).unwrap().len(), 0);
+        assert!(dir.as_ref().join("A-10.data").exists());
+        assert!(!dir.as_ref().join("nursery.data").exists());
+    }
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(std::fs::metadata(&log).unwrap().len(), 0);
+    assert!(!dir.as_ref().join("B-10.data").exists());
+    for i in 0..10 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        assert_eq!(db.get(&key).unwrap(), Some(value));
+    }
+}
+
+#[test]
+fn drop_flushes_nursery() {
+    let dir = tempdir().unwrap();
+    let log = dir.as_ref().join("nursery.log");
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..10 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    drop(db);
+    assert_eq!(std::fs::metadata(&log).unwrap().len(), 0);
+    assert!(dir.as_ref().join("A-10.data").exists());
+
+    let db = HanoiDB::open(&dir).unwrap();
+    for i in 0..10 {
+        let key = format!("key-{i}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        assert_eq!(db.get(&key).unwrap(), Some(value));
+    }
+}
+
+#[test]
+fn checkpoint() {
+    let dir = tempdir().unwrap();
+    let checkpoint_dir = tempdir().unwrap();
+    let checkpoint_dir = checkpoint_dir.path().join("checkpoint");
+    let mut db = HanoiDB::open(&dir).unwrap();
+    // Enough keys to fill a few levels and leave some in the nursery
+    for i in 0..3000 {
+        let key = format!("key-{i:04}").into_bytes();
+        db.insert(key, b"before".to_vec()).unwrap();
+    }
+    db.checkpoint(&checkpoint_dir).unwrap();
+
+    for i in 0..3000 {
+        let key = format!("key-{i:04}").into_bytes();
+        db.insert(key, b"after".to_vec()).unwrap();
+    }
+    db.insert(b"new".to_vec(), b"after".to_vec()).unwrap();
+    db.compact().unwrap();
+
+    let checkpoint = HanoiDB::open(&checkpoint_dir).unwrap();
+    assert_eq!(checkpoint.get(b"new").unwrap(), None);
+    let values: Vec<_> = checkpoint.scan().unwrap().map(|e| e.unwrap().1).collect();
+    assert_eq!(values.len(), 3000);
+    assert!(values.iter().all(|value| value == b"before"));
+    assert_eq!(db.get(b"key-0000").unwrap(), Some(b"after".to_vec()));
+}
+
+#[test]
+fn stream_values() {
+    use std::io::Read;
+
+    fn read(db: &HanoiDB, key: &[u8]) -> Option<Vec<u8>> {
+        db.value_reader(key).unwrap().map(|mut reader| {
+            let mut value = vec![];
+            reader.read_to_end(&mut value).unwrap();
+            value
+        })
+    }
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let large: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    db.insert(b"large".to_vec(), large.clone()).unwrap();
+    db.insert(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.delete(b"deleted".to_vec()).unwrap();
+    db.insert(b"nursery".to_vec(), b"value".to_vec()).unwrap();
+
+    assert!(read(&db, b"large").unwrap() == large);
+    assert_eq!(read(&db, b"nursery"), Some(b"value".to_vec()));
+    assert_eq!(read(&db, b"deleted"), None);
+    assert_eq!(read(&db, b"missing"), None);
+
+    // A tombstone in a newer level file hides the value in an older one
+    db.flush().unwrap();
+    assert_eq!(read(&db, b"deleted"), None);
+}
+
+#[test]
+fn key_prefix_compression() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_key_prefix_compression(true)
+        .open()
+        .unwrap();
+    for i in 0..3000 {
+        let key = format!("users/by-id/{i:06}").into_bytes();
+        let value = format!("value-{i}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    db.compact().unwrap();
+    assert!(db.verify().unwrap().is_ok());
+    assert_eq!(
+        db.get(b"users/by-id/001234").unwrap(),
+        Some(b"value-1234".to_vec())
+    );
+    assert_eq!(db.scan_prefix(b"users/by-id/0029").unwrap().count(), 100);
+    drop(db);
+
+    // Files written with prefix compression are read without the option too
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.scan().unwrap().count(), 3000);
+}
+
+#[test]
+fn bloom_stats() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_bloom_fp_rate(0.05)
+        .open()
+        .unwrap();
+    assert!(db.bloom_stats().trees.is_empty());
+    for i in 0..5000 {
+        let key = format!("key-{i}").into_bytes();
+        db.insert(key, b"value".to_vec()).unwrap();
+    }
+    db.compact().unwrap();
+
+    let stats = db.bloom_stats();
+    assert_eq!(stats.fp_rate, 0.05);
+    assert!(!stats.trees.is_empty());
+    // Every key is in exactly one file after compacting
+    let estimated: u64 = stats.trees.iter().map(|tree| tree.estimated_items).sum();
+    assert!((4750..=5250).contains(&estimated), "estimated {estimated}");
+    for tree in &stats.trees {
+        assert!(tree.path.exists());
+        assert!(tree.num_bits > 0);
+    }
+}
+
+#[test]
+fn tombstones_are_dropped_at_max_level() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_min_level(10)
+        .with_max_level(11)
+        .open()
+        .unwrap();
+    for i in 0..1500 {
+        let key = format!("key-{i:04}").into_bytes();
+        db.insert(key, b"value".to_vec()).unwrap();
+    }
+    db.compact().unwrap();
+    assert!(dir.as_ref().join("A-11.data").exists());
+
+    // The tombstones reach the last level in a second file, which is merged with
+    // the values they delete
+    for i in 0..500 {
+        db.delete(format!("key-{i:04}").into_bytes()).unwrap();
+    }
+    db.delete_range(
+        Included(b"key-1000".to_vec()),
+        Excluded(b"key-1100".to_vec()),
+    )
+    .unwrap();
+    for i in 0..600 {
+        let key = format!("new-{i:04}").into_bytes();
+        db.insert(key, b"value".to_vec()).unwrap();
+    }
+    db.compact().unwrap();
+    assert!(!dir.as_ref().join("B-11.data").exists());
+
+    let deepest = dir.as_ref().join("A-11.data");
+    let entries: Vec<Entry> = dump_file(&deepest)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| !entry.is_pos_len())
+        .collect();
+    assert!(!entries.is_empty());
+    assert!(entries
+        .iter()
+        .all(|entry| !entry.is_deleted() && !entry.is_range_deleted()));
+    assert_eq!(db.get(b"key-0000").unwrap(), None);
+    assert_eq!(db.get(b"key-1050").unwrap(), None);
+    assert_eq!(entries.len(), 1500);
+    assert_eq!(db.scan().unwrap().count(), 1500);
+}
+
+#[test]
+fn debug_lookup() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let value = |value: &str| Entry::key_val(b"key".to_vec(), value.as_bytes().to_vec()).unwrap();
+    assert!(db.debug_lookup(b"key").unwrap().is_empty());
+
+    // Enough other keys that the first value is merged into the second level
+    db.insert(b"key".to_vec(), b"oldest".to_vec()).unwrap();
+    for i in 0..1500 {
+        let key = format!("other-{i}").into_bytes();
+        db.insert(key, b"value".to_vec()).unwrap();
+    }
+    db.compact().unwrap();
+    db.insert(b"key".to_vec(), b"older".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"key".to_vec(), b"newest".to_vec()).unwrap();
+
+    assert_eq!(
+        db.debug_lookup(b"key").unwrap(),
+        [
+            (Source::Nursery, value("newest")),
+            (Source::Level(10), value("older")),
+            (Source::Level(11), value("oldest")),
+        ]
+    );
+    assert_eq!(db.get(b"key").unwrap(), Some(b"newest".to_vec()));
+}
+
+#[test]
+fn custom_comparator() {
+    use std::ops::Bound::*;
+    use std::sync::Arc;
+
+    // Orders decimal keys by their numeric value, so "9" comes before "10"
+    fn numeric() -> Comparator {
+        Arc::new(|a: &[u8], b: &[u8]| {
+            let number = |key: &[u8]| std::str::from_utf8(key).unwrap().parse::<u64>().unwrap();
+            number(a).cmp(&number(b))
+        })
+    }
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_comparator(numeric())
+        .open()
+        .unwrap();
+    for i in (0..2000u64).rev() {
+        db.insert(i.to_string().into_bytes(), i.to_string().into_bytes())
+            .unwrap();
+    }
+    db.compact().unwrap();
+    db.insert(b"2000".to_vec(), b"2000".to_vec()).unwrap();
+    db.delete(b"5".to_vec()).unwrap();
+    assert!(db.verify().unwrap().is_ok());
+
+    let keys: Vec<u64> = db
+        .keys()
+        .unwrap()
+        .map(|key| String::from_utf8(key.unwrap()).unwrap().parse().unwrap())
+        .collect();
+    let expected: Vec<u64> = (0..=2000).filter(|i| *i != 5).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(db.get(b"1999").unwrap(), Some(b"1999".to_vec()));
+    assert_eq!(db.get(b"5").unwrap(), None);
+    assert_eq!(db.first_key().unwrap(), Some(b"0".to_vec()));
+    assert_eq!(db.last_key().unwrap(), Some(b"2000".to_vec()));
+
+    let range: Vec<_> = db
+        .keys_range(Included(b"8".to_vec()), Excluded(b"12".to_vec()))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(range, [&b"8"[..], b"9", b"10", b"11"]);
+    assert_eq!(
+        db.delete_range(Included(b"100".to_vec()), Excluded(b"200".to_vec()))
+            .unwrap(),
+        100
+    );
+    assert_eq!(db.get(b"150").unwrap(), None);
+    assert_eq!(db.get(b"200").unwrap(), Some(b"200".to_vec()));
+    assert_eq!(db.scan_prefix(b"19").unwrap().count(), 101);
+    drop(db);
+
+    let db = OpenOptions::new(&dir)
+        .with_comparator(numeric())
+        .open()
+        .unwrap();
+    assert_eq!(db.scan().unwrap().count(), 2000 - 100);
+    assert_eq!(db.get(b"1000").unwrap(), Some(b"1000".to_vec()));
+}
+
+#[test]
+fn bloom_seed() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_bloom_seed(0x5eed)
+        .open()
+        .unwrap();
+    for i in 0..2000 {
+        db.insert(format!("key-{i}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.compact().unwrap();
+    drop(db);
+
+    // The seed is stored in the files, so they can be read without it
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key-1234").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"key-2000").unwrap(), None);
+    db.insert(b"key-2000".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.scan().unwrap().count(), 2001);
+    assert!(db.verify().unwrap().is_ok());
+}
+
+#[test]
+fn range_size_estimate() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir).open().unwrap();
+    assert_eq!(db.range_size_estimate(b"a", b"z").unwrap(), 0);
+    for i in 0..20000 {
+        let key = format!("key-{i:05}").into_bytes();
+        db.insert(key, vec![b'v'; 100]).unwrap();
+    }
+    db.compact().unwrap();
+
+    let mut last = 0;
+    for end in [1, 10, 100, 1000, 5000, 10000, 20000] {
+        let to = format!("key-{end:05}").into_bytes();
+        let estimate = db.range_size_estimate(b"key-00000", &to).unwrap();
+        assert!(estimate >= last, "{estimate} < {last} for {end} keys");
+        last = estimate;
+    }
+    let whole = db.range_size_estimate(b"", b"l").unwrap();
+    assert_eq!(whole, last);
+    assert!(whole > 20000 * 100);
+    assert!(whole < db.stats().levels.iter().map(LevelStats::total_size).sum());
+    assert_eq!(db.range_size_estimate(b"a", b"b").unwrap(), 0);
+}
+
+#[test]
+fn open_tree_file() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir).open().unwrap();
+    for i in 0..2000 {
+        let key = format!("key-{i:04}").into_bytes();
+        db.insert(key, format!("value-{i}").into_bytes()).unwrap();
+    }
+    db.delete(b"key-0100".to_vec()).unwrap();
+    db.compact().unwrap();
+    drop(db);
+
+    let path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "data"))
+        .unwrap();
+    let tree = Tree::open(&path).unwrap();
+    assert_eq!(tree.get(b"key-1234").unwrap(), Some(b"value-1234".to_vec()));
+    assert_eq!(tree.get(b"key-0100").unwrap(), None);
+    assert_eq!(tree.get(b"key-2000").unwrap(), None);
+
+    let range: Vec<_> = tree
+        .range(
+            Excluded(b"key-0098".to_vec()),
+            Included(b"key-0102".to_vec()),
+        )
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(range, [&b"key-0099"[..], b"key-0101", b"key-0102"]);
+    assert_eq!(tree.range(Unbounded, Unbounded).unwrap().count(), 1999);
+    assert_eq!(
+        tree.range(Included(b"key-1990".to_vec()), Unbounded)
+            .unwrap()
+            .count(),
+        10
+    );
+}
+
+#[test]
+fn iter_streams_nursery() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_min_level(13)
+        .with_sync_strategy(SyncStrategy::Never)
+        .open()
+        .unwrap();
+    for i in (1..2000).step_by(2) {
+        db.insert(format!("key-{i:04}").into_bytes(), b"level".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    for i in (0..4000).step_by(2) {
+        db.insert(format!("key-{i:04}").into_bytes(), b"nursery".to_vec())
+            .unwrap();
+    }
+    db.insert(b"key-0001".to_vec(), b"nursery".to_vec())
+        .unwrap();
+    db.delete(b"key-0003".to_vec()).unwrap();
+    assert!(db.stats().nursery_entries > 2000);
+
+    let mut iter = db.iter().unwrap();
+    let mut seen = vec![];
+    for (key, value) in iter.by_ref().take(10).map(Result::unwrap) {
+        seen.push((String::from_utf8(key).unwrap(), value));
+    }
+    // Writes made while iterating are not seen by the iterator
+    db.insert(b"key-0005a".to_vec(), b"new".to_vec()).unwrap();
+    db.delete(b"key-3998".to_vec()).unwrap();
+    for (key, value) in iter.map(Result::unwrap) {
+        seen.push((String::from_utf8(key).unwrap(), value));
+    }
+
+    let mut expected: Vec<(String, Vec<u8>)> = (0..4000)
+        .filter(|i| *i != 3 && (i % 2 == 0 || *i < 2000))
+        .map(|i| {
+            let value = if i % 2 == 0 || i == 1 {
+                "nursery"
+            } else {
+                "level"
+            };
+            (format!("key-{i:04}"), value.as_bytes().to_vec())
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(seen, expected);
+    assert_eq!(db.get(b"key-0005a").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.get(b"key-3998").unwrap(), None);
+}
+
+#[test]
+fn create_modes() {
+    for create_mode in [
+        CreateMode::OpenOrCreate,
+        CreateMode::CreateNew,
+        CreateMode::Truncate,
+    ] {
+        let dir = tempdir().unwrap();
+        let db = OpenOptions::new(&dir)
+            .with_create_mode(create_mode)
+            .open()
+            .unwrap();
+        assert_eq!(db.scan().unwrap().count(), 0, "{create_mode:?}");
+    }
+
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.as_ref().join("notes.txt"), b"not a database file").unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    let db = OpenOptions::new(&dir)
+        .with_create_mode(CreateMode::OpenOrCreate)
+        .open()
+        .unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    drop(db);
+
+    let result = OpenOptions::new(&dir)
+        .with_create_mode(CreateMode::CreateNew)
+        .open();
+    assert!(matches!(result, Err(Error::DatabaseExists { .. })));
+
+    let db = OpenOptions::new(&dir)
+        .with_create_mode(CreateMode::Truncate)
+        .open()
+        .unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    assert!(dir.as_ref().join("notes.txt").exists());
+}
+
+#[test]
+fn empty_values() {
+    use std::io::Read;
+
+    fn check(db: &HanoiDB, stage: &str) {
+        assert_eq!(db.get(b"empty").unwrap(), Some(vec![]), "{stage}");
+        assert_eq!(db.get(b"missing").unwrap(), None, "{stage}");
+        assert!(db.contains_key(b"empty").unwrap(), "{stage}");
+        assert_eq!(
+            db.get_many(&[b"empty", b"missing"]).unwrap(),
+            [Some(vec![]), None],
+            "{stage}"
+        );
+        let mut value = vec![b'x'];
+        let mut reader = db.value_reader(b"empty").unwrap().unwrap();
+        reader.read_to_end(&mut value).unwrap();
+        assert_eq!(value, b"x", "{stage}");
+        let scanned: Vec<_> = db.scan().unwrap().map(Result::unwrap).collect();
+        assert!(scanned.contains(&(b"empty".to_vec(), vec![])), "{stage}");
+    }
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"empty".to_vec(), vec![]).unwrap();
+    check(&db, "nursery");
+    // Recovered from the nursery log
+    db.close_without_flush();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    check(&db, "log");
+
+    db.flush().unwrap();
+    check(&db, "tree");
+    // Merged with enough other keys to reach the next level
+    for i in 0..1500 {
+        db.insert(format!("other-{i}").into_bytes(), vec![])
+            .unwrap();
+    }
+    db.compact().unwrap();
+    check(&db, "merged");
+    assert!(db
+        .stats()
+        .levels
+        .iter()
+        .any(|level| level.level > 10 && level.file_count() > 0));
+}
+
+#[test]
+fn auto_compact_interval() {
+    fn pending_merges(db: &HanoiDB) -> bool {
+        db.stats()
+            .levels
+            .iter()
+            .any(|level| level.merging || (level.a.is_some() && level.b.is_some()))
+    }
+    let interval = Duration::from_millis(20);
+    let burst = |auto_compact: bool| {
+        let dir = tempdir().unwrap();
+        let mut options = OpenOptions::new(&dir)
+            .with_sync_strategy(SyncStrategy::Never)
+            .with_merge_strategy(MergeStrategy::Predictable)
+            .with_background_merge(true);
+        if auto_compact {
+            options = options.with_auto_compact_interval(interval);
+        }
+        let mut db = options.open().unwrap();
+        for i in 0..5000 {
+            db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        std::thread::sleep(interval * 10);
+        (dir, db)
+    };
+
+    // Without writes, nothing drives the merges left over from the burst
+    let (_dir, db) = burst(false);
+    assert!(pending_merges(&db));
+    drop(db);
+
+    let (_dir, db) = burst(true);
+    let mut waited = interval * 10;
+    while pending_merges(&db) {
+        assert!(waited < Duration::from_secs(10), "merges did not finish");
+        std::thread::sleep(interval);
+        waited += interval;
+    }
+    assert_eq!(db.scan().unwrap().count(), 5000);
+}
+
+#[test]
+fn batched_sync_strategies() {
+    for strategy in [
+        SyncStrategy::Never,
+        SyncStrategy::EveryN(1000),
+        SyncStrategy::Interval(Duration::from_millis(50)),
+    ] {
+        let dir = tempdir().unwrap();
+        {
+            let mut db = OpenOptions::new(&dir)
+                .with_sync_strategy(strategy)
+                .open()
+                .unwrap();
+            for i in 0..1500 {
+                let key = format!("key-{i}").into_bytes();
+                let value = format!("value-{i}").into_bytes();
+                db.insert(key, value).unwrap();
+            }
+        }
+        let db = HanoiDB::open(&dir).unwrap();
+        for i in 0..1500 {
+            let key = format!("key-{i}").into_bytes();
+            let value = format!("value-{i}").into_bytes();
+            assert_eq!(db.get(&key).unwrap(), Some(value), "{strategy:?}");
+        }
+    }
+}
+
+#[test]
+fn destroy_database() {
+    let dir = tempdir().unwrap();
+    let unrelated = dir.as_ref().join("notes.txt");
+    std::fs::write(&unrelated, "keep me").unwrap();
+    {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        for i in 0..2048 {
+            let key = format!("key-{i}").into_bytes();
+            let value = format!("value-{i}").into_bytes();
+            db.insert(key, value).unwrap();
+        }
+        db.insert(b"nursery".to_vec(), b"value".to_vec()).unwrap();
+        // Snapshots taken together get directories of their own
+        let snapshots = [db.snapshot().unwrap(), db.snapshot().unwrap()];
+        assert!(snapshots.iter().all(|s| s.get(b"key-0").unwrap().is_some()));
+    }
+    // The directory of a snapshot left behind by a crash
+    let crashed = dir.as_ref().join("snapshot-1234-0");
+    std::fs::create_dir(&crashed).unwrap();
+    std::fs::write(crashed.join("A-10.data"), b"").unwrap();
+    let result = OpenOptions::new(&dir)
+        .with_create_mode(CreateMode::CreateNew)
+        .open();
+    assert!(matches!(result, Err(Error::DatabaseExists { .. })));
+    HanoiDB::destroy(&dir).unwrap();
+    assert_eq!(ls(&dir), "  notes.txt\n");
+    // Destroying again is a no-op
+    HanoiDB::destroy(&dir).unwrap();
+
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key-0").unwrap(), None);
+    assert_eq!(db.get(b"nursery").unwrap(), None);
+    assert_eq!(db.scan().unwrap().count(), 0);
+}
+
+#[test]
+fn snapshot_isolation() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..1500 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+    let snapshot = db.snapshot().unwrap();
+
+    // Overwrite and delete keys, and write enough to force promotions and merges
+    db.delete(b"key-0000".to_vec()).unwrap();
+    db.insert(b"key-1499".to_vec(), b"new".to_vec()).unwrap();
+    for i in 1500..4000 {
+        let key = format!("key-{i:04}").into_bytes();
+        let value = format!("value-{i:04}").into_bytes();
+        db.insert(key, value).unwrap();
+    }
+
+    assert_eq!(
+        snapshot.get(b"key-0000").unwrap(),
+        Some(b"value-0000".to_vec())
+    );
+    assert_eq!(
+        snapshot.get(b"key-1499").unwrap(),
+        Some(b"value-1499".to_vec())
+    );
+    assert_eq!(snapshot.get(b"key-1500").unwrap(), None);
+    assert_eq!(snapshot.scan().unwrap().count(), 1500);
+    assert_eq!(
+        snapshot
+            .range(Included(b"key-1490".to_vec()), Unbounded)
+            .unwrap()
+            .count(),
+        10
+    );
+    assert_eq!(db.get(b"key-0000").unwrap(), None);
+    assert_eq!(db.get(b"key-1499").unwrap(), Some(b"new".to_vec()));
+
+    drop(snapshot);
+    assert!(!ls(&dir).contains("snapshot-"));
+}
+
+#[test]
+fn scan_reports_corrupted_files() {
+    let dir = tempdir().unwrap();
+    {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        for i in 0..1024 {
+            db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+    }
+    let data = dir.path().join("A-10.data");
+    let mut contents = std::fs::read(&data).unwrap();
+    contents[100] ^= 0xFF;
+    std::fs::write(&data, contents).unwrap();
+
+    let db = HanoiDB::open(&dir).unwrap();
+    let results: Vec<_> = db.scan().unwrap().collect();
+    assert!(results.iter().any(|result| result.is_err()));
+    assert!(db.fold(0, |count, _, _| count + 1).is_err());
+}
+
+#[test]
+fn empty_key() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(vec![], b"empty".to_vec()).unwrap();
+    assert_eq!(db.get(b"").unwrap(), Some(b"empty".to_vec()));
+
+    // Push the empty key through promotion and merges into the levels
+    for i in 0..3000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    assert_eq!(db.get(b"").unwrap(), Some(b"empty".to_vec()));
+    let first = db.scan().unwrap().next().unwrap().unwrap();
+    assert_eq!(first, (vec![], b"empty".to_vec()));
+    let keys: Vec<Vec<u8>> = db
+        .range(Included(vec![]), Excluded(b"key-0002".to_vec()))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(
+        keys,
+        vec![vec![], b"key-0000".to_vec(), b"key-0001".to_vec()]
+    );
+    let keys: Vec<Vec<u8>> = db
+        .range(Excluded(vec![]), Excluded(b"key-0001".to_vec()))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"key-0000".to_vec()]);
+
+    db.delete(vec![]).unwrap();
+    assert_eq!(db.get(b"").unwrap(), None);
+    for i in 3000..5000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    assert_eq!(db.get(b"").unwrap(), None);
+    let first = db.scan().unwrap().next().unwrap().unwrap();
+    assert_eq!(first.0, b"key-0000".to_vec());
+}
+
+#[test]
+fn level_compression() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_level_compression(|level| match level {
+            10 | 11 => Compression::None,
+            _ => Compression::Lz4,
+        })
+        .open()
+        .unwrap();
+    for i in 0..8192 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    // Enough to fill the deeper levels, and a flush puts a file in the first level
+    db.insert(b"last".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    // The compression byte is the last byte of the header of the first block, after
+    // the magic. Which levels hold files depends on how the merges are scheduled, so
+    // every file is checked against the compression of its level.
+    let block_compression = |name: &str| std::fs::read(dir.path().join(name)).unwrap()[10];
+    let mut levels = vec![];
+    for level in db.stats().levels {
+        for (prefix, size) in [("A", level.a), ("B", level.b), ("C", level.c)] {
+            if size.is_none() {
+                continue;
+            }
+            let expected = match level.level {
+                10 | 11 => Compression::None,
+                _ => Compression::Lz4,
+            };
+            let name = format!("{prefix}-{}.data", level.level);
+            assert_eq!(block_compression(&name), expected as u8, "{name}");
+            levels.push(level.level);
+        }
+    }
+    assert!(levels.iter().any(|level| *level <= 11), "{levels:?}");
+    assert!(levels.iter().any(|level| *level >= 12), "{levels:?}");
+}
+
+#[test]
+fn level_compression_of_kept_merges() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_level_compression(|level| match level {
+            10 => Compression::None,
+            _ => Compression::Lz4,
+        })
+        .open()
+        .unwrap();
+    // Writing the same keys twice merges two files of level 10 into one that still
+    // fits the level, so it stays there, and is rewritten without compression
+    for value in [b"old", b"new"] {
+        for i in 0..1024 {
+            db.insert(format!("key-{i:04}").into_bytes(), value.to_vec())
+                .unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.compact().unwrap();
+    let stats = db.stats();
+    assert_eq!(stats.levels[0].file_count(), 1);
+    assert!(stats.levels[1..]
+        .iter()
+        .all(|level| level.file_count() == 0));
+    let block_compression = |name: &str| std::fs::read(dir.path().join(name)).unwrap()[10];
+    assert_eq!(block_compression("A-10.data"), Compression::None as u8);
+    assert_eq!(db.get(b"key-0512").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 1024);
+    assert!(!ls(&dir).contains("R-10.data"));
+}
+
+#[test]
+fn min_fanout() {
+    let dir = tempdir().unwrap();
+    let error = OpenOptions::new(&dir).with_min_fanout(1).open().err();
+    assert!(matches!(error, Some(Error::MinFanoutTooSmall(1))));
+
+    let mut db = OpenOptions::new(&dir)
+        .with_block_size(512)
+        .with_min_fanout(64)
+        .open()
+        .unwrap();
+    for i in 0..3000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.compact().unwrap();
+    assert_eq!(db.get(b"key-1234").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 3000);
+}
+
+#[test]
+fn block_size() {
+    let dir = tempdir().unwrap();
+    let error = OpenOptions::new(&dir).with_block_size(100).open().err();
+    assert!(matches!(error, Some(Error::BlockSizeTooSmall(100))));
+
+    let mut db = OpenOptions::new(&dir).with_block_size(1024).open().unwrap();
+    for i in 0..3000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    assert_eq!(db.get(b"key-1234").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 3000);
+}
+
+#[test]
+fn bloom_fp_rate() {
+    for rate in [0.0, 1.0, f64::NAN] {
+        let dir = tempdir().unwrap();
+        let error = OpenOptions::new(&dir).with_bloom_fp_rate(rate).open().err();
+        assert!(matches!(error, Some(Error::InvalidBloomFpRate(_))));
+    }
+
+    let mut bloom_lens = vec![];
+    for rate in [0.01, 0.001] {
+        let dir = tempdir().unwrap();
+        let mut db = OpenOptions::new(&dir)
+            .with_bloom_fp_rate(rate)
+            .open()
+            .unwrap();
+        for i in 0..1024 {
+            db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        for i in 1024..2048 {
+            assert_eq!(db.get(format!("key-{i:04}").as_bytes()).unwrap(), None);
+        }
+        assert_eq!(db.get(b"key-0512").unwrap(), Some(b"value".to_vec()));
+
+        let contents = std::fs::read(dir.path().join("A-10.data")).unwrap();
+        let bloom_len = &contents[contents.len() - 20..contents.len() - 16];
+        bloom_lens.push(u32::from_be_bytes(bloom_len.try_into().unwrap()));
+    }
+    assert!(
+        bloom_lens[1] > bloom_lens[0],
+        "bloom lengths: {bloom_lens:?}"
+    );
+}
+
+#[test]
+fn predictable_merge_strategy() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_merge_strategy(MergeStrategy::Predictable)
+        .open()
+        .unwrap();
+    for i in 0..10000 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    for i in (0..10000).step_by(7) {
+        assert_eq!(
+            db.get(format!("key-{i:05}").as_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+    assert_eq!(db.scan().unwrap().count(), 10000);
+}
+
+#[test]
+fn background_merge() {
+    let dir = tempdir().unwrap();
+    let open = || {
+        OpenOptions::new(&dir)
+            .with_sync_strategy(SyncStrategy::Never)
+            .with_background_merge(true)
+            .open()
+            .unwrap()
+    };
+    let mut db = open();
+    for i in 0..100_000 {
+        db.insert(format!("key-{i:06}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    for i in (0..100_000).step_by(13) {
+        assert_eq!(
+            db.get(format!("key-{i:06}").as_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+    assert_eq!(db.scan().unwrap().count(), 100_000);
+
+    // Dropping the database waits for the queued merges
+    drop(db);
+    let db = open();
+    for i in (0..100_000).step_by(13) {
+        assert_eq!(
+            db.get(format!("key-{i:06}").as_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+    assert_eq!(db.scan().unwrap().count(), 100_000);
+}
+
+#[test]
+fn stats() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir).open().unwrap();
+    let stats = db.stats();
+    assert_eq!(stats.nursery_entries, 0);
+    assert_eq!(stats.levels.len(), 16);
+    assert!(stats.levels.iter().all(|level| level.file_count() == 0));
+
+    // 6 nursery flushes of 1024 entries move 4096 entries to level 12 and 2048
+    // entries to level 11, and the rest stay in the nursery
+    for i in 0..6244 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    let stats = db.stats();
+    assert_eq!(stats.nursery_entries, 100);
+    assert!(stats.nursery_bytes > 0);
+    let occupied: Vec<u32> = stats
+        .levels
+        .iter()
+        .filter(|level| level.file_count() > 0)
+        .map(|level| level.level)
+        .collect();
+    assert_eq!(occupied, vec![11, 12]);
+    for level in &stats.levels[1..=2] {
+        let on_disk = std::fs::metadata(dir.path().join(format!("A-{}.data", level.level)))
+            .unwrap()
+            .len();
+        assert_eq!(level.a, Some(on_disk));
+        assert_eq!(level.total_size(), on_disk);
+    }
+    assert!(stats.levels[2].total_size() > stats.levels[1].total_size());
+}
+
+#[test]
+fn level_info() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_min_level(8)
+        .with_max_level(14)
+        .open()
+        .unwrap();
+    let levels = db.level_info();
+    assert_eq!(levels.len(), 14 - 8 + 1);
+    assert_eq!(levels[0].level, 8);
+    assert_eq!(levels[0].capacity, 256);
+    for pair in levels.windows(2) {
+        assert_eq!(pair[1].level, pair[0].level + 1);
+        assert_eq!(pair[1].capacity, pair[0].capacity * 2);
+    }
+    assert!(levels
+        .iter()
+        .all(|level| level.files == 0 && level.entries == 0));
+
+    // A flush puts the nursery's entries into the first level
+    for i in 0..100 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    let levels = db.level_info();
+    assert_eq!(levels[0].files, 1);
+    assert_eq!(levels[0].entries, 100);
+}
+
+#[test]
+fn compact() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_sync_strategy(SyncStrategy::Never)
+        .open()
+        .unwrap();
+    for i in 0..10000 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    for i in (0..10000).step_by(3) {
+        db.delete(format!("key-{i:05}").into_bytes()).unwrap();
+    }
+    db.compact().unwrap();
+
+    let files = ls(&dir);
+    assert!(!files.contains("X-"), "{files}");
+    assert!(!files.contains("M-"), "{files}");
+    let stats = db.stats();
+    assert_eq!(stats.nursery_entries, 0);
+    assert!(stats
+        .levels
+        .iter()
+        .all(|level| level.file_count() <= 1 && !level.merging));
+    for i in 0..10000 {
+        let expected = (i % 3 != 0).then(|| b"value".to_vec());
+        assert_eq!(db.get(format!("key-{i:05}").as_bytes()).unwrap(), expected);
+    }
+}
+
+#[test]
+fn shared_handle() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let db = SharedHanoiDB::new(
+        OpenOptions::new(&dir)
+            .with_sync_strategy(SyncStrategy::Never)
+            .open()
+            .unwrap(),
+    );
+    let writers: Vec<_> = (0..2)
+        .map(|writer| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..2000 {
+                    db.insert(
+                        format!("key-{writer}-{i:05}").into_bytes(),
+                        b"value".to_vec(),
+                    )
+                    .unwrap();
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in (0..2000).step_by(20) {
+                    // Keys are written in order, so a later key is only present if
+                    // the earlier ones are
+                    if db
+                        .get(format!("key-0-{i:05}").as_bytes())
+                        .unwrap()
+                        .is_some()
+                    {
+                        for j in (0..i).step_by(97) {
+                            assert!(db
+                                .get(format!("key-0-{j:05}").as_bytes())
+                                .unwrap()
+                                .is_some());
+                        }
+                    }
+                    let scanned = db
+                        .range(Included(b"key-1-".to_vec()), Excluded(b"key-2-".to_vec()))
+                        .unwrap()
+                        .map(|kv| kv.unwrap().0)
+                        .take(10)
+                        .collect::<Vec<_>>();
+                    assert!(scanned.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            })
+        })
+        .collect();
+    for thread in writers.into_iter().chain(readers) {
+        thread.join().unwrap();
+    }
+
+    for writer in 0..2 {
+        for i in 0..2000 {
+            assert_eq!(
+                db.get(format!("key-{writer}-{i:05}").as_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+    }
+    db.delete(b"key-0-00000".to_vec()).unwrap();
+    assert_eq!(db.get(b"key-0-00000").unwrap(), None);
+    assert_eq!(db.read().scan().unwrap().count(), 3999);
+}
+
+#[test]
+fn export_ndjson() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+    db.insert(b"a".to_vec(), vec![0xFB, 0xFF]).unwrap();
+    db.insert(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+    db.delete(b"deleted".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"empty".to_vec(), vec![]).unwrap();
+
+    let mut out = vec![];
+    db.export_ndjson(&mut out).unwrap();
+    let lines: Vec<(String, String)> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let object: serde_json::Value = serde_json::from_str(line).unwrap();
+            (
+                object["key"].as_str().unwrap().to_owned(),
+                object["value"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            ("YQ==".to_owned(), "+/8=".to_owned()),
+            ("ZW1wdHk=".to_owned(), "".to_owned()),
+            ("Zm9v".to_owned(), "YmFy".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn ingest_sorted_level_compression() {
+    let dir = tempdir().unwrap();
+    let level_compression = |level| match level {
+        10 => Compression::None,
+        _ => Compression::Lz4,
+    };
+    let mut db = OpenOptions::new(&dir)
+        .with_level_compression(level_compression)
+        .open()
+        .unwrap();
+    let keys = |prefix: &'static str| {
+        (0..3000).map(move |i| (format!("{prefix}-{i:04}").into_bytes(), b"value".to_vec()))
+    };
+    db.ingest_sorted(keys("sized")).unwrap();
+    // Without a size hint the file is written for the first level, and then
+    // rewritten for the level it goes into
+    let mut unsized_keys = keys("unsized");
+    db.ingest_sorted(std::iter::from_fn(move || unsized_keys.next()))
+        .unwrap();
+    let ls = ls(&dir);
+    assert!(!ls.contains("ingest"), "{ls}");
+
+    // The compression byte is the last byte of the header of the first block
+    let block_compression = |name: &str| std::fs::read(dir.path().join(name)).unwrap()[10];
+    let mut files = 0;
+    for level in db.stats().levels {
+        for (prefix, size) in [("A", level.a), ("B", level.b), ("C", level.c)] {
+            if size.is_some() {
+                let name = format!("{prefix}-{}.data", level.level);
+                let expected = level_compression(level.level);
+                assert_eq!(block_compression(&name), expected as u8, "{name}");
+                assert!(level.level >= 12, "{name}");
+                files += 1;
+            }
+        }
+    }
+    assert!(files > 0);
+    assert_eq!(db.get(b"sized-2999").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"unsized-2999").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn ingest_sorted() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir).open().unwrap();
+    db.insert(b"existing".to_vec(), b"value".to_vec()).unwrap();
+    db.ingest_sorted((0..50_000).map(|i| {
+        (
+            format!("key-{i:05}").into_bytes(),
+            format!("value-{i}").into_bytes(),
+        )
+    }))
+    .unwrap();
+    assert!(!ls(&dir).contains("ingest.data"));
+    assert_eq!(db.get(b"existing").unwrap(), Some(b"value".to_vec()));
+    for i in (0..50_000).step_by(101) {
+        assert_eq!(
+            db.get(format!("key-{i:05}").as_bytes()).unwrap(),
+            Some(format!("value-{i}").into_bytes())
+        );
+    }
+    let range: Vec<_> = db
+        .range(
+            Included(b"key-10000".to_vec()),
+            Excluded(b"key-10100".to_vec()),
+        )
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(range.len(), 100);
+    assert_eq!(range[0].0, b"key-10000");
+    assert_eq!(range[99].0, b"key-10099");
+
+    // Writes after ingesting still work and merge normally
+    for i in 0..3000 {
+        db.insert(format!("new-{i:04}").into_bytes(), b"new".to_vec())
+            .unwrap();
+    }
+    assert_eq!(db.scan().unwrap().count(), 53_001);
+
+    let unsorted = vec![
+        (b"b".to_vec(), b"value".to_vec()),
+        (b"a".to_vec(), b"value".to_vec()),
+    ];
+    assert!(matches!(
+        db.ingest_sorted(unsorted.into_iter()),
+        Err(Error::OutOfOrderWrite)
+    ));
+    let repeated = vec![
+        (b"a".to_vec(), b"value".to_vec()),
+        (b"a".to_vec(), b"value".to_vec()),
+    ];
+    assert!(matches!(
+        db.ingest_sorted(repeated.into_iter()),
+        Err(Error::OutOfOrderWrite)
+    ));
+    assert_eq!(db.get(b"a").unwrap(), None);
+    assert!(!ls(&dir).contains("ingest.data"));
+}
+
+#[test]
+fn verify_database() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir).open().unwrap();
+    // Leaves files in levels 11 and 12, and entries in the nursery log
+    for i in 0..6244 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{report:?}");
+    let names: Vec<_> = report
+        .files
+        .iter()
+        .map(|file| {
+            file.path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    assert_eq!(names, vec!["nursery.log", "A-11.data", "A-12.data"]);
+
+    // Corrupt the compression byte of the first block header in A-11
+    let corrupted = dir.path().join("A-11.data");
+    let mut contents = std::fs::read(&corrupted).unwrap();
+    contents[4 + 6] = 0x7F;
+    std::fs::write(&corrupted, contents).unwrap();
+
+    let report = db.verify().unwrap();
+    assert!(!report.is_ok());
+    let corrupt: Vec<_> = report.corrupt_files().collect();
+    assert_eq!(corrupt.len(), 1);
+    assert_eq!(corrupt[0].path, corrupted);
+    assert!(matches!(
+        corrupt[0].status,
+        Err(Error::CorruptedBlock { pos: 4, .. })
+    ));
+    assert_eq!(report.files.len(), 3);
+}
+
+#[test]
+fn block_cache() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_block_cache_bytes(64 * 1024)
+        .open()
+        .unwrap();
+    for i in 0..5000 {
+        db.insert(format!("key-{i:05}").into_bytes(), b"value".to_vec())
+            .unwrap();
+        // Read keys back while files are merged and replaced
+        if i % 100 == 99 {
+            for j in (0..i).step_by(37) {
+                assert_eq!(
+                    db.get(format!("key-{j:05}").as_bytes()).unwrap(),
+                    Some(b"value".to_vec())
+                );
+            }
+        }
+    }
+    db.delete(b"key-00000".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.get(b"key-00000").unwrap(), None);
+    assert_eq!(db.get(b"key-00001").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn parallel_get() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_parallel_get(true)
+        .open()
+        .unwrap();
+    // Ingesting puts the oldest version of the key in a large level, where it isn't
+    // merged with the newer versions written to the first level
+    let fill = (0..5000).map(|i| (format!("fill-{i:04}").into_bytes(), b"fill".to_vec()));
+    db.ingest_sorted(fill.chain([(b"key".to_vec(), b"oldest".to_vec())]))
+        .unwrap();
+    db.insert(b"key".to_vec(), b"newest".to_vec()).unwrap();
+    db.flush().unwrap();
+    let occupied: Vec<u32> = db
+        .stats()
+        .levels
+        .iter()
+        .filter(|level| level.file_count() > 0)
+        .map(|level| level.level)
+        .collect();
+    assert_eq!(occupied, vec![10, 13]);
+    assert_eq!(db.get(b"key").unwrap(), Some(b"newest".to_vec()));
+    assert_eq!(db.get(b"fill-0000").unwrap(), Some(b"fill".to_vec()));
+    assert_eq!(db.get(b"missing").unwrap(), None);
+
+    // A newer tombstone wins over all the older values
+    db.delete(b"key".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    drop(db);
+
+    // The sequential lookup agrees
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    assert_eq!(db.get(b"fill-4999").unwrap(), Some(b"fill".to_vec()));
+}
+
+#[test]
+fn nursery_max_bytes() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_nursery_max_bytes(256 * 1024)
+        .open()
+        .unwrap();
+    for i in 0..100 {
+        db.insert(format!("key-{i:03}").into_bytes(), vec![b'x'; 16 * 1024])
+            .unwrap();
+    }
+    let stats = db.stats();
+    assert!(stats.nursery_entries < 100);
+    assert!(stats.nursery_bytes <= 256 * 1024);
+    assert!(stats.levels.iter().any(|level| level.file_count() > 0));
+    assert_eq!(db.get(b"key-000").unwrap(), Some(vec![b'x'; 16 * 1024]));
+    assert_eq!(db.scan().unwrap().count(), 100);
+}
+
+#[test]
+fn framed_nursery_log() {
+    let dir = tempdir().unwrap();
+    {
+        let mut db = OpenOptions::new(&dir)
+            .with_nursery_log_framing(true)
+            .open()
+            .unwrap();
+        db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(db.verify().unwrap().is_ok());
+        db.close_without_flush();
+    }
+    // Framing only affects how the log is written, so it can be recovered either way
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn small_recovered_log_stays_in_nursery() {
+    let dir = tempdir().unwrap();
+    let open = || {
+        OpenOptions::new(&dir)
+            .with_recover_promote_threshold(10)
+            .open()
+            .unwrap()
+    };
+    let mut db = open();
+    for i in 0..3 {
+        db.insert(format!("key-{i}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.close_without_flush();
+
+    let db = open();
+    let stats = db.stats();
+    assert_eq!(stats.nursery_entries, 3);
+    assert!(stats.levels.iter().all(|level| level.file_count() == 0));
+    assert_eq!(db.get(b"key-2").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn first_and_last_keys() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.first_key().unwrap(), None);
+    assert_eq!(db.last_key().unwrap(), None);
+
+    // Enough keys to spread across the nursery and several levels
+    for i in 1000..4200 {
+        db.insert(format!("key-{i}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    assert!(
+        db.stats()
+            .levels
+            .iter()
+            .filter(|l| l.file_count() > 0)
+            .count()
+            > 1
+    );
+    assert_eq!(db.first_key().unwrap(), Some(b"key-1000".to_vec()));
+    assert_eq!(db.last_key().unwrap(), Some(b"key-4199".to_vec()));
+
+    db.insert(b"key-0500".to_vec(), b"value".to_vec()).unwrap();
+    assert_eq!(db.first_key().unwrap(), Some(b"key-0500".to_vec()));
+
+    // Tombstones in the nursery hide the keys in the levels
+    for key in ["key-0500", "key-1000", "key-1001", "key-4199"] {
+        db.delete(key.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(db.first_key().unwrap(), Some(b"key-1002".to_vec()));
+    assert_eq!(db.last_key().unwrap(), Some(b"key-4198".to_vec()));
+}
+
+#[test]
+fn merge_op_counter() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let increment = |value: Option<&[u8]>| {
+        let count = value.map_or(0, |value| u64::from_be_bytes(value.try_into().unwrap()));
+        Some((count + 1).to_be_bytes().to_vec())
+    };
+    for _ in 0..5 {
+        db.merge_op(b"counter".to_vec(), increment).unwrap();
+    }
+    assert_eq!(
+        db.get(b"counter").unwrap(),
+        Some(5u64.to_be_bytes().to_vec())
+    );
+
+    db.merge_op(b"counter".to_vec(), |_| None).unwrap();
+    assert_eq!(db.get(b"counter").unwrap(), None);
+    db.merge_op(b"counter".to_vec(), increment).unwrap();
+    assert_eq!(
+        db.get(b"counter").unwrap(),
+        Some(1u64.to_be_bytes().to_vec())
+    );
+}
+
+#[test]
+fn compare_and_swap_absent_to_present() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert!(db
+        .compare_and_swap(b"key", None, Some(b"value".to_vec()))
+        .unwrap());
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn compare_and_swap_present_to_new() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"old".to_vec()).unwrap();
+    assert!(db
+        .compare_and_swap(b"key", Some(b"old"), Some(b"new".to_vec()))
+        .unwrap());
+    assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn compare_and_swap_present_to_deleted() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"old".to_vec()).unwrap();
+    assert!(db.compare_and_swap(b"key", Some(b"old"), None).unwrap());
+    assert_eq!(db.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn compare_and_swap_mismatch() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"old".to_vec()).unwrap();
+    assert!(!db
+        .compare_and_swap(b"key", Some(b"other"), Some(b"new".to_vec()))
+        .unwrap());
+    assert!(!db
+        .compare_and_swap(b"key", None, Some(b"new".to_vec()))
+        .unwrap());
+    assert!(!db.compare_and_swap(b"missing", Some(b"old"), None).unwrap());
+    assert_eq!(db.get(b"key").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(db.get(b"missing").unwrap(), None);
+}
+
+#[test]
+fn get_ref_borrows_from_nursery() {
+    use std::borrow::Cow;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.insert(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+    db.delete(b"deleted".to_vec()).unwrap();
+    assert!(matches!(
+        db.get_ref(b"key").unwrap(),
+        Some(Cow::Borrowed(b"value"))
+    ));
+    assert_eq!(db.get_ref(b"deleted").unwrap(), None);
+    assert_eq!(db.get_ref(b"missing").unwrap(), None);
+
+    // Once flushed out of the nursery, the value has to be read into a new buffer
+    db.flush().unwrap();
+    assert!(matches!(
+        db.get_ref(b"key").unwrap(),
+        Some(Cow::Owned(value)) if value == b"value"
+    ));
+}
+
+#[test]
+fn typed_db_range_in_numeric_order() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db: TypedDB<u64, String> = HanoiDB::open(&dir).unwrap().into();
+    // Inserted out of order, and spanning byte lengths that would sort wrongly as
+    // decimal strings
+    for key in [300u64, 2, 1 << 40, 10, 256, 1] {
+        db.insert(&key, &format!("value-{key}")).unwrap();
+    }
+    assert_eq!(db.get(&256).unwrap(), Some("value-256".to_string()));
+    db.delete(&10).unwrap();
+    assert_eq!(db.get(&10).unwrap(), None);
+
+    let keys: Vec<u64> = db
+        .range(Included(2), Unbounded)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, [2, 256, 300, 1 << 40]);
+    let keys: Vec<u64> = db
+        .range(Unbounded, Excluded(300))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, [1, 2, 256]);
+}
+
+#[test]
+fn merge_observer() {
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let observer_events = events.clone();
+    let mut db = OpenOptions::new(&dir)
+        .with_merge_observer(Arc::new(move |event| {
+            observer_events.lock().unwrap().push(event)
+        }))
+        .open()
+        .unwrap();
+    // Four nursery promotions into level 10, merged into two files for level 11,
+    // which are merged into one for level 12
+    for i in 0..4096 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.compact().unwrap();
+
+    let events = events.lock().unwrap();
+    for level in [10, 11] {
+        let level_events = events.iter().filter(|event| event.level == level);
+        let steps: usize = level_events.clone().map(|event| event.steps).sum();
+        let merged: usize = level_events
+            .filter(|event| event.complete)
+            .map(|event| event.entries_merged)
+            .sum();
+        assert_eq!(steps, 4096, "level {level}");
+        assert_eq!(merged, 4096, "level {level}");
+    }
+    assert!(events.iter().all(|event| event.level <= 11));
+}
+
+#[test]
+fn plan_compaction() {
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let steps = Arc::new(Mutex::new(vec![]));
+    let observer_steps = steps.clone();
+    let mut db = OpenOptions::new(&dir)
+        .with_merge_observer(Arc::new(move |event| {
+            observer_steps.lock().unwrap().push(event.steps)
+        }))
+        .open()
+        .unwrap();
+    assert_eq!(db.plan_compaction().total_merges(), 0);
+    // Leaves a file in level 10 for the part-filled nursery to be merged with
+    for i in 0..5620 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    let plan = db.plan_compaction();
+    assert_eq!(plan, db.plan_compaction());
+    assert_eq!(plan.levels.first().unwrap().level, 10);
+    assert!(plan.total_merges() > 0);
+    let before: usize = steps.lock().unwrap().len();
+    db.compact().unwrap();
+
+    let taken: usize = steps.lock().unwrap()[before..].iter().sum();
+    assert_eq!(plan.total_steps(), taken as u64);
+    assert_eq!(db.plan_compaction().total_merges(), 0);
+}
+
+#[test]
+fn plan_compaction_with_two_levels() {
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let steps = Arc::new(Mutex::new(vec![]));
+    let observer_steps = steps.clone();
+    let mut db = OpenOptions::new(&dir)
+        .with_min_level(10)
+        .with_max_level(11)
+        .with_merge_observer(Arc::new(move |event| {
+            observer_steps.lock().unwrap().push(event.steps)
+        }))
+        .open()
+        .unwrap();
+    // The last level's merges grow past its level size, and are kept there
+    for i in 0..6000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    let plan = db.plan_compaction();
+    assert_eq!(plan.levels.len(), 2);
+    let before: usize = steps.lock().unwrap().len();
+    db.compact().unwrap();
+
+    let taken: usize = steps.lock().unwrap()[before..].iter().sum();
+    assert_eq!(plan.total_steps(), taken as u64);
+    assert_eq!(db.plan_compaction().total_merges(), 0);
+    assert!(!dir.as_ref().join("A-12.data").exists());
+    assert_eq!(db.scan().unwrap().count(), 6000);
+    assert_eq!(db.get(b"key-5999").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn delete_range() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..300 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    // Already deleted keys are not counted again
+    db.delete(b"key-150".to_vec()).unwrap();
+
+    let deleted = db
+        .delete_range(Included(b"key-100".to_vec()), Excluded(b"key-200".to_vec()))
+        .unwrap();
+    assert_eq!(deleted, 99);
+    assert_eq!(db.get(b"key-099").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"key-100").unwrap(), None);
+    assert_eq!(db.get(b"key-199").unwrap(), None);
+    assert_eq!(db.get(b"key-200").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 200);
+}
+
+#[test]
+fn range_tombstone_shadows_older_levels() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let check = |db: &HanoiDB| {
+        let keys: Vec<Vec<u8>> = db.scan().unwrap().map(|kv| kv.unwrap().0).collect();
+        let mut expected: Vec<Vec<u8>> = (0..2000)
+            .filter(|i| !(500..1500).contains(i) || *i == 1000)
+            .map(|i| format!("key-{i:04}").into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        expected.reverse();
+        let reversed: Vec<Vec<u8>> = db
+            .range_rev(Unbounded, Unbounded)
+            .unwrap()
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        assert_eq!(reversed, expected);
+        assert_eq!(db.get(b"key-0499").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"key-0500").unwrap(), None);
+        assert_eq!(db.get(b"key-1000").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"key-1499").unwrap(), None);
+        assert_eq!(db.get(b"key-1500").unwrap(), Some(b"old".to_vec()));
+    };
+
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..2000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"old".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    let deleted = db
+        .delete_range(
+            Included(b"key-0500".to_vec()),
+            Excluded(b"key-1500".to_vec()),
+        )
+        .unwrap();
+    assert_eq!(deleted, 1000);
+    // A key written after the range tombstone is live again
+    db.insert(b"key-1000".to_vec(), b"new".to_vec()).unwrap();
+    check(&db);
+
+    // The range tombstone is recovered from the nursery log
+    db.close_without_flush();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    check(&db);
+
+    db.flush().unwrap();
+    check(&db);
+    db.compact().unwrap();
+    check(&db);
+    drop(db);
+
+    let db = HanoiDB::open(&dir).unwrap();
+    check(&db);
+}
+
+#[test]
+fn newest_value_wins_across_levels_and_files() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let check = |db: &HanoiDB, expected: &[u8]| {
+        let expected = Some(expected.to_vec());
+        assert_eq!(db.get(b"key-1000").unwrap(), expected);
+        let find = |entries: Vec<(Vec<u8>, Vec<u8>)>| {
+            let found: Vec<_> = entries
+                .into_iter()
+                .filter(|(key, _)| key == b"key-1000")
+                .collect();
+            assert_eq!(found.len(), 1);
+            found.into_iter().next().map(|(_, value)| value)
+        };
+        let forward = db.scan().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(find(forward), expected);
+        let reverse = db
+            .range_rev(Unbounded, Unbounded)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(find(reverse), expected);
+    };
+
+    // Too many keys for level 10, so they go into level 11
+    db.ingest_sorted((0..2000).map(|i| (format!("key-{i:04}").into_bytes(), b"level-11".to_vec())))
+        .unwrap();
+    check(&db, b"level-11");
+    // Then into files A, B and C of level 10, and finally the nursery
+    for file in ["A", "B", "C"] {
+        db.insert(b"key-1000".to_vec(), file.as_bytes().to_vec())
+            .unwrap();
+        db.flush().unwrap();
+        check(&db, file.as_bytes());
+    }
+    db.insert(b"key-1000".to_vec(), b"nursery".to_vec())
+        .unwrap();
+    check(&db, b"nursery");
+
+    let sources: Vec<Source> = db
+        .debug_lookup(b"key-1000")
+        .unwrap()
+        .into_iter()
+        .map(|(source, _)| source)
+        .collect();
+    assert_eq!(
+        sources,
+        [
+            Source::Nursery,
+            Source::Level(10),
+            Source::Level(10),
+            Source::Level(10),
+            Source::Level(11)
+        ]
+    );
+}
+
+#[test]
+fn keys_skip_deleted() {
+    use std::ops::Bound::*;
+
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let mut live = std::collections::BTreeSet::new();
+    for i in (0..1500).rev() {
+        let key = format!("key-{i:04}").into_bytes();
+        db.insert(key.clone(), b"value".to_vec()).unwrap();
+        live.insert(key);
+    }
+    for i in (0..1500).step_by(7) {
+        let key = format!("key-{i:04}").into_bytes();
+        db.delete(key.clone()).unwrap();
+        live.remove(&key);
+    }
+
+    let keys: Vec<Vec<u8>> = db.keys().unwrap().map(|key| key.unwrap()).collect();
+    assert_eq!(keys, live.iter().cloned().collect::<Vec<_>>());
+    let keys: Vec<Vec<u8>> = db
+        .keys_range(
+            Included(b"key-0100".to_vec()),
+            Excluded(b"key-0110".to_vec()),
+        )
+        .unwrap()
+        .map(|key| key.unwrap())
+        .collect();
+    assert_eq!(
+        keys,
+        live.range(b"key-0100".to_vec()..b"key-0110".to_vec())
+            .cloned()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn open_with_empty_nursery_log() {
+    let dir = tempdir().unwrap();
+    std::fs::File::create(dir.path().join("nursery.log")).unwrap();
+
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), None);
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    drop(db);
+
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn recover_merged_file() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    for i in 0..100 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+    // As if the database crashed just after a merge of level 10 into M-10.data
+    std::fs::rename(dir.path().join("A-10.data"), dir.path().join("M-10.data")).unwrap();
+
+    let db = HanoiDB::open(&dir).unwrap();
+    assert!(dir.path().join("A-10.data").exists());
+    assert!(!dir.path().join("M-10.data").exists());
+    assert_eq!(db.get(b"key-042").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.scan().unwrap().count(), 100);
+}
+
+#[test]
+fn bad_level_file_is_named() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    drop(db);
+    let bogus = dir.path().join("B-10.data");
+    std::fs::write(
+        &bogus,
+        b"not a tree file, just some bytes that are long enough",
+    )
+    .unwrap();
+
+    let Err(err) = HanoiDB::open(&dir) else {
+        panic!("opened a database with a bogus level file");
+    };
+    assert!(matches!(&err, Error::BadLevelFile { path, .. } if *path == bogus));
+    assert!(err.to_string().contains("B-10.data"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn max_open_files() {
+    let dir = tempdir().unwrap();
+    let mut db = OpenOptions::new(&dir)
+        .with_max_open_files(3)
+        .open()
+        .unwrap();
+    for i in 0..5000 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    // The tree files of the database and its scans that this process has open
+    let open_tree_files = || {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| std::fs::read_link(fd.unwrap().path()).ok())
+            .filter(|target| {
+                target.starts_with(dir.path()) && !target.to_string_lossy().contains("nursery")
+            })
+            .count()
+    };
+
+    let mut scans: Vec<_> = (0..16).map(|_| db.scan().unwrap()).collect();
+    for _ in 0..100 {
+        for scan in scans.iter_mut() {
+            scan.next().unwrap().unwrap();
+        }
+        assert!(open_tree_files() <= 3);
+    }
+    for scan in scans {
+        assert_eq!(scan.count(), 4900);
+    }
+    assert_eq!(db.get(b"key-1234").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn temp_dir() {
+    // A tmpfs, where there is one, is on another filesystem, so files are copied
+    let mut temp_dirs = vec![tempdir().unwrap()];
+    if std::path::Path::new("/dev/shm").is_dir() {
+        temp_dirs.push(tempfile::tempdir_in("/dev/shm").unwrap());
+    }
+    for temp_dir in temp_dirs {
+        let dir = tempdir().unwrap();
+        let mut db = OpenOptions::new(&dir)
+            .with_temp_dir(temp_dir.path().to_path_buf())
+            .open()
+            .unwrap();
+        for i in 0..3000 {
+            db.insert(format!("key-{i:04}").into_bytes(), b"old".to_vec())
+                .unwrap();
+        }
+        // Links for the scan and the snapshot are made in the temp dir
+        let snapshot = db.snapshot().unwrap();
+        let mut scan = db.scan().unwrap();
+        assert_eq!(scan.next().unwrap().unwrap().0, b"key-0000");
+        assert!(ls(&temp_dir).contains(".scan-"));
+        assert!(ls(&temp_dir).contains("snapshot-"));
+        assert!(!ls(&dir).contains("scan-"));
+        assert!(!ls(&dir).contains("snapshot-"));
+
+        for i in 0..3000 {
+            db.insert(format!("key-{i:04}").into_bytes(), b"new".to_vec())
+                .unwrap();
+        }
+        db.compact().unwrap();
+        assert!(db.io_counters().merges_run > 0);
+        assert_eq!(scan.count(), 2999);
+        assert_eq!(snapshot.get(b"key-1234").unwrap(), Some(b"old".to_vec()));
+        drop(snapshot);
+        assert_eq!(ls(&temp_dir), "");
+        for i in (0..3000).step_by(7) {
+            let key = format!("key-{i:04}").into_bytes();
+            assert_eq!(db.get(&key).unwrap(), Some(b"new".to_vec()));
+        }
+        drop(db);
+
+        let db = HanoiDB::open(&dir).unwrap();
+        assert!(db.scan().unwrap().all(|entry| entry.unwrap().1 == b"new"));
+        assert_eq!(db.scan().unwrap().count(), 3000);
+    }
+}
+
+#[test]
+fn failed_background_merge() {
+    let dir = tempdir().unwrap();
+    // Merges can't create their output in a missing temp dir
+    let mut db = OpenOptions::new(&dir)
+        .with_background_merge(true)
+        .with_temp_dir(dir.path().join("missing"))
+        .open()
+        .unwrap();
+    let mut errors = vec![];
+    for i in 0..5000 {
+        if let Err(err) = db.insert(format!("key-{i:04}").into_bytes(), b"value".to_vec()) {
+            errors.push(err);
+            if errors.len() == 2 {
+                break;
+            }
+        }
+    }
+    // The first write after the merge failed reports why, and later ones that need
+    // merges report that the merges stopped
+    assert!(
+        matches!(errors[..], [Error::Io(_), Error::MergeWorkerStopped]),
+        "{errors:?}"
+    );
+    assert_eq!(db.get(b"key-0000").unwrap(), Some(b"value".to_vec()));
+    let written = db.approximate_len();
+
+    // Closing skips the flush rather than failing, and the log still has the writes
+    drop(db);
+    let db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.scan().unwrap().count() as u64, written);
+}
+
+#[test]
+fn absorb() {
+    let dir = tempdir().unwrap();
+    let other_dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let mut other = HanoiDB::open(&other_dir).unwrap();
+    for i in 0..1500 {
+        db.insert(format!("key-{i:04}").into_bytes(), b"mine".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    // Overlapping keys, one of which is only in the nursery
+    db.insert(b"key-1499".to_vec(), b"mine".to_vec()).unwrap();
+    for i in 1000..2500 {
+        other
+            .insert(format!("key-{i:04}").into_bytes(), b"theirs".to_vec())
+            .unwrap();
+    }
+    other.delete(b"key-2000".to_vec()).unwrap();
+
+    db.absorb(&other).unwrap();
+    assert_eq!(db.get(b"key-0999").unwrap(), Some(b"mine".to_vec()));
+    assert_eq!(db.get(b"key-1000").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(db.get(b"key-1499").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(db.get(b"key-1500").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(db.get(b"key-2000").unwrap(), None);
+    assert_eq!(db.scan().unwrap().count(), 2499);
+    let theirs = db
+        .scan()
+        .unwrap()
+        .filter(|kv| kv.as_ref().unwrap().1 == b"theirs")
+        .count();
+    assert_eq!(theirs, 1499);
+}
+
+#[test]
+fn absorb_deleted_keys() {
+    let dir = tempdir().unwrap();
+    let other_dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let mut other = HanoiDB::open(&other_dir).unwrap();
+    db.insert(b"key-1".to_vec(), b"mine".to_vec()).unwrap();
+    db.delete(b"key-1".to_vec()).unwrap();
+    db.delete(b"key-2".to_vec()).unwrap();
+    db.flush().unwrap();
+    // Tombstones in the nursery too
+    db.delete(b"key-3".to_vec()).unwrap();
+    for key in ["key-1", "key-2", "key-3"] {
+        other.insert(key.into(), b"theirs".to_vec()).unwrap();
+    }
+
+    db.absorb(&other).unwrap();
+    for key in ["key-1", "key-2", "key-3"] {
+        assert_eq!(db.get(key.as_bytes()).unwrap(), Some(b"theirs".to_vec()));
+    }
+    assert_eq!(db.scan().unwrap().count(), 3);
+}
+
+#[test]
+fn io_counters() {
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    assert_eq!(db.io_counters(), IoCounters::default());
+    for i in 0..100 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    assert_eq!(db.io_counters().entries_written, 100);
+
+    // The flushed file is a single leaf block
+    let before = db.io_counters();
+    assert_eq!(db.get(b"key-050").unwrap(), Some(b"value".to_vec()));
+    let after = db.io_counters();
+    assert_eq!(after.blocks_read, before.blocks_read + 1);
+    assert!(after.bytes_read > before.bytes_read);
+
+    for i in 100..200 {
+        db.insert(format!("key-{i:03}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.compact().unwrap();
+    let counters = db.io_counters();
+    assert_eq!(counters.merges_run, 1);
+    // Both flushes, then the merge of the two files
+    assert_eq!(counters.entries_written, 400);
+}
+
+#[test]
+fn read_legacy_tree_file() {
+    use std::ops::Bound::*;
+    // Written like Erlang hanoidb does: two leaves, the second one gzipped, and
+    // `key-03` and `key-07` deleted
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/legacy-han2.data"
+    );
+    assert!(matches!(
+        Tree::from_file(path),
+        Err(Error::InvalidTreeFormat(magic)) if magic == b"HAN2"
+    ));
+    let tree = Tree::from_legacy_file(path).unwrap();
+    assert_eq!(tree.value_count(), Some(8));
+    assert_eq!(tree.get(b"key-00").unwrap(), Some(b"value-00".to_vec()));
+    assert_eq!(tree.get(b"key-05").unwrap(), Some(b"value-05".to_vec()));
+    assert_eq!(tree.get(b"key-09").unwrap(), Some(b"value-09".to_vec()));
+    assert_eq!(tree.get(b"key-03").unwrap(), None);
+    assert_eq!(tree.get(b"key-10").unwrap(), None);
+    assert!(tree.get_entry(b"key-07").unwrap().unwrap().is_deleted());
+    let keys: Vec<Vec<u8>> = tree
+        .range(Unbounded, Unbounded)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = [0, 1, 2, 4, 5, 6, 8, 9]
+        .iter()
+        .map(|i| format!("key-{i:02}").into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+    assert_eq!(tree.verify().unwrap().entries, 10);
+
+    // The legacy keys can be loaded into a database
+    let dir = tempdir().unwrap();
+    let mut db = HanoiDB::open(&dir).unwrap();
+    let entries = tree.range(Unbounded, Unbounded).unwrap();
+    db.ingest_sorted(entries.map(Result::unwrap)).unwrap();
+    assert_eq!(db.get(b"key-06").unwrap(), Some(b"value-06".to_vec()));
+    assert_eq!(db.get(b"key-07").unwrap(), None);
+}
+
+#[test]
+fn open_han3_database() {
+    // Written before tree trailers held a value count: 16 keys flushed to `A-4`,
+    // then `key-16` to `key-19` and the deletes of `key-03` and `key-18` left in
+    // the nursery log
+    let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/han3-db");
+    let dir = tempdir().unwrap();
+    for file in ["A-4.data", "nursery.log"] {
+        std::fs::copy(format!("{fixture}/{file}"), dir.path().join(file)).unwrap();
+    }
+    let tree = Tree::from_file(dir.path().join("A-4.data")).unwrap();
+    assert_eq!(tree.value_count(), None);
+    assert_eq!(tree.verify().unwrap().entries, 16);
+    drop(tree);
+
+    let mut db = OpenOptions::new(&dir)
+        .with_min_level(4)
+        .with_max_level(8)
+        .open()
+        .unwrap();
+    let expected: Vec<Vec<u8>> = (0..20)
+        .filter(|i| ![3, 18].contains(i))
+        .map(|i| format!("key-{i:02}").into_bytes())
+        .collect();
+    let keys: Vec<Vec<u8>> = db.keys().unwrap().map(Result::unwrap).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(db.get(b"key-05").unwrap(), Some(b"value-05".to_vec()));
+    assert_eq!(db.get(b"key-03").unwrap(), None);
+
+    // Merging the old file writes a new one with a count
+    for i in 20..40 {
+        db.insert(format!("key-{i:02}").into_bytes(), b"value".to_vec())
+            .unwrap();
+    }
+    db.compact().unwrap();
+    assert_eq!(db.keys().unwrap().count(), 38);
+    assert_eq!(db.approximate_len(), 38);
+    assert!(db.verify().unwrap().is_ok());
+}
+
 fn ls(path: impl AsRef<std::path::Path>) -> String {
     std::fs::read_dir(path)
         .unwrap()
@@ -0,0 +1,91 @@
+#![cfg(feature = "tracing")]
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use hanoidb::*;
+
+/// Collects everything the subscriber writes, so the events can be checked.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn lines(&self) -> Vec<String> {
+        let output = self.0.lock().unwrap();
+        String::from_utf8_lossy(&output)
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+fn with_output(f: impl FnOnce()) -> Vec<String> {
+    let output = Output::default();
+    let writer = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    tracing::subscriber::with_default(subscriber, f);
+    output.lines()
+}
+
+#[test]
+fn flush_emits_a_promotion_event() {
+    let dir = tempfile::tempdir().unwrap();
+    let lines = with_output(|| {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        for i in 0..10 {
+            db.insert(format!("key-{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        db.flush().unwrap();
+    });
+
+    let nursery = lines
+        .iter()
+        .find(|line| line.contains("promoted the nursery"))
+        .expect("no nursery promotion event");
+    assert!(nursery.contains("level=10"), "{nursery}");
+    assert!(nursery.contains("entries=10"), "{nursery}");
+    assert!(nursery.contains("nursery.data"), "{nursery}");
+
+    let level = lines
+        .iter()
+        .find(|line| line.contains("promoted a file into the level"))
+        .expect("no level promotion event");
+    assert!(level.contains("level=10"), "{level}");
+    assert!(level.contains("A-10.data"), "{level}");
+    assert!(level.contains("entries=10"), "{level}");
+}
+
+#[test]
+fn merges_emit_completion_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let lines = with_output(|| {
+        let mut db = HanoiDB::open(&dir).unwrap();
+        for i in 0..3000 {
+            db.insert(format!("key-{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        db.compact().unwrap();
+    });
+
+    let merge = lines
+        .iter()
+        .find(|line| line.contains("completed a merge"))
+        .expect("no merge event");
+    assert!(merge.contains("X-10.data"), "{merge}");
+    assert!(merge.contains("outcome="), "{merge}");
+}